@@ -1,6 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use image::{codecs::jpeg::JpegEncoder, ImageBuffer, Rgb, RgbImage};
 use rand::Rng;
+use std::fs::File;
 use std::io::Cursor;
 use toojpeg::{encode_jpeg, EncodeOptions, ImageFormat};
 
@@ -43,6 +44,28 @@ fn bench_toojpeg(c: &mut Criterion) {
     });
 }
 
+// Unbuffered sinks are where per-byte writes hurt most: every write is a syscall
+fn bench_toojpeg_unbuffered_file(c: &mut Criterion) {
+    let (pixels, _) = generate_test_image(1024, 768);
+    let path = std::env::temp_dir().join("toojpeg_bench_unbuffered.jpg");
+
+    c.bench_function("encode_1024x768_toojpeg_quality90_unbuffered_file", |b| {
+        b.iter(|| {
+            let mut file = File::create(&path).unwrap();
+            let options = EncodeOptions {
+                width: 1024,
+                height: 768,
+                format: ImageFormat::RGB,
+                quality: 90,
+                ..Default::default()
+            };
+            encode_jpeg(black_box(&pixels), options, &mut file).unwrap();
+        })
+    });
+
+    let _ = std::fs::remove_file(&path);
+}
+
 fn bench_image_crate(c: &mut Criterion) {
     let (_, img) = generate_test_image(1024, 768);
     
@@ -56,5 +79,5 @@ fn bench_image_crate(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_toojpeg, bench_image_crate);
+criterion_group!(benches, bench_toojpeg, bench_toojpeg_unbuffered_file, bench_image_crate);
 criterion_main!(benches);
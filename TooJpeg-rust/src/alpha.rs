@@ -0,0 +1,62 @@
+//! Flattening of transparent images, JPEG itself has no alpha channel.
+
+/// What shows through transparent pixels when RGBA is flattened to RGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    /// A solid RGB color
+    Color([u8; 3]),
+    /// White and light gray squares of the given size in pixels, as image editors show transparency
+    Checkerboard(u32),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color([255, 255, 255])
+    }
+}
+
+impl Background {
+    // Background color at a pixel position
+    fn at(self, x: usize, y: usize) -> [u8; 3] {
+        match self {
+            Background::Color(color) => color,
+            Background::Checkerboard(size) => {
+                let size = size.max(1) as usize;
+                if (x / size + y / size).is_multiple_of(2) {
+                    [255, 255, 255]
+                } else {
+                    [204, 204, 204]
+                }
+            }
+        }
+    }
+}
+
+/// Composite RGBA rows (straight, not premultiplied alpha) onto a background
+///
+/// # Arguments
+/// * `rgba` - Input rows, 4 bytes per pixel
+/// * `rgb` - Output rows, 3 bytes per pixel
+/// * `width` - Row width in pixels
+/// * `first_row` - Image row of the first input row, keeps a checkerboard aligned across strips
+/// * `background` - What transparent pixels are blended with
+pub fn composite_alpha(rgba: &[u8], rgb: &mut [u8], width: usize, first_row: usize, background: Background) {
+    let rows = rgba.chunks_exact(width * 4).zip(rgb.chunks_exact_mut(width * 3));
+    for (row, (rgba_row, rgb_row)) in rows.enumerate() {
+        for (x, (pixel, out)) in rgba_row.chunks_exact(4).zip(rgb_row.chunks_exact_mut(3)).enumerate() {
+            let alpha = pixel[3] as u32;
+            let back = background.at(x, first_row + row);
+            for channel in 0..3 {
+                let blended = pixel[channel] as u32 * alpha + back[channel] as u32 * (255 - alpha);
+                out[channel] = ((blended + 127) / 255) as u8;
+            }
+        }
+    }
+}
+
+/// Copy the alpha channel of RGBA pixels into a grayscale buffer, e.g. for a mask sidecar
+pub fn extract_alpha(rgba: &[u8], alpha: &mut [u8]) {
+    for (pixel, out) in rgba.chunks_exact(4).zip(alpha.iter_mut()) {
+        *out = pixel[3];
+    }
+}
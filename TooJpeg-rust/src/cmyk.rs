@@ -0,0 +1,57 @@
+//! RGB to CMYK separation without color management.
+//!
+//! Device-independent approximations for print-prep pipelines that have no ICC profile at
+//! hand; an accurate separation needs the press profile.
+
+/// Convert RGB pixels to CMYK ink coverage with full gray component replacement
+///
+/// The textbook formula `K = 1 - max(R, G, B)`, with C, M and Y covering the rest,
+/// so every neutral tone is printed with black ink only.
+///
+/// # Arguments
+/// * `rgb` - Input pixels, 3 bytes each
+/// * `cmyk` - Output pixels, 4 bytes each (0 = no ink, 255 = full coverage)
+pub fn rgb_to_cmyk(rgb: &[u8], cmyk: &mut [u8]) {
+    for (pixel, ink) in rgb.chunks_exact(3).zip(cmyk.chunks_exact_mut(4)) {
+        let max = pixel[0].max(pixel[1]).max(pixel[2]) as u32;
+        if max == 0 {
+            ink.copy_from_slice(&[0, 0, 0, 255]);
+            continue;
+        }
+        for channel in 0..3 {
+            ink[channel] = (((max - pixel[channel] as u32) * 255 + max / 2) / max) as u8;
+        }
+        ink[3] = 255 - max as u8;
+    }
+}
+
+/// Convert RGB pixels to CMYK ink coverage with under-color removal and an ink limit
+///
+/// Starts from `C = 1 - R`, `M = 1 - G`, `Y = 1 - B`, moves `black_generation` (0.0 to 1.0)
+/// of the gray component `min(C, M, Y)` to the black channel, then scales C, M and Y down
+/// where the total coverage would exceed `ink_limit` (e.g. 3.0 for the 300% typical of
+/// coated paper).
+///
+/// # Arguments
+/// * `rgb` - Input pixels, 3 bytes each
+/// * `cmyk` - Output pixels, 4 bytes each (0 = no ink, 255 = full coverage)
+/// * `black_generation` - Share of the gray component printed with black ink
+/// * `ink_limit` - Maximum total coverage, 4.0 disables the limit
+pub fn rgb_to_cmyk_ucr(rgb: &[u8], cmyk: &mut [u8], black_generation: f32, ink_limit: f32) {
+    let black_generation = black_generation.clamp(0.0, 1.0);
+    for (pixel, ink) in rgb.chunks_exact(3).zip(cmyk.chunks_exact_mut(4)) {
+        let [c, m, y] = [0, 1, 2].map(|channel| 1.0 - pixel[channel] as f32 / 255.0);
+        let k = black_generation * c.min(m).min(y);
+        let mut colors = [c - k, m - k, y - k];
+
+        let total: f32 = colors.iter().sum();
+        let budget = (ink_limit - k).max(0.0);
+        if total > budget {
+            colors.iter_mut().for_each(|value| *value *= budget / total);
+        }
+
+        for (target, value) in ink.iter_mut().zip(colors.into_iter().chain([k])) {
+            *target = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
@@ -0,0 +1,878 @@
+//! Baseline and progressive JPEG decoding.
+//!
+//! Reads 8-bit Huffman-coded JPEGs (SOF0, SOF1 and SOF2) with one, three or four components,
+//! which covers the encoder's own output and nearly every camera, web or print JPEG.
+//! 12-bit extended JPEGs are read too and scaled to 8-bit pixels.
+
+use crate::toojpeg::ZIGZAG_INV;
+use crate::ImageFormat;
+
+/// A decoded image
+#[derive(Debug, Clone)]
+pub struct DecodedImage {
+    /// Image width in pixels
+    pub width: u32,
+    /// Image height in pixels
+    pub height: u32,
+    /// Layout of `pixels`: `ImageFormat::RGB`, `ImageFormat::Gray` or `ImageFormat::Cmyk`
+    pub format: ImageFormat,
+    /// Pixel data, row by row without padding
+    pub pixels: Vec<u8>,
+}
+
+/// Decode a baseline or progressive JPEG to RGB, grayscale or CMYK pixels
+///
+/// # Arguments
+/// * `data` - The complete JPEG file, starting with the SOI marker
+///
+/// # Returns
+/// The decoded image or an error message
+pub fn decode_jpeg(data: &[u8]) -> Result<DecodedImage, &'static str> {
+    read_coefficients(data)?.to_pixels()
+}
+
+/// One color component of a frame
+pub(crate) struct Component {
+    pub id: u8,
+    pub h: usize,
+    pub v: usize,
+    pub quant_index: usize,
+    /// Blocks covered by the image (the block grid of a non-interleaved scan)
+    pub blocks_wide: usize,
+    pub blocks_high: usize,
+    /// Blocks allocated, padded to whole MCUs
+    pub stride_blocks: usize,
+    pub rows_blocks: usize,
+    /// Quantized coefficients in natural (row-major) order, 64 per block
+    pub coefficients: Vec<i16>,
+}
+
+/// The entropy-decoded contents of a JPEG file
+pub(crate) struct JpegCoefficients {
+    pub width: usize,
+    pub height: usize,
+    pub max_h: usize,
+    pub max_v: usize,
+    pub mcus_wide: usize,
+    pub mcus_high: usize,
+    pub components: Vec<Component>,
+    /// Sample precision in bits, 8 or 12
+    pub precision: u8,
+    /// Quantization tables in natural order
+    pub quant_tables: [[u16; 64]; 4],
+    pub progressive: bool,
+    pub has_jfif: bool,
+    /// Color transform flag of an Adobe APP14 segment
+    pub adobe_transform: Option<u8>,
+    /// APPn and COM segments in file order, as (marker, payload)
+    pub segments: Vec<(u8, Vec<u8>)>,
+}
+
+/// Canonical Huffman table with a 9-bit fast lookup
+struct HuffmanTable {
+    // (code length << 8) | value for every 9-bit prefix of a code up to 9 bits, 0 otherwise
+    lookup: Vec<u16>,
+    max_code: [i32; 17],
+    value_offset: [i32; 17],
+    values: Vec<u8>,
+}
+
+const LOOKUP_BITS: usize = 9;
+
+impl HuffmanTable {
+    fn new(counts: &[u8], values: &[u8]) -> Result<Self, &'static str> {
+        let mut lookup = vec![0u16; 1 << LOOKUP_BITS];
+        let mut max_code = [-1i32; 17];
+        let mut value_offset = [0i32; 17];
+
+        let mut code = 0usize;
+        let mut k = 0usize;
+        for length in 1..=16 {
+            let count = counts[length - 1] as usize;
+            value_offset[length] = k as i32 - code as i32;
+            for _ in 0..count {
+                if code >= 1 << length {
+                    return Err("Invalid Huffman table");
+                }
+                if length <= LOOKUP_BITS {
+                    let shift = LOOKUP_BITS - length;
+                    for fill in 0..1 << shift {
+                        lookup[(code << shift) | fill] = ((length as u16) << 8) | values[k] as u16;
+                    }
+                }
+                code += 1;
+                k += 1;
+            }
+            if count > 0 {
+                max_code[length] = code as i32 - 1;
+            }
+            code <<= 1;
+        }
+
+        Ok(Self { lookup, max_code, value_offset, values: values.to_vec() })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, &'static str> {
+        let bits = reader.peek(16);
+        let entry = self.lookup[(bits >> (16 - LOOKUP_BITS)) as usize];
+        if entry != 0 {
+            reader.consume((entry >> 8) as u32);
+            return Ok(entry as u8);
+        }
+        for length in LOOKUP_BITS + 1..=16 {
+            let code = (bits >> (16 - length)) as i32;
+            if code <= self.max_code[length] {
+                reader.consume(length as u32);
+                return Ok(self.values[(code + self.value_offset[length]) as usize]);
+            }
+        }
+        Err("Invalid Huffman code")
+    }
+}
+
+/// Reads entropy-coded data, removing stuffed zero bytes and stopping at the next marker
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u64,
+    num_bits: u32,
+    // Once a marker is reached the reader only returns zero bits
+    at_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos, bits: 0, num_bits: 0, at_marker: false }
+    }
+
+    fn fill(&mut self) {
+        while self.num_bits <= 56 {
+            let mut byte = 0;
+            if !self.at_marker && self.pos < self.data.len() {
+                let value = self.data[self.pos];
+                if value != 0xFF {
+                    byte = value;
+                    self.pos += 1;
+                } else if self.data.get(self.pos + 1) == Some(&0) {
+                    byte = 0xFF;
+                    self.pos += 2;
+                } else {
+                    self.at_marker = true;
+                }
+            }
+            self.bits |= (byte as u64) << (56 - self.num_bits);
+            self.num_bits += 8;
+        }
+    }
+
+    /// Look at the next `count` bits (1 to 16) without consuming them
+    fn peek(&mut self, count: u32) -> u32 {
+        if self.num_bits < count {
+            self.fill();
+        }
+        (self.bits >> (64 - count)) as u32
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.bits <<= count;
+        self.num_bits -= count;
+    }
+
+    fn read_bits(&mut self, count: u32) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+        let value = self.peek(count);
+        self.consume(count);
+        value
+    }
+
+    fn read_bit(&mut self) -> bool {
+        self.read_bits(1) == 1
+    }
+
+    /// Read a `size`-bit magnitude and sign-extend it (F.2.2.1 of the spec)
+    fn receive_extend(&mut self, size: u8) -> Result<i32, &'static str> {
+        if size == 0 {
+            return Ok(0);
+        }
+        if size > 16 {
+            return Err("Invalid coefficient size");
+        }
+        let value = self.read_bits(size as u32) as i32;
+        Ok(if value < 1 << (size - 1) { value - (1 << size) + 1 } else { value })
+    }
+
+    fn skip_to_marker(&mut self) {
+        while self.pos + 1 < self.data.len() && !(self.data[self.pos] == 0xFF && self.data[self.pos + 1] != 0) {
+            self.pos += 1;
+        }
+        self.at_marker = true;
+    }
+
+    /// Drop the padding bits and step over the next RSTn marker
+    fn restart(&mut self) -> Result<(), &'static str> {
+        self.bits = 0;
+        self.num_bits = 0;
+        if !self.at_marker {
+            self.skip_to_marker();
+        }
+        // Markers may be preceded by any number of 0xFF fill bytes
+        while self.data.get(self.pos + 1) == Some(&0xFF) {
+            self.pos += 1;
+        }
+        match self.data.get(self.pos + 1) {
+            Some(0xD0..=0xD7) => {
+                self.pos += 2;
+                self.at_marker = false;
+                Ok(())
+            }
+            _ => Err("Missing restart marker"),
+        }
+    }
+
+    /// Position of the marker that ends the scan
+    fn end_position(&mut self) -> usize {
+        if !self.at_marker {
+            self.skip_to_marker();
+        }
+        self.pos
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<usize, &'static str> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(((bytes[0] as usize) << 8) | bytes[1] as usize),
+        None => Err("Unexpected end of data"),
+    }
+}
+
+/// Find the next marker at or after `pos` and step over it
+fn next_marker(data: &[u8], pos: &mut usize) -> Option<u8> {
+    while *pos + 1 < data.len() {
+        let marker = data[*pos + 1];
+        if data[*pos] == 0xFF && marker != 0xFF && marker != 0 {
+            *pos += 2;
+            return Some(marker);
+        }
+        *pos += 1;
+    }
+    None
+}
+
+/// Parse a JPEG file and entropy-decode all of its scans
+pub(crate) fn read_coefficients(data: &[u8]) -> Result<JpegCoefficients, &'static str> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return Err("Missing SOI marker");
+    }
+
+    let mut jpeg = JpegCoefficients {
+        width: 0,
+        height: 0,
+        max_h: 1,
+        max_v: 1,
+        mcus_wide: 0,
+        mcus_high: 0,
+        components: Vec::new(),
+        precision: 8,
+        quant_tables: [[0; 64]; 4],
+        progressive: false,
+        has_jfif: false,
+        adobe_transform: None,
+        segments: Vec::new(),
+    };
+    let mut dc_tables: [Option<HuffmanTable>; 4] = Default::default();
+    let mut ac_tables: [Option<HuffmanTable>; 4] = Default::default();
+    let mut restart_interval = 0;
+    let mut has_frame = false;
+    let mut has_scan = false;
+
+    let mut pos = 2;
+    // A missing EOI is tolerated, truncated files end the same way
+    while let Some(marker) = next_marker(data, &mut pos) {
+        match marker {
+            0xD9 => break,
+            // Standalone markers without a length
+            0x01 | 0xD0..=0xD8 => continue,
+            _ => {}
+        }
+
+        let length = read_u16(data, pos)?;
+        if length < 2 || pos + length > data.len() {
+            return Err("Truncated segment");
+        }
+        let segment = &data[pos + 2..pos + length];
+        pos += length;
+
+        match marker {
+            0xDB => read_quant_tables(segment, &mut jpeg.quant_tables)?,
+            0xC4 => read_huffman_tables(segment, &mut dc_tables, &mut ac_tables)?,
+            0xC0..=0xC2 => {
+                if has_frame {
+                    return Err("Multiple frames are not supported");
+                }
+                read_frame(segment, marker == 0xC2, &mut jpeg)?;
+                has_frame = true;
+            }
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err("Unsupported JPEG coding process");
+            }
+            0xDD => restart_interval = read_u16(segment, 0)?,
+            0xDA => {
+                if !has_frame {
+                    return Err("Scan before frame header");
+                }
+                pos = decode_scan(data, pos, segment, &mut jpeg, &dc_tables, &ac_tables, restart_interval)?;
+                has_scan = true;
+            }
+            0xE0..=0xEF | 0xFE => {
+                if marker == 0xE0 && segment.starts_with(b"JFIF\0") {
+                    jpeg.has_jfif = true;
+                }
+                if marker == 0xEE && segment.len() >= 12 && segment.starts_with(b"Adobe") {
+                    jpeg.adobe_transform = Some(segment[11]);
+                }
+                jpeg.segments.push((marker, segment.to_vec()));
+            }
+            // DNL, DHP, EXP and JPGn carry nothing we need
+            _ => {}
+        }
+    }
+
+    if !has_scan {
+        return Err("No image data found");
+    }
+    Ok(jpeg)
+}
+
+fn read_quant_tables(segment: &[u8], tables: &mut [[u16; 64]; 4]) -> Result<(), &'static str> {
+    let mut pos = 0;
+    while pos < segment.len() {
+        let precision = segment[pos] >> 4;
+        let index = (segment[pos] & 15) as usize;
+        pos += 1;
+        if index > 3 || precision > 1 {
+            return Err("Invalid quantization table");
+        }
+        let size = 64 << precision;
+        let values = segment.get(pos..pos + size).ok_or("Truncated quantization table")?;
+        for (zig, &natural) in ZIGZAG_INV.iter().enumerate() {
+            tables[index][natural as usize] = if precision == 0 {
+                values[zig] as u16
+            } else {
+                ((values[2 * zig] as u16) << 8) | values[2 * zig + 1] as u16
+            };
+        }
+        pos += size;
+    }
+    Ok(())
+}
+
+fn read_huffman_tables(
+    segment: &[u8],
+    dc_tables: &mut [Option<HuffmanTable>; 4],
+    ac_tables: &mut [Option<HuffmanTable>; 4],
+) -> Result<(), &'static str> {
+    let mut pos = 0;
+    while pos < segment.len() {
+        let class = segment[pos] >> 4;
+        let index = (segment[pos] & 15) as usize;
+        if index > 3 || class > 1 {
+            return Err("Invalid Huffman table");
+        }
+        let counts = segment.get(pos + 1..pos + 17).ok_or("Truncated Huffman table")?;
+        let total: usize = counts.iter().map(|&count| count as usize).sum();
+        let values = segment.get(pos + 17..pos + 17 + total).ok_or("Truncated Huffman table")?;
+        let table = HuffmanTable::new(counts, values)?;
+        if class == 0 {
+            dc_tables[index] = Some(table);
+        } else {
+            ac_tables[index] = Some(table);
+        }
+        pos += 17 + total;
+    }
+    Ok(())
+}
+
+fn read_frame(segment: &[u8], progressive: bool, jpeg: &mut JpegCoefficients) -> Result<(), &'static str> {
+    if segment.len() < 6 {
+        return Err("Truncated frame header");
+    }
+    if segment[0] != 8 && segment[0] != 12 {
+        return Err("Only 8-bit and 12-bit JPEGs are supported");
+    }
+    jpeg.precision = segment[0];
+    let height = read_u16(segment, 1)?;
+    let width = read_u16(segment, 3)?;
+    let num_components = segment[5] as usize;
+    if width == 0 || height == 0 {
+        return Err("Invalid image dimensions");
+    }
+    if num_components == 0 || segment.len() < 6 + 3 * num_components {
+        return Err("Invalid frame header");
+    }
+
+    let mut components = Vec::with_capacity(num_components);
+    for spec in segment[6..6 + 3 * num_components].chunks_exact(3) {
+        let (h, v) = ((spec[1] >> 4) as usize, (spec[1] & 15) as usize);
+        if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+            return Err("Invalid sampling factor");
+        }
+        if spec[2] > 3 {
+            return Err("Invalid quantization table");
+        }
+        components.push((spec[0], h, v, spec[2] as usize));
+    }
+
+    let max_h = components.iter().map(|c| c.1).max().unwrap_or(1);
+    let max_v = components.iter().map(|c| c.2).max().unwrap_or(1);
+    let mcus_wide = width.div_ceil(8 * max_h);
+    let mcus_high = height.div_ceil(8 * max_v);
+
+    jpeg.width = width;
+    jpeg.height = height;
+    jpeg.max_h = max_h;
+    jpeg.max_v = max_v;
+    jpeg.mcus_wide = mcus_wide;
+    jpeg.mcus_high = mcus_high;
+    jpeg.progressive = progressive;
+    jpeg.components = components
+        .into_iter()
+        .map(|(id, h, v, quant_index)| {
+            let component_width = (width * h).div_ceil(max_h);
+            let component_height = (height * v).div_ceil(max_v);
+            let stride_blocks = mcus_wide * h;
+            let rows_blocks = mcus_high * v;
+            Component {
+                id,
+                h,
+                v,
+                quant_index,
+                blocks_wide: component_width.div_ceil(8),
+                blocks_high: component_height.div_ceil(8),
+                stride_blocks,
+                rows_blocks,
+                coefficients: vec![0; stride_blocks * rows_blocks * 64],
+            }
+        })
+        .collect();
+    Ok(())
+}
+
+/// Which part of the coefficients a scan carries
+#[derive(Clone, Copy)]
+enum ScanKind {
+    Baseline,
+    DcFirst,
+    DcRefine,
+    AcFirst,
+    AcRefine,
+}
+
+struct Scan<'t> {
+    kind: ScanKind,
+    start: usize,
+    end: usize,
+    low_bit: u8,
+    // Index into the frame's components and the tables to use
+    components: Vec<(usize, Option<&'t HuffmanTable>, Option<&'t HuffmanTable>)>,
+}
+
+/// Decode one scan starting at `pos`, returns the position of the marker following it
+fn decode_scan(
+    data: &[u8],
+    pos: usize,
+    header: &[u8],
+    jpeg: &mut JpegCoefficients,
+    dc_tables: &[Option<HuffmanTable>; 4],
+    ac_tables: &[Option<HuffmanTable>; 4],
+    restart_interval: usize,
+) -> Result<usize, &'static str> {
+    let num_components = *header.first().ok_or("Truncated scan header")? as usize;
+    if num_components == 0 || num_components > 4 || header.len() < 4 + 2 * num_components {
+        return Err("Invalid scan header");
+    }
+    let params = &header[1 + 2 * num_components..];
+    let (start, end) = (params[0] as usize, params[1] as usize);
+    let (high_bit, low_bit) = (params[2] >> 4, params[2] & 15);
+
+    let kind = if !jpeg.progressive {
+        if start != 0 || end != 63 || high_bit != 0 || low_bit != 0 {
+            return Err("Invalid baseline scan");
+        }
+        ScanKind::Baseline
+    } else {
+        if end > 63 || start > end || (start == 0 && end != 0) || low_bit > 13 {
+            return Err("Invalid progressive scan");
+        }
+        if start > 0 && num_components != 1 {
+            return Err("AC scans must contain a single component");
+        }
+        match (start == 0, high_bit == 0) {
+            (true, true) => ScanKind::DcFirst,
+            (true, false) => ScanKind::DcRefine,
+            (false, true) => ScanKind::AcFirst,
+            (false, false) => ScanKind::AcRefine,
+        }
+    };
+    let needs_dc = matches!(kind, ScanKind::Baseline | ScanKind::DcFirst);
+    let needs_ac = matches!(kind, ScanKind::Baseline | ScanKind::AcFirst | ScanKind::AcRefine);
+
+    let mut components = Vec::with_capacity(num_components);
+    for spec in header[1..1 + 2 * num_components].chunks_exact(2) {
+        let index = jpeg
+            .components
+            .iter()
+            .position(|c| c.id == spec[0])
+            .ok_or("Scan references an unknown component")?;
+        let dc = dc_tables[(spec[1] >> 4) as usize & 3].as_ref();
+        let ac = ac_tables[(spec[1] & 15) as usize & 3].as_ref();
+        if (needs_dc && dc.is_none()) || (needs_ac && ac.is_none()) {
+            return Err("Missing Huffman table");
+        }
+        components.push((index, dc, ac));
+    }
+    let scan = Scan { kind, start, end, low_bit, components };
+
+    // A single-component scan covers that component's blocks one at a time,
+    // otherwise every MCU holds h x v blocks of each component
+    let (mcus_wide, mcus_high) = if num_components == 1 {
+        let component = &jpeg.components[scan.components[0].0];
+        (component.blocks_wide, component.blocks_high)
+    } else {
+        (jpeg.mcus_wide, jpeg.mcus_high)
+    };
+
+    let mut reader = BitReader::new(data, pos);
+    let mut predictions = [0i32; 4];
+    let mut eob_run = 0u32;
+    for mcu in 0..mcus_wide * mcus_high {
+        if restart_interval > 0 && mcu > 0 && mcu.is_multiple_of(restart_interval) {
+            reader.restart()?;
+            predictions = [0; 4];
+            eob_run = 0;
+        }
+        let (mcu_x, mcu_y) = (mcu % mcus_wide, mcu / mcus_wide);
+
+        for (i, &(index, dc, ac)) in scan.components.iter().enumerate() {
+            let component = &mut jpeg.components[index];
+            let (h, v) = if num_components == 1 { (1, 1) } else { (component.h, component.v) };
+            for y in 0..v {
+                for x in 0..h {
+                    let row = mcu_y * v + y;
+                    let column = mcu_x * h + x;
+                    let offset = (row * component.stride_blocks + column) * 64;
+                    let block = &mut component.coefficients[offset..offset + 64];
+                    match scan.kind {
+                        ScanKind::Baseline => {
+                            decode_dc_first(&mut reader, dc.unwrap(), &mut predictions[i], block, 0)?;
+                            decode_ac_first(&mut reader, ac.unwrap(), block, 1, 63, 0, &mut eob_run)?;
+                        }
+                        ScanKind::DcFirst => {
+                            decode_dc_first(&mut reader, dc.unwrap(), &mut predictions[i], block, scan.low_bit)?;
+                        }
+                        ScanKind::DcRefine => {
+                            if reader.read_bit() {
+                                block[0] |= 1 << scan.low_bit;
+                            }
+                        }
+                        ScanKind::AcFirst => {
+                            decode_ac_first(&mut reader, ac.unwrap(), block, scan.start, scan.end, scan.low_bit, &mut eob_run)?;
+                        }
+                        ScanKind::AcRefine => {
+                            decode_ac_refine(&mut reader, ac.unwrap(), block, scan.start, scan.end, scan.low_bit, &mut eob_run)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(reader.end_position())
+}
+
+fn decode_dc_first(
+    reader: &mut BitReader,
+    table: &HuffmanTable,
+    prediction: &mut i32,
+    block: &mut [i16],
+    low_bit: u8,
+) -> Result<(), &'static str> {
+    let size = table.decode(reader)?;
+    *prediction += reader.receive_extend(size)?;
+    block[0] = (*prediction << low_bit) as i16;
+    Ok(())
+}
+
+fn decode_ac_first(
+    reader: &mut BitReader,
+    table: &HuffmanTable,
+    block: &mut [i16],
+    start: usize,
+    end: usize,
+    low_bit: u8,
+    eob_run: &mut u32,
+) -> Result<(), &'static str> {
+    if *eob_run > 0 {
+        *eob_run -= 1;
+        return Ok(());
+    }
+    let mut k = start;
+    while k <= end {
+        let symbol = table.decode(reader)?;
+        let (run, size) = ((symbol >> 4) as usize, symbol & 15);
+        if size == 0 {
+            if run < 15 {
+                // End of band, possibly covering the following blocks too
+                *eob_run = (1 << run) - 1 + reader.read_bits(run as u32);
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        if k > end {
+            return Err("Coefficient index out of range");
+        }
+        block[ZIGZAG_INV[k] as usize] = (reader.receive_extend(size)? << low_bit) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// Successive approximation of AC coefficients (G.1.2.3 of the spec)
+fn decode_ac_refine(
+    reader: &mut BitReader,
+    table: &HuffmanTable,
+    block: &mut [i16],
+    start: usize,
+    end: usize,
+    low_bit: u8,
+    eob_run: &mut u32,
+) -> Result<(), &'static str> {
+    let positive = 1i16 << low_bit;
+    let negative = -1i16 << low_bit;
+
+    // Already nonzero coefficients get one correction bit each
+    let refine = |reader: &mut BitReader, coefficient: &mut i16| {
+        if reader.read_bit() && *coefficient & positive == 0 {
+            *coefficient += if *coefficient >= 0 { positive } else { negative };
+        }
+    };
+
+    let mut k = start;
+    if *eob_run == 0 {
+        while k <= end {
+            let symbol = table.decode(reader)?;
+            let (mut run, size) = ((symbol >> 4) as i32, symbol & 15);
+            let mut value = 0;
+            if size != 0 {
+                if size != 1 {
+                    return Err("Invalid refinement coefficient");
+                }
+                value = if reader.read_bit() { positive } else { negative };
+            } else if run != 15 {
+                *eob_run = (1 << run) + reader.read_bits(run as u32);
+                break;
+            }
+
+            // Skip `run` zero coefficients, refining the nonzero ones on the way
+            while k <= end {
+                let coefficient = &mut block[ZIGZAG_INV[k] as usize];
+                if *coefficient != 0 {
+                    refine(reader, coefficient);
+                } else {
+                    if run == 0 {
+                        break;
+                    }
+                    run -= 1;
+                }
+                k += 1;
+            }
+            if value != 0 && k <= end {
+                block[ZIGZAG_INV[k] as usize] = value;
+            }
+            k += 1;
+        }
+    }
+
+    if *eob_run > 0 {
+        while k <= end {
+            let coefficient = &mut block[ZIGZAG_INV[k] as usize];
+            if *coefficient != 0 {
+                refine(reader, coefficient);
+            }
+            k += 1;
+        }
+        *eob_run -= 1;
+    }
+    Ok(())
+}
+
+/// Inverse DCT basis: `IDCT_TABLE[x][u] = C(u) / 2 * cos((2x + 1) * u * pi / 16)`
+fn idct_table() -> [[f32; 8]; 8] {
+    let mut table = [[0.0; 8]; 8];
+    for (x, row) in table.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            let scale = if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+            *value = 0.5 * scale * (((2 * x + 1) * u) as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+    table
+}
+
+/// Dequantize and inverse transform one block into `output` (8 rows of `stride` bytes)
+///
+/// 12-bit samples are level shifted by 2048 instead of 128 and scaled down to 8 bits.
+fn idct_block(
+    coefficients: &[i16],
+    quant: &[u16; 64],
+    table: &[[f32; 8]; 8],
+    precision: u8,
+    output: &mut [u8],
+    stride: usize,
+) {
+    let (offset, scale) = if precision == 12 { (2048.0, 1.0 / 16.0) } else { (128.0, 1.0) };
+    if coefficients[1..].iter().all(|&c| c == 0) {
+        // Flat block, the common case for smooth areas
+        let value = coefficients[0] as f32 * quant[0] as f32 / 8.0;
+        let value = ((value + offset) * scale).round().clamp(0.0, 255.0) as u8;
+        for y in 0..8 {
+            output[y * stride..y * stride + 8].fill(value);
+        }
+        return;
+    }
+
+    let mut rows = [[0.0f32; 8]; 8];
+    for v in 0..8 {
+        for x in 0..8 {
+            rows[v][x] = (0..8)
+                .map(|u| table[x][u] * coefficients[v * 8 + u] as f32 * quant[v * 8 + u] as f32)
+                .sum();
+        }
+    }
+    for y in 0..8 {
+        for x in 0..8 {
+            let value: f32 = (0..8).map(|v| table[y][v] * rows[v][x]).sum();
+            output[y * stride + x] = ((value + offset) * scale).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Source positions and weights for linear upsampling of one axis, centered like the encoder's averaging
+fn upsample_weights(size: usize, factor: usize, max_factor: usize, samples: usize) -> Vec<(usize, usize, f32)> {
+    (0..size)
+        .map(|i| {
+            let position = ((i as f32 + 0.5) * factor as f32 / max_factor as f32 - 0.5).max(0.0);
+            let first = (position as usize).min(samples - 1);
+            let second = (first + 1).min(samples - 1);
+            (first, second, position - first as f32)
+        })
+        .collect()
+}
+
+impl JpegCoefficients {
+    /// Inverse transform every block of a component into a plane padded to whole MCUs
+    fn component_plane(&self, component: &Component, table: &[[f32; 8]; 8]) -> Vec<u8> {
+        let stride = component.stride_blocks * 8;
+        let mut plane = vec![0u8; stride * component.rows_blocks * 8];
+        let quant = &self.quant_tables[component.quant_index];
+        for (i, block) in component.coefficients.chunks_exact(64).enumerate() {
+            let (row, column) = (i / component.stride_blocks, i % component.stride_blocks);
+            idct_block(block, quant, table, self.precision, &mut plane[row * 8 * stride + column * 8..], stride);
+        }
+        plane
+    }
+
+    /// Whether a three-component image stores YCbCr rather than RGB, or a four-component
+    /// one YCCK rather than CMYK
+    fn is_ycbcr(&self) -> bool {
+        if self.components.len() == 4 {
+            return self.adobe_transform == Some(2);
+        }
+        match self.adobe_transform {
+            Some(transform) => transform != 0,
+            None => self.has_jfif || self.components.iter().map(|c| c.id).ne(*b"RGB"),
+        }
+    }
+
+    pub(crate) fn to_pixels(&self) -> Result<DecodedImage, &'static str> {
+        let format = match self.components.len() {
+            1 => ImageFormat::Gray,
+            3 => ImageFormat::RGB,
+            4 => ImageFormat::Cmyk,
+            _ => return Err("Unsupported number of color components"),
+        };
+        let table = idct_table();
+        let (width, height) = (self.width, self.height);
+
+        let mut samples = Vec::with_capacity(self.components.len());
+        for component in &self.components {
+            let plane = self.component_plane(component, &table);
+            let stride = component.stride_blocks * 8;
+            if component.h == self.max_h && component.v == self.max_v {
+                samples.push(plane);
+                continue;
+            }
+            // Upsample to full resolution, only within the area covered by the image
+            let columns = upsample_weights(width, component.h, self.max_h, (width * component.h).div_ceil(self.max_h));
+            let rows = upsample_weights(height, component.v, self.max_v, (height * component.v).div_ceil(self.max_v));
+            let mut full = vec![0u8; width * height];
+            for (y, &(top, bottom, wy)) in rows.iter().enumerate() {
+                for (x, &(left, right, wx)) in columns.iter().enumerate() {
+                    let sample = |row: usize, column: usize| plane[row * stride + column] as f32;
+                    let upper = sample(top, left) + (sample(top, right) - sample(top, left)) * wx;
+                    let lower = sample(bottom, left) + (sample(bottom, right) - sample(bottom, left)) * wx;
+                    full[y * width + x] = (upper + (lower - upper) * wy).round() as u8;
+                }
+            }
+            samples.push(full);
+        }
+        // Full-resolution components keep their MCU padding, the upsampled ones are exactly `width` wide
+        let strides: Vec<usize> = self
+            .components
+            .iter()
+            .map(|c| if c.h == self.max_h && c.v == self.max_v { c.stride_blocks * 8 } else { width })
+            .collect();
+
+        let mut pixels = Vec::with_capacity(width * height * self.components.len());
+        if format == ImageFormat::Gray {
+            for y in 0..height {
+                pixels.extend_from_slice(&samples[0][y * strides[0]..y * strides[0] + width]);
+            }
+        } else {
+            let ycbcr = self.is_ycbcr();
+            // Adobe stores CMYK inverted (255 = no ink). With YCCK the color part decodes
+            // straight to C, M, Y coverage, only K stays inverted.
+            let inverted = format == ImageFormat::Cmyk && self.adobe_transform.is_some();
+            for y in 0..height {
+                for x in 0..width {
+                    let c0 = samples[0][y * strides[0] + x];
+                    let c1 = samples[1][y * strides[1] + x];
+                    let c2 = samples[2][y * strides[2] + x];
+                    if ycbcr {
+                        pixels.extend_from_slice(&ycbcr_to_rgb(c0, c1, c2));
+                    } else if inverted {
+                        pixels.extend_from_slice(&[255 - c0, 255 - c1, 255 - c2]);
+                    } else {
+                        pixels.extend_from_slice(&[c0, c1, c2]);
+                    }
+                    if format == ImageFormat::Cmyk {
+                        let k = samples[3][y * strides[3] + x];
+                        pixels.push(if inverted { 255 - k } else { k });
+                    }
+                }
+            }
+        }
+
+        Ok(DecodedImage { width: width as u32, height: height as u32, format, pixels })
+    }
+}
+
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (y as f32, cb as f32 - 128.0, cr as f32 - 128.0);
+    let r = y + 1.402 * cr;
+    let g = y - 0.344136 * cb - 0.714136 * cr;
+    let b = y + 1.772 * cb;
+    [r, g, b].map(|value| value.round().clamp(0.0, 255.0) as u8)
+}
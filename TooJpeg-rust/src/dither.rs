@@ -0,0 +1,91 @@
+//! Quantization of high-precision samples to the 8 bits a baseline JPEG takes.
+//!
+//! Rounding 10-bit or float pixels straight to 8 bits turns smooth gradients such as skies
+//! into visible bands; dithering trades those bands for fine noise the eye averages out.
+
+/// How samples are rounded to 8 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Round to the nearest value
+    None,
+    /// 8x8 Bayer matrix, cheap and stable across strips
+    #[default]
+    Ordered,
+    /// Floyd-Steinberg error diffusion, smoother but each call diffuses only within its rows
+    FloydSteinberg,
+}
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Quantize interleaved samples in the range 0.0 to 1.0 to 8 bits
+///
+/// Values outside the range (e.g. resampling overshoot) are clamped.
+///
+/// # Arguments
+/// * `samples` - Input rows, `channels` values per pixel
+/// * `output` - Output rows, same layout as `samples`
+/// * `width` - Row width in pixels
+/// * `channels` - Values per pixel (1 for grayscale, 3 for RGB)
+/// * `first_row` - Image row of the first input row, keeps the ordered pattern aligned across strips
+/// * `dither` - Rounding method
+pub fn dither_to_u8(
+    samples: &[f32],
+    output: &mut [u8],
+    width: usize,
+    channels: usize,
+    first_row: usize,
+    dither: Dither,
+) {
+    let row_len = width * channels;
+    match dither {
+        Dither::None => {
+            for (value, out) in samples.iter().zip(output.iter_mut()) {
+                *out = to_u8(*value * 255.0);
+            }
+        }
+        Dither::Ordered => {
+            let rows = samples.chunks_exact(row_len).zip(output.chunks_exact_mut(row_len));
+            for (row, (in_row, out_row)) in rows.enumerate() {
+                let pattern = &BAYER_8X8[(first_row + row) % 8];
+                for (i, (value, out)) in in_row.iter().zip(out_row.iter_mut()).enumerate() {
+                    let threshold = (pattern[i / channels % 8] as f32 + 0.5) / 64.0 - 0.5;
+                    *out = to_u8(*value * 255.0 + threshold);
+                }
+            }
+        }
+        Dither::FloydSteinberg => {
+            // Error carried into the current and the next row, with a pixel of padding on both sides
+            let mut current = vec![0.0f32; row_len + 2 * channels];
+            let mut next = vec![0.0f32; row_len + 2 * channels];
+            let rows = samples.chunks_exact(row_len).zip(output.chunks_exact_mut(row_len));
+            for (in_row, out_row) in rows {
+                for i in 0..row_len {
+                    let wanted = in_row[i] * 255.0 + current[i + channels];
+                    let quantized = to_u8(wanted);
+                    out_row[i] = quantized;
+
+                    let error = wanted - quantized as f32;
+                    current[i + 2 * channels] += error * 7.0 / 16.0;
+                    next[i] += error * 3.0 / 16.0;
+                    next[i + channels] += error * 5.0 / 16.0;
+                    next[i + 2 * channels] += error / 16.0;
+                }
+                std::mem::swap(&mut current, &mut next);
+                next.fill(0.0);
+            }
+        }
+    }
+}
+
+fn to_u8(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
@@ -0,0 +1,105 @@
+//! EXIF thumbnails: a small JPEG stored in IFD1 of the APP1 Exif segment.
+//!
+//! File managers and cameras show this thumbnail instead of decoding the whole image. The
+//! whole segment, thumbnail included, has to fit in 64 KB, so thumbnails are usually
+//! 160x120 at a modest quality.
+
+// TIFF field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+/// Build an APP1 Exif payload with a JPEG thumbnail in IFD1
+///
+/// The tags of `exif` (IFD0 and the sub-IFDs it points to) are kept as they are; an IFD1
+/// already in `exif` is unlinked and replaced. The result starts with `Exif\0\0` and can be
+/// written with `insert_app_segment(jpeg, 0xE1, ...)`.
+///
+/// # Arguments
+/// * `exif` - Existing TIFF-structured EXIF data, with or without the `Exif\0\0` prefix;
+///   `None` creates a minimal IFD0
+/// * `thumbnail` - A complete baseline JPEG, e.g. a 160x120 copy encoded with `encode_jpeg`
+pub fn exif_with_thumbnail(exif: Option<&[u8]>, thumbnail: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if !thumbnail.starts_with(&[0xFF, 0xD8]) {
+        return Err("Thumbnail must be a JPEG");
+    }
+
+    // A big-endian TIFF header and an IFD0 holding just Orientation = 1
+    const MINIMAL_TIFF: [u8; 26] = [
+        b'M', b'M', 0, 42, 0, 0, 0, 8, // Header, IFD0 at 8
+        0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 1, 0, 0, // Orientation, SHORT, 1 value: 1
+        0, 0, 0, 0, // No IFD1 yet
+    ];
+    let tiff = match exif {
+        Some(exif) => exif.strip_prefix(b"Exif\0\0").unwrap_or(exif),
+        None => &MINIMAL_TIFF,
+    };
+    let big_endian = match tiff.get(..4) {
+        Some(b"MM\0*") => true,
+        Some(b"II*\0") => false,
+        _ => return Err("EXIF data has no TIFF header"),
+    };
+    let read_u16 = |pos: usize| -> Option<u16> {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let read_u32 = |pos: usize| -> Option<u32> {
+        let bytes: [u8; 4] = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+    let ifd0 = read_u32(4).ok_or("Truncated EXIF data")? as usize;
+    let entry_count = read_u16(ifd0).ok_or("Truncated EXIF data")? as usize;
+    let next_ifd_pointer = ifd0 + 2 + 12 * entry_count;
+    if next_ifd_pointer + 4 > tiff.len() {
+        return Err("Truncated EXIF data");
+    }
+
+    let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+    let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+
+    let mut output = b"Exif\0\0".to_vec();
+    output.extend_from_slice(tiff);
+    // IFD offsets have to be even
+    if !output.len().is_multiple_of(2) {
+        output.push(0);
+    }
+    let ifd1 = output.len() - 6;
+    let pointer = 6 + next_ifd_pointer;
+    output[pointer..pointer + 4].copy_from_slice(&u32_bytes(ifd1 as u32));
+
+    // Six entries and the next-IFD pointer, then the two resolutions, then the thumbnail
+    let resolution = ifd1 + 2 + 6 * 12 + 4;
+    let thumbnail_offset = resolution + 16;
+    output.extend_from_slice(&u16_bytes(6));
+    for (tag, kind, value) in [
+        (0x0103, SHORT, 6u32),                    // Compression: JPEG
+        (0x011A, RATIONAL, resolution as u32),    // XResolution
+        (0x011B, RATIONAL, resolution as u32 + 8), // YResolution
+        (0x0128, SHORT, 2),                       // ResolutionUnit: inches
+        (0x0201, LONG, thumbnail_offset as u32),  // JPEGInterchangeFormat
+        (0x0202, LONG, thumbnail.len() as u32),   // JPEGInterchangeFormatLength
+    ] {
+        output.extend_from_slice(&u16_bytes(tag));
+        output.extend_from_slice(&u16_bytes(kind));
+        output.extend_from_slice(&u32_bytes(1));
+        if kind == SHORT {
+            // SHORT values sit left-aligned in the 4-byte field
+            output.extend_from_slice(&u16_bytes(value as u16));
+            output.extend_from_slice(&[0, 0]);
+        } else {
+            output.extend_from_slice(&u32_bytes(value));
+        }
+    }
+    output.extend_from_slice(&u32_bytes(0)); // No IFD2
+    for _ in 0..2 {
+        output.extend_from_slice(&u32_bytes(72));
+        output.extend_from_slice(&u32_bytes(1));
+    }
+    output.extend_from_slice(thumbnail);
+
+    // The APP1 length field counts itself
+    if output.len() + 2 > u16::MAX as usize {
+        return Err("EXIF data and thumbnail don't fit in one APP1 segment");
+    }
+    Ok(output)
+}
@@ -0,0 +1,356 @@
+//! 12-bit extended sequential JPEG encoding (SOF1 with 12-bit sample precision).
+//!
+//! Keeps the precision of 10/12-bit sources for archival. The standard Huffman tables only
+//! cover the coefficient range of 8-bit samples, so the tables are always optimized for the
+//! image in a first pass over its quantized blocks.
+
+use crate::toojpeg::{
+    dct, generate_huffman_table, write_trailer, BitCode, BitWriter, AAN_SCALE_FACTORS, DEFAULT_QUANT_CHROMINANCE,
+    DEFAULT_QUANT_LUMINANCE, U16, U8, ZIGZAG_INV,
+};
+use crate::{EncodeOptions, ImageFormat};
+
+/// Largest sample value accepted by `encode_jpeg_12bit`
+pub const MAX_12BIT_SAMPLE: u16 = 4095;
+
+// Quantized coefficients are clamped so that DC differences stay within 15 magnitude bits
+// and AC values within 14, the limits of the 12-bit process
+const COEFFICIENT_LIMIT: i32 = (1 << 14) - 1;
+
+/// A full-resolution or subsampled plane of level-shifted samples
+struct Plane {
+    samples: Vec<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl Plane {
+    // Sample with the edges repeated past the plane's borders
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.samples[y.min(self.height - 1) * self.width + x.min(self.width - 1)]
+    }
+
+    // Average 2x2 squares, like the 8-bit encoder's 4:2:0 path
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut samples = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let sum = self.at(2 * x, 2 * y)
+                    + self.at(2 * x + 1, 2 * y)
+                    + self.at(2 * x, 2 * y + 1)
+                    + self.at(2 * x + 1, 2 * y + 1);
+                samples.push(sum / 4.0);
+            }
+        }
+        Plane { samples, width, height }
+    }
+}
+
+/// Encode 12-bit samples as an extended sequential JPEG (SOF1, 12-bit precision)
+///
+/// Takes RGB, YCbCr or grayscale samples from 0 to `MAX_12BIT_SAMPLE`; 10-bit data should
+/// be scaled up first. Quantization tables are written with 16-bit entries.
+/// `options.baseline`, `optimized` and `adaptive_quantization` have no effect, and
+/// many viewers (browsers included) cannot open 12-bit JPEGs.
+///
+/// # Arguments
+/// * `pixels` - The image samples in the format specified by `options.format`
+/// * `options` - Encoding options including dimensions, format, and quality
+/// * `output` - A writer that implements `std::io::Write` to receive the JPEG data
+pub fn encode_jpeg_12bit<W: std::io::Write>(
+    pixels: &[u16],
+    options: EncodeOptions,
+    output: &mut W,
+) -> Result<(), &'static str> {
+    let channels = match options.format {
+        ImageFormat::RGB | ImageFormat::YCbCr => 3,
+        ImageFormat::Gray => 1,
+        ImageFormat::RGBA | ImageFormat::Cmyk => return Err("12-bit encoding supports RGB, YCbCr and grayscale input"),
+    };
+    let (width, height) = (options.width as usize, options.height as usize);
+    if width == 0 || height == 0 || width > U16::MAX as usize || height > U16::MAX as usize {
+        return Err("Invalid image dimensions");
+    }
+    if width.checked_mul(height).and_then(|x| x.checked_mul(channels)).is_none_or(|len| pixels.len() < len) {
+        return Err("Input buffer too small for specified dimensions and format");
+    }
+
+    let planes = level_shifted_planes(&pixels[..width * height * channels], options.format, width, height);
+    let subsample = options.subsample && channels == 3;
+    let planes: Vec<Plane> = planes
+        .into_iter()
+        .enumerate()
+        .map(|(i, plane)| if subsample && i > 0 { plane.downsample() } else { plane })
+        .collect();
+
+    let quant_tables = [
+        quant_table(&DEFAULT_QUANT_LUMINANCE, options.quality),
+        quant_table(&DEFAULT_QUANT_CHROMINANCE, options.quality),
+    ];
+
+    // First pass: quantize every block in scan order and count the Huffman symbols
+    let factor = if subsample { 2 } else { 1 };
+    let (mcus_wide, mcus_high) = (width.div_ceil(8 * factor), height.div_ceil(8 * factor));
+    let mut blocks = Vec::new();
+    for mcu_y in 0..mcus_high {
+        for mcu_x in 0..mcus_wide {
+            for (i, plane) in planes.iter().enumerate() {
+                let blocks_per_side = if i == 0 { factor } else { 1 };
+                for block_y in 0..blocks_per_side {
+                    for block_x in 0..blocks_per_side {
+                        let x = (mcu_x * blocks_per_side + block_x) * 8;
+                        let y = (mcu_y * blocks_per_side + block_y) * 8;
+                        blocks.push((i, quantize_block(plane, x, y, &quant_tables[i.min(1)])));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut frequencies = [[0u32; 257]; 4];
+    let mut last_dc = [0i32; 3];
+    for (component, block) in &blocks {
+        let table = if *component == 0 { 0 } else { 2 };
+        let diff = block[0] as i32 - last_dc[*component];
+        last_dc[*component] = block[0] as i32;
+        frequencies[table][magnitude_bits(diff) as usize] += 1;
+        for_each_ac_symbol(block, |symbol, _| frequencies[table + 1][symbol as usize] += 1);
+    }
+
+    let mut writer = BitWriter::new(|bytes: &[u8]| output.write_all(bytes).map_err(|_| "Failed to write output"));
+    writer.write_bytes(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0])?;
+
+    // 16-bit table entries (Pq = 1)
+    let num_tables = if channels == 3 { 2 } else { 1 };
+    writer.add_marker(0xDB, (2 + num_tables * (1 + 128)) as U16)?;
+    for (index, table) in quant_tables.iter().take(num_tables).enumerate() {
+        writer.write_byte(0x10 | index as U8)?;
+        for &natural in ZIGZAG_INV.iter() {
+            let value = table[natural as usize];
+            writer.write_bytes(&[(value >> 8) as U8, value as U8])?;
+        }
+    }
+
+    writer.add_marker(0xC1, (2 + 6 + 3 * channels) as U16)?;
+    writer.write_byte(12)?;
+    writer.write_bytes(&[(height >> 8) as U8, height as U8, (width >> 8) as U8, width as U8])?;
+    writer.write_byte(channels as U8)?;
+    for id in 1..=channels {
+        let sampling = if id == 1 && subsample { 0x22 } else { 0x11 };
+        writer.write_bytes(&[id as U8, sampling, if id == 1 { 0 } else { 1 }])?;
+    }
+
+    // Optimized Huffman tables: luminance DC/AC, then chrominance DC/AC
+    let mut codes = [[BitCode::new(0, 0); 256]; 4];
+    let mut segment = Vec::new();
+    for (table, frequencies) in frequencies.iter().enumerate().take(2 * num_tables) {
+        let (counts, values) = optimal_code_lengths(frequencies);
+        generate_huffman_table(&counts, &values, &mut codes[table]);
+        segment.push((((table % 2) << 4) | (table / 2)) as U8);
+        segment.extend_from_slice(&counts);
+        segment.extend_from_slice(&values);
+    }
+    writer.add_marker(0xC4, (2 + segment.len()) as U16)?;
+    writer.write_bytes(&segment)?;
+
+    writer.add_marker(0xDA, (2 + 1 + 2 * channels + 3) as U16)?;
+    writer.write_byte(channels as U8)?;
+    for id in 1..=channels {
+        writer.write_bytes(&[id as U8, if id == 1 { 0x00 } else { 0x11 }])?;
+    }
+    writer.write_bytes(&[0, 63, 0])?;
+
+    // Second pass: entropy code the stored blocks
+    let mut last_dc = [0i32; 3];
+    for (component, block) in &blocks {
+        let table = if *component == 0 { 0 } else { 2 };
+        let diff = block[0] as i32 - last_dc[*component];
+        last_dc[*component] = block[0] as i32;
+        let (dc_codes, ac_codes) = (&codes[table], &codes[table + 1]);
+
+        let size = magnitude_bits(diff);
+        writer.write_bits(dc_codes[size as usize].code, dc_codes[size as usize].num_bits)?;
+        write_magnitude(&mut writer, diff, size)?;
+
+        let mut result = Ok(());
+        for_each_ac_symbol(block, |symbol, value| {
+            if result.is_ok() {
+                let code = ac_codes[symbol as usize];
+                result = writer
+                    .write_bits(code.code, code.num_bits)
+                    .and_then(|_| write_magnitude(&mut writer, value, symbol & 15));
+            }
+        });
+        result?;
+    }
+
+    write_trailer(&mut writer)
+}
+
+// Split the input into level-shifted planes: Y (or gray) and, for color, Cb and Cr
+fn level_shifted_planes(pixels: &[u16], format: ImageFormat, width: usize, height: usize) -> Vec<Plane> {
+    let plane = |samples: Vec<f32>| Plane { samples, width, height };
+    match format {
+        ImageFormat::Gray => vec![plane(pixels.iter().map(|&v| v as f32 - 2048.0).collect())],
+        ImageFormat::YCbCr => (0..3)
+            .map(|c| plane(pixels.chunks_exact(3).map(|p| p[c] as f32 - 2048.0).collect()))
+            .collect(),
+        _ => {
+            let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+            for pixel in pixels.chunks_exact(3) {
+                let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                planes[0].push(0.299 * r + 0.587 * g + 0.114 * b - 2048.0);
+                planes[1].push(-0.1687 * r - 0.3313 * g + 0.500 * b);
+                planes[2].push(0.500 * r - 0.4187 * g - 0.0813 * b);
+            }
+            planes.into_iter().map(plane).collect()
+        }
+    }
+}
+
+// The 8-bit tables scaled by quality like the baseline encoder's, but with 16-bit entries,
+// in natural order
+fn quant_table(base: &[U8; 64], quality: U8) -> [U16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+    base.map(|value| ((value as u32 * scale + 50) / 100).clamp(1, U16::MAX as u32) as U16)
+}
+
+// Transform and quantize the 8x8 block at (x, y), returning zigzag-ordered coefficients
+fn quantize_block(plane: &Plane, x: usize, y: usize, quant: &[U16; 64]) -> [i16; 64] {
+    let mut block = [0.0f32; 64];
+    for row in 0..8 {
+        for column in 0..8 {
+            block[row * 8 + column] = plane.at(x + column, y + row);
+        }
+    }
+    for offset in 0..8 {
+        dct(&mut block[offset * 8..], 1);
+    }
+    for offset in 0..8 {
+        dct(&mut block[offset..], 8);
+    }
+
+    let mut quantized = [0i16; 64];
+    for (i, value) in quantized.iter_mut().enumerate() {
+        let natural = ZIGZAG_INV[i] as usize;
+        let scale = AAN_SCALE_FACTORS[natural / 8] * AAN_SCALE_FACTORS[natural % 8] * 8.0;
+        let coefficient = (block[natural] / scale / quant[natural] as f32).round() as i32;
+        *value = coefficient.clamp(-COEFFICIENT_LIMIT, COEFFICIENT_LIMIT) as i16;
+    }
+    quantized
+}
+
+// Run-length symbols (run << 4 | size) of the AC coefficients, with ZRL and EOB
+fn for_each_ac_symbol(block: &[i16; 64], mut emit: impl FnMut(u8, i32)) {
+    let mut run = 0;
+    for &value in &block[1..] {
+        if value == 0 {
+            run += 1;
+            continue;
+        }
+        while run >= 16 {
+            emit(0xF0, 0);
+            run -= 16;
+        }
+        emit((run << 4) | magnitude_bits(value as i32), value as i32);
+        run = 0;
+    }
+    if run > 0 {
+        emit(0x00, 0);
+    }
+}
+
+// Number of bits needed for the magnitude of a value (its JPEG size category)
+fn magnitude_bits(value: i32) -> u8 {
+    (32 - value.unsigned_abs().leading_zeros()) as u8
+}
+
+// Write the magnitude bits of a value, negative values as their one's complement
+fn write_magnitude<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+    writer: &mut BitWriter<W>,
+    value: i32,
+    size: u8,
+) -> Result<(), &'static str> {
+    if size == 0 {
+        return Ok(());
+    }
+    let bits = if value < 0 { value + (1 << size) - 1 } else { value };
+    writer.write_bits(bits as U16, size)
+}
+
+// Huffman code lengths limited to 16 bits from symbol frequencies (Annex K.2 of the spec).
+// Returns the number of codes per length and the symbols sorted by code length.
+fn optimal_code_lengths(frequencies: &[u32; 257]) -> ([U8; 16], Vec<U8>) {
+    let mut frequencies = frequencies.map(|f| f as u64);
+    // Reserve one code so that no real code consists of only 1 bits
+    frequencies[256] = 1;
+    let mut code_size = [0usize; 257];
+    let mut others = [usize::MAX; 257];
+
+    loop {
+        // The two least frequent symbols, the larger index first on ties
+        let mut c1 = None;
+        for i in 0..257 {
+            if frequencies[i] > 0 && c1.is_none_or(|c: usize| frequencies[i] <= frequencies[c]) {
+                c1 = Some(i);
+            }
+        }
+        let mut c2 = None;
+        for i in 0..257 {
+            if frequencies[i] > 0 && Some(i) != c1 && c2.is_none_or(|c: usize| frequencies[i] <= frequencies[c]) {
+                c2 = Some(i);
+            }
+        }
+        let (Some(mut c1), Some(mut c2)) = (c1, c2) else {
+            break;
+        };
+
+        frequencies[c1] += frequencies[c2];
+        frequencies[c2] = 0;
+        code_size[c1] += 1;
+        while others[c1] != usize::MAX {
+            c1 = others[c1];
+            code_size[c1] += 1;
+        }
+        others[c1] = c2;
+        code_size[c2] += 1;
+        while others[c2] != usize::MAX {
+            c2 = others[c2];
+            code_size[c2] += 1;
+        }
+    }
+
+    let mut bits = [0usize; 33];
+    for &size in code_size.iter().filter(|&&size| size > 0) {
+        bits[size] += 1;
+    }
+    // Move codes longer than 16 bits up the tree
+    for i in (17..=32).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    // Drop the reserved code, which is one of the longest
+    if let Some(longest) = (1..=16).rev().find(|&i| bits[i] > 0) {
+        bits[longest] -= 1;
+    }
+
+    let mut counts = [0; 16];
+    for (count, &value) in counts.iter_mut().zip(&bits[1..=16]) {
+        *count = value as U8;
+    }
+    let mut values = Vec::new();
+    for size in 1..=32 {
+        values.extend((0..256).filter(|&symbol| code_size[symbol] == size).map(|symbol| symbol as U8));
+    }
+    (counts, values)
+}
@@ -0,0 +1,193 @@
+//! Gain-map HDR JPEGs in the Ultra HDR layout (Android 14+), readable as plain SDR JPEGs.
+//!
+//! The SDR base image comes first and a grayscale gain map JPEG is appended after its EOI.
+//! The base image locates the gain map through a Multi-Picture Format (MPF) index in APP2
+//! and an XMP container directory in APP1. The gain map itself carries its parameters twice:
+//! as Adobe `hdrgm` XMP, and as ISO 21496-1 binary metadata in APP2.
+
+use crate::segment::{jfif_end, push_segment};
+use crate::xmp::{insert_xmp, XMP_NAMESPACE};
+
+/// Gain map parameters in the ISO 21496-1 model, for a single-channel gain map
+///
+/// A display with `h` stops of headroom renders each pixel as
+/// `hdr = (sdr + base_offset) * 2^(gain * w) - alternate_offset`, with
+/// `gain = lerp(gain_map_min, gain_map_max, sample^(1 / gamma))` and `w` going from 0 at
+/// `base_hdr_headroom` to 1 at `alternate_hdr_headroom`. Boosts and headrooms are in stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GainMapMetadata {
+    /// Boost encoded by gain map value 0, log2 (`hdrgm:GainMapMin`)
+    pub gain_map_min: f32,
+    /// Boost encoded by gain map value 1, log2 (`hdrgm:GainMapMax`)
+    pub gain_map_max: f32,
+    /// Gamma the gain map samples were encoded with (`hdrgm:Gamma`)
+    pub gamma: f32,
+    /// Offset added to the SDR base image in linear light (`hdrgm:OffsetSDR`)
+    pub base_offset: f32,
+    /// Offset added to the HDR rendition in linear light (`hdrgm:OffsetHDR`)
+    pub alternate_offset: f32,
+    /// Display headroom up to which the base image is shown unchanged, log2 (`hdrgm:HDRCapacityMin`)
+    pub base_hdr_headroom: f32,
+    /// Display headroom at which the gain map is fully applied, log2 (`hdrgm:HDRCapacityMax`)
+    pub alternate_hdr_headroom: f32,
+}
+
+const ISO_NAMESPACE: &[u8] = b"urn:iso:std:iso:ts:21496:-1\0";
+// Denominator for the rationals of the ISO metadata
+const ISO_DENOMINATOR: u32 = 1_000_000;
+
+/// Combine an SDR JPEG and a grayscale gain map JPEG into one Ultra HDR JPEG
+///
+/// Both inputs are complete JPEGs, as produced by `encode_jpeg`. The gain map is usually
+/// encoded at a quarter or half of the base image's resolution; viewers upscale it. XMP
+/// already in `primary` is kept, the container directory is added to it.
+///
+/// # Arguments
+/// * `primary` - SDR base image
+/// * `gain_map` - Gain map, its samples encoded as described by `metadata`
+/// * `metadata` - Gain map parameters
+/// * `output` - Buffer that receives the combined JPEG
+pub fn write_ultra_hdr(
+    primary: &[u8],
+    gain_map: &[u8],
+    metadata: &GainMapMetadata,
+    output: &mut Vec<u8>,
+) -> Result<(), &'static str> {
+    if !primary.starts_with(&[0xFF, 0xD8]) || !gain_map.starts_with(&[0xFF, 0xD8]) {
+        return Err("Primary image and gain map must be JPEGs");
+    }
+
+    let mut gain_map_segments = Vec::new();
+    push_segment(&mut gain_map_segments, 0xE1, &[XMP_NAMESPACE, gain_map_xmp(metadata).as_bytes()].concat())?;
+    push_segment(&mut gain_map_segments, 0xE2, &[ISO_NAMESPACE, &iso_metadata(metadata)].concat())?;
+    let gain_map_length = gain_map.len() + gain_map_segments.len();
+
+    // The container directory joins any XMP the base image already has
+    let mut with_xmp = Vec::new();
+    insert_xmp(primary, &primary_xmp(gain_map_length), None, &mut with_xmp)?;
+    let primary = &with_xmp[..];
+
+    let mut primary_segments = Vec::new();
+    // The base image only announces the ISO version, its parameters live with the gain map
+    push_segment(&mut primary_segments, 0xE2, &[ISO_NAMESPACE, &[0, 0, 0, 0]].concat())?;
+
+    // New segments go after SOI and the JFIF header, which has to stay first
+    let insert_at = jfif_end(primary)?;
+    // MPF offsets count from the segment's TIFF header: 4 bytes of marker and length, then "MPF\0"
+    let mpf_tiff_start = insert_at + primary_segments.len() + 8;
+    let primary_length = primary.len() + primary_segments.len() + MPF_SEGMENT_LENGTH;
+    let gain_map_offset = primary_length - mpf_tiff_start;
+    push_segment(&mut primary_segments, 0xE2, &mpf_index(primary_length, gain_map_length, gain_map_offset))?;
+
+    output.reserve(primary_length + gain_map_length);
+    output.extend_from_slice(&primary[..insert_at]);
+    output.extend_from_slice(&primary_segments);
+    output.extend_from_slice(&primary[insert_at..]);
+
+    let gain_map_insert_at = jfif_end(gain_map)?;
+    output.extend_from_slice(&gain_map[..gain_map_insert_at]);
+    output.extend_from_slice(&gain_map_segments);
+    output.extend_from_slice(&gain_map[gain_map_insert_at..]);
+    Ok(())
+}
+
+// Marker, length, "MPF\0", TIFF header, an IFD with three entries, and two MP entries
+const MPF_SEGMENT_LENGTH: usize = 4 + 4 + 8 + (2 + 3 * 12 + 4) + 2 * 16;
+
+// Multi-Picture Format index (CIPA DC-007) listing the base image and the gain map
+fn mpf_index(primary_length: usize, gain_map_length: usize, gain_map_offset: usize) -> Vec<u8> {
+    const UNDEFINED: u16 = 7;
+    const LONG: u16 = 4;
+    const ENTRIES_OFFSET: u32 = 8 + 2 + 3 * 12 + 4;
+
+    let mut mpf = b"MPF\0".to_vec();
+    // Big-endian TIFF header with the IFD right after it
+    mpf.extend_from_slice(&[b'M', b'M', 0, 42, 0, 0, 0, 8]);
+    mpf.extend_from_slice(&3u16.to_be_bytes());
+    for (tag, kind, count, value) in [
+        (0xB000u16, UNDEFINED, 4u32, u32::from_be_bytes(*b"0100")), // MPFVersion
+        (0xB001, LONG, 1, 2),                                      // NumberOfImages
+        (0xB002, UNDEFINED, 2 * 16, ENTRIES_OFFSET),                // MPEntry
+    ] {
+        mpf.extend_from_slice(&tag.to_be_bytes());
+        mpf.extend_from_slice(&kind.to_be_bytes());
+        mpf.extend_from_slice(&count.to_be_bytes());
+        mpf.extend_from_slice(&value.to_be_bytes());
+    }
+    mpf.extend_from_slice(&0u32.to_be_bytes()); // No next IFD
+
+    // Attributes (baseline MP primary image, then an untyped JPEG), size, offset, no dependents
+    for (attributes, size, offset) in [(0x0003_0000u32, primary_length, 0), (0, gain_map_length, gain_map_offset)] {
+        mpf.extend_from_slice(&attributes.to_be_bytes());
+        mpf.extend_from_slice(&(size as u32).to_be_bytes());
+        mpf.extend_from_slice(&(offset as u32).to_be_bytes());
+        mpf.extend_from_slice(&[0, 0, 0, 0]);
+    }
+    mpf
+}
+
+// Container directory telling Ultra HDR readers where the gain map is
+fn primary_xmp(gain_map_length: usize) -> String {
+    format!(
+        concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+            r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:Container="http://ns.google.com/photos/1.0/container/""#,
+            r#" xmlns:Item="http://ns.google.com/photos/1.0/container/item/""#,
+            r#" xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/" hdrgm:Version="1.0">"#,
+            r#"<Container:Directory><rdf:Seq>"#,
+            r#"<rdf:li rdf:parseType="Resource"><Container:Item Item:Semantic="Primary" Item:Mime="image/jpeg"/></rdf:li>"#,
+            r#"<rdf:li rdf:parseType="Resource"><Container:Item Item:Semantic="GainMap" Item:Mime="image/jpeg""#,
+            r#" Item:Length="{}"/></rdf:li>"#,
+            r#"</rdf:Seq></Container:Directory>"#,
+            r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+        ),
+        gain_map_length
+    )
+}
+
+// Adobe hdrgm parameters of the gain map
+fn gain_map_xmp(metadata: &GainMapMetadata) -> String {
+    format!(
+        concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+            r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/""#,
+            r#" hdrgm:Version="1.0" hdrgm:GainMapMin="{}" hdrgm:GainMapMax="{}" hdrgm:Gamma="{}""#,
+            r#" hdrgm:OffsetSDR="{}" hdrgm:OffsetHDR="{}" hdrgm:HDRCapacityMin="{}" hdrgm:HDRCapacityMax="{}""#,
+            r#" hdrgm:BaseRenditionIsHDR="False"/>"#,
+            r#"</rdf:RDF></x:xmpmeta>"#,
+        ),
+        metadata.gain_map_min,
+        metadata.gain_map_max,
+        metadata.gamma,
+        metadata.base_offset,
+        metadata.alternate_offset,
+        metadata.base_hdr_headroom,
+        metadata.alternate_hdr_headroom,
+    )
+}
+
+// ISO 21496-1 binary metadata: versions, flags, then big-endian rationals
+fn iso_metadata(metadata: &GainMapMetadata) -> Vec<u8> {
+    // Minimum and writer version 0, a single channel, applied in the base image's color space
+    let mut iso = vec![0, 0, 0, 0, 1 << 6];
+    let unsigned = |iso: &mut Vec<u8>, value: f32| {
+        let numerator = (value.max(0.0) * ISO_DENOMINATOR as f32).round() as u32;
+        iso.extend_from_slice(&numerator.to_be_bytes());
+        iso.extend_from_slice(&ISO_DENOMINATOR.to_be_bytes());
+    };
+    let signed = |iso: &mut Vec<u8>, value: f32| {
+        let numerator = (value * ISO_DENOMINATOR as f32).round() as i32;
+        iso.extend_from_slice(&numerator.to_be_bytes());
+        iso.extend_from_slice(&ISO_DENOMINATOR.to_be_bytes());
+    };
+    unsigned(&mut iso, metadata.base_hdr_headroom);
+    unsigned(&mut iso, metadata.alternate_hdr_headroom);
+    signed(&mut iso, metadata.gain_map_min);
+    signed(&mut iso, metadata.gain_map_max);
+    unsigned(&mut iso, metadata.gamma);
+    signed(&mut iso, metadata.base_offset);
+    signed(&mut iso, metadata.alternate_offset);
+    iso
+}
@@ -1,7 +1,8 @@
 //! A Rust port of the TooJpeg JPEG encoder with performance optimizations.
 //! 
 //! This library provides a simple interface for encoding RGB(A) images to JPEG format
-//! with various quality and optimization settings.
+//! with various quality and optimization settings, a decoder for reading them back, and
+//! lossless rotation/cropping of existing JPEGs.
 
 #![warn(missing_docs)]
 #![forbid(unsafe_code)]
@@ -11,10 +12,35 @@
 extern crate alloc;
 
 mod toojpeg;
+mod stream;
+mod decoder;
+mod transform;
+mod cmyk;
+mod alpha;
+mod dither;
+mod exif;
+mod extended;
+mod gainmap;
+mod mjpeg;
+mod segment;
+mod xmp;
 
+pub use alpha::{composite_alpha, extract_alpha, Background};
+pub use cmyk::{rgb_to_cmyk, rgb_to_cmyk_ucr};
+pub use dither::{dither_to_u8, Dither};
+pub use decoder::{decode_jpeg, DecodedImage};
+pub use exif::exif_with_thumbnail;
+pub use extended::{encode_jpeg_12bit, MAX_12BIT_SAMPLE};
+pub use gainmap::{write_ultra_hdr, GainMapMetadata};
+pub use mjpeg::{write_mjpeg_avi, MjpegAviWriter, MjpegMultipartWriter};
+pub use segment::insert_app_segment;
+pub use stream::JpegStreamEncoder;
+pub use transform::{transform_jpeg, CropRegion, Transform, TransformOptions};
+pub use xmp::{insert_xmp, read_xmp, Xmp};
 pub use toojpeg::{
     BitWriter, 
     write_jpeg,
+    JpegParams,
     BitCode,
     U8, U16, I16, I32
 };
@@ -24,12 +50,14 @@ pub use toojpeg::{
 pub enum ImageFormat {
     /// RGB format (3 bytes per pixel)
     RGB,
-    /// RGBA format (4 bytes per pixel, alpha is ignored)
+    /// RGBA format (4 bytes per pixel), composited onto `EncodeOptions::background`
     RGBA,
     /// Grayscale format (1 byte per pixel)
     Gray,
     /// YCbCr format (3 bytes per pixel, interleaved Y-Cb-Cr)
     YCbCr,
+    /// CMYK ink coverage (4 bytes per pixel, 0 = no ink), written as an Adobe YCCK JPEG
+    Cmyk,
 }
 
 /// JPEG encoding options
@@ -49,6 +77,12 @@ pub struct EncodeOptions {
     pub optimized: bool,
     /// Whether to downsample chroma channels (4:2:0 subsampling)
     pub subsample: bool,
+    /// Whether to adapt quantization per block: busy textured regions drop more
+    /// high-frequency detail while flat gradients (sky, skin) are preserved.
+    /// The output stays baseline-decodable since the quantization tables don't change.
+    pub adaptive_quantization: bool,
+    /// What transparent pixels of RGBA input are composited onto
+    pub background: Background,
 }
 
 impl Default for EncodeOptions {
@@ -61,6 +95,8 @@ impl Default for EncodeOptions {
             baseline: true,
             optimized: true,
             subsample: true,
+            adaptive_quantization: false,
+            background: Background::default(),
         }
     }
 }
@@ -82,7 +118,7 @@ pub fn encode_jpeg<W: std::io::Write>(
     // Input validation
     let bytes_per_pixel = match options.format {
         ImageFormat::RGB | ImageFormat::YCbCr => 3,
-        ImageFormat::RGBA => 4,
+        ImageFormat::RGBA | ImageFormat::Cmyk => 4,
         ImageFormat::Gray => 1,
     };
     
@@ -94,7 +130,7 @@ pub fn encode_jpeg<W: std::io::Write>(
     // Input validation
     let bytes_per_pixel = match options.format {
         ImageFormat::RGB | ImageFormat::YCbCr => 3,
-        ImageFormat::RGBA => 4,
+        ImageFormat::RGBA | ImageFormat::Cmyk => 4,
         ImageFormat::Gray => 1,
     };
     
@@ -106,30 +142,44 @@ pub fn encode_jpeg<W: std::io::Write>(
         return Err("Input buffer too small for specified dimensions and format");
     }
 
+    // JPEG has no alpha channel, so RGBA is flattened onto the background first
+    let flattened;
+    let pixels = if options.format == ImageFormat::RGBA {
+        let width = options.width as usize;
+        let mut rgb = vec![0u8; width * options.height as usize * 3];
+        composite_alpha(pixels, &mut rgb, width, 0, options.background);
+        flattened = rgb;
+        &flattened[..]
+    } else {
+        pixels
+    };
+
     // Convert to the format expected by write_jpeg
     let is_rgb = matches!(options.format, ImageFormat::RGB | ImageFormat::RGBA);
     let is_ycbcr = matches!(options.format, ImageFormat::YCbCr);
     let quality = options.quality.clamp(1, 100) as u8;
     
     // Create a BitWriter for the output
-    let mut writer = BitWriter::new(|byte| {
-        output.write_all(&[byte]).map_err(|_| "Failed to write output")
+    let mut writer = BitWriter::new(|bytes: &[u8]| {
+        output.write_all(bytes).map_err(|_| "Failed to write output")
     });
 
     // Call the low-level write_jpeg function
     // Determine if format is YCbCr
     let is_ycbcr = matches!(options.format, ImageFormat::YCbCr);
-    write_jpeg(
-        &mut writer,
-        pixels,
-        options.width as u16,
-        options.height as u16,
+    let is_cmyk = matches!(options.format, ImageFormat::Cmyk);
+    let params = JpegParams {
+        width: options.width as u16,
+        height: options.height as u16,
         is_rgb,
         is_ycbcr,
+        is_cmyk,
         quality,
-        options.subsample,
-        None // comment
-    )
+        subsample: options.subsample,
+        adaptive: options.adaptive_quantization,
+        comment: None,
+    };
+    write_jpeg(&mut writer, pixels, &params)
 }
 
 #[cfg(test)]
@@ -0,0 +1,300 @@
+//! Motion JPEG: sequences of independently encoded JPEG frames, in an AVI (RIFF) container or
+//! as a multipart stream.
+//!
+//! Both writers stream frames straight to their output, so a recording only holds one frame
+//! in memory at a time. AVI keeps a 16-byte index entry per frame until `finish`.
+
+use std::io::{Seek, SeekFrom, Write};
+use std::time::Duration;
+
+// Size of everything before the first frame chunk, see `MjpegAviWriter::header`
+const AVI_HEADER_SIZE: usize = 224;
+// Readers commonly treat RIFF sizes as signed
+const MAX_AVI_SIZE: u64 = i32::MAX as u64;
+
+/// Writes JPEG frames into a Motion JPEG AVI
+///
+/// The headers are written right away with placeholder sizes and rewritten by `finish`, which
+/// is why the output has to be seekable. Every frame is a key frame, so the file seeks and
+/// cuts cleanly. AVI 1.0 files are limited to 2 GB; `write_frame` fails beyond that.
+///
+/// ```
+/// use std::io::Cursor;
+/// use toojpeg::{encode_jpeg, EncodeOptions, ImageFormat, MjpegAviWriter};
+///
+/// let options = EncodeOptions { width: 32, height: 16, format: ImageFormat::Gray, ..Default::default() };
+/// let mut writer = MjpegAviWriter::new(Cursor::new(Vec::new()), 32, 16, 30).unwrap();
+/// for level in [0u8, 128, 255] {
+///     let mut jpeg = Vec::new();
+///     encode_jpeg(&[level; 32 * 16], options, &mut jpeg).unwrap();
+///     writer.write_frame(&jpeg).unwrap();
+/// }
+/// let avi = writer.finish().unwrap().into_inner();
+/// assert_eq!(&avi[..4], b"RIFF");
+/// ```
+pub struct MjpegAviWriter<W: Write + Seek> {
+    output: W,
+    start: u64,
+    width: u32,
+    height: u32,
+    fps: u32,
+    // Bytes of frame chunks written after "movi"
+    movi_size: u64,
+    largest_frame: u32,
+    index: Vec<u8>,
+}
+
+impl<W: Write + Seek> MjpegAviWriter<W> {
+    /// Create a writer and write the AVI headers at the output's current position
+    ///
+    /// # Arguments
+    /// * `output` - A seekable writer, such as a `File` or a `Cursor<Vec<u8>>`
+    /// * `width` - Frame width in pixels
+    /// * `height` - Frame height in pixels
+    /// * `fps` - Frame rate; timestamps passed to `write_frame_at` are rounded to it
+    pub fn new(mut output: W, width: u32, height: u32, fps: u32) -> Result<Self, &'static str> {
+        if width == 0 || height == 0 || fps == 0 {
+            return Err("Motion JPEG needs a frame size and a frame rate");
+        }
+        let start = output.stream_position().map_err(|_| "Failed to write output")?;
+        let mut writer = Self { output, start, width, height, fps, movi_size: 0, largest_frame: 0, index: Vec::new() };
+        let header = writer.header();
+        writer.output.write_all(&header).map_err(|_| "Failed to write output")?;
+        Ok(writer)
+    }
+
+    /// Number of frame slots written, including the empty ones `write_frame_at` inserts
+    pub fn frame_count(&self) -> u32 {
+        (self.index.len() / 16) as u32
+    }
+
+    /// Append a JPEG as the next frame
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> Result<(), &'static str> {
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            return Err("Motion JPEG frames must be JPEGs");
+        }
+        self.write_chunk(jpeg)
+    }
+
+    /// Append a JPEG at its capture time, counted from the start of the recording
+    ///
+    /// AVI plays at a constant rate, so the timestamp picks the frame slot. Slots skipped since
+    /// the last frame get empty chunks, during which players keep showing the previous frame;
+    /// a frame arriving early for its slot takes the next free one.
+    pub fn write_frame_at(&mut self, jpeg: &[u8], timestamp: Duration) -> Result<(), &'static str> {
+        let slot = (timestamp.as_secs_f64() * self.fps as f64).round() as u64;
+        while (self.frame_count() as u64) < slot {
+            self.write_chunk(&[])?;
+        }
+        self.write_frame(jpeg)
+    }
+
+    /// Write the index and the final sizes, returning the output
+    pub fn finish(mut self) -> Result<W, &'static str> {
+        if self.index.is_empty() {
+            return Err("Motion JPEG needs at least one frame");
+        }
+        let io_error = |_| "Failed to write output";
+        self.output.write_all(b"idx1").map_err(io_error)?;
+        self.output.write_all(&(self.index.len() as u32).to_le_bytes()).map_err(io_error)?;
+        self.output.write_all(&self.index).map_err(io_error)?;
+
+        let end = self.output.stream_position().map_err(io_error)?;
+        let header = self.header();
+        self.output.seek(SeekFrom::Start(self.start)).map_err(io_error)?;
+        self.output.write_all(&header).map_err(io_error)?;
+        self.output.seek(SeekFrom::Start(end)).map_err(io_error)?;
+        self.output.flush().map_err(io_error)?;
+        Ok(self.output)
+    }
+
+    // Write a "00dc" chunk and index it; empty chunks mark dropped frames
+    fn write_chunk(&mut self, jpeg: &[u8]) -> Result<(), &'static str> {
+        let padded_size = 8 + jpeg.len().next_multiple_of(2) as u64;
+        let file_size = AVI_HEADER_SIZE as u64 + self.movi_size + padded_size + 8 + self.index.len() as u64 + 16;
+        if file_size > MAX_AVI_SIZE {
+            return Err("Motion JPEG data exceeds the size of an AVI file");
+        }
+
+        let io_error = |_| "Failed to write output";
+        self.output.write_all(b"00dc").map_err(io_error)?;
+        self.output.write_all(&(jpeg.len() as u32).to_le_bytes()).map_err(io_error)?;
+        self.output.write_all(jpeg).map_err(io_error)?;
+        if jpeg.len() % 2 == 1 {
+            self.output.write_all(&[0]).map_err(io_error)?;
+        }
+
+        // Offsets count from the "movi" list type, whose 4 bytes precede the first chunk
+        self.index.extend_from_slice(b"00dc");
+        let flags: u32 = if jpeg.is_empty() { 0 } else { 0x10 }; // AVIIF_KEYFRAME
+        self.index.extend_from_slice(&flags.to_le_bytes());
+        self.index.extend_from_slice(&(4 + self.movi_size as u32).to_le_bytes());
+        self.index.extend_from_slice(&(jpeg.len() as u32).to_le_bytes());
+        self.movi_size += padded_size;
+        self.largest_frame = self.largest_frame.max(jpeg.len() as u32);
+        Ok(())
+    }
+
+    // RIFF header, main header, one video stream, and the start of the "movi" list
+    fn header(&self) -> Vec<u8> {
+        let (width, height, fps) = (self.width, self.height, self.fps);
+        let frame_count = self.frame_count();
+        let riff_size = if self.index.is_empty() {
+            0
+        } else {
+            AVI_HEADER_SIZE as u32 - 8 + self.movi_size as u32 + 8 + self.index.len() as u32
+        };
+
+        let mut header = Vec::with_capacity(AVI_HEADER_SIZE);
+        let u32_le = |header: &mut Vec<u8>, value: u32| header.extend_from_slice(&value.to_le_bytes());
+        header.extend_from_slice(b"RIFF");
+        u32_le(&mut header, riff_size);
+        header.extend_from_slice(b"AVI LIST");
+        u32_le(&mut header, 192); // hdrl
+        header.extend_from_slice(b"hdrlavih");
+        u32_le(&mut header, 56);
+        u32_le(&mut header, 1_000_000 / fps); // Microseconds per frame
+        u32_le(&mut header, self.largest_frame.saturating_mul(fps)); // Maximum bytes per second
+        u32_le(&mut header, 0); // Padding granularity
+        u32_le(&mut header, 0x10); // AVIF_HASINDEX
+        u32_le(&mut header, frame_count);
+        u32_le(&mut header, 0); // Initial frames
+        u32_le(&mut header, 1); // Streams
+        u32_le(&mut header, self.largest_frame); // Suggested buffer size
+        u32_le(&mut header, width);
+        u32_le(&mut header, height);
+        header.extend_from_slice(&[0; 16]); // Reserved
+
+        header.extend_from_slice(b"LIST");
+        u32_le(&mut header, 116); // strl
+        header.extend_from_slice(b"strlstrh");
+        u32_le(&mut header, 56);
+        header.extend_from_slice(b"vidsMJPG");
+        u32_le(&mut header, 0); // Flags
+        u32_le(&mut header, 0); // Priority and language
+        u32_le(&mut header, 0); // Initial frames
+        u32_le(&mut header, 1); // Scale
+        u32_le(&mut header, fps); // Rate: rate / scale frames per second
+        u32_le(&mut header, 0); // Start
+        u32_le(&mut header, frame_count); // Length
+        u32_le(&mut header, self.largest_frame); // Suggested buffer size
+        u32_le(&mut header, u32::MAX); // Quality: default
+        u32_le(&mut header, 0); // Sample size: varies
+        header.extend_from_slice(&[0, 0, 0, 0]); // Frame rectangle: left, top
+        header.extend_from_slice(&(width.min(i16::MAX as u32) as u16).to_le_bytes());
+        header.extend_from_slice(&(height.min(i16::MAX as u32) as u16).to_le_bytes());
+
+        header.extend_from_slice(b"strf");
+        u32_le(&mut header, 40); // BITMAPINFOHEADER
+        u32_le(&mut header, 40);
+        u32_le(&mut header, width);
+        u32_le(&mut header, height);
+        header.extend_from_slice(&1u16.to_le_bytes()); // Planes
+        header.extend_from_slice(&24u16.to_le_bytes()); // Bits per pixel
+        header.extend_from_slice(b"MJPG");
+        u32_le(&mut header, width.saturating_mul(height).saturating_mul(3)); // Image size
+        header.extend_from_slice(&[0; 16]); // Resolution and palette
+
+        header.extend_from_slice(b"LIST");
+        u32_le(&mut header, 4 + self.movi_size as u32);
+        header.extend_from_slice(b"movi");
+        debug_assert_eq!(header.len(), AVI_HEADER_SIZE);
+        header
+    }
+}
+
+/// Write JPEG frames as a Motion JPEG AVI
+///
+/// The whole AVI is built in memory, see `MjpegAviWriter` for recording to a file.
+///
+/// # Arguments
+/// * `frames` - Complete JPEGs, as produced by `encode_jpeg`, all `width` x `height`
+/// * `width` - Frame width in pixels
+/// * `height` - Frame height in pixels
+/// * `fps` - Frame rate
+/// * `output` - Buffer that receives the AVI
+pub fn write_mjpeg_avi(
+    frames: &[&[u8]],
+    width: u32,
+    height: u32,
+    fps: u32,
+    output: &mut Vec<u8>,
+) -> Result<(), &'static str> {
+    if frames.is_empty() {
+        return Err("Motion JPEG needs at least one frame");
+    }
+    if frames.iter().any(|frame| !frame.starts_with(&[0xFF, 0xD8])) {
+        return Err("Motion JPEG frames must be JPEGs");
+    }
+    let mut cursor = std::io::Cursor::new(output);
+    cursor.set_position(cursor.get_ref().len() as u64);
+    let mut writer = MjpegAviWriter::new(cursor, width, height, fps)?;
+    for frame in frames {
+        writer.write_frame(frame)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes JPEG frames as a multipart Motion JPEG stream (`.mjpeg`)
+///
+/// This is the `multipart/x-mixed-replace` format IP cameras and `mjpg-streamer` serve over
+/// HTTP, and that browsers and ffmpeg play. Each part carries its frame's capture time in an
+/// `X-Timestamp` header (seconds with microseconds), so irregular frame timing survives.
+/// Nothing needs patching afterwards, so any writer works, including a socket.
+///
+/// ```
+/// use std::time::Duration;
+/// use toojpeg::{encode_jpeg, EncodeOptions, ImageFormat, MjpegMultipartWriter};
+///
+/// let options = EncodeOptions { width: 32, height: 16, format: ImageFormat::Gray, ..Default::default() };
+/// let mut jpeg = Vec::new();
+/// encode_jpeg(&[128; 32 * 16], options, &mut jpeg).unwrap();
+///
+/// let mut output = Vec::new();
+/// let mut writer = MjpegMultipartWriter::new(&mut output);
+/// writer.write_frame(&jpeg, Duration::from_millis(0)).unwrap();
+/// writer.write_frame(&jpeg, Duration::from_millis(33)).unwrap();
+/// writer.finish().unwrap();
+/// assert!(output.starts_with(b"--mjpegframe\r\n"));
+/// ```
+pub struct MjpegMultipartWriter<W: Write> {
+    output: W,
+}
+
+impl<W: Write> MjpegMultipartWriter<W> {
+    /// Boundary between frames; served over HTTP the stream's content type is
+    /// `multipart/x-mixed-replace; boundary=mjpegframe`
+    pub const BOUNDARY: &'static str = "mjpegframe";
+
+    /// Create a writer; nothing is written until the first frame
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+
+    /// Append a JPEG with its capture time, counted from the start of the recording
+    pub fn write_frame(&mut self, jpeg: &[u8], timestamp: Duration) -> Result<(), &'static str> {
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            return Err("Motion JPEG frames must be JPEGs");
+        }
+        write!(
+            self.output,
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: {}.{:06}\r\n\r\n",
+            Self::BOUNDARY,
+            jpeg.len(),
+            timestamp.as_secs(),
+            timestamp.subsec_micros()
+        )
+        .and_then(|_| self.output.write_all(jpeg))
+        .and_then(|_| self.output.write_all(b"\r\n"))
+        .map_err(|_| "Failed to write output")
+    }
+
+    /// Write the closing boundary, returning the output
+    pub fn finish(mut self) -> Result<W, &'static str> {
+        write!(self.output, "--{}--\r\n", Self::BOUNDARY)
+            .and_then(|_| self.output.flush())
+            .map_err(|_| "Failed to write output")?;
+        Ok(self.output)
+    }
+}
@@ -0,0 +1,65 @@
+//! Insertion of APPn metadata segments into existing JPEGs.
+
+/// Insert an APPn segment into a JPEG, after SOI and the JFIF header if there is one
+///
+/// The JPEG data itself is copied unchanged, so this is lossless.
+///
+/// # Arguments
+/// * `jpeg` - A complete JPEG
+/// * `marker` - Segment marker, 0xE0 (APP0) to 0xEF (APP15)
+/// * `payload` - Segment contents including its identifier (e.g. `Exif\0\0`), at most 65533 bytes
+/// * `output` - Buffer that receives the new JPEG
+pub fn insert_app_segment(jpeg: &[u8], marker: u8, payload: &[u8], output: &mut Vec<u8>) -> Result<(), &'static str> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err("Not a JPEG");
+    }
+    if !(0xE0..=0xEF).contains(&marker) {
+        return Err("Not an APPn marker");
+    }
+    let insert_at = jfif_end(jpeg)?;
+    output.reserve(jpeg.len() + payload.len() + 4);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    push_segment(output, marker, payload)?;
+    output.extend_from_slice(&jpeg[insert_at..]);
+    Ok(())
+}
+
+/// Write a segment: marker, big-endian length and payload
+pub(crate) fn push_segment(output: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<(), &'static str> {
+    let length = u16::try_from(payload.len() + 2).map_err(|_| "Metadata segment is too large")?;
+    output.extend_from_slice(&[0xFF, marker]);
+    output.extend_from_slice(&length.to_be_bytes());
+    output.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Offset just past SOI and, if present, the APP0 JFIF segment following it. Other APP0
+/// segments, such as JFXX, aren't skipped.
+pub(crate) fn jfif_end(jpeg: &[u8]) -> Result<usize, &'static str> {
+    match jpeg.get(2..11) {
+        Some([0xFF, 0xE0, high, low, b'J', b'F', b'I', b'F', 0]) => {
+            let end = 4 + u16::from_be_bytes([*high, *low]) as usize;
+            if end > jpeg.len() {
+                return Err("JFIF segment is truncated");
+            }
+            Ok(end)
+        }
+        _ => Ok(2),
+    }
+}
+
+/// Segments before the first scan, as (offset of the marker, marker, payload)
+pub(crate) fn header_segments(jpeg: &[u8]) -> impl Iterator<Item = (usize, u8, &[u8])> + '_ {
+    let mut pos = 2;
+    core::iter::from_fn(move || {
+        let [0xFF, marker, high, low] = *jpeg.get(pos..pos + 4)? else { return None };
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let end = pos + 2 + u16::from_be_bytes([high, low]) as usize;
+        let payload = jpeg.get(pos + 4..end)?;
+        let start = pos;
+        pos = end;
+        Some((start, marker, payload))
+    })
+}
@@ -0,0 +1,146 @@
+//! Incremental encoding for images that are too large to hold in memory at once.
+
+use crate::toojpeg::{write_headers, write_trailer, BitWriter, JpegParams, ScanState};
+use crate::{composite_alpha, Background, EncodeOptions, ImageFormat};
+
+type ByteSink<'a> = Box<dyn FnMut(&[u8]) -> Result<(), &'static str> + 'a>;
+
+/// A JPEG encoder that accepts the image a few rows at a time
+///
+/// The headers are written as soon as the encoder is created. Rows are then passed in
+/// top-to-bottom order through [`write_rows`](Self::write_rows), in any batch size; the
+/// encoder only ever buffers a single MCU row (16 rows with 4:2:0 subsampling, 8 otherwise),
+/// so memory use is bounded by the image width instead of its area.
+///
+/// ```
+/// use toojpeg::{EncodeOptions, ImageFormat, JpegStreamEncoder};
+///
+/// let options = EncodeOptions { width: 64, height: 48, format: ImageFormat::Gray, ..Default::default() };
+/// let mut output = Vec::new();
+/// let mut encoder = JpegStreamEncoder::new(&mut output, options).unwrap();
+/// for _ in 0..48 {
+///     encoder.write_rows(&[128u8; 64]).unwrap();
+/// }
+/// encoder.finish().unwrap();
+/// assert_eq!(&output[output.len() - 2..], [0xFF, 0xD9]);
+/// ```
+pub struct JpegStreamEncoder<'a> {
+    writer: BitWriter<ByteSink<'a>>,
+    scan: ScanState,
+    input_bytes_per_pixel: usize,
+    width: usize,
+    height: usize,
+    background: Background,
+    // One MCU row of pixels in the layout expected by the scan (alpha already flattened)
+    strip: Vec<u8>,
+    strip_rows: usize,
+    // Image row of the first row in `strip`
+    next_row: usize,
+}
+
+impl<'a> JpegStreamEncoder<'a> {
+    /// Create an encoder and write the JPEG headers to `output`
+    ///
+    /// # Arguments
+    /// * `output` - A writer that implements `std::io::Write` to receive the JPEG data
+    /// * `options` - Encoding options including dimensions, format, and quality
+    pub fn new<W: std::io::Write>(output: &'a mut W, options: EncodeOptions) -> Result<Self, &'static str> {
+        if options.width > u16::MAX as u32 || options.height > u16::MAX as u32 {
+            return Err("Invalid image dimensions");
+        }
+
+        let input_bytes_per_pixel = match options.format {
+            ImageFormat::RGB | ImageFormat::YCbCr => 3,
+            ImageFormat::RGBA | ImageFormat::Cmyk => 4,
+            ImageFormat::Gray => 1,
+        };
+        let is_rgb = matches!(options.format, ImageFormat::RGB | ImageFormat::RGBA);
+        let is_ycbcr = matches!(options.format, ImageFormat::YCbCr);
+        let is_cmyk = matches!(options.format, ImageFormat::Cmyk);
+
+        let sink: ByteSink<'a> = Box::new(move |bytes: &[u8]| {
+            output.write_all(bytes).map_err(|_| "Failed to write output")
+        });
+        let mut writer = BitWriter::new(sink);
+        let params = JpegParams {
+            width: options.width as u16,
+            height: options.height as u16,
+            is_rgb,
+            is_ycbcr,
+            is_cmyk,
+            quality: options.quality.clamp(1, 100),
+            subsample: options.subsample,
+            adaptive: options.adaptive_quantization,
+            comment: None,
+        };
+        let scan = write_headers(&mut writer, &params)?;
+
+        let width = options.width as usize;
+        let strip = Vec::with_capacity(scan.mcu_height() * width * scan.channels());
+        Ok(Self {
+            writer,
+            scan,
+            input_bytes_per_pixel,
+            width,
+            height: options.height as usize,
+            background: options.background,
+            strip,
+            strip_rows: 0,
+            next_row: 0,
+        })
+    }
+
+    /// Number of image rows per MCU row, the natural batch size for `write_rows`
+    pub fn mcu_height(&self) -> usize {
+        self.scan.mcu_height()
+    }
+
+    /// Number of image rows accepted so far
+    pub fn rows_written(&self) -> usize {
+        self.next_row + self.strip_rows
+    }
+
+    /// Append one or more complete rows in the format given by `EncodeOptions::format`
+    ///
+    /// `rows.len()` must be a multiple of the row size (`width * bytes per pixel`).
+    pub fn write_rows(&mut self, rows: &[u8]) -> Result<(), &'static str> {
+        let row_bytes = self.width * self.input_bytes_per_pixel;
+        if !rows.len().is_multiple_of(row_bytes) {
+            return Err("Input must contain whole rows");
+        }
+        if self.rows_written() + rows.len() / row_bytes > self.height {
+            return Err("More rows written than the image height");
+        }
+
+        for row in rows.chunks_exact(row_bytes) {
+            if self.input_bytes_per_pixel > self.scan.channels() {
+                // RGBA: the scan only reads RGB, so flatten onto the background
+                let start = self.strip.len();
+                self.strip.resize(start + self.width * 3, 0);
+                let image_row = self.rows_written();
+                composite_alpha(row, &mut self.strip[start..], self.width, image_row, self.background);
+            } else {
+                self.strip.extend_from_slice(row);
+            }
+            self.strip_rows += 1;
+
+            if self.strip_rows == self.mcu_height() || self.rows_written() == self.height {
+                self.scan.encode_mcu_row(&mut self.writer, &self.strip, self.next_row)?;
+                self.next_row += self.strip_rows;
+                self.strip_rows = 0;
+                self.strip.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the scan and write the End Of Image marker
+    ///
+    /// Fails if fewer rows than the image height were written.
+    pub fn finish(mut self) -> Result<(), &'static str> {
+        if self.rows_written() != self.height {
+            return Err("Not all rows were written");
+        }
+        write_trailer(&mut self.writer)
+    }
+}
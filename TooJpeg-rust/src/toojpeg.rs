@@ -15,17 +15,17 @@ fn clamp_max(v: usize, max: usize) -> usize {
     if v < max { v } else { max - 1 }
 }
 
-const DEFAULT_QUANT_LUMINANCE: [U8; 64] = [
+pub(crate) const DEFAULT_QUANT_LUMINANCE: [U8; 64] = [
     16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
     14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92,
     49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
 ];
-const DEFAULT_QUANT_CHROMINANCE: [U8; 64] = [
+pub(crate) const DEFAULT_QUANT_CHROMINANCE: [U8; 64] = [
     17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
     47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
     99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
 ];
-const ZIGZAG_INV: [U8; 64] = [
+pub(crate) const ZIGZAG_INV: [U8; 64] = [
     0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
     13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
     52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
@@ -62,105 +62,186 @@ const AC_CHROMINANCE_VALUES: [U8; 162] = [
     0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
     0xF9, 0xFA,
 ];
-const CODE_WORD_LIMIT: I16 = 2048;
+pub(crate) const CODE_WORD_LIMIT: I16 = 2048;
+
+// Output of `dct` for frequency u is the true DCT coefficient times AAN_SCALE_FACTORS[u]
+pub(crate) const AAN_SCALE_FACTORS: [f32; 8] = [1.0, 1.387039845, 1.306562965, 1.175875602, 1.0, 0.785694958, 0.541196100, 0.275899379];
+
+// Adaptive quantization: mean absolute gradient of a block (in sample units) below which
+// a block counts as flat and above which it counts as fully textured
+const AQ_FLAT_ACTIVITY: f32 = 2.0;
+const AQ_BUSY_ACTIVITY: f32 = 14.0;
+// Zero-bias thresholds of a fully textured block, for the lowest and highest AC frequency
+const AQ_BUSY_DEADZONE_LOW: f32 = 1.5;
+const AQ_BUSY_DEADZONE_HIGH: f32 = 2.5;
 
 #[derive(Copy, Clone, Debug)]
 pub struct BitCode {
-    code: U16,
-    num_bits: U8,
+    pub(crate) code: U16,
+    pub(crate) num_bits: U8,
 }
 
 impl BitCode {
-    const fn new(code: U16, num_bits: U8) -> Self {
+    pub(crate) const fn new(code: U16, num_bits: U8) -> Self {
         Self { code, num_bits }
     }
 }
 
+/// Huffman codes of one component: its DC and AC tables and the shared magnitude codewords
+#[derive(Clone, Copy)]
+pub(crate) struct HuffmanCodes<'a> {
+    pub(crate) dc: &'a [BitCode; 256],
+    pub(crate) ac: &'a [BitCode; 256],
+    pub(crate) codewords: &'a [BitCode; 2 * CODE_WORD_LIMIT as usize],
+}
+
+/// Frame parameters for `write_jpeg`
+#[derive(Debug, Clone, Copy)]
+pub struct JpegParams<'a> {
+    /// Image width in pixels
+    pub width: U16,
+    /// Image height in pixels
+    pub height: U16,
+    /// True if the input is in RGB format (3 bytes per pixel)
+    pub is_rgb: bool,
+    /// True if the input is in YCbCr format (3 bytes per pixel)
+    pub is_ycbcr: bool,
+    /// True if the input is CMYK ink coverage (4 bytes per pixel), stored as Adobe YCCK
+    pub is_cmyk: bool,
+    /// Encoding quality (1-100)
+    pub quality: U8,
+    /// Whether to downsample chroma channels (4:2:0 subsampling)
+    pub subsample: bool,
+    /// Whether to adapt quantization to local activity (see `EncodeOptions::adaptive_quantization`)
+    pub adaptive: bool,
+    /// Optional comment to include in the JPEG file
+    pub comment: Option<&'a str>,
+}
+
+// Bytes collected before the BitWriter hands them to its output in one call
+const OUTPUT_BUFFER_SIZE: usize = 16 * 1024;
+
 /// A bit writer for JPEG encoding
-pub struct BitWriter<W: FnMut(U8) -> Result<(), &'static str>> {
+///
+/// Output is collected in an internal buffer and passed to the `output` callback in chunks,
+/// so unbuffered sinks (files, sockets) aren't hit once per byte. Call `flush_buffer` when
+/// done; `write_jpeg` does this after writing the EOI marker.
+pub struct BitWriter<W: FnMut(&[U8]) -> Result<(), &'static str>> {
     output: W,
     buffer: BitBuffer,
+    bytes: Vec<U8>,
 }
 
+// Pending Huffman bits. Whole 32-bit words are moved out as soon as they are complete,
+// so at most 31 + 16 bits are ever held.
 struct BitBuffer {
-    data: u32,
+    data: u64,
     num_bits: u8,
 }
 
-impl<W: FnMut(U8) -> Result<(), &'static str>> BitWriter<W> {
+// Whether any of the four bytes of `word` is 0xFF and needs stuffing
+#[inline(always)]
+fn has_ff_byte(word: u32) -> bool {
+    // classic "has zero byte" test, applied to the inverted word
+    let inverted = !word;
+    (inverted.wrapping_sub(0x0101_0101) & !inverted & 0x8080_8080) != 0
+}
+
+impl<W: FnMut(&[U8]) -> Result<(), &'static str>> BitWriter<W> {
+    /// Create a bit writer that passes chunks of encoded bytes to `output`
     pub fn new(output: W) -> Self {
         Self {
             output,
             buffer: BitBuffer { data: 0, num_bits: 0 },
+            bytes: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
         }
     }
 
+    /// Write a single byte as-is
+    #[inline(always)]
     pub fn write_byte(&mut self, byte: U8) -> Result<(), &'static str> {
-        (self.output)(byte)
+        self.bytes.push(byte);
+        self.flush_if_full()
     }
 
-    // Writes a byte and performs byte stuffing if necessary
+    /// Write a byte and perform byte stuffing if necessary
+    #[inline(always)]
     pub fn write_stuffed_byte(&mut self, byte: U8) -> Result<(), &'static str> {
-        (self.output)(byte)?;
+        self.bytes.push(byte);
         if byte == 0xFF {
-            (self.output)(0x00)?;
+            self.bytes.push(0x00);
         }
-        Ok(())
+        self.flush_if_full()
     }
 
+    /// Write a run of bytes as-is
     pub fn write_bytes(&mut self, bytes: &[U8]) -> Result<(), &'static str> {
-        for &byte in bytes {
-            self.write_byte(byte)?;
-        }
-        Ok(())
+        self.bytes.extend_from_slice(bytes);
+        self.flush_if_full()
     }
 
+    /// Append the lowest `num_bits` bits of `code` to the entropy-coded stream
+    #[inline(always)]
     pub fn write_bits(&mut self, code: U16, num_bits: U8) -> Result<(), &'static str> {
-        // 1. grow buffer first
+        // push old bits to the left and drop the new bits into the now-free lsb positions
+        self.buffer.data = (self.buffer.data << num_bits) | code as u64;
         self.buffer.num_bits += num_bits;
-        // 2. push old bits to the left
-        self.buffer.data <<= num_bits as i32;
-        // 3. drop the new bits into the now-free lsb positions
-        self.buffer.data |= code as u32;
-    
-        while self.buffer.num_bits >= 8 {
-            self.buffer.num_bits -= 8;
-            let byte = ((self.buffer.data >> self.buffer.num_bits) & 0xFF) as U8;
-            self.write_stuffed_byte(byte)?;
+
+        if self.buffer.num_bits >= 32 {
+            self.buffer.num_bits -= 32;
+            let word = (self.buffer.data >> self.buffer.num_bits) as u32;
+            if has_ff_byte(word) {
+                for byte in word.to_be_bytes() {
+                    self.write_stuffed_byte(byte)?;
+                }
+            } else {
+                // common case: nothing to stuff, copy all four bytes at once
+                self.bytes.extend_from_slice(&word.to_be_bytes());
+                self.flush_if_full()?;
+            }
         }
         Ok(())
     }
 
+    /// Write out all pending bits, padding the last byte with 1-bits
     pub fn flush(&mut self) -> Result<(), &'static str> {
+        // whole bytes first
+        while self.buffer.num_bits >= 8 {
+            self.buffer.num_bits -= 8;
+            let byte = ((self.buffer.data >> self.buffer.num_bits) & 0xFF) as U8;
+            self.write_stuffed_byte(byte)?;
+        }
         // pad the buffer with 1-bits until we can produce one more byte
         if self.buffer.num_bits > 0 {
             let padding = 8 - self.buffer.num_bits;
-            // shift old bits left
-            self.buffer.data <<= padding;
-            // OR in padding bits (all 1s)
-            self.buffer.data |= (1u32 << padding) - 1;
-            // we now have at least 8 bits, extract exactly one byte
-            self.buffer.num_bits += padding as u8;
-            self.buffer.num_bits -= 8;
-            let byte = ((self.buffer.data >> self.buffer.num_bits) & 0xFF) as u8;
+            self.buffer.data = (self.buffer.data << padding) | ((1u64 << padding) - 1);
+            self.buffer.num_bits = 0;
+            let byte = (self.buffer.data & 0xFF) as U8;
             self.write_stuffed_byte(byte)?;
         }
         Ok(())
     }
 
-    pub fn add_marker(&mut self, marker: U8, length: U16) -> Result<(), &'static str> {
-        self.write_byte(0xFF)?;
-        self.write_byte(marker)?;
-        self.write_byte((length >> 8) as U8)?;
-        self.write_byte((length & 0xFF) as U8)
+    /// Pass all buffered bytes to the output
+    pub fn flush_buffer(&mut self) -> Result<(), &'static str> {
+        if !self.bytes.is_empty() {
+            (self.output)(&self.bytes)?;
+            self.bytes.clear();
+        }
+        Ok(())
     }
-}
 
-fn minimum<T: PartialOrd>(value: T, maximum: T) -> T {
-    if value <= maximum {
-        value
-    } else {
-        maximum
+    #[inline(always)]
+    fn flush_if_full(&mut self) -> Result<(), &'static str> {
+        if self.bytes.len() >= OUTPUT_BUFFER_SIZE {
+            self.flush_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// Write a marker segment header: 0xFF, the marker, and the big-endian segment length
+    pub fn add_marker(&mut self, marker: U8, length: U16) -> Result<(), &'static str> {
+        self.write_bytes(&[0xFF, marker, (length >> 8) as U8, (length & 0xFF) as U8])
     }
 }
 
@@ -183,7 +264,8 @@ fn rgb2cb(r: U8, g: U8, b: U8) -> f32 { -0.1687 * r as f32 - 0.3313 * g as f32 +
 #[inline(always)]
 fn rgb2cr(r: U8, g: U8, b: U8) -> f32 { 0.500 * r as f32 - 0.4187 * g as f32 - 0.0813 * b as f32 }
 
-fn dct(block: &mut [f32], stride: usize) {
+/// Scaled AAN forward DCT of 8 values `stride` apart, see `AAN_SCALE_FACTORS`
+pub(crate) fn dct(block: &mut [f32], stride: usize) {
     const SQRT_HALF_SQRT: f32 = 1.306562965;
     const INV_SQRT: f32 = 0.707106781;
     const HALF_SQRT_SQRT: f32 = 0.382683432;
@@ -235,16 +317,49 @@ fn dct(block: &mut [f32], stride: usize) {
     block[3 * stride] = z7 - z2;
 }
 
+// Masking strength of a block for adaptive quantization, from 0.0 (flat, e.g. sky or skin)
+// to 1.0 (busy texture where quantization noise is hidden by the content itself)
+fn block_masking(block: &[[f32; 8]; 8]) -> f32 {
+    let mut activity = 0.0;
+    for y in 0..8 {
+        for x in 0..8 {
+            if x < 7 {
+                activity += (block[y][x + 1] - block[y][x]).abs();
+            }
+            if y < 7 {
+                activity += (block[y + 1][x] - block[y][x]).abs();
+            }
+        }
+    }
+    // 2 * 7 * 8 neighbouring pairs per block
+    let activity = activity / 112.0;
+    clamp((activity - AQ_FLAT_ACTIVITY) / (AQ_BUSY_ACTIVITY - AQ_FLAT_ACTIVITY), 0.0, 1.0)
+}
+
+// Quantize a scaled coefficient with a zero-bias threshold: values whose magnitude stays
+// below `threshold` become zero, all others are truncated like the non-adaptive path does.
+// A threshold of 1 is therefore plain truncation.
+#[inline(always)]
+fn quantize_deadzone(value: f32, threshold: f32) -> I16 {
+    if value.abs() < threshold {
+        0
+    } else {
+        value as I16
+    }
+}
+
 #[inline]
-fn encode_block<W: FnMut(U8) -> Result<(), &'static str>>(
+fn encode_block<W: FnMut(&[U8]) -> Result<(), &'static str>>(
     writer: &mut BitWriter<W>,
     block: &mut [[f32; 8]; 8],
     scaled: &[f32; 64],
     last_dc: I16,
-    huffman_dc: &[BitCode; 256],
-    huffman_ac: &[BitCode; 256],
-    codewords: &[BitCode; 4096],
+    codes: HuffmanCodes,
+    adaptive: bool,
 ) -> Result<I16, &'static str> {
+    // Must be measured on the spatial samples, before the DCT runs
+    let masking = if adaptive { Some(block_masking(block)) } else { None };
+
     // Flatten block safely
     let mut block64 = [0.0f32; 64];
     for y in 0..8 {
@@ -266,22 +381,43 @@ fn encode_block<W: FnMut(U8) -> Result<(), &'static str>>(
     let dc = block64[0] as I32 + if block64[0] >= 0.0 { 0.5 } else { -0.5 } as I32;
     let dc = dc as I16;
 
-    let mut pos_non_zero = 0;
     let mut quantized = [0; 64];
+    quantized[0] = dc;
     for i in 1..64 {
         let value = block64[ZIGZAG_INV[i] as usize];
-        quantized[i] = value as I16;
-        if quantized[i] != 0 {
-            pos_non_zero = i;
-        }
+        quantized[i] = match masking {
+            // Flat blocks are quantized exactly like the non-adaptive path, so smooth
+            // gradients lose nothing, busy blocks drop more of the high frequencies
+            Some(masking) => {
+                let busy = AQ_BUSY_DEADZONE_LOW + (AQ_BUSY_DEADZONE_HIGH - AQ_BUSY_DEADZONE_LOW) * i as f32 / 63.0;
+                quantize_deadzone(value, 1.0 + masking * (busy - 1.0))
+            }
+            None => value as I16,
+        };
     }
 
-    let diff = dc - last_dc;
+    write_block_coefficients(writer, &quantized, last_dc, codes)?;
+    Ok(dc)
+}
+
+/// Entropy-code one block of quantized coefficients in zigzag order
+///
+/// `last_dc` is the DC value of the previous block of the same component.
+pub(crate) fn write_block_coefficients<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+    writer: &mut BitWriter<W>,
+    quantized: &[I16; 64],
+    last_dc: I16,
+    codes: HuffmanCodes,
+) -> Result<(), &'static str> {
+    let HuffmanCodes { dc: huffman_dc, ac: huffman_ac, codewords } = codes;
+    let pos_non_zero = quantized[1..].iter().rposition(|&ac| ac != 0).map_or(0, |i| i + 1);
+    // Computed in 32 bits, the difference of two 16-bit DC values can exceed the I16 range
+    let diff = quantized[0] as I32 - last_dc as I32;
 
     // Clamp diff to valid range for codewords indexing
-    let min_bound = -(CODE_WORD_LIMIT - 1);
-    let max_bound = CODE_WORD_LIMIT - 1;
-    let clamped_diff = diff.clamp(min_bound, max_bound);
+    let min_bound = -(CODE_WORD_LIMIT as I32 - 1);
+    let max_bound = CODE_WORD_LIMIT as I32 - 1;
+    let clamped_diff = diff.clamp(min_bound, max_bound) as I16;
     
     if clamped_diff == 0 {
         writer.write_bits(huffman_dc[0].code, huffman_dc[0].num_bits)?;
@@ -319,10 +455,10 @@ fn encode_block<W: FnMut(U8) -> Result<(), &'static str>>(
         writer.write_bits(huffman_ac[0].code, huffman_ac[0].num_bits)?;
     }
 
-    Ok(dc)
+    Ok(())
 }
 
-fn generate_huffman_table(num_codes: &[U8], values: &[U8], result: &mut [BitCode]) {
+pub(crate) fn generate_huffman_table(num_codes: &[U8], values: &[U8], result: &mut [BitCode]) {
     let mut huffman_code = 0;
     let mut value_index = 0;
     for num_bits in 1..=16 {
@@ -340,34 +476,24 @@ fn generate_huffman_table(num_codes: &[U8], values: &[U8], result: &mut [BitCode
 ///
 /// # Arguments
 /// * `writer` - Bit writer for output
-/// * `pixels` - Image pixel data in either RGB (3 bytes per pixel) or grayscale (1 byte per pixel) format
-/// * `width` - Image width in pixels
-/// * `height` - Image height in pixels
-/// * `is_rgb` - True if the input is in RGB format, false for grayscale
-/// * `is_ycbcr` - True if the input is in YCbCr format, false for RGB or grayscale
-/// * `quality` - Encoding quality (1-100)
-/// * `downsample` - Whether to downsample chroma channels (4:2:0 subsampling)
-/// * `comment` - Optional comment to include in the JPEG file
+/// * `pixels` - Image pixel data in the format given by `params` (grayscale when none of
+///   `is_rgb`, `is_ycbcr` and `is_cmyk` is set)
+/// * `params` - Dimensions, pixel format and encoding settings
 ///
 /// # Returns
 /// `Result<(), &'static str>` indicating success or an error message
-pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
+pub fn write_jpeg<W: FnMut(&[U8]) -> Result<(), &'static str>>(
     writer: &mut BitWriter<W>,
     pixels: &[U8],
-    width: U16,
-    height: U16,
-    is_rgb: bool,
-    is_ycbcr: bool,
-    quality: U8,
-    subsample: bool,
-    comment: Option<&str>,
+    params: &JpegParams,
 ) -> Result<(), &'static str> {
+    let JpegParams { width, height, is_rgb, is_ycbcr, is_cmyk, .. } = *params;
     // Use the writer directly instead of creating a new variable
     if width == 0 || height == 0 {
         return Err("Invalid image dimensions");
     }
 
-    let bytes_per_pixel = if is_rgb || is_ycbcr { 3 } else { 1 };
+    let bytes_per_pixel = if is_cmyk { 4 } else if is_rgb || is_ycbcr { 3 } else { 1 };
 
     // For YCbCr with 4:2:0 subsampling, dimensions must be even
     // Note: Odd dimensions are now supported with edge-replicated padding
@@ -380,14 +506,69 @@ pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
         return Err("Input buffer too small for specified dimensions and format");
     }
 
-    let is_color = is_rgb || is_ycbcr;
-    let num_components = if is_color { 3 } else { 1 };
+    let mut scan = write_headers(writer, params)?;
+
+    // Each MCU row only needs its own rows of the image, so hand it a slice of just those
+    let row_bytes = width as usize * bytes_per_pixel;
+    for first_row in (0..height as usize).step_by(scan.mcu_height()) {
+        let last_row = (first_row + scan.mcu_height()).min(height as usize);
+        scan.encode_mcu_row(writer, &pixels[first_row * row_bytes..last_row * row_bytes], first_row)?;
+    }
+
+    write_trailer(writer)
+}
+
+/// Encoder state shared by all MCU rows of a scan: tables, Huffman codes and the DC predictors.
+///
+/// Created by `write_headers` once the markers up to SOS are written, then fed one MCU row
+/// at a time by `encode_mcu_row`, which is what lets the streaming encoder keep only a
+/// single MCU row of pixels in memory.
+pub(crate) struct ScanState {
+    width: usize,
+    height: usize,
+    is_color: bool,
+    is_ycbcr: bool,
+    is_cmyk: bool,
+    subsample: bool,
+    adaptive: bool,
+    channels: usize,
+    scaled_lum_row: [f32; 64],
+    scaled_ch_row: [f32; 64],
+    huffman_luminance_dc: [BitCode; 256],
+    huffman_luminance_ac: [BitCode; 256],
+    huffman_chrominance_dc: [BitCode; 256],
+    huffman_chrominance_ac: [BitCode; 256],
+    codewords: [BitCode; 2 * CODE_WORD_LIMIT as usize],
+    // DC of the previous block of each component (Y, Cb, Cr, K)
+    last_dc: [I16; 4],
+}
+
+/// Write all markers from SOI up to and including SOS and prepare the scan state
+///
+/// Takes the same arguments as `write_jpeg`, minus the pixels.
+pub(crate) fn write_headers<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+    writer: &mut BitWriter<W>,
+    params: &JpegParams,
+) -> Result<ScanState, &'static str> {
+    let JpegParams { width, height, is_rgb, is_ycbcr, is_cmyk, quality, subsample, adaptive, comment } = *params;
+    if width == 0 || height == 0 {
+        return Err("Invalid image dimensions");
+    }
+
+    let is_color = is_rgb || is_ycbcr || is_cmyk;
+    let num_components = if is_cmyk { 4 } else if is_color { 3 } else { 1 };
     let subsample = subsample && is_color;
-    let channels = if is_color { 3 } else { 1 };
-    // let mut bit_writer = BitWriter::new(&mut output); // Remove this line, not used
+    let channels = num_components;
 
-    eprintln!("Writing SOI & APP0");
-    writer.write_bytes(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0])?;
+    if is_cmyk {
+        // JFIF only allows gray and YCbCr, four components are identified by Adobe's
+        // APP14 segment instead: version 100, no flags, transform 2 (YCCK)
+        log::trace!("Writing SOI & APP14");
+        writer.write_bytes(&[0xFF, 0xD8, 0xFF, 0xEE, 0, 14, b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, 2])?;
+    } else {
+        log::trace!("Writing SOI & APP0");
+        writer.write_bytes(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0])?;
+    }
 
     if let Some(comment) = comment {
         let length = comment.len();
@@ -412,7 +593,7 @@ pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
 
 
     let table_length = 2 + (if is_color { 2 } else { 1 }) * (1 + 64);
-    eprintln!("Writing DQT");
+    log::trace!("Writing DQT");
     writer.add_marker(0xDB, table_length as U16)?;
     writer.write_byte(0)?;
     writer.write_bytes(&quant_luminance)?;
@@ -423,7 +604,7 @@ pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
 
 
     let frame_length = 2 + 6 + 3 * num_components;
-    eprintln!("Writing SOF0");
+    log::trace!("Writing SOF0");
     writer.add_marker(0xC0, frame_length as U16)?;
     writer.write_byte(8)?;
     writer.write_byte((height >> 8) as U8)?;
@@ -432,50 +613,26 @@ pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
     writer.write_byte(width as U8)?;
     writer.write_byte(num_components as U8)?;
     for id in 1..=num_components {
+        // Y and K are full resolution and share the luminance tables
+        let is_luma = id == 1 || id == 4;
         writer.write_byte(id as U8)?;
-        writer.write_byte(if id == 1 && subsample { 0x22 } else { 0x11 })?;
-        writer.write_byte(if id == 1 { 0 } else { 1 })?;
+        writer.write_byte(if is_luma && subsample { 0x22 } else { 0x11 })?;
+        writer.write_byte(if is_luma { 0 } else { 1 })?;
     }
 
-    let htable_length = if is_color { 2 + 208 + 208 } else { 2 + 208 };
-    eprintln!("Writing DHT");
-    writer.add_marker(0xC4, htable_length as U16)?;
-    writer.write_byte(0)?;
-    writer.write_bytes(&DC_LUMINANCE_CODES_PER_BITSIZE)?;
-    writer.write_bytes(&DC_LUMINANCE_VALUES)?;
-    writer.write_byte(0x10)?;
-    writer.write_bytes(&AC_LUMINANCE_CODES_PER_BITSIZE)?;
-    writer.write_bytes(&AC_LUMINANCE_VALUES)?;
-
-    let mut huffman_luminance_dc = [BitCode::new(0, 0); 256];
-    let mut huffman_luminance_ac = [BitCode::new(0, 0); 256];
-    generate_huffman_table(&DC_LUMINANCE_CODES_PER_BITSIZE, &DC_LUMINANCE_VALUES, &mut huffman_luminance_dc);
-    generate_huffman_table(&AC_LUMINANCE_CODES_PER_BITSIZE, &AC_LUMINANCE_VALUES, &mut huffman_luminance_ac);
-
-    let mut huffman_chrominance_dc = [BitCode::new(0, 0); 256];
-    let mut huffman_chrominance_ac = [BitCode::new(0, 0); 256];
-    if is_color {
-        writer.write_byte(1)?;
-        writer.write_bytes(&DC_CHROMINANCE_CODES_PER_BITSIZE)?;
-        writer.write_bytes(&DC_CHROMINANCE_VALUES)?;
-        writer.write_byte(0x11)?;
-        writer.write_bytes(&AC_CHROMINANCE_CODES_PER_BITSIZE)?;
-        writer.write_bytes(&AC_CHROMINANCE_VALUES)?;
-        generate_huffman_table(&DC_CHROMINANCE_CODES_PER_BITSIZE, &DC_CHROMINANCE_VALUES, &mut huffman_chrominance_dc);
-        generate_huffman_table(&AC_CHROMINANCE_CODES_PER_BITSIZE, &AC_CHROMINANCE_VALUES, &mut huffman_chrominance_ac);
-    }
+    let [huffman_luminance_dc, huffman_luminance_ac, huffman_chrominance_dc, huffman_chrominance_ac] =
+        write_huffman_tables(writer, is_color)?;
 
     let scan_length = 2 + 1 + 2 * num_components + 3;
-    eprintln!("Writing SOS");
+    log::trace!("Writing SOS");
     writer.add_marker(0xDA, scan_length as U16)?;
     writer.write_byte(num_components as U8)?;
     for id in 1..=num_components {
         writer.write_byte(id as U8)?;
-        writer.write_byte(if id == 1 { 0x00 } else { 0x11 })?;
+        writer.write_byte(if id == 1 || id == 4 { 0x00 } else { 0x11 })?;
     }
     writer.write_bytes(&[0, 63, 0])?;
 
-    const AAN_SCALE_FACTORS: [f32; 8] = [1.0, 1.387039845, 1.306562965, 1.175875602, 1.0, 0.785694958, 0.541196100, 0.275899379];
     // Precompute zigzag-scaled quantization tables
     let mut scaled_luminance_zigzag = [0.0; 64];
     let mut scaled_chrominance_zigzag = [0.0; 64];
@@ -495,8 +652,63 @@ pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
         scaled_ch_row[pos] = scaled_chrominance_zigzag[zig];
     }
 
-    let mut codewords_array = [BitCode::new(0, 0); 2 * CODE_WORD_LIMIT as usize];
-    let _codewords = &mut codewords_array[CODE_WORD_LIMIT as usize..];
+    Ok(ScanState {
+        width: width as usize,
+        height: height as usize,
+        is_color,
+        is_ycbcr,
+        is_cmyk,
+        subsample,
+        adaptive,
+        channels,
+        scaled_lum_row,
+        scaled_ch_row,
+        huffman_luminance_dc,
+        huffman_luminance_ac,
+        huffman_chrominance_dc,
+        huffman_chrominance_ac,
+        codewords: codeword_table(),
+        last_dc: [0; 4],
+    })
+}
+
+/// Write the standard Huffman tables (DHT), chrominance only for color images
+///
+/// Returns the luminance DC/AC and chrominance DC/AC codes, in that order.
+pub(crate) fn write_huffman_tables<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+    writer: &mut BitWriter<W>,
+    is_color: bool,
+) -> Result<[[BitCode; 256]; 4], &'static str> {
+    let htable_length = if is_color { 2 + 208 + 208 } else { 2 + 208 };
+    log::trace!("Writing DHT");
+    writer.add_marker(0xC4, htable_length as U16)?;
+    writer.write_byte(0)?;
+    writer.write_bytes(&DC_LUMINANCE_CODES_PER_BITSIZE)?;
+    writer.write_bytes(&DC_LUMINANCE_VALUES)?;
+    writer.write_byte(0x10)?;
+    writer.write_bytes(&AC_LUMINANCE_CODES_PER_BITSIZE)?;
+    writer.write_bytes(&AC_LUMINANCE_VALUES)?;
+
+    let mut tables = [[BitCode::new(0, 0); 256]; 4];
+    generate_huffman_table(&DC_LUMINANCE_CODES_PER_BITSIZE, &DC_LUMINANCE_VALUES, &mut tables[0]);
+    generate_huffman_table(&AC_LUMINANCE_CODES_PER_BITSIZE, &AC_LUMINANCE_VALUES, &mut tables[1]);
+
+    if is_color {
+        writer.write_byte(1)?;
+        writer.write_bytes(&DC_CHROMINANCE_CODES_PER_BITSIZE)?;
+        writer.write_bytes(&DC_CHROMINANCE_VALUES)?;
+        writer.write_byte(0x11)?;
+        writer.write_bytes(&AC_CHROMINANCE_CODES_PER_BITSIZE)?;
+        writer.write_bytes(&AC_CHROMINANCE_VALUES)?;
+        generate_huffman_table(&DC_CHROMINANCE_CODES_PER_BITSIZE, &DC_CHROMINANCE_VALUES, &mut tables[2]);
+        generate_huffman_table(&AC_CHROMINANCE_CODES_PER_BITSIZE, &AC_CHROMINANCE_VALUES, &mut tables[3]);
+    }
+    Ok(tables)
+}
+
+/// Magnitude bits of every value in `-CODE_WORD_LIMIT + 1..CODE_WORD_LIMIT`, indexed by `value + CODE_WORD_LIMIT`
+pub(crate) fn codeword_table() -> [BitCode; 2 * CODE_WORD_LIMIT as usize] {
+    let mut codewords = [BitCode::new(0, 0); 2 * CODE_WORD_LIMIT as usize];
     let mut num_bits = 1;
     let mut mask = 1;
     for value in 1..CODE_WORD_LIMIT {
@@ -504,219 +716,192 @@ pub fn write_jpeg<W: FnMut(U8) -> Result<(), &'static str>>(
             num_bits += 1;
             mask = (mask << 1) | 1;
         }
-        codewords_array[(CODE_WORD_LIMIT - value) as usize] = BitCode::new((mask - value) as U16, num_bits);
-        codewords_array[(CODE_WORD_LIMIT + value) as usize] = BitCode::new(value as U16, num_bits);
+        codewords[(CODE_WORD_LIMIT - value) as usize] = BitCode::new((mask - value) as U16, num_bits);
+        codewords[(CODE_WORD_LIMIT + value) as usize] = BitCode::new(value as U16, num_bits);
     }
+    codewords
+}
 
-    let mcu_size = if subsample { 16 } else { 8 };
-    let max_width = width - 1;
-    let max_height = height - 1;
-    
-    // Calculate padded dimensions that are multiples of MCU size
-    let padded_width = if subsample {
-        (width + 15) & !15  // Round up to next multiple of 16 for 4:2:0
-    } else {
-        (width + 7) & !7    // Round up to next multiple of 8 for 4:4:4
-    };
-    
-    let padded_height = if subsample {
-        (height + 15) & !15  // Round up to next multiple of 16 for 4:2:0
-    } else {
-        (height + 7) & !7    // Round up to next multiple of 8 for 4:4:4
-    };
-
-    // Process MCUs using padded dimensions to ensure complete blocks
-    let sampling = if subsample { 2 } else { 1 };
-    let mcu_size = 8 * sampling;
-    let mut last_y_dc = 0;
-    let mut last_cb_dc = 0;
-    let mut last_cr_dc = 0;
-    let mut y_block = [[0.0; 8]; 8];
-    let mut cb_block = [[0.0; 8]; 8];
-    let mut cr_block = [[0.0; 8]; 8];
-
-    // Process MCUs using padded dimensions to ensure complete blocks
-    for mcu_y in (0..padded_height).step_by(mcu_size as usize) {
-        for mcu_x in (0..padded_width).step_by(mcu_size as usize) {
+/// Flush the remaining bits and write the End Of Image marker
+pub(crate) fn write_trailer<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+    writer: &mut BitWriter<W>,
+) -> Result<(), &'static str> {
+    log::trace!("Writing EOI");
+    writer.flush()?;
+    writer.write_byte(0xFF)?;
+    writer.write_byte(0xD9)?;
+    writer.flush_buffer()
+}
+
+impl ScanState {
+    fn luminance_codes(&self) -> HuffmanCodes<'_> {
+        HuffmanCodes { dc: &self.huffman_luminance_dc, ac: &self.huffman_luminance_ac, codewords: &self.codewords }
+    }
+
+    fn chrominance_codes(&self) -> HuffmanCodes<'_> {
+        HuffmanCodes { dc: &self.huffman_chrominance_dc, ac: &self.huffman_chrominance_ac, codewords: &self.codewords }
+    }
+
+    /// Number of image rows covered by one MCU row (16 for 4:2:0, 8 otherwise)
+    pub(crate) fn mcu_height(&self) -> usize {
+        if self.subsample { 16 } else { 8 }
+    }
+
+    /// Bytes per input pixel expected by `encode_mcu_row`
+    pub(crate) fn channels(&self) -> usize {
+        self.channels
+    }
+
+    // Byte offset of a pixel inside a strip starting at image row `first_row`.
+    // Coordinates past the right/bottom edge replicate the last column/row.
+    #[inline(always)]
+    fn pixel_pos(&self, row: usize, column: usize, first_row: usize) -> usize {
+        let src_row = clamp_max(row, self.height) - first_row;
+        let src_col = clamp_max(column, self.width);
+        (src_row * self.width + src_col) * self.channels
+    }
+
+    /// Encode one row of MCUs
+    ///
+    /// `strip` holds the image rows `first_row..first_row + n`, where `n` is `mcu_height()`
+    /// or whatever remains at the bottom of the image. `first_row` must be a multiple of
+    /// `mcu_height()` and strips must arrive top to bottom.
+    pub(crate) fn encode_mcu_row<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+        &mut self,
+        writer: &mut BitWriter<W>,
+        strip: &[U8],
+        first_row: usize,
+    ) -> Result<(), &'static str> {
+        let mcu_size = self.mcu_height();
+        let rows = (self.height - first_row).min(mcu_size);
+        if strip.len() < rows * self.width * self.channels {
+            return Err("Input buffer too small for specified dimensions and format");
+        }
+
+        // Calculate padded width that is a multiple of the MCU size
+        let padded_width = self.width.div_ceil(mcu_size) * mcu_size;
+
+        let mut cb_block = [[0.0; 8]; 8];
+        let mut cr_block = [[0.0; 8]; 8];
+
+        for mcu_x in (0..padded_width).step_by(mcu_size) {
             // Y block processing
-            for _block_y in (0..mcu_size).step_by(8) {
-                for _block_x in (0..mcu_size).step_by(8) {
-                    for delta_y in 0..8 {
-                        let row = mcu_y + _block_y + delta_y;
-                        let column_start = mcu_x + _block_x;
-                        
-                        for delta_x in 0..8 {
-                            // Calculate source coordinates with clamping
-                            let src_row = clamp_max(row as usize, height as usize);
-                            let src_col = clamp_max((column_start + delta_x) as usize, width as usize);
-                            let pixel_pos = (src_row * width as usize + src_col) * channels;
-                            
-                            if !is_color {
-                                // Grayscale case
-                                y_block[delta_y as usize][delta_x as usize] = pixels[pixel_pos] as f32 - 128.0;
-                            } else {
-                                // RGB case
-                                let r = pixels[pixel_pos];
-                                let g = pixels[pixel_pos + 1];
-                                let b = pixels[pixel_pos + 2];
-                                
-                                // Always calculate Y component
-                                y_block[delta_y as usize][delta_x as usize] = rgb2y(r, g, b) - 128.0;
-
-                                // For non-downsampled color, also calculate Cb and Cr
-                                if !subsample {
-                                    cb_block[delta_y as usize][delta_x as usize] = rgb2cb(r, g, b);
-                                    cr_block[delta_y as usize][delta_x as usize] = rgb2cr(r, g, b);
-                                }
-                            }
-                        }
-                    }
-                    last_y_dc = encode_block(
-                        writer,
-                        &mut y_block,
-                        &scaled_lum_row,
-                        last_y_dc,
-                        &huffman_luminance_dc,
-                        &huffman_luminance_ac,
-                        &codewords_array,
-                    )?;
-                }
-            }
+            self.encode_full_resolution_blocks(writer, strip, first_row, mcu_x, 0)?;
 
             // Chroma block processing
-            if is_color {
-                if subsample {
-                    for delta_y in (0..8).rev() {
-                        let row = mcu_y + 2 * delta_y;
-                        let mut column: u16 = mcu_x;
-                        
-                        for delta_x in 0..8 {
-                            if is_ycbcr {
-                                // Calculate source coordinates with clamping
-                                let src_row = clamp_max(row as usize, height as usize);
-                                let src_col = clamp_max(column as usize, width as usize);
-                                let pixel_pos = (src_row * width as usize + src_col) * 3;
-                                
-                                // Read Cb and Cr from input pixels with bounds checking
-                                if (pixel_pos + 2) < pixels.len() {
-                                    cb_block[delta_y as usize][delta_x as usize] = pixels[pixel_pos + 1] as f32 - 128.0;
-                                    cr_block[delta_y as usize][delta_x as usize] = pixels[pixel_pos + 2] as f32 - 128.0;
-                                } else {
-                                    // Fallback to neutral values if out of bounds (shouldn't happen with proper clamping)
-                                    cb_block[delta_y as usize][delta_x as usize] = 0.0;
-                                    cr_block[delta_y as usize][delta_x as usize] = 0.0;
-                                }
-                                column = column.saturating_add(1);
-                                continue;
-                            }
-
-                            // For RGB input with downsampling
-                            let src_row1 = clamp_max(row as usize, height as usize);
-                            let src_row2 = clamp_max((row + 1) as usize, height as usize);
-                            let src_col1 = clamp_max(column as usize, width as usize);
-                            let src_col2 = clamp_max((column + 1) as usize, width as usize);
-                            
-                            // Calculate positions for 2x2 block with clamping
-                            let p00 = (src_row1 * width as usize + src_col1) * 3;
-                            let p01 = (src_row1 * width as usize + src_col2) * 3;
-                            let p10 = (src_row2 * width as usize + src_col1) * 3;
-                            let p11 = (src_row2 * width as usize + src_col2) * 3;
-                            
-                            // Safely access pixel values with bounds checking
-                            let r_sum = pixels[p00] as u32 + pixels[p01] as u32 + pixels[p10] as u32 + pixels[p11] as u32;
-                            let g_sum = pixels[p00 + 1] as u32 + pixels[p01 + 1] as u32 + pixels[p10 + 1] as u32 + pixels[p11 + 1] as u32;
-                            let b_sum = pixels[p00 + 2] as u32 + pixels[p01 + 2] as u32 + pixels[p10 + 2] as u32 + pixels[p11 + 2] as u32;
-
-                            // Compute average with rounding
-                            let r_avg = ((r_sum + 2) / 4) as u8;
-                            let g_avg = ((g_sum + 2) / 4) as u8;
-                            let b_avg = ((b_sum + 2) / 4) as u8;
-
-                            cb_block[delta_y as usize][delta_x as usize] = rgb2cb(r_avg, g_avg, b_avg);
-                            cr_block[delta_y as usize][delta_x as usize] = rgb2cr(r_avg, g_avg, b_avg);
-
-                            column += 2;
+            if !self.is_color {
+                continue;
+            }
+            // With 4:2:0 every chroma sample averages a 2x2 pixel square
+            let step = if self.subsample { 2 } else { 1 };
+            for delta_y in 0..8 {
+                for delta_x in 0..8 {
+                    let row = first_row + step * delta_y;
+                    let column = mcu_x + step * delta_x;
+                    let (cb, cr) = if self.subsample {
+                        let p00 = self.pixel_pos(row, column, first_row);
+                        let p01 = self.pixel_pos(row, column + 1, first_row);
+                        let p10 = self.pixel_pos(row + 1, column, first_row);
+                        let p11 = self.pixel_pos(row + 1, column + 1, first_row);
+                        // Compute average with rounding
+                        let average = |offset: usize| {
+                            let sum = strip[p00 + offset] as u32 + strip[p01 + offset] as u32
+                                + strip[p10 + offset] as u32 + strip[p11 + offset] as u32;
+                            ((sum + 2) / 4) as u8
+                        };
+                        if self.is_ycbcr {
+                            (average(1) as f32 - 128.0, average(2) as f32 - 128.0)
+                        } else {
+                            // CMYK ink coverage takes the place of RGB, see `luma_sample`
+                            let (r, g, b) = (average(0), average(1), average(2));
+                            (rgb2cb(r, g, b), rgb2cr(r, g, b))
                         }
-                    }
-
-                    last_cb_dc = encode_block(
-                        writer,
-                        &mut cb_block,
-                        &scaled_ch_row,
-                        last_cb_dc,
-                        &huffman_chrominance_dc,
-                        &huffman_chrominance_ac,
-                        &codewords_array,
-                    )?;
-                    last_cr_dc = encode_block(
-                        writer,
-                        &mut cr_block,
-                        &scaled_ch_row,
-                        last_cr_dc,
-                        &huffman_chrominance_dc,
-                        &huffman_chrominance_ac,
-                        &codewords_array,
-                    )?;
-                } else {
-                    // Non-downsampled chroma (4:4:4)
-                    for block_y in (0..mcu_size).step_by(8) {
-                        for block_x in (0..mcu_size).step_by(8) {
-                            for delta_y in 0..8 {
-                                let row = minimum(mcu_y + block_y + delta_y, max_height);
-                                let mut column = minimum(mcu_x + block_x, max_width);
-                                for delta_x in 0..8 {
-                                    let pixel_pos = (row as usize * width as usize + column as usize) * 3;
-                                    if pixel_pos + 2 < pixels.len() {
-                                        if is_ycbcr {
-                                            // For interleaved YCbCr input, convert from unsigned (0-255) to signed (-128 to 127)
-                                            cb_block[delta_y as usize][delta_x as usize] = pixels[pixel_pos + 1] as f32 - 128.0;
-                                            cr_block[delta_y as usize][delta_x as usize] = pixels[pixel_pos + 2] as f32 - 128.0;
-                                        } else {
-                                            let r = pixels[pixel_pos];
-                                            let g = pixels[pixel_pos + 1];
-                                            let b = pixels[pixel_pos + 2];
-                                            cb_block[delta_y as usize][delta_x as usize] = rgb2cb(r, g, b);
-                                            cr_block[delta_y as usize][delta_x as usize] = rgb2cr(r, g, b);
-                                        }
-                                    } else {
-                                        // Pad with neutral values if we're at the edge of the image
-                                        cb_block[delta_y as usize][delta_x as usize] = 0.0;
-                                        cr_block[delta_y as usize][delta_x as usize] = 0.0;
-                                    }
-                                    column += 1;
-                                }
-                            }
-
-                            last_cb_dc = encode_block(
-                                writer,
-                                &mut cb_block,
-                                &scaled_ch_row,
-                                last_cb_dc,
-                                &huffman_chrominance_dc,
-                                &huffman_chrominance_ac,
-                                &codewords_array,
-                            )?;
-                            last_cr_dc = encode_block(
-                                writer,
-                                &mut cr_block,
-                                &scaled_ch_row,
-                                last_cr_dc,
-                                &huffman_chrominance_dc,
-                                &huffman_chrominance_ac,
-                                &codewords_array,
-                            )?;
+                    } else {
+                        let pixel_pos = self.pixel_pos(row, column, first_row);
+                        if self.is_ycbcr {
+                            // For interleaved YCbCr input, convert from unsigned (0-255) to signed (-128 to 127)
+                            (strip[pixel_pos + 1] as f32 - 128.0, strip[pixel_pos + 2] as f32 - 128.0)
+                        } else {
+                            let (r, g, b) = (strip[pixel_pos], strip[pixel_pos + 1], strip[pixel_pos + 2]);
+                            (rgb2cb(r, g, b), rgb2cr(r, g, b))
                         }
-                    }
+                    };
+                    cb_block[delta_y][delta_x] = cb;
+                    cr_block[delta_y][delta_x] = cr;
                 }
             }
+
+            self.last_dc[1] = encode_block(
+                writer,
+                &mut cb_block,
+                &self.scaled_ch_row,
+                self.last_dc[1],
+                self.chrominance_codes(),
+                self.adaptive,
+            )?;
+            self.last_dc[2] = encode_block(
+                writer,
+                &mut cr_block,
+                &self.scaled_ch_row,
+                self.last_dc[2],
+                self.chrominance_codes(),
+                self.adaptive,
+            )?;
+
+            // K block processing
+            if self.is_cmyk {
+                self.encode_full_resolution_blocks(writer, strip, first_row, mcu_x, 3)?;
+            }
         }
+
+        Ok(())
     }
 
-    // Write End Of Image
-    eprintln!("Writing EOI");
-    writer.flush()?;
-    writer.write_byte(0xFF)?;
-    writer.write_byte(0xD9)?;
+    // Sample of the full-resolution component `component` (0 = Y, 3 = K), level-shifted to -128..127
+    //
+    // CMYK follows Adobe's convention: C, M and Y ink coverage are encoded as if they were R, G and B,
+    // and K is stored inverted (255 = no ink).
+    #[inline(always)]
+    fn luma_sample(&self, strip: &[U8], pixel_pos: usize, component: usize) -> f32 {
+        if component == 3 {
+            127.0 - strip[pixel_pos + 3] as f32
+        } else if !self.is_color || self.is_ycbcr {
+            // Grayscale and YCbCr already carry luma in the first byte
+            strip[pixel_pos] as f32 - 128.0
+        } else {
+            rgb2y(strip[pixel_pos], strip[pixel_pos + 1], strip[pixel_pos + 2]) - 128.0
+        }
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    // Encode the 1 (or 4 with subsampling) blocks of a full-resolution component in one MCU
+    fn encode_full_resolution_blocks<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+        &mut self,
+        writer: &mut BitWriter<W>,
+        strip: &[U8],
+        first_row: usize,
+        mcu_x: usize,
+        component: usize,
+    ) -> Result<(), &'static str> {
+        let mcu_size = self.mcu_height();
+        let mut block = [[0.0; 8]; 8];
+        for block_y in (0..mcu_size).step_by(8) {
+            for block_x in (0..mcu_size).step_by(8) {
+                for (delta_y, row) in block.iter_mut().enumerate() {
+                    for (delta_x, sample) in row.iter_mut().enumerate() {
+                        let pixel_pos = self.pixel_pos(first_row + block_y + delta_y, mcu_x + block_x + delta_x, first_row);
+                        *sample = self.luma_sample(strip, pixel_pos, component);
+                    }
+                }
+                self.last_dc[component] = encode_block(
+                    writer,
+                    &mut block,
+                    &self.scaled_lum_row,
+                    self.last_dc[component],
+                    self.luminance_codes(),
+                    self.adaptive,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+//! Lossless rotation, flipping and cropping of existing JPEGs.
+//!
+//! Works on the quantized DCT coefficients like `jpegtran`: blocks are moved and their
+//! coefficients transposed or sign-flipped, so no decode/re-encode generation loss occurs.
+
+use crate::decoder::{read_coefficients, Component, JpegCoefficients};
+use crate::toojpeg::{
+    codeword_table, write_block_coefficients, write_huffman_tables, write_trailer, BitWriter, HuffmanCodes, U16,
+    U8, CODE_WORD_LIMIT, ZIGZAG_INV,
+};
+
+/// A lossless rotation or flip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transform {
+    /// Keep the image as it is
+    #[default]
+    None,
+    /// Mirror left to right
+    FlipHorizontal,
+    /// Mirror top to bottom
+    FlipVertical,
+    /// Mirror across the top-left to bottom-right diagonal
+    Transpose,
+    /// Mirror across the top-right to bottom-left diagonal
+    Transverse,
+    /// Rotate 90 degrees clockwise
+    Rotate90,
+    /// Rotate 180 degrees
+    Rotate180,
+    /// Rotate 270 degrees clockwise
+    Rotate270,
+}
+
+const ALL_TRANSFORMS: [Transform; 8] = [
+    Transform::None,
+    Transform::FlipHorizontal,
+    Transform::FlipVertical,
+    Transform::Transpose,
+    Transform::Transverse,
+    Transform::Rotate90,
+    Transform::Rotate180,
+    Transform::Rotate270,
+];
+
+impl Transform {
+    /// The transform that makes an image stored with the given EXIF orientation (1-8) upright
+    ///
+    /// Unknown values are treated as 1 (already upright).
+    pub fn from_exif_orientation(orientation: u16) -> Self {
+        match orientation {
+            2 => Transform::FlipHorizontal,
+            3 => Transform::Rotate180,
+            4 => Transform::FlipVertical,
+            5 => Transform::Transpose,
+            6 => Transform::Rotate90,
+            7 => Transform::Transverse,
+            8 => Transform::Rotate270,
+            _ => Transform::None,
+        }
+    }
+
+    /// The transform equivalent to applying `self` and then `next`
+    pub fn then(self, next: Transform) -> Transform {
+        let combined = |point| next.apply(self.apply(point));
+        ALL_TRANSFORMS
+            .into_iter()
+            .find(|t| t.apply((1, 0)) == combined((1, 0)) && t.apply((0, 1)) == combined((0, 1)))
+            .unwrap_or(Transform::None)
+    }
+
+    // Every transform is an optional transpose followed by optional horizontal and vertical mirroring
+    fn parts(self) -> (bool, bool, bool) {
+        match self {
+            Transform::None => (false, false, false),
+            Transform::FlipHorizontal => (false, true, false),
+            Transform::FlipVertical => (false, false, true),
+            Transform::Rotate180 => (false, true, true),
+            Transform::Transpose => (true, false, false),
+            Transform::Rotate90 => (true, true, false),
+            Transform::Rotate270 => (true, false, true),
+            Transform::Transverse => (true, true, true),
+        }
+    }
+
+    // Effect on a direction vector, enough to tell the transforms apart
+    fn apply(self, (x, y): (i32, i32)) -> (i32, i32) {
+        let (transpose, flip_x, flip_y) = self.parts();
+        let (x, y) = if transpose { (y, x) } else { (x, y) };
+        (if flip_x { -x } else { x }, if flip_y { -y } else { y })
+    }
+}
+
+/// Region to keep, in pixels of the rotated/flipped image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropRegion {
+    /// Left edge, rounded down to a multiple of the MCU width (8 or 16 pixels)
+    pub x: u32,
+    /// Top edge, rounded down to a multiple of the MCU height (8 or 16 pixels)
+    pub y: u32,
+    /// Width in pixels, the right edge stays where requested
+    pub width: u32,
+    /// Height in pixels, the bottom edge stays where requested
+    pub height: u32,
+}
+
+/// Options for `transform_jpeg`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransformOptions {
+    /// Rotation or flip, relative to the image as a viewer displays it (after EXIF orientation)
+    pub transform: Transform,
+    /// Optional region to keep after the transform
+    pub crop: Option<CropRegion>,
+}
+
+/// Rotate, flip and/or crop a JPEG without re-encoding it
+///
+/// The EXIF orientation is applied together with the requested transform and then reset
+/// to 1, so the output displays the same everywhere. APPn and COM segments are kept.
+/// The output is always a baseline JPEG with the standard Huffman tables.
+///
+/// Partial MCUs cannot be mirrored, so like `jpegtran -trim` a right or bottom edge that
+/// would end up on the left or top is cut to a whole number of MCUs (at most 15 pixels).
+///
+/// # Arguments
+/// * `data` - The complete JPEG file to transform
+/// * `options` - The transform and optional crop region
+/// * `output` - A writer that implements `std::io::Write` to receive the JPEG data
+pub fn transform_jpeg<W: std::io::Write>(
+    data: &[u8],
+    options: TransformOptions,
+    output: &mut W,
+) -> Result<(), &'static str> {
+    let mut jpeg = read_coefficients(data)?;
+    if jpeg.precision != 8 {
+        return Err("Only 8-bit JPEGs can be transformed");
+    }
+
+    let orientation = jpeg
+        .segments
+        .iter()
+        .filter(|(marker, _)| *marker == 0xE1)
+        .find_map(|(_, payload)| exif_orientation_position(payload).map(|(pos, big_endian)| read_u16(payload, pos, big_endian)));
+    let transform = Transform::from_exif_orientation(orientation.unwrap_or(1)).then(options.transform);
+
+    let transformed = transform_coefficients(&jpeg, transform, options.crop)?;
+    for (marker, payload) in jpeg.segments.iter_mut() {
+        if *marker == 0xE1 {
+            if let Some((pos, big_endian)) = exif_orientation_position(payload) {
+                let value = if big_endian { [0, 1] } else { [1, 0] };
+                payload[pos..pos + 2].copy_from_slice(&value);
+            }
+        }
+    }
+    let transformed = JpegCoefficients { segments: std::mem::take(&mut jpeg.segments), ..transformed };
+
+    let mut writer = BitWriter::new(|bytes: &[u8]| output.write_all(bytes).map_err(|_| "Failed to write output"));
+    write_coefficients(&mut writer, &transformed)
+}
+
+/// Move and transform the coefficient blocks of every component
+fn transform_coefficients(
+    jpeg: &JpegCoefficients,
+    transform: Transform,
+    crop: Option<CropRegion>,
+) -> Result<JpegCoefficients, &'static str> {
+    let (transpose, flip_x, flip_y) = transform.parts();
+    let (max_h, max_v) = if transpose { (jpeg.max_v, jpeg.max_h) } else { (jpeg.max_h, jpeg.max_v) };
+    let (mcu_width, mcu_height) = (8 * max_h, 8 * max_v);
+
+    let (mut width, mut height) = if transpose { (jpeg.height, jpeg.width) } else { (jpeg.width, jpeg.height) };
+    // Partial MCUs at the mirrored edges are dropped
+    if flip_x {
+        width -= width % mcu_width;
+    }
+    if flip_y {
+        height -= height % mcu_height;
+    }
+    if width == 0 || height == 0 {
+        return Err("Image is too small to transform losslessly");
+    }
+    let (full_width, full_height) = (width, height);
+
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if let Some(crop) = crop {
+        let (x, y) = (crop.x as usize, crop.y as usize);
+        if crop.width == 0 || crop.height == 0 || x >= width || y >= height {
+            return Err("Crop region is outside the image");
+        }
+        crop_x = x - x % mcu_width;
+        crop_y = y - y % mcu_height;
+        width = (x + crop.width as usize).min(width) - crop_x;
+        height = (y + crop.height as usize).min(height) - crop_y;
+    }
+
+    let mcus_wide = width.div_ceil(mcu_width);
+    let mcus_high = height.div_ceil(mcu_height);
+
+    let components = jpeg
+        .components
+        .iter()
+        .map(|source| {
+            let (h, v) = if transpose { (source.v, source.h) } else { (source.h, source.v) };
+            let stride_blocks = mcus_wide * h;
+            let rows_blocks = mcus_high * v;
+            // Blocks across the whole transformed image, mirrored positions count from its far edge
+            let span_x = full_width.div_ceil(mcu_width) * h;
+            let span_y = full_height.div_ceil(mcu_height) * v;
+            let (offset_x, offset_y) = (crop_x / mcu_width * h, crop_y / mcu_height * v);
+
+            let mut coefficients = vec![0; stride_blocks * rows_blocks * 64];
+            for row in 0..rows_blocks {
+                for column in 0..stride_blocks {
+                    let (x, y) = (column + offset_x, row + offset_y);
+                    if x >= span_x || y >= span_y {
+                        continue;
+                    }
+                    let x = if flip_x { span_x - 1 - x } else { x };
+                    let y = if flip_y { span_y - 1 - y } else { y };
+                    let (x, y) = if transpose { (y, x) } else { (x, y) };
+                    if x >= source.stride_blocks || y >= source.rows_blocks {
+                        continue;
+                    }
+                    let from = (y * source.stride_blocks + x) * 64;
+                    let to = (row * stride_blocks + column) * 64;
+                    transform_block(&source.coefficients[from..from + 64], &mut coefficients[to..to + 64], transform);
+                }
+            }
+
+            let component_width = (width * h).div_ceil(max_h);
+            let component_height = (height * v).div_ceil(max_v);
+            Component {
+                id: source.id,
+                h,
+                v,
+                quant_index: source.quant_index,
+                blocks_wide: component_width.div_ceil(8),
+                blocks_high: component_height.div_ceil(8),
+                stride_blocks,
+                rows_blocks,
+                coefficients,
+            }
+        })
+        .collect();
+
+    // Coefficient (u, v) moves to (v, u), so its quantizer has to move with it
+    let mut quant_tables = jpeg.quant_tables;
+    if transpose {
+        for table in quant_tables.iter_mut() {
+            let original = *table;
+            for (i, value) in table.iter_mut().enumerate() {
+                *value = original[(i % 8) * 8 + i / 8];
+            }
+        }
+    }
+
+    Ok(JpegCoefficients {
+        width,
+        height,
+        max_h,
+        max_v,
+        mcus_wide,
+        mcus_high,
+        components,
+        precision: jpeg.precision,
+        quant_tables,
+        progressive: false,
+        has_jfif: jpeg.has_jfif,
+        adobe_transform: jpeg.adobe_transform,
+        segments: Vec::new(),
+    })
+}
+
+/// Transform one block of coefficients in natural order
+///
+/// Transposing the pixels transposes the coefficients; mirroring negates the odd
+/// horizontal (or vertical) frequencies.
+fn transform_block(source: &[i16], target: &mut [i16], transform: Transform) {
+    let (transpose, flip_x, flip_y) = transform.parts();
+    for v in 0..8 {
+        for u in 0..8 {
+            let value = if transpose { source[u * 8 + v] } else { source[v * 8 + u] };
+            let negate = (flip_x && u % 2 == 1) != (flip_y && v % 2 == 1);
+            target[v * 8 + u] = if negate { -value } else { value };
+        }
+    }
+}
+
+/// Write coefficients as a baseline JPEG, including the segments they carry
+pub(crate) fn write_coefficients<W: FnMut(&[U8]) -> Result<(), &'static str>>(
+    writer: &mut BitWriter<W>,
+    jpeg: &JpegCoefficients,
+) -> Result<(), &'static str> {
+    if jpeg.width > U16::MAX as usize || jpeg.height > U16::MAX as usize {
+        return Err("Invalid image dimensions");
+    }
+    let num_components = jpeg.components.len();
+
+    writer.write_bytes(&[0xFF, 0xD8])?;
+    for (marker, payload) in &jpeg.segments {
+        writer.add_marker(*marker, (payload.len() + 2) as U16)?;
+        writer.write_bytes(payload)?;
+    }
+
+    // Baseline only allows 8-bit quantization tables, larger ones need the extended process
+    let mut tables: Vec<usize> = jpeg.components.iter().map(|c| c.quant_index).collect();
+    tables.sort_unstable();
+    tables.dedup();
+    let extended = tables.iter().any(|&t| jpeg.quant_tables[t].iter().any(|&q| q > 255));
+    for &table in &tables {
+        let precision = if jpeg.quant_tables[table].iter().any(|&q| q > 255) { 1 } else { 0 };
+        writer.add_marker(0xDB, (2 + 1 + (64 << precision)) as U16)?;
+        writer.write_byte((precision << 4) | table as U8)?;
+        for &natural in ZIGZAG_INV.iter() {
+            let value = jpeg.quant_tables[table][natural as usize];
+            if precision == 1 {
+                writer.write_byte((value >> 8) as U8)?;
+            }
+            writer.write_byte(value as U8)?;
+        }
+    }
+
+    writer.add_marker(if extended { 0xC1 } else { 0xC0 }, (2 + 6 + 3 * num_components) as U16)?;
+    writer.write_byte(8)?;
+    writer.write_bytes(&(jpeg.height as U16).to_be_bytes())?;
+    writer.write_bytes(&(jpeg.width as U16).to_be_bytes())?;
+    writer.write_byte(num_components as U8)?;
+    for component in &jpeg.components {
+        writer.write_bytes(&[component.id, ((component.h << 4) | component.v) as U8, component.quant_index as U8])?;
+    }
+
+    let huffman = write_huffman_tables(writer, num_components > 1)?;
+    let codewords = codeword_table();
+
+    writer.add_marker(0xDA, (2 + 1 + 2 * num_components + 3) as U16)?;
+    writer.write_byte(num_components as U8)?;
+    for (i, component) in jpeg.components.iter().enumerate() {
+        writer.write_bytes(&[component.id, if uses_luminance_tables(i) { 0x00 } else { 0x11 }])?;
+    }
+    writer.write_bytes(&[0, 63, 0])?;
+
+    let mut last_dc = vec![0i16; num_components];
+    let mut write_block = |writer: &mut BitWriter<W>, index: usize, block: &[i16]| {
+        let mut zigzag = [0; 64];
+        for (value, &natural) in zigzag.iter_mut().zip(ZIGZAG_INV.iter()) {
+            *value = block[natural as usize];
+        }
+        // Baseline Huffman codes hold magnitudes up to 2047, for coefficients and DC differences
+        // alike; corrupt input can carry larger ones
+        let limit = CODE_WORD_LIMIT as i32 - 1;
+        let dc_diff = zigzag[0] as i32 - last_dc[index] as i32;
+        if dc_diff.abs() > limit || zigzag.iter().any(|&value| (value as i32).abs() > limit) {
+            return Err("Coefficient out of range for a baseline JPEG");
+        }
+        let (dc, ac) = if uses_luminance_tables(index) { (&huffman[0], &huffman[1]) } else { (&huffman[2], &huffman[3]) };
+        write_block_coefficients(writer, &zigzag, last_dc[index], HuffmanCodes { dc, ac, codewords: &codewords })?;
+        last_dc[index] = zigzag[0];
+        Ok::<(), &'static str>(())
+    };
+
+    if num_components == 1 {
+        // A single-component scan is not interleaved: only the blocks covering the image are coded
+        let component = &jpeg.components[0];
+        for row in 0..component.blocks_high {
+            for column in 0..component.blocks_wide {
+                let offset = (row * component.stride_blocks + column) * 64;
+                write_block(writer, 0, &component.coefficients[offset..offset + 64])?;
+            }
+        }
+    } else {
+        for mcu_y in 0..jpeg.mcus_high {
+            for mcu_x in 0..jpeg.mcus_wide {
+                for (index, component) in jpeg.components.iter().enumerate() {
+                    for y in 0..component.v {
+                        for x in 0..component.h {
+                            let row = mcu_y * component.v + y;
+                            let column = mcu_x * component.h + x;
+                            let offset = (row * component.stride_blocks + column) * 64;
+                            write_block(writer, index, &component.coefficients[offset..offset + 64])?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    write_trailer(writer)
+}
+
+// Y and the K of CMYK/YCCK get the luminance Huffman tables, like the encoder does
+fn uses_luminance_tables(component: usize) -> bool {
+    component == 0 || component == 3
+}
+
+fn read_u16(data: &[u8], pos: usize, big_endian: bool) -> u16 {
+    let bytes = [data[pos], data[pos + 1]];
+    if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+}
+
+/// Offset of the orientation value inside an APP1 Exif payload, and whether the TIFF data is big-endian
+fn exif_orientation_position(payload: &[u8]) -> Option<(usize, bool)> {
+    const TIFF_START: usize = 6;
+    let tiff = payload.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u32 = |pos: usize| {
+        let bytes: [u8; 4] = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) } as usize)
+    };
+    let ifd = read_u32(4)?;
+    let count = read_u16(tiff.get(ifd..ifd + 2)?, 0, big_endian) as usize;
+    for i in 0..count {
+        let entry = tiff.get(ifd + 2 + 12 * i..ifd + 14 + 12 * i)?;
+        // Orientation (0x0112), a single SHORT stored inline
+        if read_u16(entry, 0, big_endian) == 0x0112 && read_u16(entry, 2, big_endian) == 3 {
+            return Some((TIFF_START + ifd + 2 + 12 * i + 8, big_endian));
+        }
+    }
+    None
+}
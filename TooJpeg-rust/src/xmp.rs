@@ -0,0 +1,203 @@
+//! XMP metadata in APP1 segments, with Extended XMP for packets over 64 KB.
+//!
+//! A JPEG holds one standard XMP packet in a single APP1 segment. Whatever doesn't fit goes
+//! into an Extended XMP packet split across further APP1 segments; the standard packet names
+//! it by the MD5 digest of its contents in `xmpNote:HasExtendedXMP`, and readers merge the two.
+
+use crate::segment::{header_segments, push_segment};
+
+pub(crate) const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const EXTENSION_NAMESPACE: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+// Largest standard packet: an APP1 payload of 65533 bytes minus the namespace
+const MAX_PACKET: usize = 65533 - XMP_NAMESPACE.len();
+// Extended XMP data per segment, after the namespace, GUID, full length and offset
+const EXTENDED_CHUNK: usize = 65400;
+
+/// XMP of a JPEG
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xmp {
+    /// The standard packet
+    pub standard: String,
+    /// The Extended XMP packet the standard one refers to, reassembled from its segments
+    pub extended: Option<String>,
+}
+
+/// Read the XMP of a JPEG, `None` when it has none
+pub fn read_xmp(jpeg: &[u8]) -> Option<Xmp> {
+    let standard = header_segments(jpeg)
+        .filter(|(_, marker, _)| *marker == 0xE1)
+        .find_map(|(_, _, payload)| payload.strip_prefix(XMP_NAMESPACE))?;
+    let standard = String::from_utf8(standard.to_vec()).ok()?;
+    let extended = extended_guid(&standard).and_then(|guid| read_extended(jpeg, guid));
+    Some(Xmp { standard, extended })
+}
+
+/// Add XMP to a JPEG, splitting it into Extended XMP when it exceeds one segment
+///
+/// When the JPEG already has XMP, the `rdf:Description`s of `xmp` are appended to its packet,
+/// so independent writers (EXIF tools, depth maps, gain maps) can each add theirs. A standard
+/// packet too large for one segment moves into Extended XMP as a whole, leaving a stub that
+/// points to it. Only one Extended XMP packet is allowed per JPEG. New XMP goes after the
+/// JFIF and EXIF segments, which readers expect first.
+///
+/// # Arguments
+/// * `jpeg` - A complete JPEG
+/// * `xmp` - An XMP packet (`<x:xmpmeta>` with an `rdf:RDF` element, optionally wrapped in
+///   `<?xpacket?>` instructions)
+/// * `extended_xmp` - Properties to store as Extended XMP, such as large binary data
+/// * `output` - Buffer that receives the new JPEG
+pub fn insert_xmp(jpeg: &[u8], xmp: &str, extended_xmp: Option<&str>, output: &mut Vec<u8>) -> Result<(), &'static str> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err("Not a JPEG");
+    }
+
+    let existing = header_segments(jpeg)
+        .filter(|(_, marker, _)| *marker == 0xE1)
+        .find_map(|(start, _, payload)| Some((start, core::str::from_utf8(payload.strip_prefix(XMP_NAMESPACE)?).ok()?)));
+    let mut standard = match existing {
+        Some((_, packet)) => merge_packets(packet, xmp)?,
+        None => xmp.to_string(),
+    };
+    let has_extended = existing.is_some_and(|(_, packet)| extended_guid(packet).is_some());
+
+    let mut extended = extended_xmp.map(str::to_string);
+    if extended.is_some() && has_extended {
+        return Err("JPEG already has Extended XMP");
+    }
+    if extended.is_none() && !has_extended && standard.len() > MAX_PACKET {
+        extended = Some(standard);
+        standard = String::from(concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+            r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about=""/>"#,
+            r#"</rdf:RDF></x:xmpmeta>"#,
+        ));
+    }
+
+    let mut segments = Vec::new();
+    if let Some(extended) = &extended {
+        let guid = md5_hex(extended.as_bytes());
+        standard = with_extended_guid(&standard, &guid)?;
+        push_segment(&mut segments, 0xE1, &[XMP_NAMESPACE, standard.as_bytes()].concat())?;
+        let full_length = u32::try_from(extended.len()).map_err(|_| "Extended XMP is too large")?;
+        for (index, chunk) in extended.as_bytes().chunks(EXTENDED_CHUNK).enumerate() {
+            let offset = (index * EXTENDED_CHUNK) as u32;
+            let header = [EXTENSION_NAMESPACE, guid.as_bytes(), &full_length.to_be_bytes(), &offset.to_be_bytes()].concat();
+            push_segment(&mut segments, 0xE1, &[&header[..], chunk].concat())?;
+        }
+    } else {
+        if standard.len() > MAX_PACKET {
+            return Err("XMP packet is too large");
+        }
+        push_segment(&mut segments, 0xE1, &[XMP_NAMESPACE, standard.as_bytes()].concat())?;
+    }
+
+    // The new packet takes the place of the old one, or goes after the JFIF and EXIF headers
+    let (insert_at, resume_at) = match existing {
+        Some((start, packet)) => (start, start + 4 + XMP_NAMESPACE.len() + packet.len()),
+        None => {
+            let after_exif = header_segments(jpeg)
+                .take_while(|(_, marker, payload)| *marker == 0xE0 || (*marker == 0xE1 && payload.starts_with(b"Exif\0\0")))
+                .last()
+                .map_or(2, |(start, _, payload)| start + 4 + payload.len());
+            (after_exif, after_exif)
+        }
+    };
+    output.reserve(jpeg.len() + segments.len());
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&segments);
+    output.extend_from_slice(&jpeg[resume_at..]);
+    Ok(())
+}
+
+// Append the descriptions inside `addition`'s rdf:RDF to those of `packet`
+fn merge_packets(packet: &str, addition: &str) -> Result<String, &'static str> {
+    const NO_RDF: &str = "XMP packet has no rdf:RDF element";
+    let insert_at = packet.rfind("</rdf:RDF>").ok_or(NO_RDF)?;
+    let open = addition.find("<rdf:RDF").ok_or(NO_RDF)?;
+    let inner_start = open + addition[open..].find('>').ok_or(NO_RDF)? + 1;
+    let inner_end = addition.rfind("</rdf:RDF>").filter(|&end| end >= inner_start).ok_or(NO_RDF)?;
+    Ok([&packet[..insert_at], &addition[inner_start..inner_end], &packet[insert_at..]].concat())
+}
+
+// Declare the Extended XMP's GUID on the packet's first rdf:Description
+fn with_extended_guid(packet: &str, guid: &str) -> Result<String, &'static str> {
+    const DESCRIPTION: &str = "<rdf:Description";
+    let at = packet.find(DESCRIPTION).ok_or("XMP packet has no rdf:Description element")? + DESCRIPTION.len();
+    let tag_end = packet[at..].find('>').map_or(packet.len(), |end| at + end);
+    let namespace = if packet[at..tag_end].contains("xmlns:xmpNote=") {
+        ""
+    } else {
+        r#" xmlns:xmpNote="http://ns.adobe.com/xmp/note/""#
+    };
+    Ok(format!(r#"{}{} xmpNote:HasExtendedXMP="{}"{}"#, &packet[..at], namespace, guid, &packet[at..]))
+}
+
+// GUID from `xmpNote:HasExtendedXMP`, as an attribute or an element
+fn extended_guid(packet: &str) -> Option<&str> {
+    let at = packet.find("xmpNote:HasExtendedXMP")? + "xmpNote:HasExtendedXMP".len();
+    let value = packet[at..].trim_start_matches(['=', '"', '\'', '>', ' ']);
+    value.get(..32).filter(|guid| guid.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+// Reassemble the Extended XMP segments carrying `guid`
+//
+// The full length is read from the file, so the buffer is only allocated once the segments'
+// payloads are known to add up to it
+fn read_extended(jpeg: &[u8], guid: &str) -> Option<String> {
+    let chunks: Vec<(usize, usize, &[u8])> = header_segments(jpeg)
+        .filter(|(_, marker, _)| *marker == 0xE1)
+        .filter_map(|(_, _, payload)| payload.strip_prefix(EXTENSION_NAMESPACE))
+        .filter(|chunk| chunk.len() >= 40 && &chunk[..32] == guid.as_bytes())
+        .map(|chunk| {
+            let full_length = u32::from_be_bytes([chunk[32], chunk[33], chunk[34], chunk[35]]) as usize;
+            let offset = u32::from_be_bytes([chunk[36], chunk[37], chunk[38], chunk[39]]) as usize;
+            (full_length, offset, &chunk[40..])
+        })
+        .collect();
+    let full_length = chunks.first()?.0;
+    let received: usize = chunks.iter().map(|(_, _, data)| data.len()).sum();
+    if received != full_length || chunks.iter().any(|&(length, _, _)| length != full_length) {
+        return None;
+    }
+
+    let mut extended = vec![0; full_length];
+    for (_, offset, data) in chunks {
+        extended.get_mut(offset..offset.checked_add(data.len())?)?.copy_from_slice(data);
+    }
+    String::from_utf8(extended).ok()
+}
+
+// MD5 digest as 32 uppercase hex digits, the GUID format of Extended XMP
+fn md5_hex(data: &[u8]) -> String {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: [u32; 64] = core::array::from_fn(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32);
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks_exact(64) {
+        let words: [u32; 16] = core::array::from_fn(|i| u32::from_le_bytes(block[4 * i..4 * i + 4].try_into().unwrap()));
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(constants[i]).wrapping_add(words[g]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated.rotate_left(SHIFTS[(i / 16) * 4 + i % 4]));
+        }
+        for (value, new) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(new);
+        }
+    }
+    state.iter().flat_map(|word| word.to_le_bytes()).map(|byte| format!("{:02X}", byte)).collect()
+}
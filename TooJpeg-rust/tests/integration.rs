@@ -1,6 +1,10 @@
 use std::fs::File;
 use std::io::{self, Read, Write};
-use toojpeg::{encode_jpeg, EncodeOptions, ImageFormat};
+use toojpeg::{
+    composite_alpha, decode_jpeg, dither_to_u8, encode_jpeg, encode_jpeg_12bit, exif_with_thumbnail, extract_alpha, insert_app_segment, insert_xmp, read_xmp, rgb_to_cmyk, rgb_to_cmyk_ucr, transform_jpeg,
+    write_mjpeg_avi, write_ultra_hdr, Background, CropRegion, Dither, EncodeOptions, GainMapMetadata, ImageFormat, JpegStreamEncoder,
+    MjpegAviWriter, MjpegMultipartWriter, Transform, TransformOptions,
+};
 
 #[test]
 fn test_encode_rgb_image() -> io::Result<()> {
@@ -205,3 +209,877 @@ fn test_quality_settings() -> io::Result<()> {
     
     Ok(())
 }
+
+// Deterministic pseudo-random texture so size comparisons are reproducible
+fn textured_image(width: usize, height: usize) -> Vec<u8> {
+    let mut state = 0x2545_F491u32;
+    let mut pixels = vec![0; width * height * 3];
+    for (i, value) in pixels.iter_mut().enumerate() {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let x = (i / 3) % width;
+        *value = (96 + x / 2 + (state >> 26) as usize) as u8;
+    }
+    pixels
+}
+
+fn gradient_image(width: usize, height: usize) -> Vec<u8> {
+    let mut pixels = vec![0; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            let idx = (y * width + x) * 3;
+            pixels[idx] = (60 + x * 120 / width) as u8;
+            pixels[idx + 1] = (110 + y * 80 / height) as u8;
+            pixels[idx + 2] = (200 - x * 40 / width) as u8;
+        }
+    }
+    pixels
+}
+
+fn psnr(original: &[u8], decoded: &[u8]) -> f64 {
+    let mse = original
+        .iter()
+        .zip(decoded)
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum::<f64>()
+        / original.len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+fn encode_and_decode(pixels: &[u8], width: usize, height: usize, adaptive: bool) -> (usize, Vec<u8>) {
+    let options = EncodeOptions {
+        width: width as u32,
+        height: height as u32,
+        format: ImageFormat::RGB,
+        quality: 85,
+        adaptive_quantization: adaptive,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    encode_jpeg(pixels, options, &mut output).unwrap();
+    let decoded = image::load_from_memory_with_format(&output, image::ImageFormat::Jpeg)
+        .expect("adaptive output must stay baseline-decodable")
+        .to_rgb8()
+        .into_raw();
+    (output.len(), decoded)
+}
+
+#[test]
+fn test_adaptive_quantization_shrinks_texture() {
+    let (width, height) = (128, 128);
+    let pixels = textured_image(width, height);
+
+    let (plain_size, plain) = encode_and_decode(&pixels, width, height, false);
+    let (adaptive_size, adaptive) = encode_and_decode(&pixels, width, height, true);
+
+    // Smaller file, at most a marginal loss since the noise masks the dropped detail
+    assert!(adaptive_size < plain_size);
+    assert!(psnr(&pixels, &adaptive) > psnr(&pixels, &plain) - 1.0);
+}
+
+#[test]
+fn test_adaptive_quantization_preserves_gradients() {
+    let (width, height) = (128, 128);
+    let pixels = gradient_image(width, height);
+
+    let (plain_size, plain) = encode_and_decode(&pixels, width, height, false);
+    let (adaptive_size, adaptive) = encode_and_decode(&pixels, width, height, true);
+
+    // Flat areas are quantized exactly like the non-adaptive encoder does
+    assert!(psnr(&pixels, &adaptive) >= psnr(&pixels, &plain));
+    assert!(adaptive_size <= plain_size);
+}
+
+#[test]
+fn test_stream_encoder_matches_encode_jpeg() {
+    // Odd dimensions so the last MCU row and column are partial
+    let (width, height) = (37, 29);
+    let pixels = gradient_image(width, height);
+
+    for &subsample in &[true, false] {
+        let options = EncodeOptions {
+            width: width as u32,
+            height: height as u32,
+            format: ImageFormat::RGB,
+            subsample,
+            ..Default::default()
+        };
+
+        let mut expected = Vec::new();
+        encode_jpeg(&pixels, options, &mut expected).unwrap();
+
+        // Feed batches that don't line up with MCU rows
+        let mut streamed = Vec::new();
+        let mut encoder = JpegStreamEncoder::new(&mut streamed, options).unwrap();
+        for rows in pixels.chunks(5 * width * 3) {
+            encoder.write_rows(rows).unwrap();
+        }
+        assert_eq!(encoder.rows_written(), height);
+        encoder.finish().unwrap();
+
+        assert_eq!(streamed, expected);
+    }
+}
+
+#[test]
+fn test_stream_encoder_rejects_incomplete_image() {
+    let options = EncodeOptions {
+        width: 16,
+        height: 16,
+        format: ImageFormat::Gray,
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    let mut encoder = JpegStreamEncoder::new(&mut output, options).unwrap();
+    assert!(encoder.write_rows(&[0u8; 15]).is_err()); // not a whole row
+    encoder.write_rows(&[0u8; 16 * 8]).unwrap();
+    assert!(encoder.write_rows(&[0u8; 16 * 9]).is_err()); // past the bottom
+    assert_eq!(encoder.finish().unwrap_err(), "Not all rows were written");
+}
+
+// Counts how often the encoder touches its sink
+struct CountingWriter {
+    bytes: Vec<u8>,
+    calls: usize,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.calls += 1;
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_output_is_written_in_chunks() {
+    let (width, height) = (256, 256);
+    let pixels = textured_image(width, height);
+    let options = EncodeOptions {
+        width: width as u32,
+        height: height as u32,
+        format: ImageFormat::RGB,
+        quality: 95,
+        ..Default::default()
+    };
+
+    let mut expected = Vec::new();
+    encode_jpeg(&pixels, options, &mut expected).unwrap();
+
+    let mut sink = CountingWriter { bytes: Vec::new(), calls: 0 };
+    encode_jpeg(&pixels, options, &mut sink).unwrap();
+
+    assert_eq!(sink.bytes, expected);
+    // A handful of buffer flushes, not one call per byte
+    assert!(sink.calls * 1000 < sink.bytes.len());
+}
+
+fn decode_with_image_crate(data: &[u8]) -> Vec<u8> {
+    image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)
+        .unwrap()
+        .to_rgb8()
+        .into_raw()
+}
+
+#[test]
+fn test_decode_round_trip() {
+    // Odd dimensions so the last MCU row and column are partial
+    let (width, height) = (37, 29);
+    let pixels = gradient_image(width, height);
+
+    for subsample in [false, true] {
+        let options = EncodeOptions {
+            width: width as u32,
+            height: height as u32,
+            format: ImageFormat::RGB,
+            quality: 95,
+            subsample,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        encode_jpeg(&pixels, options, &mut output).unwrap();
+
+        let decoded = decode_jpeg(&output).unwrap();
+        assert_eq!((decoded.width, decoded.height), (width as u32, height as u32));
+        assert_eq!(decoded.format, ImageFormat::RGB);
+        assert!(psnr(&pixels, &decoded.pixels) > 40.0, "subsample: {}", subsample);
+
+        // Only rounding differences against an independent decoder
+        assert!(psnr(&decode_with_image_crate(&output), &decoded.pixels) > 45.0);
+    }
+}
+
+#[test]
+fn test_decode_grayscale_round_trip() {
+    let (width, height) = (50, 21);
+    let pixels: Vec<u8> = (0..width * height).map(|i| (i % width * 5) as u8).collect();
+    let options = EncodeOptions {
+        width: width as u32,
+        height: height as u32,
+        format: ImageFormat::Gray,
+        quality: 95,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    encode_jpeg(&pixels, options, &mut output).unwrap();
+
+    let decoded = decode_jpeg(&output).unwrap();
+    assert_eq!(decoded.format, ImageFormat::Gray);
+    assert_eq!(decoded.pixels.len(), width * height);
+    assert!(psnr(&pixels, &decoded.pixels) > 40.0);
+}
+
+#[test]
+fn test_decode_progressive_with_restarts() {
+    let (width, height) = (83, 45);
+    let pixels = textured_image(width, height);
+
+    let mut output = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut output, 90);
+    encoder.set_progressive(true);
+    encoder.set_restart_interval(3);
+    encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_0);
+    encoder.encode(&pixels, width as u16, height as u16, jpeg_encoder::ColorType::Rgb).unwrap();
+
+    let decoded = decode_jpeg(&output).unwrap();
+    assert_eq!((decoded.width, decoded.height), (width as u32, height as u32));
+    assert!(psnr(&decode_with_image_crate(&output), &decoded.pixels) > 45.0);
+}
+
+#[test]
+fn test_decode_successive_approximation() {
+    // Progressive file with DC and AC refinement scans
+    let data = std::fs::read("tests/images/progressive.jpg").unwrap();
+
+    let decoded = decode_jpeg(&data).unwrap();
+    assert_eq!((decoded.width, decoded.height), (32, 23));
+    assert!(psnr(&decode_with_image_crate(&data), &decoded.pixels) > 45.0);
+}
+
+#[test]
+fn test_decode_rejects_invalid_data() {
+    assert!(decode_jpeg(&[]).is_err());
+    assert!(decode_jpeg(b"not a jpeg").is_err());
+
+    let options = EncodeOptions { width: 16, height: 16, format: ImageFormat::Gray, ..Default::default() };
+    let mut output = Vec::new();
+    encode_jpeg(&[128; 256], options, &mut output).unwrap();
+    // Cut inside the quantization tables
+    assert!(decode_jpeg(&output[..40]).is_err());
+}
+
+fn encode_rgb(pixels: &[u8], width: usize, height: usize, subsample: bool) -> Vec<u8> {
+    let options = EncodeOptions {
+        width: width as u32,
+        height: height as u32,
+        format: ImageFormat::RGB,
+        quality: 90,
+        subsample,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    encode_jpeg(pixels, options, &mut output).unwrap();
+    output
+}
+
+fn transform(data: &[u8], transform: Transform, crop: Option<CropRegion>) -> Vec<u8> {
+    let mut output = Vec::new();
+    transform_jpeg(data, TransformOptions { transform, crop }, &mut output).unwrap();
+    output
+}
+
+// Rotate RGB pixels 90 degrees clockwise
+fn rotate_pixels(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut rotated = vec![0; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let target = (x * height + (height - 1 - y)) * 3;
+            rotated[target..target + 3].copy_from_slice(&pixels[(y * width + x) * 3..][..3]);
+        }
+    }
+    rotated
+}
+
+#[test]
+fn test_transform_rotate_matches_pixel_rotation() {
+    let (width, height) = (48, 32);
+    let jpeg = encode_rgb(&textured_image(width, height), width, height, true);
+    let original = decode_jpeg(&jpeg).unwrap();
+
+    let rotated = decode_jpeg(&transform(&jpeg, Transform::Rotate90, None)).unwrap();
+    assert_eq!((rotated.width, rotated.height), (height as u32, width as u32));
+    let expected = rotate_pixels(&original.pixels, width, height);
+    assert!(psnr(&expected, &rotated.pixels) > 50.0);
+}
+
+#[test]
+fn test_transform_is_lossless() {
+    let (width, height) = (48, 32);
+    let jpeg = encode_rgb(&textured_image(width, height), width, height, true);
+    let original = decode_jpeg(&jpeg).unwrap();
+
+    // Four quarter turns, or flipping twice, give back the exact same pixels
+    let mut turned = jpeg.clone();
+    for _ in 0..4 {
+        turned = transform(&turned, Transform::Rotate90, None);
+    }
+    assert_eq!(decode_jpeg(&turned).unwrap().pixels, original.pixels);
+
+    let flipped = transform(&transform(&jpeg, Transform::Transverse, None), Transform::Transverse, None);
+    assert_eq!(decode_jpeg(&flipped).unwrap().pixels, original.pixels);
+}
+
+#[test]
+fn test_transform_trims_partial_mcus() {
+    let (width, height) = (37, 29);
+    let jpeg = encode_rgb(&textured_image(width, height), width, height, true);
+
+    // The partial right column of MCUs can't be mirrored, the bottom one can stay
+    let flipped = decode_jpeg(&transform(&jpeg, Transform::FlipHorizontal, None)).unwrap();
+    assert_eq!((flipped.width, flipped.height), (32, 29));
+
+    let transposed = decode_jpeg(&transform(&jpeg, Transform::Transpose, None)).unwrap();
+    assert_eq!((transposed.width, transposed.height), (29, 37));
+}
+
+#[test]
+fn test_transform_crop() {
+    let (width, height) = (40, 40);
+    let jpeg = encode_rgb(&textured_image(width, height), width, height, false);
+    let original = decode_jpeg(&jpeg).unwrap();
+
+    // The left/top edges snap to the 8x8 MCU grid, the right/bottom ones are kept
+    let crop = CropRegion { x: 10, y: 16, width: 19, height: 100 };
+    let cropped = decode_jpeg(&transform(&jpeg, Transform::None, Some(crop))).unwrap();
+    assert_eq!((cropped.width, cropped.height), (21, 24));
+
+    // Without subsampling every block decodes on its own, so the pixels match exactly
+    for y in 0..24 {
+        let row = &original.pixels[((16 + y) * width + 8) * 3..][..21 * 3];
+        assert_eq!(&cropped.pixels[y * 21 * 3..][..21 * 3], row);
+    }
+}
+
+#[test]
+fn test_transform_rejects_out_of_range_coefficients() {
+    // A 16x8 grayscale JPEG whose Huffman tables allow 15 and 16-bit DC differences: the two
+    // blocks decode to DC -32767 and 32767, which baseline Huffman codes can't represent
+    let jpeg: Vec<u8> = [
+        &[0xFF, 0xD8][..],
+        &[0xFF, 0xDB, 0, 67, 0],
+        &[1; 64],
+        &[0xFF, 0xC0, 0, 11, 8, 0, 8, 0, 16, 1, 1, 0x11, 0],
+        // DC: symbol 15 coded `0`, symbol 16 coded `10`; AC: end of block coded `0`
+        &[0xFF, 0xC4, 0, 21, 0x00, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 15, 16],
+        &[0xFF, 0xC4, 0, 20, 0x10, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00],
+        &[0xFF, 0xDA, 0, 8, 1, 1, 0x00, 0, 63, 0],
+        &[0x00, 0x00, 0x5F, 0xFF, 0x00, 0xCF],
+        &[0xFF, 0xD9],
+    ]
+    .concat();
+    assert!(decode_jpeg(&jpeg).is_ok());
+
+    let options = TransformOptions { transform: Transform::Rotate180, crop: None };
+    let result = transform_jpeg(&jpeg, options, &mut Vec::new());
+    assert_eq!(result.unwrap_err(), "Coefficient out of range for a baseline JPEG");
+}
+
+#[test]
+fn test_transform_applies_and_resets_exif_orientation() {
+    let (width, height) = (48, 32);
+    let jpeg = encode_rgb(&textured_image(width, height), width, height, true);
+
+    // Big-endian Exif with IFD0 holding only Orientation = 6 (rotate 90 clockwise to display)
+    let exif: Vec<u8> = [
+        &b"Exif\0\0MM\0\x2a\0\0\0\x08"[..],
+        &[0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0],
+    ]
+    .concat();
+    let mut tagged = jpeg[..2].to_vec();
+    tagged.extend_from_slice(&[0xFF, 0xE1, 0, (exif.len() + 2) as u8]);
+    tagged.extend_from_slice(&exif);
+    tagged.extend_from_slice(&jpeg[2..]);
+
+    let output = transform(&tagged, Transform::None, None);
+    let decoded = decode_jpeg(&output).unwrap();
+    assert_eq!((decoded.width, decoded.height), (height as u32, width as u32));
+
+    let original = decode_jpeg(&jpeg).unwrap();
+    assert!(psnr(&rotate_pixels(&original.pixels, width, height), &decoded.pixels) > 50.0);
+
+    // The Exif segment is kept, now saying the pixels are upright
+    let position = output.windows(6).position(|w| w == b"Exif\0\0").unwrap();
+    assert_eq!(&output[position + 16..position + 28], &[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 1, 0, 0]);
+}
+
+#[test]
+fn test_transform_composition() {
+    assert_eq!(Transform::Rotate90.then(Transform::Rotate90), Transform::Rotate180);
+    assert_eq!(Transform::Rotate90.then(Transform::Rotate180), Transform::Rotate270);
+    assert_eq!(Transform::FlipHorizontal.then(Transform::FlipVertical), Transform::Rotate180);
+    assert_eq!(Transform::Transpose.then(Transform::FlipHorizontal), Transform::Rotate90);
+    assert_eq!(Transform::from_exif_orientation(8).then(Transform::Rotate90), Transform::None);
+}
+
+#[test]
+fn test_rgb_to_cmyk() {
+    let rgb = [255, 255, 255, 0, 0, 0, 255, 0, 0, 128, 128, 128];
+    let mut cmyk = [0; 16];
+    rgb_to_cmyk(&rgb, &mut cmyk);
+    assert_eq!(cmyk, [0, 0, 0, 0, 0, 0, 0, 255, 0, 255, 255, 0, 0, 0, 0, 127]);
+
+    // No black generation and no ink limit is the plain complement
+    rgb_to_cmyk_ucr(&rgb[..6], &mut cmyk[..8], 0.0, 4.0);
+    assert_eq!(cmyk[..8], [0, 0, 0, 0, 255, 255, 255, 0]);
+
+    // Half the gray component moves to K, the remaining CMY is scaled to fit 150% total
+    rgb_to_cmyk_ucr(&[0, 0, 0], &mut cmyk[..4], 0.5, 1.5);
+    assert_eq!(cmyk[..4], [85, 85, 85, 128]);
+}
+
+#[test]
+fn test_encode_cmyk_round_trip() {
+    let (width, height) = (48, 32);
+    let mut cmyk = vec![0; width * height * 4];
+    rgb_to_cmyk_ucr(&gradient_image(width, height), &mut cmyk, 0.7, 3.0);
+
+    for subsample in [false, true] {
+        let options = EncodeOptions {
+            width: width as u32,
+            height: height as u32,
+            format: ImageFormat::Cmyk,
+            quality: 95,
+            subsample,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        encode_jpeg(&cmyk, options, &mut output).unwrap();
+
+        // Adobe APP14 with the YCCK transform instead of JFIF
+        assert_eq!(&output[2..18], b"\xFF\xEE\x00\x0EAdobe\x00\x64\x00\x00\x00\x00\x02");
+        assert!(!output.windows(4).any(|w| w == b"JFIF"));
+
+        let decoded = decode_jpeg(&output).unwrap();
+        assert_eq!(decoded.format, ImageFormat::Cmyk);
+        assert!(psnr(&cmyk, &decoded.pixels) > 40.0, "subsample: {}", subsample);
+
+        // Four components survive lossless transforms too
+        let mut turned = output.clone();
+        for _ in 0..2 {
+            turned = transform(&turned, Transform::Rotate180, None);
+        }
+        assert_eq!(decode_jpeg(&turned).unwrap().pixels, decoded.pixels);
+    }
+}
+
+#[test]
+fn test_composite_alpha() {
+    let rgba = [200, 100, 0, 255, 200, 100, 0, 0, 0, 0, 0, 128, 10, 20, 30, 0];
+    let mut rgb = [0; 12];
+    composite_alpha(&rgba, &mut rgb, 4, 0, Background::Color([0, 0, 255]));
+    assert_eq!(rgb, [200, 100, 0, 0, 0, 255, 0, 0, 127, 0, 0, 255]);
+
+    // Squares alternate along the row and flip on the next row of squares
+    composite_alpha(&rgba, &mut rgb, 4, 0, Background::Checkerboard(1));
+    assert_eq!(rgb[3..6], [204, 204, 204]);
+    assert_eq!(rgb[9..12], [204, 204, 204]);
+    composite_alpha(&rgba, &mut rgb, 4, 1, Background::Checkerboard(1));
+    assert_eq!(rgb[3..6], [255, 255, 255]);
+
+    let mut alpha = [0; 4];
+    extract_alpha(&rgba, &mut alpha);
+    assert_eq!(alpha, [255, 0, 128, 0]);
+}
+
+#[test]
+fn test_encode_rgba_uses_background() {
+    let (width, height) = (24, 20);
+    // Opaque gradient on the left half, fully transparent blue on the right
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for (i, pixel) in gradient_image(width, height).chunks_exact(3).enumerate() {
+        if i % width < width / 2 {
+            rgba.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 255]);
+        } else {
+            rgba.extend_from_slice(&[0, 0, 255, 0]);
+        }
+    }
+    let background = Background::Color([40, 160, 90]);
+    let mut expected = vec![0; width * height * 3];
+    composite_alpha(&rgba, &mut expected, width, 0, background);
+
+    let options = EncodeOptions {
+        width: width as u32,
+        height: height as u32,
+        format: ImageFormat::RGBA,
+        quality: 95,
+        subsample: false,
+        background,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    encode_jpeg(&rgba, options, &mut output).unwrap();
+    assert!(psnr(&expected, &decode_jpeg(&output).unwrap().pixels) > 35.0);
+
+    // The streaming encoder flattens the same way, also with a checkerboard split across strips
+    for background in [background, Background::Checkerboard(5)] {
+        let options = EncodeOptions { background, subsample: true, ..options };
+        let mut expected = Vec::new();
+        encode_jpeg(&rgba, options, &mut expected).unwrap();
+
+        let mut streamed = Vec::new();
+        let mut encoder = JpegStreamEncoder::new(&mut streamed, options).unwrap();
+        for rows in rgba.chunks(3 * width * 4) {
+            encoder.write_rows(rows).unwrap();
+        }
+        encoder.finish().unwrap();
+        assert_eq!(streamed, expected);
+    }
+}
+
+#[test]
+fn test_dither_to_u8() {
+    // A 10-bit level that falls between two 8-bit values
+    let (width, height) = (32, 32);
+    let level = 401.0 / 1023.0;
+    let samples = vec![level; width * height];
+    let mut output = vec![0u8; width * height];
+
+    dither_to_u8(&samples, &mut output, width, 1, 0, Dither::None);
+    assert!(output.iter().all(|&value| value == 100));
+
+    for dither in [Dither::Ordered, Dither::FloydSteinberg] {
+        dither_to_u8(&samples, &mut output, width, 1, 0, dither);
+        // Only the two neighbouring values are used, in proportions that keep the average
+        assert!(output.iter().all(|&value| value == 99 || value == 100), "{:?}", dither);
+        let mean = output.iter().map(|&value| value as f32).sum::<f32>() / output.len() as f32;
+        assert!((mean - level * 255.0).abs() < 0.05, "{:?}: {}", dither, mean);
+    }
+
+    // Overshoot from resampling is clamped
+    dither_to_u8(&[-0.2, 1.3, 0.0], &mut output[..3], 1, 3, 0, Dither::FloydSteinberg);
+    assert_eq!(output[..3], [0, 255, 0]);
+}
+
+#[test]
+fn test_encode_12bit() {
+    let (width, height) = (45, 30);
+    // 12-bit version of the usual test image plus a ramp finer than 8 bits can represent
+    let pixels: Vec<u16> = gradient_image(width, height)
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| value as u16 * 16 + (i % 16) as u16)
+        .collect();
+
+    for (format, channels) in [(ImageFormat::RGB, 3), (ImageFormat::Gray, 1)] {
+        for subsample in [false, true] {
+            let samples: Vec<u16> = pixels.chunks_exact(3).flat_map(|p| p[..channels].to_vec()).collect();
+            let options = EncodeOptions {
+                width: width as u32,
+                height: height as u32,
+                format,
+                quality: 95,
+                subsample,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            encode_jpeg_12bit(&samples, options, &mut output).unwrap();
+
+            // SOF1 with 12-bit precision and 16-bit quantization tables
+            let sof = output.windows(3).position(|w| w == [0xFF, 0xC1, 0x00]).unwrap();
+            assert_eq!(output[sof + 4], 12);
+            let dqt = output.windows(2).position(|w| w == [0xFF, 0xDB]).unwrap();
+            assert_eq!(output[dqt + 4] >> 4, 1);
+
+            // The decoder scales 12-bit samples down to 8 bits
+            let decoded = decode_jpeg(&output).unwrap();
+            assert_eq!((decoded.width, decoded.height), (width as u32, height as u32));
+            let expected: Vec<u8> = samples.iter().map(|&v| ((v as f32) / 16.0).round().min(255.0) as u8).collect();
+            let quality = psnr(&expected, &decoded.pixels);
+            assert!(quality > 38.0, "{:?} subsample {}: {}", format, subsample, quality);
+        }
+    }
+
+    let options = EncodeOptions { width: 2, height: 2, format: ImageFormat::RGBA, ..Default::default() };
+    assert!(encode_jpeg_12bit(&[0; 16], options, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn test_write_ultra_hdr() {
+    let (width, height) = (48, 32);
+    let options = EncodeOptions { width: width as u32, height: height as u32, format: ImageFormat::RGB, ..Default::default() };
+    let mut primary = Vec::new();
+    encode_jpeg(&gradient_image(width, height), options, &mut primary).unwrap();
+
+    let gain_pixels: Vec<u8> = (0..24 * 16).map(|i| (i % 24 * 10) as u8).collect();
+    let options = EncodeOptions { width: 24, height: 16, format: ImageFormat::Gray, ..Default::default() };
+    let mut gain_map = Vec::new();
+    encode_jpeg(&gain_pixels, options, &mut gain_map).unwrap();
+
+    let metadata = GainMapMetadata {
+        gain_map_min: 0.0,
+        gain_map_max: 2.0,
+        gamma: 1.0,
+        base_offset: 0.0,
+        alternate_offset: 0.0,
+        base_hdr_headroom: 0.0,
+        alternate_hdr_headroom: 2.0,
+    };
+    let mut output = Vec::new();
+    write_ultra_hdr(&primary, &gain_map, &metadata, &mut output).unwrap();
+
+    // Plain decoders see the base image, with JFIF still first
+    let decoded = decode_jpeg(&output).unwrap();
+    assert_eq!((decoded.width, decoded.height), (width as u32, height as u32));
+    assert_eq!(&output[2..4], [0xFF, 0xE0]);
+
+    // The second MP entry points at the gain map, relative to the MPF TIFF header
+    let tiff_start = output.windows(4).position(|w| w == b"MPF\0").unwrap() + 4;
+    let entry = tiff_start + 8 + 2 + 3 * 12 + 4 + 16;
+    let read_u32 = |pos: usize| u32::from_be_bytes(output[pos..pos + 4].try_into().unwrap()) as usize;
+    let (primary_size, gain_map_size, gain_map_offset) = (read_u32(entry - 12), read_u32(entry + 4), read_u32(entry + 8));
+    assert_eq!(tiff_start + gain_map_offset, primary_size);
+    assert_eq!(primary_size + gain_map_size, output.len());
+
+    let appended = &output[primary_size..];
+    let xmp = String::from_utf8_lossy(&output[..primary_size]);
+    assert!(xmp.contains(&format!("Item:Length=\"{}\"", appended.len())));
+    assert!(String::from_utf8_lossy(appended).contains("hdrgm:GainMapMax=\"2\""));
+    let decoded = decode_jpeg(appended).unwrap();
+    assert_eq!((decoded.width, decoded.height), (24, 16));
+    assert!(psnr(&gain_pixels, &decoded.pixels) > 30.0);
+
+    assert!(write_ultra_hdr(&primary, &gain_pixels, &metadata, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn test_insert_app_segment() {
+    let options = EncodeOptions { width: 16, height: 16, format: ImageFormat::Gray, ..Default::default() };
+    let mut jpeg = Vec::new();
+    encode_jpeg(&[128; 256], options, &mut jpeg).unwrap();
+
+    let payload = b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>";
+    let mut output = Vec::new();
+    insert_app_segment(&jpeg, 0xE1, payload, &mut output).unwrap();
+    assert_eq!(output.len(), jpeg.len() + payload.len() + 4);
+
+    // The segment lands after the JFIF header and the image data is untouched
+    let decoded = decode_jpeg(&output).unwrap();
+    assert!(decoded.pixels.iter().all(|&p| p.abs_diff(128) <= 1));
+    assert_eq!(&output[..2], [0xFF, 0xD8]);
+    assert_eq!(&output[2..4], [0xFF, 0xE0]);
+    assert_eq!(&output[20..22], [0xFF, 0xE1]);
+    assert_eq!(&output[24..24 + payload.len()], payload);
+
+    assert!(insert_app_segment(&jpeg, 0xC0, payload, &mut Vec::new()).is_err());
+    assert!(insert_app_segment(&[0; 4], 0xE1, payload, &mut Vec::new()).is_err());
+
+    // A JFIF header longer than the data is an error, not a panic
+    for length in [11, 17, 19] {
+        assert!(insert_app_segment(&jpeg[..length], 0xE1, payload, &mut Vec::new()).is_err());
+    }
+    // APP0 segments other than JFIF stay where they are, after the new segment
+    let jfxx = [&[0xFF, 0xD8, 0xFF, 0xE0, 0, 8][..], b"JFXX ", &[0xFF, 0xD9]].concat();
+    let mut output = Vec::new();
+    insert_app_segment(&jfxx, 0xE1, payload, &mut output).unwrap();
+    assert_eq!(&output[2..4], [0xFF, 0xE1]);
+    assert_eq!(&output[output.len() - 12..], &jfxx[2..]);
+}
+
+#[test]
+fn test_exif_with_thumbnail() {
+    let options = EncodeOptions { width: 48, height: 32, format: ImageFormat::RGB, ..Default::default() };
+    let mut jpeg = Vec::new();
+    encode_jpeg(&gradient_image(48, 32), options, &mut jpeg).unwrap();
+    let thumbnail_pixels = gradient_image(12, 8);
+    let options = EncodeOptions { width: 12, height: 8, format: ImageFormat::RGB, quality: 75, ..Default::default() };
+    let mut thumbnail = Vec::new();
+    encode_jpeg(&thumbnail_pixels, options, &mut thumbnail).unwrap();
+
+    // Little-endian EXIF with one IFD0 entry (Orientation = 6) and no IFD1
+    let exif = [b'I', b'I', 42, 0, 8, 0, 0, 0, 1, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0];
+    for (source, big_endian) in [(Some(&exif[..]), false), (None, true)] {
+        let payload = exif_with_thumbnail(source, &thumbnail).unwrap();
+        assert!(payload.starts_with(b"Exif\0\0"));
+        let tiff = &payload[6..];
+        let read_u16 = |pos: usize| {
+            let bytes = [tiff[pos], tiff[pos + 1]];
+            if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+        };
+        let read_u32 = |pos: usize| {
+            let bytes: [u8; 4] = tiff[pos..pos + 4].try_into().unwrap();
+            (if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }) as usize
+        };
+
+        // IFD0 keeps its entry and now links to IFD1
+        assert_eq!(read_u16(8), 1);
+        assert_eq!(read_u16(10), 0x0112);
+        assert_eq!(read_u16(18), if source.is_some() { 6 } else { 1 });
+        let ifd1 = read_u32(22);
+        assert_eq!(ifd1 % 2, 0);
+
+        let entries: Vec<(u16, usize)> = (0..read_u16(ifd1) as usize)
+            .map(|i| (read_u16(ifd1 + 2 + 12 * i), ifd1 + 2 + 12 * i + 8))
+            .collect();
+        let value = |tag: u16| entries.iter().find(|(t, _)| *t == tag).map(|&(_, pos)| pos).unwrap();
+        assert_eq!(read_u16(value(0x0103)), 6);
+        let (offset, length) = (read_u32(value(0x0201)), read_u32(value(0x0202)));
+        assert_eq!(&tiff[offset..offset + length], &thumbnail[..]);
+
+        let mut output = Vec::new();
+        insert_app_segment(&jpeg, 0xE1, &payload, &mut output).unwrap();
+        let decoded = decode_jpeg(&output).unwrap();
+        assert_eq!((decoded.width, decoded.height), (48, 32));
+        let decoded = decode_jpeg(&tiff[offset..offset + length]).unwrap();
+        assert!(psnr(&thumbnail_pixels, &decoded.pixels) > 25.0);
+    }
+
+    assert!(exif_with_thumbnail(Some(b"not exif"), &thumbnail).is_err());
+    assert!(exif_with_thumbnail(None, &[0; 16]).is_err());
+    assert!(exif_with_thumbnail(None, &vec![0xFF, 0xD8].repeat(40_000)).is_err());
+}
+
+#[test]
+fn test_write_mjpeg_avi() {
+    let frames: Vec<Vec<u8>> = [0u8, 128, 255]
+        .iter()
+        .map(|&level| {
+            let options = EncodeOptions { width: 16, height: 8, format: ImageFormat::Gray, ..Default::default() };
+            let mut jpeg = Vec::new();
+            encode_jpeg(&[level; 128], options, &mut jpeg).unwrap();
+            jpeg
+        })
+        .collect();
+    let frame_refs: Vec<&[u8]> = frames.iter().map(Vec::as_slice).collect();
+    let mut avi = Vec::new();
+    write_mjpeg_avi(&frame_refs, 16, 8, 25, &mut avi).unwrap();
+
+    let u32_at = |pos: usize| u32::from_le_bytes(avi[pos..pos + 4].try_into().unwrap());
+    assert_eq!(&avi[..4], b"RIFF");
+    assert_eq!(u32_at(4) as usize, avi.len() - 8);
+    assert_eq!(&avi[8..12], b"AVI ");
+    // avih: 40000 microseconds per frame, three frames, 16x8
+    assert_eq!(&avi[24..28], b"avih");
+    assert_eq!(u32_at(32), 40_000);
+    assert_eq!(u32_at(48), 3);
+    assert_eq!((u32_at(64), u32_at(68)), (16, 8));
+
+    // Each index entry points at a "00dc" chunk holding the frame, relative to "movi"
+    let movi = avi.windows(4).position(|w| w == b"movi").unwrap();
+    let idx1 = avi.windows(4).rposition(|w| w == b"idx1").unwrap();
+    assert_eq!(u32_at(idx1 + 4), 3 * 16);
+    for (i, frame) in frames.iter().enumerate() {
+        let entry = idx1 + 8 + 16 * i;
+        assert_eq!(&avi[entry..entry + 4], b"00dc");
+        let chunk = movi + u32_at(entry + 8) as usize;
+        assert_eq!(&avi[chunk..chunk + 4], b"00dc");
+        assert_eq!(u32_at(chunk + 4) as usize, frame.len());
+        let decoded = decode_jpeg(&avi[chunk + 8..chunk + 8 + frame.len()]).unwrap();
+        assert_eq!((decoded.width, decoded.height), (16, 8));
+    }
+
+    assert!(write_mjpeg_avi(&[], 16, 8, 25, &mut Vec::new()).is_err());
+    assert!(write_mjpeg_avi(&[&[0; 4]], 16, 8, 25, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn test_mjpeg_writers_with_timestamps() {
+    let options = EncodeOptions { width: 16, height: 8, format: ImageFormat::Gray, ..Default::default() };
+    let mut jpeg = Vec::new();
+    encode_jpeg(&[100; 128], options, &mut jpeg).unwrap();
+
+    // At 10 fps, a frame at 300 ms lands in slot 3 after two empty slots
+    // Written at the output's current position, after what is already there
+    let mut cursor = io::Cursor::new(b"prefix".to_vec());
+    cursor.set_position(6);
+    let mut writer = MjpegAviWriter::new(cursor, 16, 8, 10).unwrap();
+    writer.write_frame_at(&jpeg, std::time::Duration::ZERO).unwrap();
+    writer.write_frame_at(&jpeg, std::time::Duration::from_millis(300)).unwrap();
+    writer.write_frame_at(&jpeg, std::time::Duration::from_millis(310)).unwrap();
+    assert_eq!(writer.frame_count(), 5);
+    let output = writer.finish().unwrap().into_inner();
+    assert!(output.starts_with(b"prefix"));
+    let avi = &output[6..];
+    let u32_at = |pos: usize| u32::from_le_bytes(avi[pos..pos + 4].try_into().unwrap());
+    assert_eq!(u32_at(4) as usize, avi.len() - 8);
+    assert_eq!(u32_at(48), 5);
+    let idx1 = avi.len() - 8 - 5 * 16;
+    assert_eq!(&avi[idx1..idx1 + 4], b"idx1");
+    let sizes: Vec<u32> = (0..5).map(|i| u32_at(idx1 + 8 + 16 * i + 12)).collect();
+    assert_eq!(sizes, [jpeg.len() as u32, 0, 0, jpeg.len() as u32, jpeg.len() as u32]);
+
+    let mut stream = Vec::new();
+    let mut writer = MjpegMultipartWriter::new(&mut stream);
+    writer.write_frame(&jpeg, std::time::Duration::from_micros(1_500_250)).unwrap();
+    assert!(writer.write_frame(&[0; 4], std::time::Duration::ZERO).is_err());
+    writer.finish().unwrap();
+    let header = format!(
+        "--mjpegframe\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: 1.500250\r\n\r\n",
+        jpeg.len()
+    );
+    assert!(stream.starts_with(header.as_bytes()));
+    assert_eq!(&stream[header.len()..header.len() + jpeg.len()], &jpeg[..]);
+    assert!(stream.ends_with(b"\r\n--mjpegframe--\r\n"));
+}
+
+#[test]
+fn test_insert_and_read_xmp() {
+    let options = EncodeOptions { width: 16, height: 16, format: ImageFormat::Gray, ..Default::default() };
+    let mut jpeg = Vec::new();
+    encode_jpeg(&[128; 256], options, &mut jpeg).unwrap();
+    let packet = |body: &str| {
+        format!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">{}</rdf:RDF></x:xmpmeta>"#,
+            body
+        )
+    };
+    let rating = packet(r#"<rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="4"/>"#);
+    let tool = packet(r#"<rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreatorTool="test"/>"#);
+
+    // A second packet is merged into the first instead of adding another segment
+    let mut with_rating = Vec::new();
+    insert_xmp(&jpeg, &rating, None, &mut with_rating).unwrap();
+    assert_eq!(read_xmp(&with_rating).unwrap().standard, rating);
+    let mut with_both = Vec::new();
+    insert_xmp(&with_rating, &tool, None, &mut with_both).unwrap();
+    let xmp = read_xmp(&with_both).unwrap();
+    assert!(xmp.standard.contains(r#"xmp:Rating="4""#) && xmp.standard.contains(r#"xmp:CreatorTool="test""#));
+    assert_eq!(xmp.standard.matches("<rdf:RDF").count(), 1);
+    assert_eq!(xmp.extended, None);
+    assert!(decode_jpeg(&with_both).is_ok());
+
+    // Extended XMP is named by its MD5 digest
+    let mut with_extended = Vec::new();
+    insert_xmp(&jpeg, &rating, Some("abc"), &mut with_extended).unwrap();
+    let xmp = read_xmp(&with_extended).unwrap();
+    assert!(xmp.standard.contains(r#"xmpNote:HasExtendedXMP="900150983CD24FB0D6963F7D28E17F72""#));
+    assert_eq!(xmp.extended.as_deref(), Some("abc"));
+    assert!(insert_xmp(&with_extended, &tool, Some("def"), &mut Vec::new()).is_err());
+
+    // A full length the segments don't add up to is ignored rather than allocated
+    let namespace = b"http://ns.adobe.com/xmp/extension/\0";
+    let length_at = with_extended.windows(namespace.len()).position(|w| w == namespace).unwrap() + namespace.len() + 32;
+    let mut oversized = with_extended.clone();
+    oversized[length_at..length_at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    let xmp = read_xmp(&oversized).unwrap();
+    assert!(xmp.standard.contains("xmpNote:HasExtendedXMP"));
+    assert_eq!(xmp.extended, None);
+
+    // A packet over 64 KB moves to Extended XMP across several segments
+    let large = packet(&format!(r#"<rdf:Description rdf:about="" xmlns:a="urn:a" a:Data="{}"/>"#, "x".repeat(150_000)));
+    let mut with_large = Vec::new();
+    insert_xmp(&jpeg, &large, None, &mut with_large).unwrap();
+    let xmp = read_xmp(&with_large).unwrap();
+    assert!(xmp.standard.len() < 1000);
+    assert_eq!(xmp.extended.as_deref(), Some(&large[..]));
+    let decoded = decode_jpeg(&with_large).unwrap();
+    assert!(decoded.pixels.iter().all(|&p| p.abs_diff(128) <= 1));
+
+    assert!(insert_xmp(&jpeg, "<not-xmp/>", Some("abc"), &mut Vec::new()).is_err());
+    assert!(read_xmp(&jpeg).is_none());
+}
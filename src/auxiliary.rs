@@ -0,0 +1,213 @@
+//! Auxiliary images of the primary image: depth maps, portrait mattes and gain maps.
+//!
+//! Portrait photos carry a depth map and segmentation mattes next to the photo itself. They
+//! can be exported as grayscale JPEGs, or the depth map can ride along inside the converted
+//! JPEG as Google's GDepth XMP, which is what Android gallery apps read for refocusing.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use libheif_rs::{AuxiliaryImagesFilter, ColorSpace, HeifContext, ImageHandle, LibHeif};
+use serde::Serialize;
+use toojpeg::{encode_jpeg, EncodeOptions, ImageFormat};
+use tracing::{debug, info_span};
+
+use crate::gainmap::APPLE_GAIN_MAP_TYPE;
+
+/// What an auxiliary image holds, from its type URN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AuxiliaryKind {
+    /// Depth or disparity map, brighter is closer
+    Depth,
+    /// Apple's portrait effects matte separating people from the background
+    PortraitMatte,
+    /// Apple's semantic segmentation matte for skin
+    SkinMatte,
+    /// Apple's semantic segmentation matte for hair
+    HairMatte,
+    /// Apple's semantic segmentation matte for teeth
+    TeethMatte,
+    /// Apple's semantic segmentation matte for glasses
+    GlassesMatte,
+    /// Apple's semantic segmentation matte for the sky
+    SkyMatte,
+    /// Apple's HDR gain map, see `ConvertOptions::hdr_gain_map`
+    HdrGainMap,
+    /// Any other auxiliary type
+    Other,
+}
+
+impl AuxiliaryKind {
+    fn from_urn(urn: &str) -> Self {
+        match urn {
+            "urn:mpeg:hevc:2015:auxid:2" | "urn:mpeg:mpegB:cicp:systems:auxiliary:depth" => AuxiliaryKind::Depth,
+            "urn:com:apple:photo:2018:aux:portraiteffectsmatte" => AuxiliaryKind::PortraitMatte,
+            "urn:com:apple:photo:2019:aux:semanticskinmatte" => AuxiliaryKind::SkinMatte,
+            "urn:com:apple:photo:2019:aux:semantichairmatte" => AuxiliaryKind::HairMatte,
+            "urn:com:apple:photo:2019:aux:semanticteethmatte" => AuxiliaryKind::TeethMatte,
+            "urn:com:apple:photo:2020:aux:semanticglassesmatte" => AuxiliaryKind::GlassesMatte,
+            "urn:com:apple:photo:2023:aux:semanticskymatte" => AuxiliaryKind::SkyMatte,
+            APPLE_GAIN_MAP_TYPE => AuxiliaryKind::HdrGainMap,
+            _ => AuxiliaryKind::Other,
+        }
+    }
+
+    /// Short name used in exported file names (`photo.depth.jpg`)
+    pub fn name(self) -> &'static str {
+        match self {
+            AuxiliaryKind::Depth => "depth",
+            AuxiliaryKind::PortraitMatte => "matte",
+            AuxiliaryKind::SkinMatte => "skin",
+            AuxiliaryKind::HairMatte => "hair",
+            AuxiliaryKind::TeethMatte => "teeth",
+            AuxiliaryKind::GlassesMatte => "glasses",
+            AuxiliaryKind::SkyMatte => "sky",
+            AuxiliaryKind::HdrGainMap => "gainmap",
+            AuxiliaryKind::Other => "aux",
+        }
+    }
+}
+
+/// An auxiliary image of the primary image
+#[derive(Debug, Clone, Serialize)]
+pub struct AuxiliaryImage {
+    /// What the image holds
+    pub kind: AuxiliaryKind,
+    /// The type URN as stored in the file
+    pub urn: String,
+    /// Width in pixels, usually lower than the photo's
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+}
+
+/// List the auxiliary images of a HEIC's primary image, other than its alpha plane
+pub fn list_auxiliary_images(heic_path: &str) -> Result<Vec<AuxiliaryImage>, Box<dyn std::error::Error>> {
+    let context = HeifContext::read_from_file(heic_path)?;
+    let image_handle = context.primary_image_handle()?;
+    Ok(auxiliary_handles(&image_handle).into_iter().map(|(image, _)| image).collect())
+}
+
+/// Export each auxiliary image of a HEIC as a grayscale JPEG next to `jpeg_path`
+///
+/// `photo.jpg` gets `photo.depth.jpg`, `photo.matte.jpg` and so on; repeated kinds are
+/// numbered (`photo.aux.2.jpg`). Returns the paths written, in file order.
+pub fn export_auxiliary_images(heic_path: &str, jpeg_path: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let _span = info_span!("export_auxiliary_images", input = heic_path).entered();
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_file(heic_path)?;
+    let image_handle = context.primary_image_handle()?;
+
+    let mut written: Vec<PathBuf> = Vec::new();
+    for (image, handle) in auxiliary_handles(&image_handle) {
+        let (samples, width, height) = decode_gray(&lib_heif, &handle)?;
+        let options = EncodeOptions { width, height, format: ImageFormat::Gray, quality: 95, ..Default::default() };
+        let mut output = Vec::new();
+        encode_jpeg(&samples, options, &mut output).map_err(|e| anyhow!(e))?;
+
+        let mut path = Path::new(jpeg_path).with_extension(format!("{}.jpg", image.kind.name()));
+        let mut index = 1;
+        while written.contains(&path) {
+            index += 1;
+            path = Path::new(jpeg_path).with_extension(format!("{}.{}.jpg", image.kind.name(), index));
+        }
+        std::fs::write(&path, output)?;
+        debug!(urn = image.urn, path = %path.display(), "exported auxiliary image");
+        written.push(path);
+    }
+    Ok(written)
+}
+
+// Auxiliary images without the alpha plane, which the conversion itself handles
+pub(crate) fn auxiliary_handles(image_handle: &ImageHandle) -> Vec<(AuxiliaryImage, ImageHandle)> {
+    image_handle
+        .auxiliary_images(AuxiliaryImagesFilter::OMIT_ALPHA)
+        .into_iter()
+        .map(|handle| {
+            let urn = handle.auxiliary_type().unwrap_or_default();
+            let image = AuxiliaryImage {
+                kind: AuxiliaryKind::from_urn(&urn),
+                urn,
+                width: handle.width(),
+                height: handle.height(),
+            };
+            (image, handle)
+        })
+        .collect()
+}
+
+/// Decode an auxiliary image to packed 8-bit grayscale, returning the samples and size
+pub(crate) fn decode_gray(
+    lib_heif: &LibHeif,
+    handle: &ImageHandle,
+) -> Result<(Vec<u8>, u32, u32), Box<dyn std::error::Error>> {
+    let image_data = lib_heif.decode(handle, ColorSpace::Monochrome, None)?;
+    let plane = image_data.planes().y.ok_or_else(|| anyhow!("Decoded auxiliary image has no luma plane"))?;
+    let (width, height) = (plane.width, plane.height);
+    let mut samples = Vec::with_capacity(width as usize * height as usize);
+    for row in plane.data.chunks(plane.stride).take(height as usize) {
+        if plane.storage_bits_per_pixel > 8 {
+            // 10/12-bit depth maps come as little-endian 16-bit samples, keep the top 8 bits
+            let shift = plane.bits_per_pixel.saturating_sub(8);
+            let row = row[..width as usize * 2].chunks_exact(2);
+            samples.extend(row.map(|s| (u16::from_le_bytes([s[0], s[1]]) >> shift).min(255) as u8));
+        } else {
+            samples.extend_from_slice(&row[..width as usize]);
+        }
+    }
+    Ok((samples, width, height))
+}
+
+/// GDepth XMP carrying the image's depth map, or `None` without a depth map
+///
+/// Returns the standard packet with the depth parameters and the Extended XMP packet holding
+/// the map itself, as Google's depth format lays them out; the map keeps its full resolution.
+/// libheif-rs doesn't expose the depth representation info, so the map is declared as inverse
+/// range with nominal near and far planes of 1 and 100: relative depth, which is what
+/// refocusing needs, is preserved.
+pub(crate) fn depth_xmp(
+    lib_heif: &LibHeif,
+    image_handle: &ImageHandle,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let Some((_, handle)) = auxiliary_handles(image_handle).into_iter().find(|(image, _)| image.kind == AuxiliaryKind::Depth)
+    else {
+        return Ok(None);
+    };
+    let (samples, width, height) = decode_gray(lib_heif, &handle)?;
+    let options = EncodeOptions { width, height, format: ImageFormat::Gray, quality: 90, ..Default::default() };
+    let mut jpeg = Vec::new();
+    encode_jpeg(&samples, options, &mut jpeg).map_err(|e| anyhow!(e))?;
+    debug!(width, height, bytes = jpeg.len(), "embedding depth map");
+
+    let packet = |attributes: String| {
+        format!(
+            concat!(
+                r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+                r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+                r#"<rdf:Description rdf:about="" xmlns:GDepth="http://ns.google.com/photos/1.0/depthmap/"{}/>"#,
+                r#"</rdf:RDF></x:xmpmeta>"#,
+            ),
+            attributes
+        )
+    };
+    let standard = packet(r#" GDepth:Format="RangeInverse" GDepth:Near="1" GDepth:Far="100" GDepth:Mime="image/jpeg""#.to_string());
+    let extended = packet(format!(r#" GDepth:Data="{}""#, base64(&jpeg)));
+    Ok(Some((standard, extended)))
+}
+
+// Standard base64 with padding, as XMP binary properties use
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,191 @@
+//! Batch conversion of files and directory trees into one output directory.
+//!
+//! Directory inputs are walked recursively and their subdirectories recreated under the
+//! output directory, so `DCIM/2023/IMG_0001.HEIC` and `DCIM/2024/IMG_0001.HEIC` don't land
+//! on the same JPEG. Outputs that would still clash, with each other or with files already
+//! there, are resolved by a `CollisionStrategy`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, info_span, warn};
+
+use crate::{convert_heic_to_jpeg_with_options, is_heic_path, list_images, numbered_output_path, ConvertOptions, ImageSelection};
+
+/// What `convert_batch` does when an output path is taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionStrategy {
+    /// Add a counter to the name: `photo.jpg`, `photo (2).jpg`, `photo (3).jpg`, … Unlike
+    /// `_2`, the counter can't clash with the names `ImageSelection::All` numbers images with.
+    #[default]
+    Suffix,
+    /// Always add a hash of the input path: `photo-3f2a9c1e.jpg`. The name is the same on
+    /// every run, so a rerun replaces what the last one wrote.
+    Hash,
+    /// Leave the existing file and skip the input
+    Skip,
+    /// Replace the existing file; of inputs with the same output, the last one wins
+    Overwrite,
+}
+
+/// Options for `convert_batch`
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Look for HEIC files in subdirectories of directory inputs (on by default)
+    pub recursive: bool,
+    /// How outputs with the same path are told apart
+    pub collisions: CollisionStrategy,
+    /// Conversion options applied to every file
+    pub convert: ConvertOptions,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self { recursive: true, collisions: CollisionStrategy::default(), convert: ConvertOptions::default() }
+    }
+}
+
+/// Outcome of `convert_batch`, with inputs in the order they were found
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    /// Inputs converted, with the JPEG written for each
+    pub converted: Vec<(PathBuf, PathBuf)>,
+    /// Inputs skipped by `CollisionStrategy::Skip`, with the output that was taken
+    pub skipped: Vec<(PathBuf, PathBuf)>,
+    /// Inputs, or directories, that failed, with the error
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Convert HEIC files and directories of them into `output_dir`, mirroring the folder structure
+///
+/// A file input is written to `output_dir` itself. The HEIC files (`.heic`, `.heics`) of a
+/// directory input go to the same relative path under `output_dir`, with its subdirectories
+/// created as needed; the directory's own name isn't repeated. Files are converted one after
+/// another, so peak memory stays that of a single conversion. A failing file doesn't stop the
+/// batch, it is listed in the report. With `ImageSelection::All`, the numbered JPEGs of a file
+/// with several images (`photo_1.jpg`, `photo_2.jpg`, …) are its outputs for collisions.
+///
+/// # Arguments
+/// * `inputs` - HEIC files and directories
+/// * `output_dir` - Root directory for the JPEGs, created if missing
+/// * `width` - Target width (0 to keep original)
+/// * `height` - Target height (0 to keep original)
+/// * `resize_filter` - Resize filter to use ("lanczos", "bilinear", "catmull_rom" or "mitchell")
+/// * `options` - Batch and conversion options
+pub fn convert_batch<P: AsRef<Path>>(
+    inputs: &[P],
+    output_dir: &str,
+    width: u32,
+    height: u32,
+    resize_filter: &str,
+    options: &BatchOptions,
+) -> Result<BatchReport, Box<dyn std::error::Error>> {
+    let _span = info_span!("convert_batch", output = output_dir, inputs = inputs.len()).entered();
+    let output_dir = Path::new(output_dir);
+    fs::create_dir_all(output_dir)?;
+
+    let mut report = BatchReport::default();
+    let mut files = Vec::new();
+    for input in inputs {
+        let input = input.as_ref();
+        if input.is_dir() {
+            match find_heic_files(input, options.recursive) {
+                Ok(found) => {
+                    files.extend(found.into_iter().map(|file| {
+                        let relative = file.parent().and_then(|parent| parent.strip_prefix(input).ok()).map(Path::to_path_buf);
+                        (file, relative.unwrap_or_default())
+                    }));
+                }
+                Err(e) => report.failed.push((input.to_path_buf(), e.to_string())),
+            }
+        } else {
+            files.push((input.to_path_buf(), PathBuf::new()));
+        }
+    }
+
+    // Outputs are claimed in input order whether or not their conversion succeeds, so names
+    // don't depend on which conversions fail
+    let mut taken = HashSet::new();
+    for (input, relative) in files {
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let dir = output_dir.join(relative);
+        let output = dir.join(format!("{}.jpg", stem));
+        // `ImageSelection::All` numbers the JPEGs of a file with several images
+        let image_count = match options.convert.images {
+            ImageSelection::All => input.to_str().and_then(|input| list_images(input).ok()).map_or(1, |images| images.len()),
+            _ => 1,
+        };
+        let written = |path: &Path| match image_count {
+            0 | 1 => vec![path.to_path_buf()],
+            count => (1..=count).map(|number| numbered_output_path(&path.to_string_lossy(), number)).collect(),
+        };
+        let is_taken = |path: &Path| written(path).iter().any(|path| taken.contains(path) || path.exists());
+        let output = match options.collisions {
+            CollisionStrategy::Hash => dir.join(format!("{}-{:08x}.jpg", stem, fnv1a(input.to_string_lossy().as_bytes()))),
+            _ if !is_taken(&output) => output,
+            CollisionStrategy::Suffix => (2..).map(|number| dir.join(format!("{} ({}).jpg", stem, number))).find(|path| !is_taken(path)).unwrap(),
+            CollisionStrategy::Skip => {
+                debug!(input = %input.display(), output = %output.display(), "output exists, skipping");
+                report.skipped.push((input, output));
+                continue;
+            }
+            CollisionStrategy::Overwrite => output,
+        };
+        taken.extend(written(&output));
+
+        match convert_file(&input, &output, width, height, resize_filter, &options.convert) {
+            Ok(()) => report.converted.push((input, output)),
+            Err(e) => {
+                warn!("Failed to convert {}: {}", input.display(), e);
+                report.failed.push((input, e.to_string()));
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn convert_file(
+    input: &Path,
+    output: &Path,
+    width: u32,
+    height: u32,
+    resize_filter: &str,
+    options: &ConvertOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let input = input.to_str().ok_or("Input path is not valid UTF-8")?;
+    let output = output.to_str().ok_or("Output path is not valid UTF-8")?;
+    convert_heic_to_jpeg_with_options(input, output, width, height, resize_filter, options)?;
+    Ok(())
+}
+
+/// List the HEIC files (`.heic`, `.heics`) of a directory, and of its subdirectories when
+/// `recursive`, sorted by path
+pub fn find_heic_files<P: AsRef<Path>>(dir: P, recursive: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    walk(dir.as_ref(), recursive, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+fn walk(dir: &Path, recursive: bool, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                walk(&path, recursive, found)?;
+            }
+        } else if is_heic_path(&path.to_string_lossy()) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+// 32-bit FNV-1a, stable across runs and Rust versions unlike `DefaultHasher`
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
@@ -0,0 +1,291 @@
+//! `heic2jpeg`: the converter the Android app uses, for desktops and servers.
+//!
+//! Inputs are HEIC files, glob patterns (quoted, for shells that don't expand them) and
+//! directories. Files are converted in parallel; the exit code is 0 when every file was
+//! converted or skipped, 1 when any failed and 2 for invalid arguments.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use clap::{Parser, ValueEnum};
+use heic_to_jpeg_rust::{
+    convert_heic_to_jpeg_with_options, find_heic_files, is_heic_path, list_images, ConvertOptions, ScrubPolicy,
+};
+use rayon::prelude::*;
+
+/// Convert HEIC photos to JPEG
+#[derive(Debug, Parser)]
+#[command(name = "heic2jpeg", version, about)]
+struct Args {
+    /// HEIC files, glob patterns such as `photos/*.heic`, or directories
+    #[arg(required = true)]
+    inputs: Vec<String>,
+    /// Directory to write the JPEGs to, next to each input by default
+    #[arg(short, long)]
+    output_dir: Option<PathBuf>,
+    /// Output file name: `{stem}` is the input's name without extension, `{index}` its
+    /// position among the inputs, from 1
+    #[arg(short, long, default_value = "{stem}.jpg")]
+    name: String,
+    /// JPEG quality from 1 to 100 [default: 95, or 90 when resizing]
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+    /// Resize to `WIDTHxHEIGHT`, to `WIDTHx` or `xHEIGHT` keeping the aspect ratio, or by
+    /// `PERCENT%`
+    #[arg(short, long, value_parser = parse_resize)]
+    resize: Option<Resize>,
+    /// Resize filter
+    #[arg(long, value_enum, default_value_t = Filter::Lanczos)]
+    filter: Filter,
+    /// Chroma subsampling
+    #[arg(long, value_enum, default_value_t = Subsampling::S420)]
+    subsampling: Subsampling,
+    /// What happens to the EXIF and XMP metadata
+    #[arg(short, long, value_enum, default_value_t = Metadata::Drop)]
+    metadata: Metadata,
+    /// What to do when an output file already exists
+    #[arg(short, long, value_enum, default_value_t = Existing::Skip)]
+    existing: Existing,
+    /// Convert the HEIC files in subdirectories of directory inputs too, mirroring the
+    /// subdirectories in the output directory
+    #[arg(short = 'R', long)]
+    recursive: bool,
+    /// Number of files converted at once [default: one per CPU core]
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+    /// Only print errors
+    #[arg(long)]
+    quiet: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Resize {
+    Exact(u32, u32),
+    Width(u32),
+    Height(u32),
+    Percent(u32),
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Filter {
+    Lanczos,
+    Bilinear,
+    CatmullRom,
+    Mitchell,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Subsampling {
+    /// 4:2:0, smaller files
+    #[value(name = "420")]
+    S420,
+    /// 4:4:4, full color resolution
+    #[value(name = "444")]
+    S444,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Metadata {
+    /// Leave the metadata out
+    Drop,
+    /// Copy EXIF and XMP over
+    Keep,
+    /// Copy them without location, serial numbers, device and face regions
+    Scrub,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Existing {
+    /// Leave the existing file and move on
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Leave the existing file and count the input as failed
+    Fail,
+}
+
+// One file to convert
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    if let Some(threads) = args.threads {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            eprintln!("error: can't start {} threads: {}", threads, e);
+            return ExitCode::from(2);
+        }
+    }
+
+    let failed = AtomicUsize::new(0);
+    let jobs = collect_jobs(&args, &failed);
+    if jobs.is_empty() && failed.load(Ordering::Relaxed) == 0 {
+        eprintln!("error: no HEIC files found");
+        return ExitCode::from(2);
+    }
+
+    let options = ConvertOptions {
+        quality: args.quality,
+        subsample_chroma: matches!(args.subsampling, Subsampling::S420),
+        preserve_metadata: !matches!(args.metadata, Metadata::Drop),
+        scrub: matches!(args.metadata, Metadata::Scrub).then(ScrubPolicy::default),
+        ..Default::default()
+    };
+    let converted = AtomicUsize::new(0);
+    let skipped = AtomicUsize::new(0);
+    jobs.par_iter().for_each(|job| match convert(job, &args, &options) {
+        Ok(true) => {
+            converted.fetch_add(1, Ordering::Relaxed);
+            if !args.quiet {
+                println!("{} -> {}", job.input.display(), job.output.display());
+            }
+        }
+        Ok(false) => {
+            skipped.fetch_add(1, Ordering::Relaxed);
+            if !args.quiet {
+                println!("{} exists, skipped", job.output.display());
+            }
+        }
+        Err(e) => {
+            failed.fetch_add(1, Ordering::Relaxed);
+            eprintln!("error: {}: {}", job.input.display(), e);
+        }
+    });
+
+    let failed = failed.into_inner();
+    if !args.quiet {
+        eprintln!("{} converted, {} skipped, {} failed", converted.into_inner(), skipped.into_inner(), failed);
+    }
+    if failed > 0 { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+// Expand the inputs into files with their output paths, counting inputs that match nothing
+// or clash with another input's output as failed
+fn collect_jobs(args: &Args, failed: &AtomicUsize) -> Vec<Job> {
+    // Input files with the directory input they were found in, if any
+    let mut files: Vec<(PathBuf, Option<PathBuf>)> = Vec::new();
+    for input in &args.inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            let found = match find_heic_files(path, args.recursive) {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!("error: {}: {}", input, e);
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            files.extend(found.into_iter().map(|file| (file, Some(path.to_path_buf()))));
+        } else if path.is_file() {
+            files.push((path.to_path_buf(), None));
+        } else {
+            let matches: Vec<PathBuf> = match glob::glob(input) {
+                Ok(paths) => paths.flatten().filter(|path| is_heic_path(&path.to_string_lossy())).collect(),
+                Err(e) => {
+                    eprintln!("error: {}: {}", input, e);
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            if matches.is_empty() {
+                eprintln!("error: {}: no such HEIC file", input);
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            files.extend(matches.into_iter().map(|path| (path, None)));
+        }
+    }
+
+    let mut outputs: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut jobs = Vec::new();
+    for (index, (input, root)) in files.into_iter().enumerate() {
+        let output = output_path(args, &input, root.as_deref(), index + 1);
+        if let Some(other) = outputs.get(&output) {
+            eprintln!("error: {}: {} is also the output of {}", input.display(), output.display(), other.display());
+            failed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        outputs.insert(output.clone(), input.clone());
+        jobs.push(Job { input, output });
+    }
+    jobs
+}
+
+// Where an input goes: the output directory, plus its subdirectory within the directory input
+// it came from, or else the input's own directory
+fn output_path(args: &Args, input: &Path, root: Option<&Path>, index: usize) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let name = args.name.replace("{stem}", &stem).replace("{index}", &index.to_string());
+    let parent = input.parent().unwrap_or(Path::new(""));
+    let dir = match &args.output_dir {
+        Some(output_dir) => {
+            let relative = root.and_then(|root| parent.strip_prefix(root).ok()).unwrap_or(Path::new(""));
+            output_dir.join(relative)
+        }
+        None => parent.to_path_buf(),
+    };
+    dir.join(name)
+}
+
+// Convert one file; `false` when it was skipped because the output exists
+fn convert(job: &Job, args: &Args, options: &ConvertOptions) -> Result<bool, Box<dyn std::error::Error>> {
+    if job.output.exists() {
+        match args.existing {
+            Existing::Skip => return Ok(false),
+            Existing::Fail => return Err(format!("{} already exists", job.output.display()).into()),
+            Existing::Overwrite => {}
+        }
+    }
+    if let Some(dir) = job.output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let input = job.input.to_str().ok_or("path is not valid UTF-8")?;
+    let output = job.output.to_str().ok_or("output path is not valid UTF-8")?;
+    let (width, height) = match args.resize {
+        Some(resize) => target_size(input, resize)?,
+        None => (0, 0),
+    };
+    let filter = match args.filter {
+        Filter::Lanczos => "lanczos",
+        Filter::Bilinear => "bilinear",
+        Filter::CatmullRom => "catmull_rom",
+        Filter::Mitchell => "mitchell",
+    };
+    convert_heic_to_jpeg_with_options(input, output, width, height, filter, options)?;
+    Ok(true)
+}
+
+// Output size for the primary image
+fn target_size(input: &str, resize: Resize) -> Result<(u32, u32), Box<dyn std::error::Error>> {
+    let images = list_images(input)?;
+    let primary = images.iter().find(|image| image.is_primary).ok_or("no primary image")?;
+    let (width, height) = (primary.width as u64, primary.height as u64);
+    let scaled = |value: u64, numerator: u64, denominator: u64| ((value * numerator + denominator / 2) / denominator).max(1) as u32;
+    Ok(match resize {
+        Resize::Exact(width, height) => (width, height),
+        Resize::Width(new_width) => (new_width, scaled(height, new_width as u64, width)),
+        Resize::Height(new_height) => (scaled(width, new_height as u64, height), new_height),
+        Resize::Percent(percent) => (scaled(width, percent as u64, 100), scaled(height, percent as u64, 100)),
+    })
+}
+
+fn parse_resize(spec: &str) -> Result<Resize, String> {
+    let number = |text: &str| text.parse::<u32>().ok().filter(|&value| value > 0);
+    let invalid = || format!("expected WIDTHxHEIGHT, WIDTHx, xHEIGHT or PERCENT%, got `{}`", spec);
+    if let Some(percent) = spec.strip_suffix('%') {
+        return number(percent).map(Resize::Percent).ok_or_else(invalid);
+    }
+    match spec.split_once('x') {
+        Some((width, "")) => number(width).map(Resize::Width),
+        Some(("", height)) => number(height).map(Resize::Height),
+        Some((width, height)) => number(width).zip(number(height)).map(|(width, height)| Resize::Exact(width, height)),
+        None => None,
+    }
+    .ok_or_else(invalid)
+}
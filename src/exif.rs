@@ -0,0 +1,490 @@
+//! Minimal reader for the TIFF structure EXIF data is stored in, and the EXIF segment of
+//! converted JPEGs.
+
+use libheif_rs::ImageHandle;
+use toojpeg::exif_with_thumbnail;
+use tracing::warn;
+
+use crate::metadata::MetadataEdits;
+use crate::privacy::{scrub_exif, ScrubPolicy, ScrubReport};
+
+/// One IFD entry, with the position of its value resolved
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+    /// Start of the value in the TIFF data, inside the entry when it fits in 4 bytes
+    pub value_offset: usize,
+}
+
+/// TIFF data with its byte order
+pub(crate) struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// Parse the byte order from an `II*\0` or `MM\0*` header
+    pub(crate) fn new(data: &'a [u8]) -> Option<Self> {
+        match data.get(..4)? {
+            b"II*\0" => Some(Self { data, big_endian: false }),
+            b"MM\0*" => Some(Self { data, big_endian: true }),
+            _ => None,
+        }
+    }
+
+    /// Data without a TIFF header whose offsets count from its start, such as maker notes
+    pub(crate) fn with_byte_order(data: &'a [u8], big_endian: bool) -> Self {
+        Self { data, big_endian }
+    }
+
+    pub(crate) fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// Offset of IFD0
+    pub(crate) fn first_ifd(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    pub(crate) fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    pub(crate) fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    /// Entries of the IFD at `ifd`, stopping at the first one that runs past the data
+    pub(crate) fn entries(&self, ifd: usize) -> impl Iterator<Item = Entry> + '_ {
+        let count = self.u16_at(ifd).unwrap_or(0) as usize;
+        (0..count).map_while(move |i| {
+            let pos = ifd + 2 + 12 * i;
+            let kind = self.u16_at(pos + 2)?;
+            let count = self.u32_at(pos + 4)?;
+            let size = type_size(kind).saturating_mul(count as usize);
+            let value_offset = if size <= 4 { pos + 8 } else { self.u32_at(pos + 8)? as usize };
+            Some(Entry { tag: self.u16_at(pos)?, kind, count, value_offset })
+        })
+    }
+
+    pub(crate) fn find(&self, ifd: usize, tag: u16) -> Option<Entry> {
+        self.entries(ifd).find(|entry| entry.tag == tag)
+    }
+
+    /// Raw bytes of an entry's value
+    pub(crate) fn bytes(&self, entry: &Entry) -> Option<&'a [u8]> {
+        let size = type_size(entry.kind).checked_mul(entry.count as usize)?;
+        self.data.get(entry.value_offset..entry.value_offset.checked_add(size)?)
+    }
+
+    /// First value of a RATIONAL or SRATIONAL entry
+    pub(crate) fn rational(&self, entry: &Entry) -> Option<f64> {
+        let (numerator, denominator) = (self.u32_at(entry.value_offset)?, self.u32_at(entry.value_offset + 4)?);
+        let value = match entry.kind {
+            5 => numerator as f64 / denominator as f64,
+            10 => numerator as i32 as f64 / denominator as i32 as f64,
+            _ => return None,
+        };
+        value.is_finite().then_some(value)
+    }
+}
+
+// Size in bytes of one value of a TIFF field type
+fn type_size(kind: u16) -> usize {
+    match kind {
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+/// TIFF data of the image's Exif metadata block, without HEIF's offset prefix
+pub(crate) fn heif_exif(image_handle: &ImageHandle) -> Option<Vec<u8>> {
+    let block = image_handle.all_metadata().into_iter().find(|metadata| metadata.item_type.0 == *b"Exif")?;
+    // The block starts with the big-endian offset of the TIFF header, usually past "Exif\0\0"
+    let offset = u32::from_be_bytes(block.raw_data.get(..4)?.try_into().ok()?) as usize;
+    block.raw_data.get(4 + offset..).map(<[u8]>::to_vec)
+}
+
+/// APP1 payload for the converted JPEG: the image's EXIF (with `preserve`) after `edits`,
+/// with an optional IFD1 thumbnail
+///
+/// The orientation is reset to 1 because libheif already applies the HEIF rotation while
+/// decoding; keeping it would make viewers rotate the image a second time. `None` when there
+/// is neither EXIF nor a thumbnail.
+pub(crate) fn exif_payload(
+    image_handle: &ImageHandle,
+    preserve: bool,
+    edits: &MetadataEdits,
+    scrub: Option<(&ScrubPolicy, &mut ScrubReport)>,
+    thumbnail: Option<&[u8]>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut tiff = if preserve { heif_exif(image_handle) } else { None };
+    if let Some(tiff) = tiff.as_mut() {
+        reset_orientation(tiff);
+    }
+    // The scrub runs before the edits, which may add an artist or position back on purpose
+    if let (Some((policy, report)), Some(heif_tiff)) = (scrub, tiff.as_deref()) {
+        tiff = Some(scrub_exif(heif_tiff, policy, report)?);
+    }
+    if !edits.is_empty() {
+        tiff = Some(edits.apply(tiff.as_deref())?);
+    }
+    if let Some(thumbnail) = thumbnail {
+        match exif_with_thumbnail(tiff.as_deref(), thumbnail) {
+            Ok(payload) => return Ok(Some(payload)),
+            Err(e) => warn!("leaving out the EXIF thumbnail: {}", e),
+        }
+    }
+    let Some(tiff) = tiff else { return Ok(None) };
+    let payload = [&b"Exif\0\0"[..], &tiff].concat();
+    if payload.len() + 2 > u16::MAX as usize {
+        warn!("EXIF data is too large for an APP1 segment, leaving it out");
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+// Set the IFD0 Orientation tag to 1 (upright) where there is one
+fn reset_orientation(tiff: &mut [u8]) {
+    const ORIENTATION: u16 = 0x0112;
+    let Some(reader) = Tiff::new(tiff) else { return };
+    let big_endian = reader.is_big_endian();
+    let Some(entry) = reader.first_ifd().and_then(|ifd| reader.find(ifd, ORIENTATION)) else { return };
+    // A truncated IFD can end inside the entry, before its value
+    let Some(field) = tiff.get_mut(entry.value_offset..entry.value_offset + 2) else { return };
+    if entry.kind == 3 {
+        field.copy_from_slice(&if big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() });
+    }
+}
+
+// IFDs an edited EXIF block is made of, indices into `ExifIfds::ifds`
+pub(crate) const IFD0: usize = 0;
+pub(crate) const EXIF_IFD: usize = 1;
+pub(crate) const INTEROP_IFD: usize = 2;
+pub(crate) const GPS_IFD: usize = 3;
+
+// Tags pointing from one IFD to another, with the IFD they point from and to
+const POINTERS: [(u16, usize, usize); 3] = [(0x8769, IFD0, EXIF_IFD), (0xA005, EXIF_IFD, INTEROP_IFD), (0x8825, IFD0, GPS_IFD)];
+
+// TIFF field types used by the writer
+pub(crate) const BYTE: u16 = 1;
+pub(crate) const ASCII: u16 = 2;
+pub(crate) const LONG: u16 = 4;
+pub(crate) const RATIONAL: u16 = 5;
+
+/// A tag with its raw value, in the byte order of the block it belongs to
+#[derive(Debug, Clone)]
+pub(crate) struct Field {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+/// EXIF tags by IFD, for editing and writing back out as TIFF
+///
+/// IFD1 and its thumbnail are dropped, `exif_with_thumbnail` adds a new one. Values are moved
+/// as they are, so maker notes that address data relative to the TIFF header (rather than to
+/// themselves, as Apple's do) come out broken; so does anything behind SubIFDs, which is left out.
+pub(crate) struct ExifIfds {
+    big_endian: bool,
+    ifds: [Vec<Field>; 4],
+}
+
+impl ExifIfds {
+    /// An empty block in big-endian byte order
+    pub(crate) fn new() -> Self {
+        Self { big_endian: true, ifds: Default::default() }
+    }
+
+    /// Read IFD0 and the Exif, Interop and GPS IFDs it points to
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        const SUB_IFDS: u16 = 0x014A;
+        let tiff = Tiff::new(data)?;
+        let mut ifds: [Vec<Field>; 4] = Default::default();
+        let mut pending = vec![(IFD0, tiff.first_ifd()?)];
+        while let Some((index, offset)) = pending.pop() {
+            for entry in tiff.entries(offset) {
+                if let Some(&(_, _, target)) = POINTERS.iter().find(|(tag, from, _)| *tag == entry.tag && *from == index) {
+                    pending.push((target, tiff.u32_at(entry.value_offset)? as usize));
+                } else if entry.tag != SUB_IFDS {
+                    let data = tiff.bytes(&entry)?.to_vec();
+                    ifds[index].push(Field { tag: entry.tag, kind: entry.kind, count: entry.count, data });
+                }
+            }
+        }
+        Some(Self { big_endian: tiff.is_big_endian(), ifds })
+    }
+
+    /// Set a tag, replacing it if present
+    pub(crate) fn set(&mut self, ifd: usize, tag: u16, kind: u16, count: u32, data: Vec<u8>) {
+        self.ifds[ifd].retain(|field| field.tag != tag);
+        self.ifds[ifd].push(Field { tag, kind, count, data });
+    }
+
+    /// Set an ASCII tag
+    pub(crate) fn set_ascii(&mut self, ifd: usize, tag: u16, text: &str) {
+        let data = [text.as_bytes(), b"\0"].concat();
+        self.set(ifd, tag, ASCII, data.len() as u32, data);
+    }
+
+    /// Set a tag holding unsigned rationals
+    pub(crate) fn set_rationals(&mut self, ifd: usize, tag: u16, values: &[(u32, u32)]) {
+        let data = values.iter().flat_map(|&(numerator, denominator)| [self.u32_bytes(numerator), self.u32_bytes(denominator)]);
+        let data = data.flatten().collect();
+        self.set(ifd, tag, RATIONAL, values.len() as u32, data);
+    }
+
+    /// Remove a tag from one IFD
+    pub(crate) fn remove_from(&mut self, ifd: usize, tag: u16) {
+        self.ifds[ifd].retain(|field| field.tag != tag);
+    }
+
+    /// Remove a tag from IFD0, the Exif IFD and the GPS IFD; removing a pointer tag drops the
+    /// IFD it points to. Interop tags share numbers with GPS tags and are left alone.
+    pub(crate) fn remove(&mut self, tag: u16) {
+        for ifd in [IFD0, EXIF_IFD, GPS_IFD] {
+            self.remove_from(ifd, tag);
+        }
+        for (pointer, _, target) in POINTERS {
+            if pointer == tag {
+                self.ifds[target].clear();
+                if target == EXIF_IFD {
+                    self.ifds[INTEROP_IFD].clear();
+                }
+            }
+        }
+    }
+
+    /// Whether a tag is in IFD0, the Exif IFD or the GPS IFD; for a pointer tag, whether the
+    /// IFD it points to has any tags
+    pub(crate) fn contains(&self, tag: u16) -> bool {
+        if let Some(&(_, _, target)) = POINTERS.iter().find(|(pointer, _, _)| *pointer == tag) {
+            return !self.ifds[target].is_empty();
+        }
+        [IFD0, EXIF_IFD, GPS_IFD].iter().any(|&ifd| self.ifds[ifd].iter().any(|field| field.tag == tag))
+    }
+
+    /// Tags of one IFD, in the order they were read or set
+    pub(crate) fn fields(&self, ifd: usize) -> &[Field] {
+        &self.ifds[ifd]
+    }
+
+    pub(crate) fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    pub(crate) fn u32_bytes(&self, value: u32) -> [u8; 4] {
+        if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    fn u16_bytes(&self, value: u16) -> [u8; 2] {
+        if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    /// Serialize as TIFF: header, IFD0, then the Exif, Interop and GPS IFDs, each followed by
+    /// the values that don't fit in its entries
+    pub(crate) fn write(&self) -> Vec<u8> {
+        // Pointer entries go in for every IFD that is written; Interop needs the Exif IFD
+        let present = |index: usize| match index {
+            EXIF_IFD => !self.ifds[EXIF_IFD].is_empty() || !self.ifds[INTEROP_IFD].is_empty(),
+            _ => !self.ifds[index].is_empty(),
+        };
+        let mut ifds = self.ifds.clone();
+        for (tag, from, target) in POINTERS {
+            if present(target) {
+                ifds[from].push(Field { tag, kind: LONG, count: 1, data: vec![0; 4] });
+            }
+        }
+
+        let size = |fields: &[Field]| {
+            let values: usize = fields.iter().filter(|field| field.data.len() > 4).map(|field| field.data.len().next_multiple_of(2)).sum();
+            2 + 12 * fields.len() + 4 + values
+        };
+        let mut offsets = [0usize; 4];
+        let mut next = 8;
+        for index in [IFD0, EXIF_IFD, INTEROP_IFD, GPS_IFD] {
+            if index == IFD0 || present(index) {
+                offsets[index] = next;
+                next += size(&ifds[index]);
+            }
+        }
+        for (tag, from, target) in POINTERS {
+            if let Some(field) = ifds[from].iter_mut().find(|field| field.tag == tag) {
+                field.data = self.u32_bytes(offsets[target] as u32).to_vec();
+            }
+        }
+
+        let mut output = if self.big_endian { b"MM\0*".to_vec() } else { b"II*\0".to_vec() };
+        output.extend_from_slice(&self.u32_bytes(8));
+        for index in [IFD0, EXIF_IFD, INTEROP_IFD, GPS_IFD] {
+            if index != IFD0 && !present(index) {
+                continue;
+            }
+            let fields = &mut ifds[index];
+            fields.sort_by_key(|field| field.tag);
+            let mut values_offset = offsets[index] + 2 + 12 * fields.len() + 4;
+            let mut values = Vec::new();
+            output.extend_from_slice(&self.u16_bytes(fields.len() as u16));
+            for field in fields.iter() {
+                output.extend_from_slice(&self.u16_bytes(field.tag));
+                output.extend_from_slice(&self.u16_bytes(field.kind));
+                output.extend_from_slice(&self.u32_bytes(field.count));
+                if field.data.len() <= 4 {
+                    let mut inline = [0u8; 4];
+                    inline[..field.data.len()].copy_from_slice(&field.data);
+                    output.extend_from_slice(&inline);
+                } else {
+                    output.extend_from_slice(&self.u32_bytes(values_offset as u32));
+                    values.extend_from_slice(&field.data);
+                    if field.data.len() % 2 == 1 {
+                        values.push(0);
+                    }
+                    values_offset += field.data.len().next_multiple_of(2);
+                }
+            }
+            output.extend_from_slice(&[0; 4]); // No next IFD
+            output.extend_from_slice(&values);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IFD0 with Make and Orientation, pointing to an Exif IFD with DateTimeOriginal and to a
+    // GPS IFD with GPSLatitudeRef
+    fn sample_tiff(big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let entry = |tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+            [&u16_bytes(tag)[..], &u16_bytes(kind), &u32_bytes(count), &value].concat()
+        };
+        let (make_at, exif_at, date_at, gps_at) = (62, 68, 86, 106);
+
+        let mut tiff = if big_endian { b"MM\0*".to_vec() } else { b"II*\0".to_vec() };
+        tiff.extend_from_slice(&u32_bytes(8));
+        tiff.extend_from_slice(&u16_bytes(4));
+        tiff.extend(entry(0x010F, ASCII, 5, u32_bytes(make_at)));
+        tiff.extend(entry(0x0112, 3, 1, [u16_bytes(6), [0; 2]].concat().try_into().unwrap()));
+        tiff.extend(entry(0x8769, LONG, 1, u32_bytes(exif_at)));
+        tiff.extend(entry(0x8825, LONG, 1, u32_bytes(gps_at)));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(b"Acme\0\0");
+        assert_eq!(tiff.len(), exif_at as usize);
+        tiff.extend_from_slice(&u16_bytes(1));
+        tiff.extend(entry(0x9003, ASCII, 20, u32_bytes(date_at)));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(b"2023:01:02 03:04:05\0");
+        assert_eq!(tiff.len(), gps_at as usize);
+        tiff.extend_from_slice(&u16_bytes(1));
+        tiff.extend(entry(0x0001, ASCII, 2, *b"N\0\0\0"));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff
+    }
+
+    fn field(ifds: &ExifIfds, ifd: usize, tag: u16) -> Option<&[u8]> {
+        ifds.fields(ifd).iter().find(|field| field.tag == tag).map(|field| &field.data[..])
+    }
+
+    #[test]
+    fn test_parse_edit_write_round_trip() {
+        for big_endian in [false, true] {
+            let mut ifds = ExifIfds::parse(&sample_tiff(big_endian)).unwrap();
+            assert_eq!(ifds.is_big_endian(), big_endian);
+            assert_eq!(field(&ifds, IFD0, 0x010F), Some(&b"Acme\0"[..]));
+            assert_eq!(field(&ifds, EXIF_IFD, 0x9003), Some(&b"2023:01:02 03:04:05\0"[..]));
+            assert_eq!(field(&ifds, GPS_IFD, 0x0001), Some(&b"N\0"[..]));
+            // Pointers are implied by the IFDs, not kept as tags
+            assert!(field(&ifds, IFD0, 0x8769).is_none() && field(&ifds, IFD0, 0x8825).is_none());
+
+            ifds.set_ascii(IFD0, 0x013B, "A. Photographer");
+            ifds.set_rationals(GPS_IFD, 0x0002, &[(51, 1), (30, 1), (25200, 10000)]);
+            let written = ifds.write();
+            assert_eq!(&written[..2], if big_endian { b"MM" } else { b"II" });
+
+            let reparsed = ExifIfds::parse(&written).unwrap();
+            assert_eq!(reparsed.is_big_endian(), big_endian);
+            for ifd in [IFD0, EXIF_IFD, INTEROP_IFD, GPS_IFD] {
+                let mut expected: Vec<_> = ifds.fields(ifd).iter().map(|field| (field.tag, field.kind, field.count, field.data.clone())).collect();
+                expected.sort();
+                let mut actual: Vec<_> = reparsed.fields(ifd).iter().map(|field| (field.tag, field.kind, field.count, field.data.clone())).collect();
+                actual.sort();
+                assert_eq!(actual, expected);
+            }
+            let reader = Tiff::new(&written).unwrap();
+            let orientation = reader.find(reader.first_ifd().unwrap(), 0x0112).unwrap();
+            assert_eq!(reader.u16_at(orientation.value_offset), Some(6));
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_offsets_are_rejected() {
+        // Make's value offset, in IFD0's first entry, past the end of the data
+        let mut tiff = sample_tiff(false);
+        tiff[18..22].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(ExifIfds::parse(&tiff).is_none());
+
+        // A value that runs past the end
+        let mut tiff = sample_tiff(true);
+        let date_count_at = 68 + 2 + 4;
+        tiff[date_count_at..date_count_at + 4].copy_from_slice(&0x4000_0000u32.to_be_bytes());
+        assert!(ExifIfds::parse(&tiff).is_none());
+
+        // An IFD outside the data has no entries
+        let mut tiff = sample_tiff(false);
+        tiff[54..58].copy_from_slice(&0x10000u32.to_le_bytes());
+        let ifds = ExifIfds::parse(&tiff).unwrap();
+        assert!(ifds.fields(GPS_IFD).is_empty());
+        assert!(!ifds.contains(0x8825));
+
+        assert!(ExifIfds::parse(b"XX*\0\x08\0\0\0").is_none());
+    }
+
+    #[test]
+    fn test_reset_orientation() {
+        let mut tiff = sample_tiff(false);
+        reset_orientation(&mut tiff);
+        assert_eq!(tiff[30..32], 1u16.to_le_bytes());
+
+        // IFD0 cut off inside its last entry, the Orientation tag, before and inside its value
+        let ifd = [&b"MM\0*\0\0\0\x08\0\x01"[..], &[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6]].concat();
+        for length in [18, 19] {
+            let mut tiff = ifd[..length].to_vec();
+            reset_orientation(&mut tiff);
+            assert_eq!(tiff, ifd[..length]);
+        }
+        let mut tiff = ifd.clone();
+        reset_orientation(&mut tiff);
+        assert_eq!(tiff[18..20], [0, 1]);
+    }
+
+    #[test]
+    fn test_removing_tags_and_ifds() {
+        let mut ifds = ExifIfds::parse(&sample_tiff(true)).unwrap();
+        ifds.set_ascii(INTEROP_IFD, 0x0001, "R98");
+        ifds.remove(0x010F);
+        assert!(!ifds.contains(0x010F));
+        assert!(ifds.contains(0x0112));
+
+        // Removing a pointer drops the IFD behind it, and the Exif IFD takes Interop along
+        ifds.remove(0x8825);
+        assert!(!ifds.contains(0x0001) && !ifds.contains(0x8825));
+        assert!(!ifds.fields(INTEROP_IFD).is_empty());
+        ifds.remove(0x8769);
+        assert!(ifds.fields(EXIF_IFD).is_empty() && ifds.fields(INTEROP_IFD).is_empty());
+
+        let written = ifds.write();
+        let reader = Tiff::new(&written).unwrap();
+        let tags: Vec<u16> = reader.entries(reader.first_ifd().unwrap()).map(|entry| entry.tag).collect();
+        assert_eq!(tags, [0x0112]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Apple HDR gain maps, carried over into Ultra HDR JPEGs.
+//!
+//! iPhones store an auxiliary grayscale image that tells HDR displays how much to brighten
+//! each pixel of the SDR photo. Apple scales it by a headroom derived from two maker note
+//! values; the ISO 21496-1 model used by Ultra HDR stores log2 boosts instead, so the
+//! samples are re-encoded through a lookup table.
+
+use anyhow::anyhow;
+use fast_image_resize::{images::Image, FilterType, PixelType, ResizeAlg, ResizeOptions, Resizer};
+use libheif_rs::{ImageHandle, LibHeif};
+use toojpeg::{encode_jpeg, EncodeOptions, GainMapMetadata, ImageFormat};
+use tracing::debug;
+
+use crate::auxiliary::decode_gray;
+use crate::exif::{heif_exif, Tiff};
+
+/// Auxiliary image type of Apple's HDR gain map
+pub(crate) const APPLE_GAIN_MAP_TYPE: &str = "urn:com:apple:photo:2020:aux:hdrgainmap";
+
+/// A gain map JPEG and the parameters for `write_ultra_hdr`
+pub(crate) struct EncodedGainMap {
+    pub jpeg: Vec<u8>,
+    pub metadata: GainMapMetadata,
+}
+
+/// Encode the image's Apple gain map as an Ultra HDR gain map JPEG
+///
+/// Returns `None` when the image has no gain map or its maker note leaves no headroom.
+/// With a `target_size`, the gain map is resized by the same factor as the image.
+pub(crate) fn encode_apple_gain_map(
+    lib_heif: &LibHeif,
+    image_handle: &ImageHandle,
+    target_size: Option<(u32, u32)>,
+) -> Result<Option<EncodedGainMap>, Box<dyn std::error::Error>> {
+    let Some(gain_map_handle) = image_handle
+        .auxiliary_images(None)
+        .into_iter()
+        .find(|aux| aux.auxiliary_type().is_ok_and(|kind| kind == APPLE_GAIN_MAP_TYPE))
+    else {
+        return Ok(None);
+    };
+    let Some(headroom) = heif_exif(image_handle).as_deref().and_then(apple_headroom).filter(|&h| h > 1.0) else {
+        debug!("gain map without usable headroom, writing SDR only");
+        return Ok(None);
+    };
+
+    let (mut samples, mut width, mut height) = decode_gray(lib_heif, &gain_map_handle)?;
+
+    let metadata = apple_to_iso(&mut samples, headroom);
+
+    if let Some((target_width, target_height)) = target_size {
+        let new_width = (width as u64 * target_width as u64 / image_handle.width() as u64).max(1) as u32;
+        let new_height = (height as u64 * target_height as u64 / image_handle.height() as u64).max(1) as u32;
+        let src_image = Image::from_vec_u8(width, height, samples, PixelType::U8)?;
+        let mut dst_image = Image::new(new_width, new_height, PixelType::U8);
+        let resize_options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(FilterType::Bilinear));
+        Resizer::new().resize(&src_image, &mut dst_image, &resize_options)?;
+        samples = dst_image.into_vec();
+        (width, height) = (new_width, new_height);
+    }
+
+    let options = EncodeOptions {
+        width,
+        height,
+        format: ImageFormat::Gray,
+        quality: 85,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    encode_jpeg(&samples, options, &mut output).map_err(|e| anyhow!(e))?;
+    debug!(headroom, width, height, bytes = output.len(), "encoded gain map");
+    Ok(Some(EncodedGainMap { jpeg: output, metadata }))
+}
+
+// Linear HDR headroom from maker note tags 0x0021 and 0x0030, per Apple's
+// "Applying Apple HDR effect to your photos"
+fn apple_headroom(exif: &[u8]) -> Option<f32> {
+    const EXIF_IFD: u16 = 0x8769;
+    const MAKER_NOTE: u16 = 0x927C;
+
+    let tiff = Tiff::new(exif)?;
+    let exif_ifd = tiff.find(tiff.first_ifd()?, EXIF_IFD)?;
+    let maker_note = tiff.find(tiff.u32_at(exif_ifd.value_offset)? as usize, MAKER_NOTE)?;
+    let maker_note = tiff.bytes(&maker_note)?;
+
+    // "Apple iOS\0", a version, the byte order, then an IFD whose offsets count from the note's start
+    if !maker_note.starts_with(b"Apple iOS\0") {
+        return None;
+    }
+    let note = Tiff::with_byte_order(maker_note, maker_note.get(12..14)? == b"MM");
+    let maker33 = note.rational(&note.find(14, 0x0021)?)?;
+    let maker48 = note.rational(&note.find(14, 0x0030)?)?;
+
+    let stops = match (maker33 < 1.0, maker48 <= 0.01) {
+        (true, true) => -20.0 * maker48 + 1.8,
+        (true, false) => -0.101 * maker48 + 1.601,
+        (false, true) => -70.0 * maker48 + 3.0,
+        (false, false) => -0.303 * maker48 + 2.303,
+    };
+    Some(2f64.powf(stops.max(0.0)) as f32)
+}
+
+// Re-encode Apple gain map samples in place and describe them in the ISO model.
+//
+// Apple brightens by `1 + (headroom - 1) * gain`, with `gain` the sample after the sRGB
+// transfer curve. ISO stores `log2(boost)` spread over 0..=255 between the minimum boost of
+// 1 and the maximum of `headroom`, with gamma 1 and no offsets.
+fn apple_to_iso(samples: &mut [u8], headroom: f32) -> GainMapMetadata {
+    let max_stops = headroom.log2();
+    let table: Vec<u8> = (0..=255u8)
+        .map(|value| {
+            let gain = srgb_to_linear(value as f32 / 255.0);
+            let stops = (1.0 + (headroom - 1.0) * gain).log2();
+            (stops / max_stops * 255.0).round().clamp(0.0, 255.0) as u8
+        })
+        .collect();
+    for sample in samples.iter_mut() {
+        *sample = table[*sample as usize];
+    }
+
+    GainMapMetadata {
+        gain_map_min: 0.0,
+        gain_map_max: max_stops,
+        gamma: 1.0,
+        base_offset: 0.0,
+        alternate_offset: 0.0,
+        base_hdr_headroom: 0.0,
+        alternate_hdr_headroom: max_stops,
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,505 @@
+//! Inspection of HEIF files for diagnosing conversions: structure, color and metadata.
+//!
+//! `inspect` describes what a file holds without decoding any pixels, so it stays fast on
+//! large files. The result serializes to JSON for support tickets and app detail sheets.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use libheif_rs::{Chroma, ColorProfile, ColorSpace, HeifContext, ImageHandle, RgbChroma};
+use serde::Serialize;
+
+use crate::auxiliary::{auxiliary_handles, AuxiliaryImage, AuxiliaryKind};
+use crate::exif::{heif_exif, ExifIfds, EXIF_IFD, GPS_IFD, IFD0, INTEROP_IFD};
+use crate::xmp::heif_xmp;
+
+/// What a HEIF file holds, as returned by `inspect`
+#[derive(Debug, Clone, Serialize)]
+pub struct HeifInspection {
+    /// Major brand of the `ftyp` box, e.g. `heic`, `mif1` or `msf1`
+    pub brand: String,
+    /// Compatible brands of the `ftyp` box
+    pub compatible_brands: Vec<String>,
+    /// Top-level images in file order
+    pub images: Vec<InspectedImage>,
+}
+
+/// A top-level image of a HEIF file
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectedImage {
+    /// Item ID, for `ImageSelection::Item`
+    pub id: u32,
+    /// Whether this is the image viewers show
+    pub is_primary: bool,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Bits per luma sample
+    pub luma_bits: u8,
+    /// Bits per chroma sample
+    pub chroma_bits: u8,
+    /// Chroma format libheif decodes to, e.g. `YCbCr 4:2:0` or `monochrome`
+    pub chroma: String,
+    /// Type of the color profile: `prof` or `rICC` for ICC profiles, `nclx` for coded
+    /// primaries and transfer, `None` without one
+    pub color_profile: Option<String>,
+    /// Size of the ICC profile in bytes
+    pub icc_profile_size: Option<usize>,
+    /// The `nclx` color description, which can come with an ICC profile
+    pub nclx: Option<NclxInfo>,
+    /// Whether the image has an alpha plane
+    pub has_alpha: bool,
+    /// Whether the image has a depth map
+    pub has_depth: bool,
+    /// Whether the image has an HDR gain map
+    pub has_gain_map: bool,
+    /// Number of thumbnails stored for the image
+    pub thumbnails: usize,
+    /// Auxiliary images other than the alpha plane
+    pub auxiliary_images: Vec<AuxiliaryImage>,
+    /// EXIF tags by name, e.g. `Model` or `GPSLatitude`; tags without a name are keyed by IFD
+    /// and number (`Exif 0xA460`)
+    pub exif: BTreeMap<String, String>,
+    /// XMP properties by qualified name, e.g. `xmp:Rating`; array items are joined with `, `
+    pub xmp: BTreeMap<String, String>,
+}
+
+/// An `nclx` color description, with the names libheif uses
+#[derive(Debug, Clone, Serialize)]
+pub struct NclxInfo {
+    /// Color primaries, e.g. `ITU_R_BT_709_5`
+    pub color_primaries: String,
+    /// Transfer characteristics, e.g. `IEC_61966_2_1` (sRGB) or `ITU_R_BT_2100_0_PQ`
+    pub transfer_characteristics: String,
+    /// Matrix coefficients, e.g. `ITU_R_BT_601_6`
+    pub matrix_coefficients: String,
+    /// Whether samples use the full range rather than the video range
+    pub full_range: bool,
+}
+
+impl HeifInspection {
+    /// The inspection as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("inspection results have string keys only")
+    }
+}
+
+/// Describe a HEIF file: brands, top-level images, color, auxiliary images and metadata
+pub fn inspect(path: &str) -> Result<HeifInspection, Box<dyn std::error::Error>> {
+    let (brand, compatible_brands) = read_brands(path)?;
+    let context = HeifContext::read_from_file(path)?;
+    let images = context.top_level_image_handles().iter().map(inspect_image).collect();
+    Ok(HeifInspection { brand, compatible_brands, images })
+}
+
+fn inspect_image(image_handle: &ImageHandle) -> InspectedImage {
+    let auxiliary_images: Vec<AuxiliaryImage> = auxiliary_handles(image_handle).into_iter().map(|(image, _)| image).collect();
+    let has_kind = |kind| auxiliary_images.iter().any(|image| image.kind == kind);
+
+    let chroma = match image_handle.preferred_decoding_colorspace() {
+        Ok(ColorSpace::YCbCr(chroma)) => format!("YCbCr {}", chroma_name(chroma)),
+        Ok(ColorSpace::Rgb(RgbChroma::C444)) => "RGB 4:4:4".to_string(),
+        Ok(ColorSpace::Rgb(chroma)) => format!("RGB {:?}", chroma),
+        Ok(ColorSpace::Monochrome) => "monochrome".to_string(),
+        Ok(ColorSpace::Undefined) | Err(_) => "unknown".to_string(),
+    };
+
+    let icc = image_handle.color_profile_raw();
+    let nclx = image_handle.color_profile_nclx().map(|nclx| NclxInfo {
+        color_primaries: format!("{:?}", nclx.color_primaries()),
+        transfer_characteristics: format!("{:?}", nclx.transfer_characteristics()),
+        matrix_coefficients: format!("{:?}", nclx.matrix_coefficients()),
+        full_range: nclx.full_range_flag() != 0,
+    });
+    let color_profile = match &icc {
+        Some(icc) => Some(String::from_utf8_lossy(&icc.profile_type().0).into_owned()),
+        None => nclx.as_ref().map(|_| "nclx".to_string()),
+    };
+
+    InspectedImage {
+        id: image_handle.item_id(),
+        is_primary: image_handle.is_primary(),
+        width: image_handle.width(),
+        height: image_handle.height(),
+        luma_bits: image_handle.luma_bits_per_pixel(),
+        chroma_bits: image_handle.chroma_bits_per_pixel(),
+        chroma,
+        color_profile,
+        icc_profile_size: icc.map(|icc| icc.data.len()),
+        nclx,
+        has_alpha: image_handle.has_alpha_channel(),
+        has_depth: image_handle.has_depth_image() || has_kind(AuxiliaryKind::Depth),
+        has_gain_map: has_kind(AuxiliaryKind::HdrGainMap),
+        thumbnails: image_handle.number_of_thumbnails(),
+        exif: heif_exif(image_handle).map(|tiff| exif_fields(&tiff)).unwrap_or_default(),
+        xmp: heif_xmp(image_handle).map(|xmp| xmp_properties(&xmp)).unwrap_or_default(),
+        auxiliary_images,
+    }
+}
+
+fn chroma_name(chroma: Chroma) -> &'static str {
+    match chroma {
+        Chroma::C420 => "4:2:0",
+        Chroma::C422 => "4:2:2",
+        Chroma::C444 => "4:4:4",
+    }
+}
+
+// Major and compatible brands from the `ftyp` box that starts every HEIF file
+fn read_brands(path: &str) -> Result<(String, Vec<String>), Box<dyn std::error::Error>> {
+    let mut header = Vec::new();
+    File::open(path)?.take(4096).read_to_end(&mut header)?;
+    let size = header.get(..4).map_or(0, |size| u32::from_be_bytes(size.try_into().unwrap()) as usize);
+    if header.get(4..8) != Some(b"ftyp") || size < 16 || header.len() < 16 {
+        return Err(anyhow::anyhow!("Not a HEIF file, it doesn't start with an ftyp box: {}", path).into());
+    }
+    let fourcc = |brand: &[u8]| String::from_utf8_lossy(brand).into_owned();
+    let brand = fourcc(&header[8..12]);
+    let compatible = header[16..size.min(header.len())].chunks_exact(4).map(fourcc).collect();
+    Ok((brand, compatible))
+}
+
+// Names of common tags, per IFD since GPS and Interop tags reuse numbers. The pointers to
+// other IFDs are followed rather than listed.
+const IFD0_TAGS: [(u16, &str); 17] = [
+    (0x010E, "ImageDescription"),
+    (0x010F, "Make"),
+    (0x0110, "Model"),
+    (0x0112, "Orientation"),
+    (0x011A, "XResolution"),
+    (0x011B, "YResolution"),
+    (0x0128, "ResolutionUnit"),
+    (0x0131, "Software"),
+    (0x0132, "DateTime"),
+    (0x013B, "Artist"),
+    (0x013C, "HostComputer"),
+    (0x013E, "WhitePoint"),
+    (0x013F, "PrimaryChromaticities"),
+    (0x0211, "YCbCrCoefficients"),
+    (0x0213, "YCbCrPositioning"),
+    (0x0214, "ReferenceBlackWhite"),
+    (0x8298, "Copyright"),
+];
+const EXIF_TAGS: [(u16, &str); 55] = [
+    (0x829A, "ExposureTime"),
+    (0x829D, "FNumber"),
+    (0x8822, "ExposureProgram"),
+    (0x8827, "ISOSpeedRatings"),
+    (0x8830, "SensitivityType"),
+    (0x9000, "ExifVersion"),
+    (0x9003, "DateTimeOriginal"),
+    (0x9004, "DateTimeDigitized"),
+    (0x9010, "OffsetTime"),
+    (0x9011, "OffsetTimeOriginal"),
+    (0x9012, "OffsetTimeDigitized"),
+    (0x9101, "ComponentsConfiguration"),
+    (0x9102, "CompressedBitsPerPixel"),
+    (0x9201, "ShutterSpeedValue"),
+    (0x9202, "ApertureValue"),
+    (0x9203, "BrightnessValue"),
+    (0x9204, "ExposureBiasValue"),
+    (0x9205, "MaxApertureValue"),
+    (0x9206, "SubjectDistance"),
+    (0x9207, "MeteringMode"),
+    (0x9208, "LightSource"),
+    (0x9209, "Flash"),
+    (0x920A, "FocalLength"),
+    (0x9214, "SubjectArea"),
+    (0x927C, "MakerNote"),
+    (0x9286, "UserComment"),
+    (0x9290, "SubSecTime"),
+    (0x9291, "SubSecTimeOriginal"),
+    (0x9292, "SubSecTimeDigitized"),
+    (0xA000, "FlashpixVersion"),
+    (0xA001, "ColorSpace"),
+    (0xA002, "PixelXDimension"),
+    (0xA003, "PixelYDimension"),
+    (0xA20E, "FocalPlaneXResolution"),
+    (0xA20F, "FocalPlaneYResolution"),
+    (0xA210, "FocalPlaneResolutionUnit"),
+    (0xA217, "SensingMethod"),
+    (0xA300, "FileSource"),
+    (0xA301, "SceneType"),
+    (0xA401, "CustomRendered"),
+    (0xA402, "ExposureMode"),
+    (0xA403, "WhiteBalance"),
+    (0xA404, "DigitalZoomRatio"),
+    (0xA405, "FocalLengthIn35mmFilm"),
+    (0xA406, "SceneCaptureType"),
+    (0xA420, "ImageUniqueID"),
+    (0xA430, "CameraOwnerName"),
+    (0xA431, "BodySerialNumber"),
+    (0xA432, "LensSpecification"),
+    (0xA433, "LensMake"),
+    (0xA434, "LensModel"),
+    (0xA435, "LensSerialNumber"),
+    (0xA460, "CompositeImage"),
+    (0xA462, "SourceExposureTimesOfCompositeImage"),
+    (0xA500, "Gamma"),
+];
+const INTEROP_TAGS: [(u16, &str); 2] = [(0x0001, "InteroperabilityIndex"), (0x0002, "InteroperabilityVersion")];
+const GPS_TAGS: [(u16, &str); 25] = [
+    (0x0000, "GPSVersionID"),
+    (0x0001, "GPSLatitudeRef"),
+    (0x0002, "GPSLatitude"),
+    (0x0003, "GPSLongitudeRef"),
+    (0x0004, "GPSLongitude"),
+    (0x0005, "GPSAltitudeRef"),
+    (0x0006, "GPSAltitude"),
+    (0x0007, "GPSTimeStamp"),
+    (0x0008, "GPSSatellites"),
+    (0x0009, "GPSStatus"),
+    (0x000A, "GPSMeasureMode"),
+    (0x000B, "GPSDOP"),
+    (0x000C, "GPSSpeedRef"),
+    (0x000D, "GPSSpeed"),
+    (0x000E, "GPSTrackRef"),
+    (0x000F, "GPSTrack"),
+    (0x0010, "GPSImgDirectionRef"),
+    (0x0011, "GPSImgDirection"),
+    (0x0012, "GPSMapDatum"),
+    (0x0017, "GPSDestBearingRef"),
+    (0x0018, "GPSDestBearing"),
+    (0x001B, "GPSProcessingMethod"),
+    (0x001D, "GPSDateStamp"),
+    (0x001E, "GPSDifferential"),
+    (0x001F, "GPSHPositioningError"),
+];
+
+// Values longer than this are summarized by their size, as are binary ones
+const MAX_VALUE_LENGTH: usize = 256;
+
+/// EXIF tags of TIFF-structured data by name, with their values as text
+pub(crate) fn exif_fields(tiff: &[u8]) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    let Some(ifds) = ExifIfds::parse(tiff) else { return fields };
+    let big_endian = ifds.is_big_endian();
+    for (ifd, ifd_name) in [(IFD0, "IFD0"), (EXIF_IFD, "Exif"), (INTEROP_IFD, "Interop"), (GPS_IFD, "GPS")] {
+        let names: &[(u16, &str)] = match ifd {
+            IFD0 => &IFD0_TAGS,
+            EXIF_IFD => &EXIF_TAGS,
+            INTEROP_IFD => &INTEROP_TAGS,
+            _ => &GPS_TAGS,
+        };
+        for field in ifds.fields(ifd) {
+            let name = match names.iter().find(|(tag, _)| *tag == field.tag) {
+                Some((_, name)) => name.to_string(),
+                None => format!("{} 0x{:04X}", ifd_name, field.tag),
+            };
+            fields.insert(name, field_text(field.kind, &field.data, big_endian));
+        }
+    }
+    fields
+}
+
+// A field's value as text: strings as they are, numbers separated by commas, rationals as
+// fractions and binary data by its size
+fn field_text(kind: u16, data: &[u8], big_endian: bool) -> String {
+    let u16_at = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1]];
+        if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+    };
+    let u32_at = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    };
+    let binary = || format!("({} bytes)", data.len());
+    let values: Vec<String> = match kind {
+        1 => data.iter().map(u8::to_string).collect(),
+        2 => return String::from_utf8_lossy(data).trim_end_matches('\0').to_string(),
+        3 => data.chunks_exact(2).map(|value| u16_at(value).to_string()).collect(),
+        4 => data.chunks_exact(4).map(|value| u32_at(value).to_string()).collect(),
+        5 => data.chunks_exact(8).map(|value| format!("{}/{}", u32_at(value), u32_at(&value[4..]))).collect(),
+        6 => data.iter().map(|&value| (value as i8).to_string()).collect(),
+        // UNDEFINED: version strings such as "0232" are readable, the rest is binary
+        7 if data.len() <= 16 && data.iter().all(|byte| byte.is_ascii_graphic()) => {
+            return String::from_utf8_lossy(data).into_owned()
+        }
+        8 => data.chunks_exact(2).map(|value| (u16_at(value) as i16).to_string()).collect(),
+        9 => data.chunks_exact(4).map(|value| (u32_at(value) as i32).to_string()).collect(),
+        10 => data
+            .chunks_exact(8)
+            .map(|value| format!("{}/{}", u32_at(value) as i32, u32_at(&value[4..]) as i32))
+            .collect(),
+        _ => return binary(),
+    };
+    let text = values.join(", ");
+    if text.len() > MAX_VALUE_LENGTH { binary() } else { text }
+}
+
+/// Simple XMP properties by qualified name: attributes of `rdf:Description`s and elements
+/// holding text, with the items of `rdf:Seq`, `rdf:Bag` and `rdf:Alt` arrays joined
+pub(crate) fn xmp_properties(xmp: &str) -> BTreeMap<String, String> {
+    let mut properties: BTreeMap<String, String> = BTreeMap::new();
+    let mut add = |name: &str, value: &str| {
+        let value = unescape(value.trim());
+        let value = if value.len() > MAX_VALUE_LENGTH { format!("({} bytes)", value.len()) } else { value };
+        properties.entry(name.to_string()).and_modify(|values| *values = format!("{}, {}", values, value)).or_insert(value);
+    };
+    // Enclosing elements, innermost last
+    let mut open: Vec<&str> = Vec::new();
+
+    let mut rest = xmp;
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        // Text belongs to the innermost property, or to the property around an array item
+        if !text.trim().is_empty() {
+            if let Some(property) = open.iter().rev().find(|name| is_property(name)) {
+                add(property, text);
+            }
+        }
+        let Some(end) = rest[start..].find('>').map(|end| start + end) else { break };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            if let Some(at) = open.iter().rposition(|open| *open == name.trim()) {
+                open.truncate(at);
+            }
+            continue;
+        }
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, mut attributes) = tag.split_once(|c: char| c.is_whitespace()).unwrap_or((tag, ""));
+        while let Some((attribute, after)) = attributes.split_once('=') {
+            let after = after.trim_start();
+            let Some(quote) = after.chars().next().filter(|&c| c == '"' || c == '\'') else { break };
+            let Some((value, after)) = after[1..].split_once(quote) else { break };
+            let attribute = attribute.trim();
+            if is_property(attribute) {
+                add(attribute, value);
+            }
+            attributes = after;
+        }
+        if !self_closing {
+            open.push(name);
+        }
+    }
+    properties
+}
+
+// Whether a qualified name is a property rather than RDF syntax or a namespace declaration
+fn is_property(name: &str) -> bool {
+    match name.split_once(':') {
+        Some((prefix, _)) => !matches!(prefix, "rdf" | "x" | "xml" | "xmlns"),
+        None => false,
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::ASCII;
+
+    #[test]
+    fn test_field_text_by_type() {
+        assert_eq!(field_text(1, &[1, 2, 255], false), "1, 2, 255");
+        assert_eq!(field_text(2, b"Apple\0\0", false), "Apple");
+        assert_eq!(field_text(3, &[0, 6, 1, 0], true), "6, 256");
+        assert_eq!(field_text(3, &[0, 6, 1, 0], false), "1536, 1");
+        assert_eq!(field_text(4, &[0, 0, 1, 0], true), "256");
+        assert_eq!(field_text(5, &[0, 0, 0, 1, 0, 0, 0, 60], true), "1/60");
+        assert_eq!(field_text(6, &[0xFF, 1], false), "-1, 1");
+        assert_eq!(field_text(7, b"0232", false), "0232");
+        assert_eq!(field_text(7, &[0, 1, 2], false), "(3 bytes)");
+        assert_eq!(field_text(8, &[0xFF, 0xFE], true), "-2");
+        assert_eq!(field_text(9, &[0xFF, 0xFF, 0xFF, 0xFF], false), "-1");
+        assert_eq!(field_text(10, &[0xFF, 0xFF, 0xFF, 0xFD, 0, 0, 0, 3], true), "-3/3");
+        assert_eq!(field_text(12, &[0; 8], false), "(8 bytes)");
+        // Long lists are summarized rather than printed
+        assert_eq!(field_text(1, &[9; 200], false), "(200 bytes)");
+    }
+
+    #[test]
+    fn test_exif_fields_are_named_per_ifd() {
+        let mut ifds = ExifIfds::new();
+        ifds.set_ascii(IFD0, 0x010F, "Apple");
+        ifds.set(IFD0, 0x0112, 3, 1, vec![0, 6]);
+        ifds.set_rationals(EXIF_IFD, 0x829A, &[(1, 120)]);
+        ifds.set(EXIF_IFD, 0xC000, ASCII, 2, b"x\0".to_vec());
+        ifds.set_ascii(INTEROP_IFD, 0x0001, "R98");
+        ifds.set_ascii(GPS_IFD, 0x0001, "N");
+        let fields = exif_fields(&ifds.write());
+        let expected = [
+            ("Make", "Apple"),
+            ("Orientation", "6"),
+            ("ExposureTime", "1/120"),
+            ("Exif 0xC000", "x"),
+            ("InteroperabilityIndex", "R98"),
+            ("GPSLatitudeRef", "N"),
+        ];
+        assert_eq!(fields, expected.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect());
+        assert!(exif_fields(b"MM\0*").is_empty());
+    }
+
+    #[test]
+    fn test_xmp_attributes_and_elements() {
+        let xmp = concat!(
+            "<?xpacket begin=\"\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF>",
+            "<rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmp:Rating = '5'",
+            " tiff:Make=\"Apple\"/>",
+            "<rdf:Description><photoshop:City> Paris </photoshop:City><!-- note --></rdf:Description>",
+            "</rdf:RDF></x:xmpmeta>",
+        );
+        let properties = xmp_properties(xmp);
+        let expected = [("photoshop:City", "Paris"), ("tiff:Make", "Apple"), ("xmp:Rating", "5")];
+        assert_eq!(properties, expected.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect());
+    }
+
+    #[test]
+    fn test_xmp_arrays_are_joined() {
+        let xmp = concat!(
+            "<rdf:Description>",
+            "<dc:subject><rdf:Bag><rdf:li>beach</rdf:li><rdf:li>sunset</rdf:li></rdf:Bag></dc:subject>",
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">Evening</rdf:li></rdf:Alt></dc:title>",
+            "<dc:creator><rdf:Seq><rdf:li>Ana</rdf:li></rdf:Seq></dc:creator>",
+            "</rdf:Description>",
+        );
+        let properties = xmp_properties(xmp);
+        assert_eq!(properties["dc:subject"], "beach, sunset");
+        assert_eq!(properties["dc:title"], "Evening");
+        assert_eq!(properties["dc:creator"], "Ana");
+        assert_eq!(properties.len(), 3);
+    }
+
+    #[test]
+    fn test_xmp_values_are_unescaped() {
+        let xmp = r#"<rdf:Description dc:rights="&lt;&#169;&gt; &quot;A&amp;B&quot;"><dc:source>Tom &amp; Jerry&apos;s</dc:source></rdf:Description>"#;
+        let properties = xmp_properties(xmp);
+        assert_eq!(properties["dc:rights"], "<&#169;> \"A&B\"");
+        assert_eq!(properties["dc:source"], "Tom & Jerry's");
+        // `&amp;` is unescaped last, so escaped entities stay escaped
+        assert_eq!(unescape("&amp;lt;"), "&lt;");
+    }
+
+    #[test]
+    fn test_read_brands() {
+        let path = std::env::temp_dir().join(format!("inspect_brands_{}.heic", std::process::id()));
+        let path = path.to_str().unwrap();
+        let ftyp = [&24u32.to_be_bytes()[..], b"ftypheic", &[0; 4], b"mif1heic", b"mdat"].concat();
+        std::fs::write(path, &ftyp).unwrap();
+        let brands = read_brands(path).unwrap();
+        assert_eq!(brands, ("heic".to_string(), vec!["mif1".to_string(), "heic".to_string()]));
+
+        // A box size past the end of the header reads the brands that are there
+        std::fs::write(path, [&64u32.to_be_bytes()[..], b"ftypmsf1", &[0; 4], b"msf1"].concat()).unwrap();
+        assert_eq!(read_brands(path).unwrap(), ("msf1".to_string(), vec!["msf1".to_string()]));
+
+        let headers = [&b"\0\0\0\x18moovheic\0\0\0\0"[..], b"\0\0\0\x08ftypheic", b"\0\0\0\x18ftyphe", b"\0\0\0\x18ftypheic\0\0\0", b""];
+        for header in headers {
+            std::fs::write(path, header).unwrap();
+            assert!(read_brands(path).is_err());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
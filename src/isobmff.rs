@@ -0,0 +1,990 @@
+//! Just enough of the ISO base media file format (ISO/IEC 14496-12) and HEIF (ISO/IEC 23008-12)
+//! to reach the coded images libheif-rs doesn't hand out one by one.
+//!
+//! libheif assembles a grid image (iPhones store photos as 512x512 HEVC tiles) into one
+//! image during decode, and the libheif-rs version used here can't decode a single tile. So
+//! the grid's layout is read from the file's `meta` box, and each tile's coded data and
+//! properties are wrapped into a minimal single-image HEIF by `single_image_heif`, which
+//! libheif decodes like any other file. Coded data is read from the file on demand.
+//!
+//! Image sequences (`msf1`) store their frames as samples of a video track in the `moov`
+//! box, which this libheif version can't read at all. `Track` finds the samples through the
+//! track's sample table and wraps each frame the same way.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Result};
+
+// Largest box read into memory; `meta` boxes are a few kilobytes
+const MAX_BOX_SIZE: u64 = 64 << 20;
+// Largest coded item read into memory; a 48 MP HEVC tile is well below a megabyte
+const MAX_ITEM_SIZE: u64 = 256 << 20;
+// Most frames of a sequence track; a Live Photo has about 90
+const MAX_SAMPLES: u32 = 1 << 20;
+
+// Properties that change an image's geometry: rotation, mirroring and cropping
+const TRANSFORMATIVE_PROPERTIES: [&[u8; 4]; 3] = [b"irot", b"imir", b"clap"];
+
+// Sample table boxes locating a track's frames, all of them full boxes
+const SAMPLE_TABLE_BOXES: [&[u8; 4]; 7] = [b"stsd", b"stsz", b"stco", b"co64", b"stsc", b"stss", b"ctts"];
+
+// Big-endian reads from a box payload, failing past its end
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .position
+            .checked_add(count)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| anyhow!("HEIF box is truncated"))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    // Unsigned integer of 0, 4 or 8 bytes, as `iloc` sizes its fields
+    fn uint(&mut self, size: u8) -> Result<u64> {
+        match size {
+            0 => Ok(0),
+            4 => Ok(self.u32()? as u64),
+            8 => Ok(u64::from_be_bytes(self.bytes(8)?.try_into()?)),
+            _ => bail!("Unsupported iloc field size {}", size),
+        }
+    }
+
+    // Item ID, 16 bits in version 0 boxes and 32 bits after
+    fn item_id(&mut self, wide: bool) -> Result<u32> {
+        if wide { self.u32() } else { Ok(self.u16()? as u32) }
+    }
+
+    // Version and flags of a full box
+    fn full_box_header(&mut self) -> Result<(u8, u32)> {
+        let header = self.u32()?;
+        Ok(((header >> 24) as u8, header & 0xFF_FFFF))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.position..];
+        self.position = self.data.len();
+        rest
+    }
+}
+
+/// A box inside a payload read into memory
+pub(crate) struct BoxRef<'a> {
+    pub(crate) kind: [u8; 4],
+    /// Payload, after the size and type
+    pub(crate) payload: &'a [u8],
+    /// Whole box, header included
+    pub(crate) raw: &'a [u8],
+}
+
+/// The boxes contained in `data`, in order
+pub(crate) fn child_boxes(data: &[u8]) -> Result<Vec<BoxRef<'_>>> {
+    let mut boxes = Vec::new();
+    let mut reader = Reader::new(data);
+    while reader.position < data.len() {
+        let start = reader.position;
+        let size = reader.u32()? as u64;
+        let kind: [u8; 4] = reader.bytes(4)?.try_into()?;
+        let size = match size {
+            0 => (data.len() - start) as u64,
+            1 => u64::from_be_bytes(reader.bytes(8)?.try_into()?),
+            size => size,
+        };
+        let header = reader.position - start;
+        if size < header as u64 || size > (data.len() - start) as u64 {
+            bail!("HEIF box {} has an invalid size", String::from_utf8_lossy(&kind));
+        }
+        let end = start + size as usize;
+        boxes.push(BoxRef { kind, payload: &data[start + header..end], raw: &data[start..end] });
+        reader.position = end;
+    }
+    Ok(boxes)
+}
+
+// Payload of the first box of type `kind` in `data`
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    Ok(child_boxes(data)?.into_iter().find(|child| &child.kind == kind).map(|child| child.payload))
+}
+
+/// Read the payload of the first top-level box of type `kind`, skipping over the others
+pub(crate) fn read_top_level_box(file: &mut (impl Read + Seek), kind: &[u8; 4]) -> Result<Option<Vec<u8>>> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    let mut position = 0;
+    file.seek(SeekFrom::Start(0))?;
+    while position + 8 <= file_size {
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let mut size = u32::from_be_bytes(header[..4].try_into()?) as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            file.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_size = 16;
+        } else if size == 0 {
+            size = file_size - position;
+        }
+        if size < header_size || size > file_size - position {
+            bail!("HEIF box {} has an invalid size", String::from_utf8_lossy(&header[4..]));
+        }
+        if &header[4..] == kind {
+            if size - header_size > MAX_BOX_SIZE {
+                bail!("HEIF box {} is too large", String::from_utf8_lossy(kind));
+            }
+            let mut payload = Vec::new();
+            file.by_ref().take(size - header_size).read_to_end(&mut payload)?;
+            return Ok(Some(payload));
+        }
+        position += size;
+        file.seek(SeekFrom::Start(position))?;
+    }
+    Ok(None)
+}
+
+// Where an item's data is: in the file, or in the `meta` box's `idat`
+struct Location {
+    construction_method: u16,
+    base_offset: u64,
+    extents: Vec<(u64, u64)>,
+}
+
+// An item of the `meta` box
+struct Item {
+    kind: [u8; 4],
+    location: Option<Location>,
+    // Indices into `Meta::properties`, with the essential flag
+    properties: Vec<(usize, bool)>,
+}
+
+/// The items of a HEIF file's `meta` box: their types, locations, properties and references
+pub(crate) struct Meta {
+    data: Vec<u8>,
+    primary: Option<u32>,
+    items: HashMap<u32, Item>,
+    // Property boxes of `ipco`, as ranges of `data`
+    properties: Vec<Range<usize>>,
+    // `dimg` references: the tiles of each grid item, in order
+    derived_from: HashMap<u32, Vec<u32>>,
+    idat: Range<usize>,
+}
+
+impl Meta {
+    /// Read the `meta` box of a HEIF file
+    pub(crate) fn read(file: &mut (impl Read + Seek)) -> Result<Self> {
+        let data = read_top_level_box(file, b"meta")?.ok_or_else(|| anyhow!("HEIF file has no meta box"))?;
+        let mut meta = Meta {
+            data: Vec::new(),
+            primary: None,
+            items: HashMap::new(),
+            properties: Vec::new(),
+            derived_from: HashMap::new(),
+            idat: 0..0,
+        };
+        meta.parse(&data)?;
+        meta.data = data;
+        Ok(meta)
+    }
+
+    fn parse(&mut self, data: &[u8]) -> Result<()> {
+        let offset = |slice: &[u8]| slice.as_ptr() as usize - data.as_ptr() as usize;
+        // `meta` is a full box: version and flags come before its children
+        let children = data.get(4..).ok_or_else(|| anyhow!("HEIF meta box is truncated"))?;
+        let mut locations = HashMap::new();
+        let mut associations = HashMap::new();
+        for child in child_boxes(children)? {
+            match &child.kind {
+                b"pitm" => {
+                    let mut reader = Reader::new(child.payload);
+                    let (version, _) = reader.full_box_header()?;
+                    self.primary = Some(reader.item_id(version != 0)?);
+                }
+                b"iinf" => self.parse_iinf(child.payload)?,
+                b"iloc" => locations = parse_iloc(child.payload)?,
+                b"iref" => self.parse_iref(child.payload)?,
+                b"idat" => self.idat = offset(child.payload)..offset(child.payload) + child.payload.len(),
+                b"iprp" => {
+                    for iprp_child in child_boxes(child.payload)? {
+                        match &iprp_child.kind {
+                            b"ipco" => {
+                                self.properties = child_boxes(iprp_child.payload)?
+                                    .iter()
+                                    .map(|property| offset(property.raw)..offset(property.raw) + property.raw.len())
+                                    .collect();
+                            }
+                            b"ipma" => associations = parse_ipma(iprp_child.payload)?,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (id, item) in self.items.iter_mut() {
+            item.location = locations.remove(id);
+            item.properties = associations.remove(id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    fn parse_iinf(&mut self, payload: &[u8]) -> Result<()> {
+        let mut reader = Reader::new(payload);
+        let (version, _) = reader.full_box_header()?;
+        if version == 0 { reader.u16()?; } else { reader.u32()?; }
+        for entry in child_boxes(reader.rest())? {
+            if &entry.kind != b"infe" {
+                continue;
+            }
+            let mut reader = Reader::new(entry.payload);
+            let (version, _) = reader.full_box_header()?;
+            // Versions 0 and 1 predate item types, they only describe metadata items
+            if version < 2 {
+                continue;
+            }
+            let id = reader.item_id(version >= 3)?;
+            reader.u16()?; // protection index
+            let kind = reader.bytes(4)?.try_into()?;
+            self.items.insert(id, Item { kind, location: None, properties: Vec::new() });
+        }
+        Ok(())
+    }
+
+    fn parse_iref(&mut self, payload: &[u8]) -> Result<()> {
+        let mut reader = Reader::new(payload);
+        let (version, _) = reader.full_box_header()?;
+        for reference in child_boxes(reader.rest())? {
+            if &reference.kind != b"dimg" {
+                continue;
+            }
+            let mut reader = Reader::new(reference.payload);
+            let from = reader.item_id(version != 0)?;
+            let count = reader.u16()?;
+            let to = (0..count).map(|_| reader.item_id(version != 0)).collect::<Result<Vec<_>>>()?;
+            self.derived_from.entry(from).or_default().extend(to);
+        }
+        Ok(())
+    }
+
+    /// ID of the primary item, the image viewers show
+    pub(crate) fn primary_item(&self) -> Option<u32> {
+        self.primary
+    }
+
+    /// Type of an item, e.g. `hvc1`, `av01` or `grid`
+    pub(crate) fn item_type(&self, id: u32) -> Option<[u8; 4]> {
+        self.items.get(&id).map(|item| item.kind)
+    }
+
+    // The property boxes of an item with their essential flags
+    fn item_properties(&self, id: u32) -> impl Iterator<Item = (&[u8], bool)> {
+        let properties = self.items.get(&id).map_or(&[][..], |item| &item.properties[..]);
+        properties
+            .iter()
+            .filter_map(|&(index, essential)| Some((&self.data[self.properties.get(index)?.clone()], essential)))
+    }
+
+    fn has_property(&self, id: u32, kind: &[u8; 4]) -> bool {
+        self.item_properties(id).any(|(property, _)| property.get(4..8) == Some(kind))
+    }
+
+    // Width and height from an item's `ispe` property
+    fn image_size(&self, id: u32) -> Option<(u32, u32)> {
+        let (ispe, _) = self.item_properties(id).find(|(property, _)| property.get(4..8) == Some(b"ispe"))?;
+        let mut reader = Reader::new(&ispe[8..]);
+        reader.full_box_header().ok()?;
+        Some((reader.u32().ok()?, reader.u32().ok()?))
+    }
+
+    /// Read the coded data of an item
+    pub(crate) fn item_data(&self, file: &mut (impl Read + Seek), id: u32) -> Result<Vec<u8>> {
+        let location = self
+            .items
+            .get(&id)
+            .and_then(|item| item.location.as_ref())
+            .ok_or_else(|| anyhow!("HEIF item {} has no location", id))?;
+        let mut data = Vec::new();
+        for &(offset, length) in &location.extents {
+            let start = location.base_offset.checked_add(offset).ok_or_else(|| anyhow!("HEIF item {} is out of range", id))?;
+            match location.construction_method {
+                0 => {
+                    let file_size = file.seek(SeekFrom::End(0))?;
+                    // A length of 0 means up to the end of the file
+                    let length = if length == 0 { file_size.saturating_sub(start) } else { length };
+                    if start.checked_add(length).is_none_or(|end| end > file_size) {
+                        bail!("HEIF item {} is out of range", id);
+                    }
+                    if data.len() as u64 + length > MAX_ITEM_SIZE {
+                        bail!("HEIF item {} is too large", id);
+                    }
+                    file.seek(SeekFrom::Start(start))?;
+                    file.by_ref().take(length).read_to_end(&mut data)?;
+                }
+                1 => {
+                    let idat = &self.data[self.idat.clone()];
+                    let length = if length == 0 { (idat.len() as u64).saturating_sub(start) } else { length };
+                    let extent = usize::try_from(start)
+                        .ok()
+                        .zip(usize::try_from(length).ok())
+                        .and_then(|(start, length)| idat.get(start..start.checked_add(length)?))
+                        .ok_or_else(|| anyhow!("HEIF item {} is out of range", id))?;
+                    data.extend_from_slice(extent);
+                }
+                method => bail!("HEIF item {} uses unsupported construction method {}", id, method),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Layout of a grid image, `None` if the item isn't a grid or is rotated, mirrored or
+    /// cropped, which only a full decode applies
+    pub(crate) fn grid(&self, file: &mut (impl Read + Seek), id: u32) -> Result<Option<ImageGrid>> {
+        if self.item_type(id) != Some(*b"grid") {
+            return Ok(None);
+        }
+        if TRANSFORMATIVE_PROPERTIES.iter().any(|kind| self.has_property(id, kind)) {
+            return Ok(None);
+        }
+
+        let descriptor = self.item_data(file, id)?;
+        let mut reader = Reader::new(&descriptor);
+        let _version = reader.u8()?;
+        let flags = reader.u8()?;
+        let rows = reader.u8()? as u32 + 1;
+        let columns = reader.u8()? as u32 + 1;
+        let (width, height) = if flags & 1 != 0 {
+            (reader.u32()?, reader.u32()?)
+        } else {
+            (reader.u16()? as u32, reader.u16()? as u32)
+        };
+
+        let tiles = self.derived_from.get(&id).cloned().unwrap_or_default();
+        if tiles.len() != (rows * columns) as usize {
+            bail!("HEIF grid has {} tiles for {}x{}", tiles.len(), columns, rows);
+        }
+        let (tile_width, tile_height) =
+            self.image_size(tiles[0]).ok_or_else(|| anyhow!("HEIF grid tile has no size"))?;
+        if tiles.iter().any(|&tile| self.image_size(tile) != Some((tile_width, tile_height)))
+            || (tile_width as u64 * columns as u64) < width as u64
+            || (tile_height as u64 * rows as u64) < height as u64
+        {
+            bail!("HEIF grid tiles don't cover the {}x{} image", width, height);
+        }
+        Ok(Some(ImageGrid { id, rows, columns, width, height, tile_width, tile_height, tiles }))
+    }
+
+    /// A single-image HEIF holding one tile of a grid, for libheif to decode on its own
+    ///
+    /// The grid's color profile is copied to tiles without one of their own, since decoders
+    /// convert the assembled grid with it.
+    pub(crate) fn tile_heif(&self, file: &mut (impl Read + Seek), grid: &ImageGrid, index: usize) -> Result<Vec<u8>> {
+        let tile = grid.tiles[index];
+        let kind = self.item_type(tile).ok_or_else(|| anyhow!("HEIF grid tile {} is not an image", tile))?;
+        let mut properties: Vec<(&[u8], bool)> = self.item_properties(tile).collect();
+        if !self.has_property(tile, b"colr") {
+            properties.extend(self.item_properties(grid.id).filter(|(property, _)| property.get(4..8) == Some(b"colr")));
+        }
+        let data = self.item_data(file, tile)?;
+        Ok(single_image_heif(&kind, &properties, &data))
+    }
+}
+
+/// The tiles of a grid image, row by row
+#[derive(Debug)]
+pub(crate) struct ImageGrid {
+    id: u32,
+    pub(crate) rows: u32,
+    pub(crate) columns: u32,
+    /// Size of the assembled image, which crops the right and bottom tiles
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) tile_width: u32,
+    pub(crate) tile_height: u32,
+    tiles: Vec<u32>,
+}
+
+/// The first video track of an image sequence (`msf1`), whose samples are the frames
+pub(crate) struct Track {
+    // Item type the frames decode as, `hvc1` or `av01`
+    kind: [u8; 4],
+    // Boxes of the sample entry that become item properties: the decoder configuration and
+    // the color profile, with their essential flags
+    properties: Vec<(Vec<u8>, bool)>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    samples: Vec<Sample>,
+    // Frames are shown in a different order than they're coded, as with B-frames
+    reordered: bool,
+}
+
+// A coded frame in the file
+struct Sample {
+    offset: u64,
+    size: u32,
+    // Decodable on its own
+    sync: bool,
+}
+
+impl Track {
+    /// Read the first picture (`pict`) or video (`vide`) track of a file, `None` if there is
+    /// none
+    pub(crate) fn read(file: &mut (impl Read + Seek)) -> Result<Option<Self>> {
+        let Some(moov) = read_top_level_box(file, b"moov")? else {
+            return Ok(None);
+        };
+        for trak in child_boxes(&moov)?.iter().filter(|child| &child.kind == b"trak") {
+            let Some(mdia) = find_box(trak.payload, b"mdia")? else {
+                continue;
+            };
+            // `hdlr` is a full box with a 32-bit pre_defined field before the handler type
+            let handler = find_box(mdia, b"hdlr")?.and_then(|hdlr| hdlr.get(8..12));
+            if !matches!(handler, Some(b"pict" | b"vide")) {
+                continue;
+            }
+            let stbl = find_box(mdia, b"minf")?
+                .map(|minf| find_box(minf, b"stbl"))
+                .transpose()?
+                .flatten()
+                .ok_or_else(|| anyhow!("HEIF sequence track has no sample table"))?;
+            return Self::parse_sample_table(stbl).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn parse_sample_table(stbl: &[u8]) -> Result<Self> {
+        let mut entry = None;
+        let mut sizes = Vec::new();
+        let mut chunk_offsets = Vec::new();
+        let mut chunk_runs = Vec::new();
+        let mut sync_samples = None;
+        let mut reordered = false;
+        for child in child_boxes(stbl)? {
+            if !SAMPLE_TABLE_BOXES.contains(&&child.kind) {
+                continue;
+            }
+            let mut reader = Reader::new(child.payload);
+            reader.full_box_header()?;
+            match &child.kind {
+                b"stsd" => {
+                    reader.u32()?;
+                    entry = Some(parse_sample_entry(child_boxes(reader.rest())?.first())?);
+                }
+                b"stsz" => {
+                    let sample_size = reader.u32()?;
+                    let count = reader.u32()?;
+                    if count > MAX_SAMPLES {
+                        bail!("HEIF sequence has too many frames");
+                    }
+                    sizes = (0..count)
+                        .map(|_| if sample_size == 0 { reader.u32() } else { Ok(sample_size) })
+                        .collect::<Result<_>>()?;
+                }
+                b"stco" | b"co64" => {
+                    let count = reader.u32()?;
+                    let size = if &child.kind == b"co64" { 8 } else { 4 };
+                    chunk_offsets = (0..count).map(|_| reader.uint(size)).collect::<Result<_>>()?;
+                }
+                b"stsc" => {
+                    // First chunk, counted from 1, and samples per chunk of each run of chunks
+                    let count = reader.u32()?;
+                    chunk_runs = (0..count)
+                        .map(|_| {
+                            let run = (reader.u32()?, reader.u32()?);
+                            reader.u32()?; // sample description index
+                            Ok(run)
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                }
+                b"stss" => {
+                    let count = reader.u32()?;
+                    sync_samples = Some((0..count).map(|_| reader.u32()).collect::<Result<Vec<_>>>()?);
+                }
+                b"ctts" => {
+                    // Composition offsets per run of samples; any difference between them
+                    // means the display order isn't the coding order
+                    let count = reader.u32()?;
+                    let offsets = (0..count)
+                        .map(|_| Ok((reader.u32()?, reader.u32()?)))
+                        .collect::<Result<Vec<_>>>()?;
+                    let mut used = offsets.iter().filter(|(samples, _)| *samples > 0).map(|&(_, offset)| offset);
+                    let first = used.next();
+                    reordered = used.any(|offset| Some(offset) != first);
+                }
+                _ => {}
+            }
+        }
+
+        let (kind, properties, width, height) = entry.ok_or_else(|| anyhow!("HEIF sequence track has no sample description"))?;
+        let mut samples = Vec::with_capacity(sizes.len());
+        for (chunk, &chunk_offset) in chunk_offsets.iter().enumerate() {
+            let samples_per_chunk = chunk_runs
+                .iter()
+                .take_while(|&&(first_chunk, _)| first_chunk as usize <= chunk + 1)
+                .last()
+                .map_or(0, |&(_, count)| count);
+            let mut offset = chunk_offset;
+            for _ in 0..samples_per_chunk {
+                let Some(&size) = sizes.get(samples.len()) else {
+                    break;
+                };
+                samples.push(Sample { offset, size, sync: sync_samples.is_none() });
+                offset = offset.checked_add(size as u64).ok_or_else(|| anyhow!("HEIF sequence frame is out of range"))?;
+            }
+        }
+        if samples.len() != sizes.len() {
+            bail!("HEIF sequence track's chunks hold {} of its {} frames", samples.len(), sizes.len());
+        }
+        // Sync samples are numbered from 1
+        for number in sync_samples.unwrap_or_default() {
+            if let Some(sample) = (number as usize).checked_sub(1).and_then(|index| samples.get_mut(index)) {
+                sample.sync = true;
+            }
+        }
+        Ok(Self { kind, properties, width, height, samples, reordered })
+    }
+
+    /// Number of frames
+    pub(crate) fn frame_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// A single-image HEIF holding frame `index`, for libheif to decode on its own
+    ///
+    /// A frame predicted from earlier ones is stored with the frames back to the last sync
+    /// frame. The HEVC decoder returns the last picture it outputs, which is this frame when
+    /// the track has no B-frames; other predicted frames can't be decoded this way.
+    pub(crate) fn frame_heif(&self, file: &mut (impl Read + Seek), index: usize) -> Result<Vec<u8>> {
+        let number = index + 1;
+        let sync = self.samples[..=index]
+            .iter()
+            .rposition(|sample| sample.sync)
+            .ok_or_else(|| anyhow!("HEIF sequence frame {} follows no sync frame", number))?;
+        if sync != index && (&self.kind != b"hvc1" || self.reordered) {
+            bail!("HEIF sequence frame {} is predicted from others, which is only supported for HEVC without B-frames", number);
+        }
+
+        let file_size = file.seek(SeekFrom::End(0))?;
+        let mut data = Vec::new();
+        for sample in &self.samples[sync..=index] {
+            if sample.offset.checked_add(sample.size as u64).is_none_or(|end| end > file_size) {
+                bail!("HEIF sequence frame {} is out of range", number);
+            }
+            if data.len() as u64 + sample.size as u64 > MAX_ITEM_SIZE {
+                bail!("HEIF sequence frame {} is too large", number);
+            }
+            file.seek(SeekFrom::Start(sample.offset))?;
+            file.by_ref().take(sample.size as u64).read_to_end(&mut data)?;
+        }
+
+        let ispe = ispe(self.width, self.height);
+        let mut properties: Vec<(&[u8], bool)> =
+            self.properties.iter().map(|(property, essential)| (&property[..], *essential)).collect();
+        properties.push((&ispe, false));
+        Ok(single_image_heif(&self.kind, &properties, &data))
+    }
+}
+
+type SampleEntry = ([u8; 4], Vec<(Vec<u8>, bool)>, u32, u32);
+
+// Item type, properties and frame size of a visual sample entry
+fn parse_sample_entry(entry: Option<&BoxRef>) -> Result<SampleEntry> {
+    let entry = entry.ok_or_else(|| anyhow!("HEIF sequence track has no sample description"))?;
+    let (kind, config) = match &entry.kind {
+        // `hev1` may repeat parameter sets in the samples, which the decoder accepts as well
+        b"hvc1" | b"hev1" => (*b"hvc1", b"hvcC"),
+        b"av01" => (*b"av01", b"av1C"),
+        kind => bail!("HEIF sequence codec {} is not supported", String::from_utf8_lossy(kind)),
+    };
+    // Reserved fields and the data reference index, then pre-defined and reserved fields
+    // around the frame size, the resolution, frame count, compressor name and depth
+    let mut reader = Reader::new(entry.payload);
+    reader.bytes(24)?;
+    let width = reader.u16()? as u32;
+    let height = reader.u16()? as u32;
+    reader.bytes(50)?;
+
+    let mut properties = Vec::new();
+    for child in child_boxes(reader.rest())? {
+        if &child.kind == config {
+            properties.push((child.raw.to_vec(), true));
+        } else if &child.kind == b"colr" {
+            properties.push((child.raw.to_vec(), false));
+        }
+    }
+    if !properties.iter().any(|(property, _)| property.get(4..8) == Some(config)) {
+        bail!("HEIF sequence track has no decoder configuration");
+    }
+    Ok((kind, properties, width, height))
+}
+
+fn parse_iloc(payload: &[u8]) -> Result<HashMap<u32, Location>> {
+    let mut reader = Reader::new(payload);
+    let (version, _) = reader.full_box_header()?;
+    let sizes = reader.u16()?;
+    let (offset_size, length_size, base_offset_size) = ((sizes >> 12) as u8, (sizes >> 8 & 0xF) as u8, (sizes >> 4 & 0xF) as u8);
+    let index_size = if version >= 1 { (sizes & 0xF) as u8 } else { 0 };
+    let count = if version < 2 { reader.u16()? as u32 } else { reader.u32()? };
+
+    let mut locations = HashMap::new();
+    for _ in 0..count {
+        let id = reader.item_id(version >= 2)?;
+        let construction_method = if version >= 1 { reader.u16()? & 0xF } else { 0 };
+        let data_reference_index = reader.u16()?;
+        let base_offset = reader.uint(base_offset_size)?;
+        let extent_count = reader.u16()?;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            reader.uint(index_size)?;
+            extents.push((reader.uint(offset_size)?, reader.uint(length_size)?));
+        }
+        // Data in other files isn't supported, such items are left without a location
+        if data_reference_index == 0 {
+            locations.insert(id, Location { construction_method, base_offset, extents });
+        }
+    }
+    Ok(locations)
+}
+
+fn parse_ipma(payload: &[u8]) -> Result<HashMap<u32, Vec<(usize, bool)>>> {
+    let mut reader = Reader::new(payload);
+    let (version, flags) = reader.full_box_header()?;
+    let count = reader.u32()?;
+    let mut associations = HashMap::new();
+    for _ in 0..count {
+        let id = reader.item_id(version >= 1)?;
+        let association_count = reader.u8()?;
+        let mut properties = Vec::new();
+        for _ in 0..association_count {
+            let (essential, index) = if flags & 1 != 0 {
+                let value = reader.u16()?;
+                (value & 0x8000 != 0, (value & 0x7FFF) as usize)
+            } else {
+                let value = reader.u8()?;
+                (value & 0x80 != 0, (value & 0x7F) as usize)
+            };
+            // Index 0 means no property, the others count from 1
+            if index > 0 {
+                properties.push((index - 1, essential));
+            }
+        }
+        associations.entry(id).or_insert_with(Vec::new).extend(properties);
+    }
+    Ok(associations)
+}
+
+// Append a box, with `payload` writing its contents
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], payload: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    payload(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+// An `ispe` property giving an image's width and height
+fn ispe(width: u32, height: u32) -> Vec<u8> {
+    let mut property = Vec::new();
+    write_box(&mut property, b"ispe", |out| {
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&width.to_be_bytes());
+        out.extend_from_slice(&height.to_be_bytes());
+    });
+    property
+}
+
+/// A HEIF file holding one coded image item of type `kind` (`hvc1`, `av01`, …) with the
+/// given property boxes, the decoder configuration and `ispe` among them
+pub(crate) fn single_image_heif(kind: &[u8; 4], properties: &[(&[u8], bool)], data: &[u8]) -> Vec<u8> {
+    let brand: &[u8; 4] = match kind {
+        b"av01" => b"avif",
+        _ => b"heic",
+    };
+    let mut file = Vec::with_capacity(data.len() + 512);
+    write_box(&mut file, b"ftyp", |out| {
+        out.extend_from_slice(b"mif1");
+        out.extend_from_slice(&0u32.to_be_bytes());
+        out.extend_from_slice(b"mif1");
+        out.extend_from_slice(brand);
+    });
+
+    // The item's data follows the meta box, whose size doesn't depend on the offset
+    let mut data_offset_at = 0;
+    write_box(&mut file, b"meta", |out| {
+        out.extend_from_slice(&0u32.to_be_bytes());
+        write_box(out, b"hdlr", |out| {
+            out.extend_from_slice(&[0; 8]);
+            out.extend_from_slice(b"pict");
+            out.extend_from_slice(&[0; 13]);
+        });
+        write_box(out, b"pitm", |out| out.extend_from_slice(&[0, 0, 0, 0, 0, 1]));
+        write_box(out, b"iinf", |out| {
+            out.extend_from_slice(&[0, 0, 0, 0, 0, 1]);
+            write_box(out, b"infe", |out| {
+                out.extend_from_slice(&[2, 0, 0, 0, 0, 1, 0, 0]);
+                out.extend_from_slice(kind);
+                out.push(0);
+            });
+        });
+        write_box(out, b"iloc", |out| {
+            // Version 0, 32-bit offsets and lengths, one item with one extent
+            out.extend_from_slice(&[0, 0, 0, 0, 0x44, 0, 0, 1, 0, 1, 0, 0, 0, 1]);
+            data_offset_at = out.len();
+            out.extend_from_slice(&0u32.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        });
+        write_box(out, b"iprp", |out| {
+            write_box(out, b"ipco", |out| {
+                for (property, _) in properties {
+                    out.extend_from_slice(property);
+                }
+            });
+            write_box(out, b"ipma", |out| {
+                out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 1, properties.len() as u8]);
+                for (index, (_, essential)) in properties.iter().enumerate() {
+                    out.push((index as u8 + 1) | if *essential { 0x80 } else { 0 });
+                }
+            });
+        });
+    });
+    let data_offset = (file.len() + 8) as u32;
+    file[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+    write_box(&mut file, b"mdat", |out| out.extend_from_slice(data));
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A 2x1 grid of 64x48 tiles cropped to 100x40: item 1 is the grid, its descriptor in
+    // `idat`; items 2 and 3 are the tiles, their data in `mdat`
+    fn grid_heif(rotated: bool) -> Vec<u8> {
+        let tile_data: [&[u8]; 2] = [b"left tile", b"right tile!"];
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| out.extend_from_slice(b"heic\0\0\0\0mif1heic"));
+        let mut offsets_at = Vec::new();
+        write_box(&mut file, b"meta", |out| {
+            out.extend_from_slice(&[0; 4]);
+            write_box(out, b"pitm", |out| out.extend_from_slice(&[0, 0, 0, 0, 0, 1]));
+            write_box(out, b"iinf", |out| {
+                out.extend_from_slice(&[0, 0, 0, 0, 0, 3]);
+                for (id, kind) in [(1u8, b"grid"), (2, b"hvc1"), (3, b"hvc1")] {
+                    write_box(out, b"infe", |out| {
+                        out.extend_from_slice(&[2, 0, 0, 0, 0, id, 0, 0]);
+                        out.extend_from_slice(kind);
+                        out.push(0);
+                    });
+                }
+            });
+            write_box(out, b"iref", |out| {
+                out.extend_from_slice(&[0; 4]);
+                write_box(out, b"dimg", |out| out.extend_from_slice(&[0, 1, 0, 2, 0, 2, 0, 3]));
+            });
+            write_box(out, b"idat", |out| out.extend_from_slice(&[0, 0, 0, 1, 0, 100, 0, 40]));
+            write_box(out, b"iloc", |out| {
+                // Version 1: 32-bit offsets and lengths, no base offset, construction methods
+                out.extend_from_slice(&[1, 0, 0, 0, 0x44, 0, 0, 3]);
+                out.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 8]);
+                for (id, data) in [(2u8, tile_data[0]), (3, tile_data[1])] {
+                    out.extend_from_slice(&[0, id, 0, 0, 0, 0, 0, 1]);
+                    offsets_at.push(out.len());
+                    out.extend_from_slice(&[0; 4]);
+                    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                }
+            });
+            write_box(out, b"iprp", |out| {
+                write_box(out, b"ipco", |out| {
+                    write_box(out, b"hvcC", |out| out.extend_from_slice(&[1, 2, 3]));
+                    out.extend_from_slice(&ispe(64, 48));
+                    out.extend_from_slice(&ispe(100, 40));
+                    write_box(out, b"irot", |out| out.push(1));
+                });
+                write_box(out, b"ipma", |out| {
+                    out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 3]);
+                    if rotated {
+                        out.extend_from_slice(&[0, 1, 2, 3, 0x84]);
+                    } else {
+                        out.extend_from_slice(&[0, 1, 1, 3]);
+                    }
+                    out.extend_from_slice(&[0, 2, 2, 0x81, 2, 0, 3, 2, 0x81, 2]);
+                });
+            });
+        });
+        let mut offset = file.len() + 8;
+        for (at, data) in offsets_at.iter().zip(tile_data) {
+            file[*at..*at + 4].copy_from_slice(&(offset as u32).to_be_bytes());
+            offset += data.len();
+        }
+        write_box(&mut file, b"mdat", |out| {
+            for data in tile_data {
+                out.extend_from_slice(data);
+            }
+        });
+        file
+    }
+
+    // A track of three 64x48 HEVC frames in two chunks, the second frame predicted from the
+    // first; `reordered` gives the frames composition offsets that differ
+    fn sequence_heif(reordered: bool) -> Vec<u8> {
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| out.extend_from_slice(b"msf1\0\0\0\0msf1heic"));
+        let mut offsets_at = 0;
+        write_box(&mut file, b"moov", |out| {
+            write_box(out, b"trak", |out| {
+                write_box(out, b"mdia", |out| {
+                    write_box(out, b"hdlr", |out| {
+                        out.extend_from_slice(&[0; 8]);
+                        out.extend_from_slice(b"pict");
+                        out.extend_from_slice(&[0; 13]);
+                    });
+                    write_box(out, b"minf", |out| {
+                        write_box(out, b"stbl", |out| {
+                            write_box(out, b"stsd", |out| {
+                                out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+                                write_box(out, b"hvc1", |out| {
+                                    out.extend_from_slice(&[0; 24]);
+                                    out.extend_from_slice(&[0, 64, 0, 48]);
+                                    out.extend_from_slice(&[0; 50]);
+                                    write_box(out, b"hvcC", |out| out.extend_from_slice(&[1, 2, 3]));
+                                    write_box(out, b"pasp", |out| out.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]));
+                                });
+                            });
+                            write_box(out, b"stsz", |out| out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 5, 0, 0, 0, 7, 0, 0, 0, 5]));
+                            // Two frames in the first chunk, one in the second
+                            write_box(out, b"stsc", |out| {
+                                out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+                                out.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1]);
+                            });
+                            write_box(out, b"stco", |out| {
+                                out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+                                offsets_at = out.len();
+                                out.extend_from_slice(&[0; 8]);
+                            });
+                            write_box(out, b"stss", |out| out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 3]));
+                            if reordered {
+                                write_box(out, b"ctts", |out| {
+                                    out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 0]);
+                                });
+                            }
+                        });
+                    });
+                });
+            });
+        });
+        // A padding byte between the chunks
+        let first_chunk = file.len() as u32 + 8;
+        file[offsets_at..offsets_at + 4].copy_from_slice(&first_chunk.to_be_bytes());
+        file[offsets_at + 4..offsets_at + 8].copy_from_slice(&(first_chunk + 13).to_be_bytes());
+        write_box(&mut file, b"mdat", |out| out.extend_from_slice(b"firstsecond!\0third"));
+        file
+    }
+
+    #[test]
+    fn test_grid_layout_and_tile_data() {
+        let mut file = Cursor::new(grid_heif(false));
+        let meta = Meta::read(&mut file).unwrap();
+        assert_eq!(meta.primary_item(), Some(1));
+        assert_eq!(meta.item_type(1), Some(*b"grid"));
+
+        let grid = meta.grid(&mut file, 1).unwrap().unwrap();
+        assert_eq!((grid.rows, grid.columns), (1, 2));
+        assert_eq!((grid.width, grid.height), (100, 40));
+        assert_eq!((grid.tile_width, grid.tile_height), (64, 48));
+        assert_eq!(meta.item_data(&mut file, 3).unwrap(), b"right tile!");
+        assert!(meta.grid(&mut file, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_transformed_grid_is_not_tiled() {
+        let mut file = Cursor::new(grid_heif(true));
+        let meta = Meta::read(&mut file).unwrap();
+        assert!(meta.grid(&mut file, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tile_heif_holds_one_image() {
+        let mut file = Cursor::new(grid_heif(false));
+        let meta = Meta::read(&mut file).unwrap();
+        let grid = meta.grid(&mut file, 1).unwrap().unwrap();
+
+        let mut tile = Cursor::new(meta.tile_heif(&mut file, &grid, 0).unwrap());
+        let tile_meta = Meta::read(&mut tile).unwrap();
+        let primary = tile_meta.primary_item().unwrap();
+        assert_eq!(tile_meta.item_type(primary), Some(*b"hvc1"));
+        assert_eq!(tile_meta.image_size(primary), Some((64, 48)));
+        assert!(tile_meta.has_property(primary, b"hvcC"));
+        assert_eq!(tile_meta.item_data(&mut tile, primary).unwrap(), b"left tile");
+    }
+
+    #[test]
+    fn test_truncated_boxes_are_rejected() {
+        let heif = grid_heif(false);
+        let meta_start = u32::from_be_bytes(heif[..4].try_into().unwrap()) as usize;
+        let meta_end = meta_start + u32::from_be_bytes(heif[meta_start..meta_start + 4].try_into().unwrap()) as usize;
+        let truncated = heif[..meta_end - 10].to_vec();
+        assert!(Meta::read(&mut Cursor::new(truncated)).is_err());
+        assert!(child_boxes(&[0, 0, 0, 4, b'f', b'r', b'e', b'e']).is_err());
+    }
+
+    #[test]
+    fn test_track_frames_start_at_the_sync_frame() {
+        let mut file = Cursor::new(sequence_heif(false));
+        let track = Track::read(&mut file).unwrap().unwrap();
+        assert_eq!((track.frame_count(), track.width, track.height), (3, 64, 48));
+
+        for (index, data) in [(0, &b"first"[..]), (1, b"firstsecond!"), (2, b"third")] {
+            let mut frame = Cursor::new(track.frame_heif(&mut file, index).unwrap());
+            let meta = Meta::read(&mut frame).unwrap();
+            let primary = meta.primary_item().unwrap();
+            assert_eq!(meta.item_type(primary), Some(*b"hvc1"));
+            assert_eq!(meta.image_size(primary), Some((64, 48)));
+            assert!(meta.has_property(primary, b"hvcC"));
+            assert!(!meta.has_property(primary, b"pasp"));
+            assert_eq!(meta.item_data(&mut frame, primary).unwrap(), data);
+        }
+        assert!(Track::read(&mut Cursor::new(grid_heif(false))).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reordered_track_decodes_only_sync_frames() {
+        let mut file = Cursor::new(sequence_heif(true));
+        let track = Track::read(&mut file).unwrap().unwrap();
+        assert!(track.frame_heif(&mut file, 0).is_ok());
+        assert!(track.frame_heif(&mut file, 1).is_err());
+        assert!(track.frame_heif(&mut file, 2).is_ok());
+    }
+
+    #[test]
+    fn test_truncated_track_is_rejected() {
+        let mut heif = sequence_heif(false);
+        // Drop the last frame's data
+        heif.truncate(heif.len() - 2);
+        let mut file = Cursor::new(heif);
+        let track = Track::read(&mut file).unwrap().unwrap();
+        assert!(track.frame_heif(&mut file, 1).is_ok());
+        assert!(track.frame_heif(&mut file, 2).is_err());
+    }
+}
@@ -2,22 +2,62 @@
 extern crate lazy_static;
 
 use std::{fs, time::{Instant, Duration}};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::io::{BufWriter, Write};
 use std::cmp::{max, min};
-use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use libheif_rs::{
+    Chroma, ColorPrimaries, ColorSpace, HeifContext, ImageHandle, LibHeif, MatrixCoefficients, Plane, RgbChroma,
+    TransferCharacteristics,
+};
 use fast_image_resize as fr;
 use rayon::prelude::*;
 use fr::{PixelType, FilterType, ResizeAlg, Resizer};
 use fr::images::Image;
 use fr::ResizeOptions;
 use anyhow::{anyhow, Result};
-use toojpeg::{EncodeOptions, ImageFormat, encode_jpeg};
+use tracing::{debug, info_span, trace_span};
+use toojpeg::{
+    EncodeOptions, ImageFormat, JpegStreamEncoder, MAX_12BIT_SAMPLE, composite_alpha, dither_to_u8, encode_jpeg,
+    encode_jpeg_12bit, extract_alpha, insert_app_segment, insert_xmp, transform_jpeg, write_ultra_hdr,
+};
+
+pub use toojpeg::{Background, CropRegion, Dither, Transform, TransformOptions};
+pub use auxiliary::{export_auxiliary_images, list_auxiliary_images, AuxiliaryImage, AuxiliaryKind};
+pub use batch::{convert_batch, find_heic_files, BatchOptions, BatchReport, CollisionStrategy};
+pub use inspect::{inspect, HeifInspection, InspectedImage, NclxInfo};
+pub use metadata::{ExifDateTime, GpsPosition, MetadataEdits};
+pub use privacy::{ScrubPolicy, ScrubReport};
+pub use sequence::{convert_heic_sequence, SequenceOutput};
+pub use tonemap::{HdrTransfer, ToneMapOperator, ToneMapOptions};
+pub use xmp::{extract_xmp, set_xmp_property, XmpProperty};
+
+mod auxiliary;
+mod batch;
+mod exif;
+mod gainmap;
+mod inspect;
+mod isobmff;
+mod metadata;
+mod privacy;
+mod sequence;
+mod tonemap;
+mod xmp;
+
+use auxiliary::depth_xmp;
+use exif::exif_payload;
+use gainmap::encode_apple_gain_map;
+use isobmff::Meta;
+use privacy::report_auxiliary_images;
+use tonemap::ToneMapper;
+use xmp::output_xmp;
 
 #[cfg(feature = "android")]
 use jni::JNIEnv;
 #[cfg(feature = "android")]
 use jni::objects::{JClass, JObjectArray, JString};
 #[cfg(feature = "android")]
-use jni::sys::{jint, jstring};
+use jni::sys::{jbyteArray, jint, jstring};
 
 #[cfg(feature = "android")]
 fn jni_resize_filter_from_int(filter_int: jint) -> &'static str {
@@ -60,7 +100,7 @@ pub extern "system" fn Java_com_example_heictojpeg_NativeLib_convertHeicToJpeg(
 /// * `output_file` - Path where to save the output JPEG
 /// * `target_width` - Target width (0 to keep original)
 /// * `target_height` - Target height (0 to keep original)
-/// * `resize_filter` - Resize filter to use ("lanczos" or "bilinear")
+/// * `resize_filter` - Resize filter to use ("lanczos", "bilinear", "catmull_rom" or "mitchell")
 /// Convert HEIC to JPEG with optional resizing and chroma subsampling
 /// 
 /// # Arguments
@@ -72,9 +112,175 @@ pub fn convert_heic_to_jpeg(
     height: u32,
     resize_filter: &str,
 ) -> Result<ConversionTiming, Box<dyn std::error::Error>> {
+    convert_heic_to_jpeg_with_alpha(heic_path, jpeg_path, width, height, resize_filter, &AlphaOptions::default())
+}
+
+/// How images with an alpha plane (stickers, screenshots with transparency) are written
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AlphaOptions {
+    /// What shows through transparent areas, white by default
+    pub background: Background,
+    /// Also save the alpha plane as a grayscale JPEG next to the output (`photo.alpha.jpg`)
+    pub write_sidecar: bool,
+}
+
+/// Convert HEIC to JPEG with optional resizing and control over transparency
+///
+/// Same as `convert_heic_to_jpeg`, but images with an alpha plane are composited onto
+/// `alpha.background` and the alpha can be kept as a sidecar mask. Resizing happens on
+/// premultiplied colors so transparent pixels don't bleed into the edges.
+pub fn convert_heic_to_jpeg_with_alpha(
+    heic_path: &str,
+    jpeg_path: &str,
+    width: u32,
+    height: u32,
+    resize_filter: &str,
+    alpha: &AlphaOptions,
+) -> Result<ConversionTiming, Box<dyn std::error::Error>> {
+    let options = ConvertOptions { alpha: *alpha, ..Default::default() };
+    convert_heic_to_jpeg_with_options(heic_path, jpeg_path, width, height, resize_filter, &options)
+}
+
+/// Everything about a conversion besides the paths and output size
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Handling of images with an alpha plane
+    pub alpha: AlphaOptions,
+    /// How 10/12-bit images are rounded to the 8 bits a JPEG holds
+    pub dither: Dither,
+    /// Write 10/12-bit images as 12-bit extended JPEGs (SOF1) instead of dithering them to
+    /// 8 bits. Meant for archival: many viewers, browsers included, can't open these.
+    pub twelve_bit: bool,
+    /// How PQ and HLG (HDR) images are brought down to SDR; the curve is only applied when
+    /// the image's `nclx` profile declares one of those transfer functions
+    pub tone_mapping: ToneMapOptions,
+    /// Carry an iPhone HDR gain map over as an Ultra HDR JPEG (on by default). SDR viewers
+    /// show the base image as before; Android 14+ and recent browsers brighten highlights.
+    pub hdr_gain_map: bool,
+    /// Embed a portrait photo's depth map as GDepth XMP for refocusing in gallery apps, at
+    /// full resolution in Extended XMP
+    pub embed_depth: bool,
+    /// Which images of a multi-image HEIC (bursts, collections) are converted
+    pub images: ImageSelection,
+    /// Copy the HEIC's EXIF into the JPEG, with the orientation reset since the pixels come
+    /// out upright, plus a 160 pixel IFD1 thumbnail for file managers, and its XMP
+    pub preserve_metadata: bool,
+    /// XMP properties to set on the output, e.g. `XmpProperty::creator_tool("heic2jpeg")`;
+    /// added to the HEIC's XMP when it is preserved
+    pub xmp_properties: Vec<XmpProperty>,
+    /// EXIF tags to set or remove (capture time, artist, copyright, GPS); applied to the
+    /// HEIC's EXIF when it is preserved, written on their own otherwise
+    pub metadata_edits: MetadataEdits,
+    /// JPEG quality from 1 to 100; `None` uses 95, or 90 for resized images
+    pub quality: Option<u8>,
+    /// Subsample chroma to 4:2:0 (on by default), which makes files about a third smaller;
+    /// off keeps full 4:4:4 color resolution for text and sharp colored edges
+    pub subsample_chroma: bool,
+    /// Strip identifying metadata before sharing: GPS, serial numbers, make and model,
+    /// MakerNotes, XMP location and face regions, and every auxiliary image (depth maps,
+    /// mattes, gain maps). What was removed is in `ConversionTiming::scrub_report`. Edits
+    /// from `metadata_edits` and `xmp_properties` still apply afterwards.
+    pub scrub: Option<ScrubPolicy>,
+}
+
+/// Which top-level images of a HEIC are converted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageSelection {
+    /// Only the primary image, what viewers show
+    #[default]
+    Primary,
+    /// Every top-level image, written as `photo_1.jpg`, `photo_2.jpg`, … in file order.
+    /// A HEIC with a single image is still written to the path given.
+    All,
+    /// One image by its item ID, see `list_images`
+    Item(u32),
+}
+
+/// A top-level image of a HEIC, as listed by `list_images`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeicImageInfo {
+    /// Item ID, for `ImageSelection::Item`
+    pub id: u32,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Whether this is the image viewers show
+    pub is_primary: bool,
+    /// Whether the file stores a thumbnail for it
+    pub has_thumbnail: bool,
+}
+
+/// List the top-level images of a HEIC in file order
+pub fn list_images(heic_path: &str) -> Result<Vec<HeicImageInfo>, Box<dyn std::error::Error>> {
+    let context = HeifContext::read_from_file(heic_path)?;
+    let images = context
+        .top_level_image_handles()
+        .iter()
+        .map(|image_handle| HeicImageInfo {
+            id: image_handle.item_id(),
+            width: image_handle.width(),
+            height: image_handle.height(),
+            is_primary: image_handle.is_primary(),
+            has_thumbnail: image_handle.number_of_thumbnails() > 0,
+        })
+        .collect();
+    Ok(images)
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            alpha: AlphaOptions::default(),
+            dither: Dither::default(),
+            twelve_bit: false,
+            tone_mapping: ToneMapOptions::default(),
+            hdr_gain_map: true,
+            embed_depth: false,
+            images: ImageSelection::default(),
+            preserve_metadata: false,
+            xmp_properties: Vec::new(),
+            metadata_edits: MetadataEdits::default(),
+            quality: None,
+            subsample_chroma: true,
+            scrub: None,
+        }
+    }
+}
+
+impl ConvertOptions {
+    // Quality to encode at, lower by default for resized images, whose detail is softer
+    fn jpeg_quality(&self, resized: bool) -> u8 {
+        self.quality.unwrap_or(if resized { 90 } else { 95 }).clamp(1, 100)
+    }
+}
+
+/// Convert HEIC to JPEG with optional resizing and all conversion options
+///
+/// Images with more than 8 bits per channel (10-bit HEIC from recent phones) are decoded at
+/// 16 bits, resized in f32 and only dithered to 8 bits right before encoding, which avoids
+/// banding in smooth gradients such as skies. HDR images (PQ or HLG transfer, BT.2020
+/// primaries) take the same path and are tone mapped to sRGB before resizing, otherwise they
+/// come out washed out and dim.
+///
+/// 8-bit images with an Apple HDR gain map are written as Ultra HDR JPEGs: the SDR image
+/// followed by the gain map, resized along with it, see `ConvertOptions::hdr_gain_map`.
+///
+/// With `ImageSelection::All` the returned timing adds up all images.
+pub fn convert_heic_to_jpeg_with_options(
+    heic_path: &str,
+    jpeg_path: &str,
+    width: u32,
+    height: u32,
+    resize_filter: &str,
+    options: &ConvertOptions,
+) -> Result<ConversionTiming, Box<dyn std::error::Error>> {
+    let _span = info_span!("convert_heic_to_jpeg", input = heic_path).entered();
     let mut timing = ConversionTiming::default();
-    convert_heic_to_jpeg_internal(heic_path, jpeg_path, width, height, resize_filter, &mut timing)?;
+    convert_heic_to_jpeg_internal(heic_path, jpeg_path, width, height, resize_filter, options, &mut timing)?;
     timing.total = timing.decode + timing.linear + timing.resize + timing.encode;
+    timing.peak_rss = peak_rss();
+    debug!(?timing, "converted {} to {}", heic_path, jpeg_path);
     Ok(timing)
 }
 
@@ -85,6 +291,28 @@ pub struct ConversionTiming {
     pub linear: Duration,
     pub resize: Duration,
     pub encode: Duration,
+    /// Peak resident set size of the whole process in bytes (VmHWM on Linux/Android), read
+    /// after the conversion; `None` on other platforms. This is the process's high-water mark
+    /// since it started, not the conversion's own: it includes earlier work and conversions
+    /// running on other threads, so compare it across runs in fresh processes.
+    pub peak_rss: Option<u64>,
+    /// What `ConvertOptions::scrub` removed, `None` without a scrub
+    pub scrub_report: Option<ScrubReport>,
+}
+
+// Read the peak RSS (VmHWM) of this process
+fn peak_rss() -> Option<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+        let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kilobytes * 1024)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        None
+    }
 }
 
 fn convert_heic_to_jpeg_internal(
@@ -93,43 +321,149 @@ fn convert_heic_to_jpeg_internal(
     target_width: u32,
     target_height: u32,
     resize_filter: &str,
+    options: &ConvertOptions,
     timing: &mut ConversionTiming,
 ) -> Result<(), Box<dyn std::error::Error>> {
-
-
-    if !input_file.to_lowercase().ends_with(".heic") {
+    if !is_heic_path(input_file) {
         return Err(anyhow!("Input is not a HEIC file: {}", input_file).into());
     }
 
     let lib_heif = LibHeif::new();
     let context = HeifContext::read_from_file(input_file)?;
-    let image_handle = context.primary_image_handle()?;
+
+    let image_handles = match options.images {
+        ImageSelection::Primary => vec![context.primary_image_handle()?],
+        ImageSelection::Item(item_id) => vec![context.image_handle(item_id)?],
+        ImageSelection::All => context.top_level_image_handles(),
+    };
+    if let [image_handle] = &image_handles[..] {
+        return convert_image_handle(
+            &lib_heif, image_handle, output_file, target_width, target_height, resize_filter, options, timing,
+        );
+    }
+
+    for (index, image_handle) in image_handles.iter().enumerate() {
+        let numbered = numbered_output_path(output_file, index + 1);
+        let _span = info_span!("image", item_id = image_handle.item_id(), output = %numbered.display()).entered();
+        let mut image_timing = ConversionTiming::default();
+        convert_image_handle(
+            &lib_heif,
+            image_handle,
+            &numbered.to_string_lossy(),
+            target_width,
+            target_height,
+            resize_filter,
+            options,
+            &mut image_timing,
+        )?;
+        timing.decode += image_timing.decode;
+        timing.linear += image_timing.linear;
+        timing.resize += image_timing.resize;
+        timing.encode += image_timing.encode;
+        if let Some(report) = image_timing.scrub_report {
+            timing.scrub_report.get_or_insert_with(ScrubReport::default).merge(report);
+        }
+    }
+    Ok(())
+}
+
+// `photo.jpg` -> `photo_2.jpg`
+fn numbered_output_path(jpeg_path: &str, number: usize) -> PathBuf {
+    let path = Path::new(jpeg_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    path.with_file_name(format!("{}_{}{}", stem, number, extension))
+}
+
+/// Whether a path names a HEIC still (`.heic`) or image sequence (`.heics`), by extension
+pub fn is_heic_path(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".heic") || path.ends_with(".heics")
+}
+
+#[allow(clippy::too_many_arguments)]
+fn convert_image_handle(
+    lib_heif: &LibHeif,
+    image_handle: &ImageHandle,
+    output_file: &str,
+    target_width: u32,
+    target_height: u32,
+    resize_filter: &str,
+    options: &ConvertOptions,
+    timing: &mut ConversionTiming,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output_buffer = encode_image_handle(
+        lib_heif, image_handle, output_file, target_width, target_height, resize_filter, options, timing,
+    )?;
+    info_span!("write", bytes = output_buffer.len()).in_scope(|| fs::write(output_file, output_buffer))?;
+    Ok(())
+}
+
+// Convert one image to JPEG in memory. `output_file` is only used for the alpha sidecar.
+#[allow(clippy::too_many_arguments)]
+fn encode_image_handle(
+    lib_heif: &LibHeif,
+    image_handle: &ImageHandle,
+    output_file: &str,
+    target_width: u32,
+    target_height: u32,
+    resize_filter: &str,
+    options: &ConvertOptions,
+    timing: &mut ConversionTiming,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let alpha = &options.alpha;
+    let preserve_metadata = options.preserve_metadata;
+    let mut scrub_report = options.scrub.map(|_| ScrubReport::default());
+    if let Some(report) = scrub_report.as_mut() {
+        report_auxiliary_images(image_handle, report);
+    }
 
     let mut width = image_handle.width();
     let mut height = image_handle.height();
 
+    // Transparent images are decoded with their alpha plane and flattened after resizing
+    let has_alpha = image_handle.has_alpha_channel();
+    let (chroma, channels) = if has_alpha { (RgbChroma::Rgba, 4) } else { (RgbChroma::Rgb, 3) };
+
     let resize_options = if target_width == 0 || target_height == 0 {
         None
     } else {
         Some((target_width, target_height))
     };
 
+    // 10/12-bit and HDR images keep their precision until the final dither (transparent ones
+    // are stickers and screenshots, which are 8-bit SDR in practice)
+    if !has_alpha && (image_handle.luma_bits_per_pixel() > 8 || hdr_transfer(image_handle).is_some()) {
+        let encoded = encode_high_bit_depth(lib_heif, image_handle, resize_options, resize_filter, options, timing)?;
+        let mut output_buffer = encoded.jpeg;
+        if preserve_metadata || !options.metadata_edits.is_empty() {
+            output_buffer = with_exif(output_buffer, image_handle, options, scrub_report.as_mut(), encoded.thumbnail.as_deref())?;
+        }
+        if let Some(xmp) = output_xmp(image_handle, preserve_metadata, &options.xmp_properties, scrub_report.as_mut()) {
+            output_buffer = with_xmp(output_buffer, &xmp, None)?;
+        }
+        timing.scrub_report = scrub_report;
+        return Ok(output_buffer);
+    }
+
     let mut output_buffer = Vec::new();
+    let mut thumbnail = None;
 
     if let Some((new_width, new_height)) = resize_options {
         // Resize Path: Decode to RGB, linearize, resize, convert back to sRGB, then encode.
         let decode_start = Instant::now();
-        let image_data = lib_heif.decode(&image_handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+        let image_data = info_span!("decode")
+            .in_scope(|| lib_heif.decode(image_handle, ColorSpace::Rgb(chroma), None))?;
         timing.decode = decode_start.elapsed();
 
-        let rgb_bytes = image_data.planes().interleaved.unwrap().data;
+        let plane = image_data.planes().interleaved.ok_or_else(|| anyhow!("Decoded image has no interleaved plane"))?;
         
         // Create source and destination images for resizing
         let src_image = Image::from_vec_u8(
             width,
             height,
-            rgb_bytes.to_vec(),
-            PixelType::U8x3,
+            packed_rows(&plane, width as usize * channels, height as usize),
+            if has_alpha { PixelType::U8x4 } else { PixelType::U8x3 },
         )?;
         
         let mut dst_image = Image::new(
@@ -138,32 +472,33 @@ fn convert_heic_to_jpeg_internal(
             src_image.pixel_type(),
         );
         
-        // Configure resizing
-        let filter_type = match resize_filter {
-            "bilinear" => FilterType::Bilinear,
-            "catmull_rom" => FilterType::CatmullRom,
-            "mitchell" => FilterType::Mitchell,
-            // Only Lanczos3 is available in the current version
-            _ => FilterType::Lanczos3, // Default to Lanczos3
-        };
-        
         let mut resizer = Resizer::new();
         
         // CPU extensions not needed for our optimized implementation
 
-        // Create resize options with the desired filter
+        // Create resize options with the desired filter. With alpha, colors are premultiplied
+        // during the resize so fully transparent pixels don't darken the edges.
         let resize_options = ResizeOptions::new()
-            .resize_alg(ResizeAlg::Convolution(FilterType::Lanczos3));
+            .resize_alg(ResizeAlg::Convolution(resize_filter_type(resize_filter)))
+            .use_alpha(true);
         
         // Perform the resize
         let resize_start = Instant::now();
-        resizer.resize(&src_image, &mut dst_image, &resize_options)?;
+        info_span!("resize", width = new_width, height = new_height)
+            .in_scope(|| resizer.resize(&src_image, &mut dst_image, &resize_options))?;
         timing.resize = resize_start.elapsed();
         
         // Get the resized RGB data
-        let resized_rgb = dst_image.buffer();
+        let flattened;
+        let resized_rgb = if has_alpha {
+            flattened = flatten_alpha(dst_image.buffer(), new_width, new_height, alpha, output_file)?;
+            &flattened[..]
+        } else {
+            dst_image.buffer()
+        };
         
         // Convert to linear RGB for color space conversion
+        let color_span = info_span!("color").entered();
         let mut linear_rgb = vec![0.0f32; resized_rgb.len()];
         let linear_start = Instant::now();
         srgb_to_linear_wide(resized_rgb, &mut linear_rgb);
@@ -174,6 +509,7 @@ fn convert_heic_to_jpeg_internal(
         let linear_to_srgb_start = Instant::now();
         linear_to_srgb_wide(&linear_rgb, &mut rgb_out);
         timing.linear = timing.linear + linear_to_srgb_start.elapsed();
+        drop(color_span);
         
         width = new_width;
         height = new_height;
@@ -184,27 +520,43 @@ fn convert_heic_to_jpeg_internal(
             width,
             height,
             format: ImageFormat::RGB,
-            quality: 90,
+            quality: options.jpeg_quality(true),
             baseline: true,
             optimized: true,
-            subsample: true, // Always use 4:2:0 chroma subsampling for better performance, 4:4:4 if false
+            subsample: options.subsample_chroma,
+            adaptive_quantization: false,
+            background: Background::default(),
         };
         
-        encode_jpeg(&rgb_out, options, &mut output_buffer)
+        info_span!("encode")
+            .in_scope(|| encode_jpeg(&rgb_out, options, &mut output_buffer))
             .map_err(|e| anyhow::anyhow!(e))?;
+        if preserve_metadata {
+            thumbnail = Some(exif_thumbnail(&rgb_out, new_width, new_height)?);
+        }
             
         timing.encode = encode_start.elapsed();
     } else {
         // No-Resize Path: Decode to sRGB, and encode.
         // Decode the image
         let decode_start = Instant::now();
-        let image_data = lib_heif.decode(&image_handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+        let image_data = info_span!("decode")
+            .in_scope(|| lib_heif.decode(image_handle, ColorSpace::Rgb(chroma), None))?;
         timing.decode = decode_start.elapsed();
 
-        // Extract the interleaved RGB data.
-        let rgb_bytes = image_data.planes().interleaved.unwrap().data;
+        // Extract the interleaved RGB data, flattening transparency first
+        let plane = image_data.planes().interleaved.ok_or_else(|| anyhow!("Decoded image has no interleaved plane"))?;
+        let flattened;
+        let rgb_bytes = if has_alpha {
+            let rgba = packed_rows(&plane, width as usize * 4, height as usize);
+            flattened = flatten_alpha(&rgba, width, height, alpha, output_file)?;
+            &flattened[..]
+        } else {
+            plane.data
+        };
 
         // Convert to linear RGB
+        let color_span = info_span!("color").entered();
         let linear_start = Instant::now();
         let mut linear_rgb = vec![0.0f32; rgb_bytes.len()];
         srgb_to_linear_wide(rgb_bytes, &mut linear_rgb);
@@ -213,6 +565,7 @@ fn convert_heic_to_jpeg_internal(
         // Convert back to sRGB for JPEG encoding
         let mut srgb_out = vec![0u8; rgb_bytes.len()];
         linear_to_srgb_wide(&linear_rgb, &mut srgb_out);
+        drop(color_span);
         
         // Encode the RGB data to JPEG. TooJpeg will handle the RGB to YCbCr conversion.
         let encode_start = Instant::now();
@@ -220,28 +573,690 @@ fn convert_heic_to_jpeg_internal(
             width,
             height,
             format: ImageFormat::RGB,
-            quality: 95,
+            quality: options.jpeg_quality(false),
             baseline: true,
             optimized: true,
-            subsample: true, // Always use 4:2:0 chroma subsampling for better performance, 4:4:4 if false
+            subsample: options.subsample_chroma,
+            adaptive_quantization: false,
+            background: Background::default(),
         };
         
-        encode_jpeg(&srgb_out, options, &mut output_buffer)
+        info_span!("encode")
+            .in_scope(|| encode_jpeg(&srgb_out, options, &mut output_buffer))
             .map_err(|e| anyhow::anyhow!(e))?;
+        if preserve_metadata {
+            thumbnail = Some(exif_thumbnail(&srgb_out, width, height)?);
+        }
         timing.encode = encode_start.elapsed();
+    }
 
-        println!("Encode: {:.2?} ({:.1}%)", 
-            timing.encode, 
-            timing.encode.as_secs_f64() / timing.total.as_secs_f64() * 100.0
-        );
+    // Metadata goes in before the gain map, whose MPF index records the primary image's size.
+    // The depth map and the gain map add their XMP descriptions to the image's own.
+    if preserve_metadata || !options.metadata_edits.is_empty() {
+        output_buffer = with_exif(output_buffer, image_handle, options, scrub_report.as_mut(), thumbnail.as_deref())?;
+    }
+    if let Some(xmp) = output_xmp(image_handle, preserve_metadata, &options.xmp_properties, scrub_report.as_mut()) {
+        output_buffer = with_xmp(output_buffer, &xmp, None)?;
+    }
+
+    // A scrub keeps every auxiliary image out
+    let keep_auxiliary = !has_alpha && scrub_report.is_none();
+    timing.scrub_report = scrub_report;
+
+    if options.embed_depth && keep_auxiliary {
+        let encode_start = Instant::now();
+        if let Some((xmp, extended)) = info_span!("depth").in_scope(|| depth_xmp(lib_heif, image_handle))? {
+            output_buffer = with_xmp(output_buffer, &xmp, Some(&extended))?;
+        }
+        timing.encode += encode_start.elapsed();
     }
 
-    fs::write(output_file, output_buffer)?;
+    // Gain maps are only ever attached to 8-bit SDR photos, which is what iPhones write
+    if options.hdr_gain_map && keep_auxiliary {
+        let gain_map_start = Instant::now();
+        let gain_map = info_span!("gain_map").in_scope(|| encode_apple_gain_map(lib_heif, image_handle, resize_options))?;
+        if let Some(gain_map) = gain_map {
+            let mut ultra_hdr = Vec::new();
+            write_ultra_hdr(&output_buffer, &gain_map.jpeg, &gain_map.metadata, &mut ultra_hdr).map_err(|e| anyhow::anyhow!(e))?;
+            output_buffer = ultra_hdr;
+        }
+        timing.encode += gain_map_start.elapsed();
+    }
 
     // Timing is updated in-place through the mutable reference
+    Ok(output_buffer)
+}
+
+// Add the image's EXIF, scrubbed and edited as requested, and the thumbnail as an APP1 segment
+fn with_exif(
+    jpeg: Vec<u8>,
+    image_handle: &ImageHandle,
+    options: &ConvertOptions,
+    scrub_report: Option<&mut ScrubReport>,
+    thumbnail: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let scrub = options.scrub.as_ref().zip(scrub_report);
+    let Some(payload) = exif_payload(image_handle, options.preserve_metadata, &options.metadata_edits, scrub, thumbnail)? else {
+        return Ok(jpeg);
+    };
+    let mut output = Vec::with_capacity(jpeg.len() + payload.len() + 4);
+    insert_app_segment(&jpeg, 0xE1, &payload, &mut output).map_err(|e| anyhow!(e))?;
+    Ok(output)
+}
+
+// Add an XMP packet, merged into any XMP the JPEG already has
+fn with_xmp(jpeg: Vec<u8>, xmp: &str, extended: Option<&str>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut output = Vec::with_capacity(jpeg.len() + xmp.len() + extended.map_or(0, str::len) + 64);
+    insert_xmp(&jpeg, xmp, extended, &mut output).map_err(|e| anyhow!(e))?;
+    Ok(output)
+}
+
+// A JPEG and, when metadata is kept, its EXIF thumbnail
+struct EncodedImage {
+    jpeg: Vec<u8>,
+    thumbnail: Option<Vec<u8>>,
+}
+
+// Decode at 16 bits per channel, resize in f32 and dither to 8 bits just before encoding,
+// or keep 12 bits with `ConvertOptions::twelve_bit`. Also returns the EXIF thumbnail when
+// `ConvertOptions::preserve_metadata` is set.
+fn encode_high_bit_depth(
+    lib_heif: &LibHeif,
+    image_handle: &ImageHandle,
+    target_size: Option<(u32, u32)>,
+    resize_filter: &str,
+    convert_options: &ConvertOptions,
+    timing: &mut ConversionTiming,
+) -> Result<EncodedImage, Box<dyn std::error::Error>> {
+    let mut width = image_handle.width();
+    let mut height = image_handle.height();
+
+    let decode_start = Instant::now();
+    let image_data = info_span!("decode")
+        .in_scope(|| lib_heif.decode(image_handle, ColorSpace::Rgb(RgbChroma::HdrRgbLe), None))?;
+    timing.decode = decode_start.elapsed();
+
+    let plane = image_data.planes().interleaved.ok_or_else(|| anyhow!("Decoded image has no interleaved plane"))?;
+    let row_samples = width as usize * 3;
+    let mut samples = vec![0.0f32; row_samples * height as usize];
+    for (row, out) in samples.chunks_exact_mut(row_samples).enumerate() {
+        let start = row * plane.stride;
+        high_bit_depth_to_f32(&plane.data[start..start + row_samples * 2], plane.bits_per_pixel, out);
+    }
+    drop(image_data);
+
+    // Tone mapping needs the original transfer-encoded values, so it runs before resizing
+    let tone_mapper = hdr_tone_mapper(image_handle, &convert_options.tone_mapping);
+    let tonemap_start = Instant::now();
+    if let Some(mapper) = &tone_mapper {
+        info_span!("tonemap").in_scope(|| mapper.apply(&mut samples));
+    }
+    let tonemap_time = tonemap_start.elapsed();
+
+    if let Some((new_width, new_height)) = target_size {
+        let src_image = Image::from_vec_u8(width, height, bytemuck::cast_slice(&samples).to_vec(), PixelType::F32x3)?;
+        let mut dst_image = Image::new(new_width, new_height, PixelType::F32x3);
+        let resize_options = ResizeOptions::new()
+            .resize_alg(ResizeAlg::Convolution(resize_filter_type(resize_filter)));
+
+        let resize_start = Instant::now();
+        info_span!("resize", width = new_width, height = new_height)
+            .in_scope(|| Resizer::new().resize(&src_image, &mut dst_image, &resize_options))?;
+        timing.resize = resize_start.elapsed();
+
+        samples = bytemuck::pod_collect_to_vec(dst_image.buffer());
+        width = new_width;
+        height = new_height;
+    }
+
+    let options = EncodeOptions {
+        width,
+        height,
+        format: ImageFormat::RGB,
+        quality: convert_options.jpeg_quality(target_size.is_some()),
+        baseline: true,
+        optimized: true,
+        subsample: convert_options.subsample_chroma,
+        adaptive_quantization: false,
+        background: Background::default(),
+    };
+    let mut output_buffer = Vec::new();
+
+    if convert_options.twelve_bit {
+        let thumbnail = if convert_options.preserve_metadata {
+            let mut rgb = vec![0u8; samples.len()];
+            dither_to_u8(&samples, &mut rgb, width as usize, 3, 0, Dither::None);
+            Some(exif_thumbnail(&rgb, width, height)?)
+        } else {
+            None
+        };
+
+        let linear_start = Instant::now();
+        let scale = MAX_12BIT_SAMPLE as f32;
+        let samples: Vec<u16> = samples.iter().map(|&value| (value * scale).round().clamp(0.0, scale) as u16).collect();
+        timing.linear = tonemap_time + linear_start.elapsed();
+
+        let encode_start = Instant::now();
+        info_span!("encode")
+            .in_scope(|| encode_jpeg_12bit(&samples, options, &mut output_buffer))
+            .map_err(|e| anyhow::anyhow!(e))?;
+        timing.encode = encode_start.elapsed();
+        return Ok(EncodedImage { jpeg: output_buffer, thumbnail });
+    }
+
+    let linear_start = Instant::now();
+    let mut rgb = vec![0u8; samples.len()];
+    info_span!("color").in_scope(|| dither_to_u8(&samples, &mut rgb, width as usize, 3, 0, convert_options.dither));
+    timing.linear = tonemap_time + linear_start.elapsed();
+
+    let encode_start = Instant::now();
+    info_span!("encode")
+        .in_scope(|| encode_jpeg(&rgb, options, &mut output_buffer))
+        .map_err(|e| anyhow::anyhow!(e))?;
+    let thumbnail = convert_options.preserve_metadata.then(|| exif_thumbnail(&rgb, width, height)).transpose()?;
+    timing.encode = encode_start.elapsed();
+
+    Ok(EncodedImage { jpeg: output_buffer, thumbnail })
+}
+
+// Scale little-endian 16-bit samples holding `bits` significant bits to 0.0..=1.0
+fn high_bit_depth_to_f32(bytes: &[u8], bits: u8, out: &mut [f32]) {
+    let scale = 1.0 / ((1u32 << bits.clamp(1, 16)) - 1) as f32;
+    for (sample, value) in bytes.chunks_exact(2).zip(out.iter_mut()) {
+        *value = u16::from_le_bytes([sample[0], sample[1]]) as f32 * scale;
+    }
+}
+
+/// Convert HEIC to JPEG with bounded intermediate memory
+///
+/// Instead of building full-size RGB, linear and output buffers, the decoded image is
+/// color-converted and handed to the JPEG encoder one MCU row (16 rows) at a time, and the
+/// JPEG is written straight to `jpeg_path`. No resizing is applied on this path.
+///
+/// Grid images (iPhone photos are 512x512 HEVC tiles) are decoded one row of tiles at a
+/// time, so peak memory grows with the image width rather than its area. Grids that are
+/// rotated, mirrored or cropped by their HEIF properties, and images with an alpha plane, are
+/// decoded whole since libheif applies those to the assembled image.
+///
+/// When the HEIC's YCbCr matrix matches JPEG's (BT.601, full range, 8-bit, as on iPhones),
+/// the image is decoded to its native planar YCbCr 4:2:0 and the planes are interleaved strip
+/// by strip, which skips libheif's RGB conversion and halves the decoded footprint.
+///
+/// Of `options`, the quality, chroma subsampling, alpha background, dither and tone mapping
+/// apply. Images with an alpha plane are composited onto `options.alpha.background`, without
+/// a sidecar. 10/12-bit images are dithered to 8 bits strip by strip, so the ordered pattern
+/// stays aligned while Floyd-Steinberg's error diffusion restarts every 16 rows, and PQ/HLG
+/// images are tone mapped row by row. Only the primary image is converted, without metadata,
+/// gain map or depth map: the output is always a plain SDR JPEG.
+///
+/// # Arguments
+/// * `heic_path` - Path to input HEIC file
+/// * `jpeg_path` - Path where to save the output JPEG
+/// * `options` - Conversion options, see above for the ones that apply
+pub fn convert_heic_to_jpeg_streaming(
+    heic_path: &str,
+    jpeg_path: &str,
+    options: &ConvertOptions,
+) -> Result<ConversionTiming, Box<dyn std::error::Error>> {
+    let _span = info_span!("convert_heic_to_jpeg_streaming", input = heic_path).entered();
+    let mut timing = ConversionTiming::default();
+    convert_heic_to_jpeg_streaming_internal(heic_path, jpeg_path, options, &mut timing)?;
+    timing.total = timing.decode + timing.linear + timing.resize + timing.encode;
+    timing.peak_rss = peak_rss();
+    debug!(?timing, "converted {} to {}", heic_path, jpeg_path);
+    Ok(timing)
+}
+
+// A decoded tile and the image column it starts at
+type Tile = (usize, libheif_rs::Image);
+
+fn convert_heic_to_jpeg_streaming_internal(
+    input_file: &str,
+    output_file: &str,
+    convert_options: &ConvertOptions,
+    timing: &mut ConversionTiming,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_heic_path(input_file) {
+        return Err(anyhow!("Input is not a HEIC file: {}", input_file).into());
+    }
+
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_file(input_file)?;
+    let image_handle = context.primary_image_handle()?;
+
+    let width = image_handle.width();
+    let height = image_handle.height();
+
+    let has_alpha = image_handle.has_alpha_channel();
+    let tone_mapper = hdr_tone_mapper(&image_handle, &convert_options.tone_mapping);
+    let high_bit_depth = !has_alpha && (image_handle.luma_bits_per_pixel() > 8 || tone_mapper.is_some());
+    let ycbcr_passthrough = !has_alpha && !high_bit_depth && has_jpeg_compatible_ycbcr(&image_handle);
+    let color_space = if ycbcr_passthrough {
+        ColorSpace::YCbCr(Chroma::C420)
+    } else if high_bit_depth {
+        ColorSpace::Rgb(RgbChroma::HdrRgbLe)
+    } else if has_alpha {
+        ColorSpace::Rgb(RgbChroma::Rgba)
+    } else {
+        ColorSpace::Rgb(RgbChroma::Rgb)
+    };
+
+    // Grids are decoded a row of tiles at a time; anything else, or a grid whose layout can't
+    // be read, in one piece
+    let mut file = File::open(input_file)?;
+    let grid = if has_alpha {
+        None
+    } else {
+        let grid = Meta::read(&mut file).and_then(|meta| {
+            let primary = meta.primary_item().ok_or_else(|| anyhow!("HEIF file has no primary item"))?;
+            let grid = meta.grid(&mut file, primary)?;
+            Ok(grid.filter(|grid| (grid.width, grid.height) == (width, height)).map(|grid| (meta, grid)))
+        });
+        grid.unwrap_or_else(|e| {
+            debug!("decoding the whole image, its grid can't be read: {}", e);
+            None
+        })
+    };
+
+    let options = EncodeOptions {
+        width,
+        height,
+        format: if ycbcr_passthrough { ImageFormat::YCbCr } else { ImageFormat::RGB },
+        quality: convert_options.jpeg_quality(false),
+        baseline: true,
+        optimized: true,
+        subsample: convert_options.subsample_chroma,
+        adaptive_quantization: false,
+        background: convert_options.alpha.background,
+    };
+
+    let mut output = BufWriter::new(File::create(output_file)?);
+    let mut encoder = JpegStreamEncoder::new(&mut output, options)
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    // Scratch buffers for a single strip of rows
+    let row_bytes = width as usize * 3;
+    let strip_height = encoder.mcu_height();
+    let mut linear_rgb = if ycbcr_passthrough { Vec::new() } else { vec![0.0f32; row_bytes * strip_height] };
+    let mut strip = vec![0u8; row_bytes * strip_height];
+    let mut flattened_row = if has_alpha { vec![0u8; row_bytes] } else { Vec::new() };
+
+    let (bands, band_height) = match &grid {
+        Some((_, grid)) => (grid.rows as usize, grid.tile_height as usize),
+        None => (1, height as usize),
+    };
+    for band in 0..bands {
+        let top = band * band_height;
+        let bottom = (top + band_height).min(height as usize);
+
+        let decode_start = Instant::now();
+        let tiles: Vec<Tile> = info_span!("decode", band).in_scope(|| match &grid {
+            Some((meta, grid)) => (0..grid.columns as usize)
+                .map(|column| {
+                    let tile_heif = meta.tile_heif(&mut file, grid, band * grid.columns as usize + column)?;
+                    let tile_context = HeifContext::read_from_bytes(&tile_heif)?;
+                    let tile = lib_heif.decode(&tile_context.primary_image_handle()?, color_space, None)?;
+                    Ok((column * grid.tile_width as usize, tile))
+                })
+                .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>(),
+            None => Ok(vec![(0, lib_heif.decode(&image_handle, color_space, None)?)]),
+        })?;
+        timing.decode += decode_start.elapsed();
+        let tiles: Vec<_> = tiles
+            .iter()
+            .map(|(left, tile)| (*left, tile.width().min(width - *left as u32) as usize, tile.planes()))
+            .collect();
+
+        // Encoding and writing happen strip by strip, so they share one span
+        let _stream_span = info_span!("encode", band).entered();
+        for first_row in (top..bottom).step_by(strip_height) {
+            let rows = strip_height.min(bottom - first_row);
+            let strip_bytes = rows * row_bytes;
+
+            let color_span = trace_span!("color", first_row).entered();
+            let linear_start = Instant::now();
+            for (row, strip_row) in strip[..strip_bytes].chunks_exact_mut(row_bytes).enumerate() {
+                let tile_row = first_row + row - top;
+                let linear_row = if ycbcr_passthrough { &mut [][..] } else { &mut linear_rgb[row * row_bytes..(row + 1) * row_bytes] };
+                for (left, columns, planes) in &tiles {
+                    let (start, end) = (left * 3, (left + columns) * 3);
+                    if ycbcr_passthrough {
+                        match (&planes.y, &planes.cb, &planes.cr) {
+                            (Some(y), Some(cb), Some(cr)) => {
+                                interleave_ycbcr_420(y, cb, cr, tile_row, &mut strip_row[start..end], *columns)
+                            }
+                            _ => return Err(anyhow!("Decoded image has no YCbCr planes").into()),
+                        }
+                        continue;
+                    }
+                    let plane = planes
+                        .interleaved
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Decoded image has no interleaved RGB plane"))?;
+                    // Rows in the decoded plane may be padded, so copy them one at a time using the stride
+                    let plane_start = tile_row * plane.stride;
+                    let linear_row = &mut linear_row[start..end];
+                    if high_bit_depth {
+                        // Kept at full precision here, dithered to 8 bits for the whole strip below
+                        high_bit_depth_to_f32(&plane.data[plane_start..plane_start + columns * 6], plane.bits_per_pixel, linear_row);
+                        if let Some(mapper) = &tone_mapper {
+                            mapper.apply(linear_row);
+                        }
+                    } else if has_alpha {
+                        let rgba = &plane.data[plane_start..plane_start + columns * 4];
+                        let flattened_row = &mut flattened_row[start..end];
+                        composite_alpha(rgba, flattened_row, *columns, first_row + row, convert_options.alpha.background);
+                        srgb_to_linear_wide(flattened_row, linear_row);
+                    } else {
+                        srgb_to_linear_wide(&plane.data[plane_start..plane_start + columns * 3], linear_row);
+                    }
+                }
+            }
+            if high_bit_depth {
+                let dither = convert_options.dither;
+                dither_to_u8(&linear_rgb[..strip_bytes], &mut strip[..strip_bytes], width as usize, 3, first_row, dither);
+            } else if !ycbcr_passthrough {
+                linear_to_srgb_wide(&linear_rgb[..strip_bytes], &mut strip[..strip_bytes]);
+            }
+            timing.linear += linear_start.elapsed();
+            drop(color_span);
+
+            let encode_start = Instant::now();
+            encoder.write_rows(&strip[..strip_bytes])
+                .map_err(|e| anyhow::anyhow!(e))?;
+            timing.encode += encode_start.elapsed();
+        }
+    }
+
+    let encode_start = Instant::now();
+    encoder.finish().map_err(|e| anyhow::anyhow!(e))?;
+    output.flush()?;
+    timing.encode += encode_start.elapsed();
+
+    Ok(())
+}
+
+// Convolution filter for a `resize_filter` name, Lanczos3 for "lanczos" and unknown names
+fn resize_filter_type(resize_filter: &str) -> FilterType {
+    match resize_filter {
+        "bilinear" => FilterType::Bilinear,
+        "catmull_rom" => FilterType::CatmullRom,
+        "mitchell" => FilterType::Mitchell,
+        _ => FilterType::Lanczos3,
+    }
+}
+
+// Copy the rows of a decoded plane into a tightly packed buffer, dropping any stride padding
+fn packed_rows(plane: &Plane<&[u8]>, row_bytes: usize, height: usize) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(row_bytes * height);
+    for row in plane.data.chunks(plane.stride).take(height) {
+        packed.extend_from_slice(&row[..row_bytes]);
+    }
+    packed
+}
+
+// Composite RGBA onto the configured background, writing the alpha sidecar first if requested
+fn flatten_alpha(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    alpha: &AlphaOptions,
+    output_file: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if alpha.write_sidecar {
+        let mut mask = vec![0u8; width as usize * height as usize];
+        extract_alpha(rgba, &mut mask);
+        let options = EncodeOptions {
+            width,
+            height,
+            format: ImageFormat::Gray,
+            quality: 95,
+            ..Default::default()
+        };
+        let mut output = BufWriter::new(File::create(alpha_sidecar_path(output_file))?);
+        encode_jpeg(&mask, options, &mut output).map_err(|e| anyhow!(e))?;
+        output.flush()?;
+    }
+
+    let mut rgb = vec![0u8; width as usize * height as usize * 3];
+    composite_alpha(rgba, &mut rgb, width as usize, 0, alpha.background);
+    Ok(rgb)
+}
+
+// `photo.jpg` -> `photo.alpha.jpg`
+fn alpha_sidecar_path(jpeg_path: &str) -> PathBuf {
+    Path::new(jpeg_path).with_extension("alpha.jpg")
+}
+
+// HDR transfer function declared by the image's nclx profile, if any
+fn hdr_transfer(image_handle: &ImageHandle) -> Option<HdrTransfer> {
+    match image_handle.color_profile_nclx()?.transfer_characteristics() {
+        TransferCharacteristics::ITU_R_BT_2100_0_PQ => Some(HdrTransfer::Pq),
+        TransferCharacteristics::ITU_R_BT_2100_0_HLG => Some(HdrTransfer::Hlg),
+        _ => None,
+    }
+}
+
+// Tone mapper for PQ/HLG images, `None` for SDR ones
+fn hdr_tone_mapper(image_handle: &ImageHandle, options: &ToneMapOptions) -> Option<ToneMapper> {
+    let transfer = hdr_transfer(image_handle)?;
+    let bt2020 = image_handle
+        .color_profile_nclx()
+        .is_some_and(|nclx| nclx.color_primaries() == ColorPrimaries::ITU_R_BT_2020_2_and_2100_0);
+    debug!(?transfer, bt2020, "tone mapping HDR image");
+    Some(ToneMapper::new(transfer, bt2020, options))
+}
+
+// Whether the image's YCbCr samples can go into the JPEG as-is: 8-bit, full range and the
+// BT.601 matrix JPEG/JFIF uses. Without an nclx box libheif assumes exactly that.
+fn has_jpeg_compatible_ycbcr(image_handle: &ImageHandle) -> bool {
+    if image_handle.luma_bits_per_pixel() != 8 || image_handle.chroma_bits_per_pixel() != 8 {
+        return false;
+    }
+    match image_handle.color_profile_nclx() {
+        None => true,
+        Some(nclx) => {
+            nclx.full_range_flag() != 0
+                && matches!(
+                    nclx.matrix_coefficients(),
+                    MatrixCoefficients::ITU_R_BT_601_6
+                        | MatrixCoefficients::ITU_R_BT_470_6_System_B_G
+                        | MatrixCoefficients::Unspecified
+                )
+        }
+    }
+}
+
+// Interleave rows of 4:2:0 planes into Y-Cb-Cr triplets, repeating each chroma sample over
+// its 2x2 square. The encoder's own 4:2:0 averaging then recovers the original samples.
+fn interleave_ycbcr_420(
+    y: &Plane<&[u8]>,
+    cb: &Plane<&[u8]>,
+    cr: &Plane<&[u8]>,
+    first_row: usize,
+    out: &mut [u8],
+    width: usize,
+) {
+    for (row, out_row) in out.chunks_exact_mut(width * 3).enumerate() {
+        let image_row = first_row + row;
+        let y_row = &y.data[image_row * y.stride..];
+        let cb_row = &cb.data[image_row / 2 * cb.stride..];
+        let cr_row = &cr.data[image_row / 2 * cr.stride..];
+        for (x, pixel) in out_row.chunks_exact_mut(3).enumerate() {
+            pixel[0] = y_row[x];
+            pixel[1] = cb_row[x / 2];
+            pixel[2] = cr_row[x / 2];
+        }
+    }
+}
+
+/// Rotate, flip or crop an existing JPEG file without re-encoding it
+///
+/// The EXIF orientation is applied along with `options.transform`, see `toojpeg::transform_jpeg`.
+///
+/// # Arguments
+/// * `input_path` - Path to the input JPEG file
+/// * `output_path` - Path where to save the transformed JPEG (may be the input path)
+/// * `options` - Rotation/flip and optional crop region
+pub fn transform_jpeg_file(
+    input_path: &str,
+    output_path: &str,
+    options: TransformOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = fs::read(input_path)?;
+    let mut output = Vec::with_capacity(data.len());
+    info_span!("transform", input = input_path)
+        .in_scope(|| transform_jpeg(&data, options, &mut output))
+        .map_err(|e| anyhow!(e))?;
+    fs::write(output_path, output)?;
     Ok(())
 }
 
+/// Create a small JPEG preview of a HEIC, e.g. for a file list
+///
+/// Uses the thumbnail stored in the file (a `thmb` item, about 320 pixels wide on iPhones)
+/// when one is at least `max_edge` pixels on its long side, which skips decoding the full
+/// image. Otherwise the full image is decoded. Either way the result is downscaled to fit
+/// `max_edge` and encoded at quality 80. A `max_edge` of 0 keeps the full image at its
+/// source size, ignoring stored thumbnails.
+///
+/// # Arguments
+/// * `heic_path` - Path to input HEIC file
+/// * `max_edge` - Longest side of the preview in pixels
+pub fn generate_thumbnail(heic_path: &str, max_edge: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let _span = info_span!("generate_thumbnail", input = heic_path, max_edge).entered();
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_file(heic_path)?;
+    let image_handle = context.primary_image_handle()?;
+
+    // The smallest stored thumbnail that is still large enough, unless the source size
+    // was asked for
+    let thumbnail_count = if max_edge > 0 { image_handle.number_of_thumbnails() } else { 0 };
+    let mut thumbnail_ids = vec![0; thumbnail_count];
+    let count = image_handle.thumbnail_ids(&mut thumbnail_ids);
+    let thumbnail = thumbnail_ids[..count]
+        .iter()
+        .filter_map(|&id| image_handle.thumbnail(id).ok())
+        .filter(|thumbnail| thumbnail.width().max(thumbnail.height()) >= max_edge)
+        .min_by_key(|thumbnail| thumbnail.width() * thumbnail.height());
+    let source = match &thumbnail {
+        Some(thumbnail) => {
+            debug!(width = thumbnail.width(), height = thumbnail.height(), "using embedded thumbnail");
+            thumbnail
+        }
+        None => &image_handle,
+    };
+
+    let image_data = info_span!("decode")
+        .in_scope(|| lib_heif.decode(source, ColorSpace::Rgb(RgbChroma::Rgb), None))?;
+    let plane = image_data.planes().interleaved.ok_or_else(|| anyhow!("Decoded image has no interleaved plane"))?;
+    let (mut width, mut height) = (plane.width, plane.height);
+    let mut rgb = packed_rows(&plane, width as usize * 3, height as usize);
+    drop(image_data);
+
+    if max_edge > 0 {
+        (rgb, width, height) = downscale_rgb(rgb, width, height, max_edge)?;
+    }
+
+    let options = EncodeOptions {
+        width,
+        height,
+        format: ImageFormat::RGB,
+        quality: 80,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    info_span!("encode")
+        .in_scope(|| encode_jpeg(&rgb, options, &mut output))
+        .map_err(|e| anyhow!(e))?;
+    Ok(output)
+}
+
+// Shrink RGB so its long edge is at most `max_edge`, with a fast bilinear filter
+fn downscale_rgb(
+    rgb: Vec<u8>,
+    width: u32,
+    height: u32,
+    max_edge: u32,
+) -> Result<(Vec<u8>, u32, u32), Box<dyn std::error::Error>> {
+    let long_edge = width.max(height);
+    if long_edge <= max_edge {
+        return Ok((rgb, width, height));
+    }
+    let new_width = (width as u64 * max_edge as u64 / long_edge as u64).max(1) as u32;
+    let new_height = (height as u64 * max_edge as u64 / long_edge as u64).max(1) as u32;
+    let src_image = Image::from_vec_u8(width, height, rgb, PixelType::U8x3)?;
+    let mut dst_image = Image::new(new_width, new_height, PixelType::U8x3);
+    let resize_options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(FilterType::Bilinear));
+    info_span!("resize", width = new_width, height = new_height)
+        .in_scope(|| Resizer::new().resize(&src_image, &mut dst_image, &resize_options))?;
+    Ok((dst_image.into_vec(), new_width, new_height))
+}
+
+// 160 pixel JPEG for the EXIF IFD1 thumbnail, the size the EXIF spec suggests
+fn exif_thumbnail(rgb: &[u8], width: u32, height: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (rgb, width, height) = downscale_rgb(rgb.to_vec(), width, height, 160)?;
+    let options = EncodeOptions {
+        width,
+        height,
+        format: ImageFormat::RGB,
+        quality: 75,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    encode_jpeg(&rgb, options, &mut output).map_err(|e| anyhow!(e))?;
+    Ok(output)
+}
+
+#[cfg(feature = "android")]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn Java_com_example_heictojpeg_NativeLib_generateThumbnail(
+    mut env: JNIEnv,
+    _class: JClass,
+    input_path: JString,
+    max_edge: jint,
+) -> jbyteArray {
+    let input: String = env.get_string(&input_path).expect("Couldn't get java string!").into();
+    match generate_thumbnail(&input, max_edge.max(0) as u32) {
+        Ok(jpeg) => env.byte_array_from_slice(&jpeg).expect("Couldn't create java byte array!").into_raw(),
+        Err(e) => {
+            tracing::warn!("thumbnail for {} failed: {}", input, e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(feature = "android")]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn Java_com_example_heictojpeg_NativeLib_rotateJpeg(
+    mut env: JNIEnv,
+    _class: JClass,
+    input_path: JString,
+    output_path: JString,
+    degrees: jint,
+) -> jstring {
+    let input: String = env.get_string(&input_path).expect("Couldn't get java string!").into();
+    let output: String = env.get_string(&output_path).expect("Couldn't get java string!").into();
+    let transform = match degrees.rem_euclid(360) {
+        0 => Some(Transform::None),
+        90 => Some(Transform::Rotate90),
+        180 => Some(Transform::Rotate180),
+        270 => Some(Transform::Rotate270),
+        _ => None,
+    };
+    let result = match transform {
+        Some(transform) => match transform_jpeg_file(&input, &output, TransformOptions { transform, crop: None }) {
+            Ok(()) => "Successfully rotated".to_string(),
+            Err(e) => format!("Error: {}", e),
+        },
+        None => format!("Error: rotation must be a multiple of 90 degrees, got {}", degrees),
+    };
+
+    create_java_string(&mut env, &result)
+}
+
 #[cfg(feature = "android")]
 #[no_mangle]
 #[allow(non_snake_case)]
@@ -253,33 +1268,26 @@ pub extern "system" fn Java_com_example_heictojpeg_NativeLib_convertHeicBatchToJ
 ) -> jstring {
     let output_dir: String = env.get_string(&output_dir).expect("Couldn't get java string!").into();
     let num_files = env.get_array_length(&input_paths).unwrap_or(0);
-    let mut successful_conversions = 0;
-    let mut failed_conversions = 0;
-
+    let mut inputs = Vec::new();
     for i in 0..num_files {
         if let Ok(input_path_jstring) = env.get_object_array_element(&input_paths, i) {
             if let Ok(input_path) = env.get_string(&JString::from(input_path_jstring)) {
-                let input_path: String = input_path.into();
-                let file_name = std::path::Path::new(&input_path)
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("converted");
-
-                let output_path = format!("{}/{}.jpg", &output_dir, file_name);
-
-                match convert_heic_to_jpeg(&input_path, &output_path, 0, 0, "lanczos") {
-                    Ok(_) => successful_conversions += 1,
-                    Err(_) => failed_conversions += 1,
-                }
+                inputs.push(String::from(input_path));
             }
         }
     }
 
-    let result_message = format!(
-        "Batch conversion complete. Success: {}, Failed: {}",
-        successful_conversions,
-        failed_conversions
-    );
+    // Inputs all land in one folder, so same-named photos from different folders are numbered
+    // instead of overwriting each other
+    let options = BatchOptions { collisions: CollisionStrategy::Suffix, ..Default::default() };
+    let result_message = match convert_batch(&inputs, &output_dir, 0, 0, "lanczos", &options) {
+        Ok(report) => format!(
+            "Batch conversion complete. Success: {}, Failed: {}",
+            report.converted.len(),
+            report.failed.len()
+        ),
+        Err(e) => format!("Error: {}", e),
+    };
 
     create_java_string(&mut env, &result_message)
 }
@@ -352,6 +1360,34 @@ fn linear_to_srgb(c: f32) -> u8 {
     min(max((y * 255.0).round() as i32, 0), 255) as u8
 }
 
+/// Route the library's log output to logcat
+///
+/// `level`: 0 = off, 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace.
+/// Only the first call installs the logger; later calls just change the level.
+#[cfg(all(feature = "android", target_os = "android"))]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn Java_com_example_heictojpeg_NativeLib_initLogging(
+    _env: JNIEnv,
+    _class: JClass,
+    level: jint,
+) {
+    let level = match level {
+        i32::MIN..=0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_max_level(level)
+            .with_tag("heic2jpeg"),
+    );
+    log::set_max_level(level);
+}
+
 #[cfg(feature = "android")]
 #[no_mangle]
 #[allow(non_snake_case)]
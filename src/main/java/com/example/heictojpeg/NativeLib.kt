@@ -19,6 +19,12 @@ object NativeLib {
      */
     external fun testConnection(): String
 
+    /**
+     * Route native log output to logcat under the "heic2jpeg" tag
+     * @param level 0 = off, 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace
+     */
+    external fun initLogging(level: Int)
+
     /**
      * Converts a HEIC file to JPEG format
      * @param inputPath Absolute path to the input HEIC file
@@ -27,6 +33,23 @@ object NativeLib {
      */
     external fun convertHeicToJpeg(inputPath: String, outputPath: String): String
 
+    /**
+     * Creates a small JPEG preview of a HEIC file, using its embedded thumbnail when large enough
+     * @param inputPath Absolute path to the input HEIC file
+     * @param maxEdge Longest side of the preview in pixels, 0 for the full image at its source size
+     * @return The JPEG bytes, for BitmapFactory.decodeByteArray, or null on failure
+     */
+    external fun generateThumbnail(inputPath: String, maxEdge: Int): ByteArray?
+
+    /**
+     * Rotates a JPEG file without re-encoding it, also applying its EXIF orientation
+     * @param inputPath Absolute path to the input JPEG file
+     * @param outputPath Absolute path for the output JPEG file (may equal inputPath)
+     * @param degrees Clockwise rotation, a multiple of 90
+     * @return A message indicating success or failure with details
+     */
+    external fun rotateJpeg(inputPath: String, outputPath: String, degrees: Int): String
+
     /**
      * Converts a batch of HEIC files to JPEG format.
      * @param inputPaths Array of absolute paths to the input HEIC files.
@@ -0,0 +1,260 @@
+//! Edits to the EXIF of converted JPEGs: capture time, author, copyright, location.
+
+use anyhow::anyhow;
+
+use crate::exif::{ExifIfds, BYTE, EXIF_IFD, GPS_IFD, IFD0};
+
+/// EXIF tags to set or remove on the output, see `ConvertOptions::metadata_edits`
+///
+/// Edits apply on top of the HEIC's EXIF when `ConvertOptions::preserve_metadata` is set, or
+/// to a fresh EXIF block otherwise, so a conversion can stamp copyright without copying the
+/// rest. Removals run after the other edits. The text tags are EXIF ASCII, so their values
+/// have to be ASCII too: `(c) 2024`, not `© 2024`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetadataEdits {
+    /// Capture time (`DateTimeOriginal`, with `OffsetTimeOriginal` when it has an offset)
+    pub date_time_original: Option<ExifDateTime>,
+    /// Photographer (`Artist`)
+    pub artist: Option<String>,
+    /// Copyright notice (`Copyright`)
+    pub copyright: Option<String>,
+    /// Caption (`ImageDescription`)
+    pub image_description: Option<String>,
+    /// Where the photo was taken, replacing the GPS position tags
+    pub gps: Option<GpsPosition>,
+    /// Tags to remove from IFD0, the Exif IFD and the GPS IFD, e.g. `0x9003` for
+    /// `DateTimeOriginal`. Removing `0x8825` drops all GPS tags, `0x8769` the whole Exif IFD.
+    pub remove_tags: Vec<u16>,
+}
+
+/// A local date and time as EXIF stores it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExifDateTime {
+    /// Year, 0 to 9999
+    pub year: u16,
+    /// Month, 1 to 12
+    pub month: u8,
+    /// Day of the month, 1 to 31
+    pub day: u8,
+    /// Hour, 0 to 23
+    pub hour: u8,
+    /// Minute, 0 to 59
+    pub minute: u8,
+    /// Second, 0 to 59
+    pub second: u8,
+    /// Offset from UTC in minutes, e.g. 120 for `+02:00`; `None` leaves the offset unset
+    pub utc_offset_minutes: Option<i16>,
+}
+
+/// A position in WGS 84
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsPosition {
+    /// Latitude in degrees, positive north
+    pub latitude: f64,
+    /// Longitude in degrees, positive east
+    pub longitude: f64,
+    /// Altitude in meters above sea level, negative below
+    pub altitude: Option<f64>,
+}
+
+impl MetadataEdits {
+    /// Whether there is nothing to change
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply the edits to TIFF-structured EXIF data, or to an empty block, returning the new TIFF
+    pub(crate) fn apply(&self, tiff: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+        let mut ifds = match tiff {
+            Some(tiff) => ExifIfds::parse(tiff).ok_or_else(|| anyhow!("EXIF data can't be parsed for editing"))?,
+            None => ExifIfds::new(),
+        };
+
+        if let Some(date_time) = &self.date_time_original {
+            let ExifDateTime { year, month, day, hour, minute, second, utc_offset_minutes } = *date_time;
+            if year > 9999 || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+                return Err(anyhow!("Invalid capture time: {:?}", date_time));
+            }
+            let text = format!("{:04}:{:02}:{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
+            ifds.set_ascii(EXIF_IFD, 0x9003, &text);
+            match utc_offset_minutes {
+                Some(offset) if offset.abs() < 24 * 60 => {
+                    let sign = if offset < 0 { '-' } else { '+' };
+                    let text = format!("{}{:02}:{:02}", sign, offset.abs() / 60, offset.abs() % 60);
+                    ifds.set_ascii(EXIF_IFD, 0x9011, &text);
+                }
+                Some(offset) => return Err(anyhow!("Invalid UTC offset: {} minutes", offset)),
+                // A stale offset would contradict the new time
+                None => ifds.remove_from(EXIF_IFD, 0x9011),
+            }
+        }
+        let texts = [
+            (0x013B, "Artist", &self.artist),
+            (0x8298, "Copyright", &self.copyright),
+            (0x010E, "ImageDescription", &self.image_description),
+        ];
+        for (tag, name, text) in texts {
+            if let Some(text) = text {
+                // Readers decode ASCII tags as Latin-1 or the system code page, garbling UTF-8
+                if !text.is_ascii() || text.contains('\0') {
+                    return Err(anyhow!("{} has to be ASCII text without NULs: {:?}", name, text));
+                }
+                ifds.set_ascii(IFD0, tag, text);
+            }
+        }
+
+        if let Some(gps) = &self.gps {
+            if !(-90.0..=90.0).contains(&gps.latitude) || !(-180.0..=180.0).contains(&gps.longitude) {
+                return Err(anyhow!("Invalid GPS position: {}, {}", gps.latitude, gps.longitude));
+            }
+            ifds.set(GPS_IFD, 0x0000, BYTE, 4, vec![2, 3, 0, 0]); // GPSVersionID 2.3
+            ifds.set_ascii(GPS_IFD, 0x0001, if gps.latitude < 0.0 { "S" } else { "N" });
+            ifds.set_rationals(GPS_IFD, 0x0002, &degrees_minutes_seconds(gps.latitude));
+            ifds.set_ascii(GPS_IFD, 0x0003, if gps.longitude < 0.0 { "W" } else { "E" });
+            ifds.set_rationals(GPS_IFD, 0x0004, &degrees_minutes_seconds(gps.longitude));
+            match gps.altitude {
+                Some(altitude) => {
+                    ifds.set(GPS_IFD, 0x0005, BYTE, 1, vec![(altitude < 0.0) as u8]);
+                    ifds.set_rationals(GPS_IFD, 0x0006, &[((altitude.abs() * 1000.0).round() as u32, 1000)]);
+                }
+                None => {
+                    ifds.remove_from(GPS_IFD, 0x0005);
+                    ifds.remove_from(GPS_IFD, 0x0006);
+                }
+            }
+        }
+
+        for &tag in &self.remove_tags {
+            ifds.remove(tag);
+        }
+        Ok(ifds.write())
+    }
+}
+
+// Degrees, minutes and seconds to a ten-thousandth of a second, as GPS rationals
+fn degrees_minutes_seconds(value: f64) -> [(u32, u32); 3] {
+    let total = (value.abs() * 3600.0 * 10000.0).round() as u64;
+    let (degrees, rest) = (total / (3600 * 10000), total % (3600 * 10000));
+    let (minutes, seconds) = (rest / (60 * 10000), rest % (60 * 10000));
+    [(degrees as u32, 1), (minutes as u32, 1), (seconds as u32, 10000)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::{ASCII, RATIONAL};
+
+    fn field(ifds: &ExifIfds, ifd: usize, tag: u16) -> Option<Vec<u8>> {
+        ifds.fields(ifd).iter().find(|field| field.tag == tag).map(|field| field.data.clone())
+    }
+
+    fn rationals(ifds: &ExifIfds, ifd: usize, tag: u16) -> Vec<(u32, u32)> {
+        let data = field(ifds, ifd, tag).unwrap();
+        let value = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+        (0..data.len()).step_by(8).map(|at| (value(at), value(at + 4))).collect()
+    }
+
+    #[test]
+    fn test_apply_to_a_fresh_block() {
+        let edits = MetadataEdits {
+            date_time_original: Some(ExifDateTime {
+                year: 2024,
+                month: 5,
+                day: 6,
+                hour: 7,
+                minute: 8,
+                second: 9,
+                utc_offset_minutes: Some(-150),
+            }),
+            copyright: Some("(c) 2024 Someone".to_string()),
+            gps: Some(GpsPosition { latitude: -33.8568, longitude: 151.2153, altitude: Some(-5.5) }),
+            ..Default::default()
+        };
+        let ifds = ExifIfds::parse(&edits.apply(None).unwrap()).unwrap();
+        assert_eq!(field(&ifds, EXIF_IFD, 0x9003).unwrap(), b"2024:05:06 07:08:09\0");
+        assert_eq!(field(&ifds, EXIF_IFD, 0x9011).unwrap(), b"-02:30\0");
+        assert_eq!(field(&ifds, IFD0, 0x8298).unwrap(), b"(c) 2024 Someone\0");
+        assert_eq!(field(&ifds, GPS_IFD, 0x0001).unwrap(), b"S\0");
+        assert_eq!(field(&ifds, GPS_IFD, 0x0003).unwrap(), b"E\0");
+        assert_eq!(rationals(&ifds, GPS_IFD, 0x0002), [(33, 1), (51, 1), (244800, 10000)]);
+        assert_eq!(rationals(&ifds, GPS_IFD, 0x0004), [(151, 1), (12, 1), (550800, 10000)]);
+        assert_eq!(field(&ifds, GPS_IFD, 0x0005).unwrap(), [1]);
+        assert_eq!(rationals(&ifds, GPS_IFD, 0x0006), [(5500, 1000)]);
+    }
+
+    #[test]
+    fn test_gps_seconds_round_and_carry() {
+        assert_eq!(degrees_minutes_seconds(51.5007), [(51, 1), (30, 1), (25200, 10000)]);
+        assert_eq!(degrees_minutes_seconds(-0.5), [(0, 1), (30, 1), (0, 10000)]);
+        // Within half a ten-thousandth of a second of the next degree
+        assert_eq!(degrees_minutes_seconds(10.999_999_999_9), [(11, 1), (0, 1), (0, 10000)]);
+        assert_eq!(degrees_minutes_seconds(180.0), [(180, 1), (0, 1), (0, 10000)]);
+    }
+
+    #[test]
+    fn test_edits_replace_and_remove_existing_tags() {
+        let mut existing = ExifIfds::new();
+        existing.set_ascii(IFD0, 0x013B, "Old Artist");
+        existing.set_ascii(IFD0, 0x010F, "Acme");
+        existing.set_ascii(EXIF_IFD, 0x9003, "2001:01:01 00:00:00");
+        existing.set_ascii(EXIF_IFD, 0x9011, "+09:00");
+        existing.set_ascii(GPS_IFD, 0x0001, "N");
+        existing.set_rationals(GPS_IFD, 0x0006, &[(100, 1)]);
+        let tiff = existing.write();
+
+        let edits = MetadataEdits {
+            date_time_original: Some(ExifDateTime {
+                year: 2020,
+                month: 2,
+                day: 29,
+                hour: 23,
+                minute: 59,
+                second: 59,
+                utc_offset_minutes: None,
+            }),
+            artist: Some("New Artist".to_string()),
+            gps: Some(GpsPosition { latitude: 1.0, longitude: -2.0, altitude: None }),
+            remove_tags: vec![0x010F],
+            ..Default::default()
+        };
+        let ifds = ExifIfds::parse(&edits.apply(Some(&tiff)).unwrap()).unwrap();
+        assert_eq!(field(&ifds, IFD0, 0x013B).unwrap(), b"New Artist\0");
+        assert!(field(&ifds, IFD0, 0x010F).is_none());
+        assert_eq!(field(&ifds, EXIF_IFD, 0x9003).unwrap(), b"2020:02:29 23:59:59\0");
+        // The old offset and altitude would contradict the new time and position
+        assert!(field(&ifds, EXIF_IFD, 0x9011).is_none());
+        assert!(field(&ifds, GPS_IFD, 0x0006).is_none());
+        assert_eq!(field(&ifds, GPS_IFD, 0x0003).unwrap(), b"W\0");
+        let kinds: Vec<u16> = ifds.fields(GPS_IFD).iter().filter(|field| field.tag != 0x0000).map(|field| field.kind).collect();
+        assert!(kinds.iter().all(|&kind| kind == ASCII || kind == RATIONAL));
+
+        // Removing the GPS pointer drops the new position too, removals run last
+        let edits = MetadataEdits { remove_tags: vec![0x8825], ..edits };
+        let ifds = ExifIfds::parse(&edits.apply(Some(&tiff)).unwrap()).unwrap();
+        assert!(ifds.fields(GPS_IFD).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_edits_are_rejected() {
+        let date_time = ExifDateTime { year: 2024, month: 13, day: 1, hour: 0, minute: 0, second: 0, utc_offset_minutes: None };
+        let edits = MetadataEdits { date_time_original: Some(date_time), ..Default::default() };
+        assert!(edits.apply(None).is_err());
+        let date_time = ExifDateTime { month: 1, utc_offset_minutes: Some(24 * 60), ..date_time };
+        let edits = MetadataEdits { date_time_original: Some(date_time), ..Default::default() };
+        assert!(edits.apply(None).is_err());
+        let edits = MetadataEdits {
+            gps: Some(GpsPosition { latitude: 91.0, longitude: 0.0, altitude: None }),
+            ..Default::default()
+        };
+        assert!(edits.apply(None).is_err());
+        for text in ["© 2024 Someone", "Zoë", "A\0B"] {
+            let edits = MetadataEdits { copyright: Some(text.to_string()), ..Default::default() };
+            assert!(edits.apply(None).is_err());
+            let edits = MetadataEdits { artist: Some(text.to_string()), image_description: Some("ok".to_string()), ..Default::default() };
+            assert!(edits.apply(None).is_err());
+        }
+        let edits = MetadataEdits { artist: Some("A".to_string()), ..Default::default() };
+        assert!(edits.apply(Some(b"not a TIFF")).is_err());
+        assert!(MetadataEdits::default().is_empty() && !edits.is_empty());
+    }
+}
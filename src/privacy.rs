@@ -0,0 +1,268 @@
+//! Privacy scrubbing: stripping identifying metadata from converted JPEGs before sharing.
+//!
+//! The scrub removes location, device and owner identification from the EXIF and XMP that
+//! `ConvertOptions::preserve_metadata` would copy, and keeps the HEIC's auxiliary images
+//! (depth maps, portrait mattes, gain maps) out of the output. What was removed is listed in
+//! a `ScrubReport`, so apps can show it before the photo is shared.
+
+use anyhow::anyhow;
+use libheif_rs::ImageHandle;
+
+use crate::auxiliary::auxiliary_handles;
+use crate::exif::ExifIfds;
+
+/// What a privacy scrub keeps, see `ConvertOptions::scrub`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrubPolicy {
+    /// Keep the EXIF Orientation tag. The converted pixels are upright, so it is always 1.
+    pub keep_orientation: bool,
+    /// Keep the EXIF ColorSpace, Gamma and interoperability tags. The JPEG holds sRGB pixels
+    /// and no ICC profile either way.
+    pub keep_color_profile: bool,
+}
+
+/// What a privacy scrub removed, in the order it was found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Descriptions such as `EXIF GPS position`, `EXIF Model` or `XMP mwg-rs:Regions`
+    pub removed: Vec<String>,
+}
+
+impl ScrubReport {
+    fn add(&mut self, item: String) {
+        if !self.removed.contains(&item) {
+            self.removed.push(item);
+        }
+    }
+
+    /// Add the items of another report, e.g. for another image of the same file
+    pub(crate) fn merge(&mut self, other: ScrubReport) {
+        for item in other.removed {
+            self.add(item);
+        }
+    }
+}
+
+// Identifying EXIF tags, from IFD0 and the Exif IFD
+const IDENTIFYING_TAGS: [(u16, &str); 13] = [
+    (0x8825, "GPS position"),
+    (0x010F, "Make"),
+    (0x0110, "Model"),
+    (0x0131, "Software"),
+    (0x013B, "Artist"),
+    (0x013C, "HostComputer"),
+    (0x927C, "MakerNote"),
+    (0xA420, "ImageUniqueID"),
+    (0xA430, "CameraOwnerName"),
+    (0xA431, "BodySerialNumber"),
+    (0xA433, "LensMake"),
+    (0xA434, "LensModel"),
+    (0xA435, "LensSerialNumber"),
+];
+const ORIENTATION_TAGS: [(u16, &str); 1] = [(0x0112, "Orientation")];
+const COLOR_TAGS: [(u16, &str); 3] = [(0xA001, "ColorSpace"), (0xA500, "Gamma"), (0xA005, "interoperability")];
+
+// XMP properties removed, by qualified name or name prefix with the conventional prefixes
+const IDENTIFYING_XMP: [&str; 18] = [
+    "exif:GPS",
+    "photoshop:City",
+    "photoshop:State",
+    "photoshop:Country",
+    "Iptc4xmpCore:Location",
+    "Iptc4xmpCore:CountryCode",
+    "Iptc4xmpExt:LocationCreated",
+    "Iptc4xmpExt:LocationShown",
+    "mwg-rs:Regions",
+    "MP:RegionInfo",
+    "tiff:Make",
+    "tiff:Model",
+    "aux:SerialNumber",
+    "aux:LensSerialNumber",
+    "aux:OwnerName",
+    "exifEX:BodySerialNumber",
+    "exifEX:LensSerialNumber",
+    "exifEX:CameraOwnerName",
+];
+
+/// Remove identifying tags from TIFF-structured EXIF data
+pub(crate) fn scrub_exif(tiff: &[u8], policy: &ScrubPolicy, report: &mut ScrubReport) -> anyhow::Result<Vec<u8>> {
+    let mut ifds = ExifIfds::parse(tiff).ok_or_else(|| anyhow!("EXIF data can't be parsed for scrubbing"))?;
+    let mut tags = IDENTIFYING_TAGS.to_vec();
+    if !policy.keep_orientation {
+        tags.extend(ORIENTATION_TAGS);
+    }
+    if !policy.keep_color_profile {
+        tags.extend(COLOR_TAGS);
+    }
+    for (tag, name) in tags {
+        if ifds.contains(tag) {
+            ifds.remove(tag);
+            report.add(format!("EXIF {}", name));
+        }
+    }
+    Ok(ifds.write())
+}
+
+/// Remove identifying properties from an XMP packet
+pub(crate) fn scrub_xmp(xmp: &str, report: &mut ScrubReport) -> String {
+    let mut xmp = xmp.to_string();
+    for prefix in IDENTIFYING_XMP {
+        let (scrubbed, removed) = remove_xmp_properties(&xmp, prefix);
+        xmp = scrubbed;
+        for name in removed {
+            report.add(format!("XMP {}", name));
+        }
+    }
+    xmp
+}
+
+/// List the auxiliary images a scrub removes: all of them but the alpha plane, including
+/// mattes a conversion never writes, since they describe the people in the photo
+pub(crate) fn report_auxiliary_images(image_handle: &ImageHandle, report: &mut ScrubReport) {
+    for (image, _) in auxiliary_handles(image_handle) {
+        report.add(format!("auxiliary image {}", image.urn));
+    }
+}
+
+// Remove the attributes and elements whose qualified name starts with `prefix`, returning
+// the packet and the names removed
+fn remove_xmp_properties(xmp: &str, prefix: &str) -> (String, Vec<String>) {
+    let mut output = String::with_capacity(xmp.len());
+    let mut removed = Vec::new();
+    let mut rest = xmp;
+    while let Some(at) = rest.find(prefix) {
+        let before = &rest[..at];
+        let name_end = rest[at..].find(|c: char| c == '=' || c == '>' || c == '/' || c.is_whitespace()).map_or(rest.len(), |end| at + end);
+        let name = &rest[at..name_end];
+
+        // Attribute: ` name="value"`, removed along with the whitespace before it
+        if before.ends_with(|c: char| c.is_whitespace()) && rest[name_end..].starts_with('=') {
+            let quote = rest[name_end + 1..].chars().next().filter(|&c| c == '"' || c == '\'');
+            if let Some(value_end) = quote.and_then(|quote| rest[name_end + 2..].find(quote)) {
+                output.push_str(before.trim_end());
+                removed.push(name.to_string());
+                rest = &rest[name_end + 2 + value_end + 1..];
+                continue;
+            }
+        }
+        // Element: `<name .../>` or `<name ...>...</name>`
+        if let Some(before_element) = before.strip_suffix('<') {
+            let tag_end = rest[at..].find('>').map(|end| at + end);
+            let element_end = match tag_end {
+                Some(end) if rest[..end].ends_with('/') => Some(end + 1),
+                Some(end) => rest[end..].find(&format!("</{}>", name)).map(|close| end + close + name.len() + 3),
+                None => None,
+            };
+            if let Some(element_end) = element_end {
+                output.push_str(before_element);
+                removed.push(name.to_string());
+                rest = &rest[element_end..];
+                continue;
+            }
+        }
+        output.push_str(&rest[..name_end]);
+        rest = &rest[name_end..];
+    }
+    output.push_str(rest);
+    (output, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::{EXIF_IFD, GPS_IFD, IFD0, INTEROP_IFD};
+
+    fn sample_exif() -> Vec<u8> {
+        let mut ifds = ExifIfds::new();
+        ifds.set_ascii(IFD0, 0x010F, "Apple");
+        ifds.set_ascii(IFD0, 0x0110, "iPhone 15 Pro");
+        ifds.set(IFD0, 0x0112, 3, 1, vec![0, 6]);
+        ifds.set_ascii(IFD0, 0x0132, "2024:05:06 07:08:09");
+        ifds.set(EXIF_IFD, 0xA001, 3, 1, vec![0, 1]);
+        ifds.set_ascii(EXIF_IFD, 0xA434, "iPhone 15 Pro back camera");
+        ifds.set_ascii(INTEROP_IFD, 0x0001, "R98");
+        ifds.set_ascii(GPS_IFD, 0x0001, "N");
+        ifds.write()
+    }
+
+    #[test]
+    fn test_scrub_exif_removes_identifying_tags() {
+        let mut report = ScrubReport::default();
+        let scrubbed = scrub_exif(&sample_exif(), &ScrubPolicy::default(), &mut report).unwrap();
+        let ifds = ExifIfds::parse(&scrubbed).unwrap();
+        for tag in [0x8825, 0x010F, 0x0110, 0xA434, 0x0112, 0xA001, 0xA005] {
+            assert!(!ifds.contains(tag), "tag {:04X} kept", tag);
+        }
+        assert!(ifds.fields(INTEROP_IFD).is_empty());
+        assert!(ifds.contains(0x0132));
+        assert_eq!(
+            report.removed,
+            ["EXIF GPS position", "EXIF Make", "EXIF Model", "EXIF LensModel", "EXIF Orientation", "EXIF ColorSpace", "EXIF interoperability"]
+        );
+    }
+
+    #[test]
+    fn test_scrub_exif_policy_keeps_orientation_and_color() {
+        let policy = ScrubPolicy { keep_orientation: true, keep_color_profile: true };
+        let mut report = ScrubReport::default();
+        let ifds = ExifIfds::parse(&scrub_exif(&sample_exif(), &policy, &mut report).unwrap()).unwrap();
+        assert!(ifds.contains(0x0112));
+        assert!(ifds.contains(0xA001));
+        assert_eq!(ifds.fields(INTEROP_IFD).len(), 1);
+        assert!(!ifds.contains(0x8825));
+        assert_eq!(report.removed, ["EXIF GPS position", "EXIF Make", "EXIF Model", "EXIF LensModel"]);
+
+        assert!(scrub_exif(b"not a tiff", &policy, &mut report).is_err());
+    }
+
+    #[test]
+    fn test_remove_xmp_attributes() {
+        let xmp = r#"<rdf:Description rdf:about="" exif:GPSLatitude="51,30.0N"
+            exif:GPSLongitude='0,7.5W' exif:ExposureTime="1/60" tiff:Make="Apple"/>"#;
+        let (scrubbed, removed) = remove_xmp_properties(xmp, "exif:GPS");
+        assert_eq!(scrubbed, r#"<rdf:Description rdf:about="" exif:ExposureTime="1/60" tiff:Make="Apple"/>"#);
+        assert_eq!(removed, ["exif:GPSLatitude", "exif:GPSLongitude"]);
+    }
+
+    #[test]
+    fn test_remove_xmp_elements() {
+        let xmp = concat!(
+            "<rdf:Description>",
+            "<exif:GPSAltitude>12/1</exif:GPSAltitude>",
+            "<exif:GPSVersionID rdf:resource=\"2.2\"/>",
+            "<exif:ISO>100</exif:ISO>",
+            "</rdf:Description>",
+        );
+        let (scrubbed, removed) = remove_xmp_properties(xmp, "exif:GPS");
+        assert_eq!(scrubbed, "<rdf:Description><exif:ISO>100</exif:ISO></rdf:Description>");
+        assert_eq!(removed, ["exif:GPSAltitude", "exif:GPSVersionID"]);
+    }
+
+    #[test]
+    fn test_remove_xmp_nested_regions() {
+        let xmp = concat!(
+            "<rdf:Description>",
+            "<mwg-rs:Regions rdf:parseType=\"Resource\">",
+            "<mwg-rs:RegionList><rdf:Bag><rdf:li mwg-rs:Name=\"Alice\" mwg-rs:Type=\"Face\"/></rdf:Bag></mwg-rs:RegionList>",
+            "</mwg-rs:Regions>",
+            "<dc:title>Beach</dc:title>",
+            "</rdf:Description>",
+        );
+        let mut report = ScrubReport::default();
+        let scrubbed = scrub_xmp(xmp, &mut report);
+        assert_eq!(scrubbed, "<rdf:Description><dc:title>Beach</dc:title></rdf:Description>");
+        assert_eq!(report.removed, ["XMP mwg-rs:Regions"]);
+    }
+
+    #[test]
+    fn test_prefix_inside_values_is_kept() {
+        let xmp = r#"<rdf:Description dc:description="tiff:Make exif:GPSLatitude" xmp:Label="<tiff:Model>"/>"#;
+        let mut report = ScrubReport::default();
+        assert_eq!(scrub_xmp(xmp, &mut report), xmp);
+        assert!(report.removed.is_empty());
+
+        let xmp = "<dc:subject>exif:GPS data</dc:subject>";
+        assert_eq!(scrub_xmp(xmp, &mut report), xmp);
+        assert!(report.removed.is_empty());
+    }
+}
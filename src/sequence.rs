@@ -0,0 +1,205 @@
+//! Image sequences and bursts: frame extraction, the key photo, or a Motion JPEG AVI.
+//!
+//! Sequences stored as a video track (`msf1` files, `.heics`, whose frames live in a `moov`
+//! box) are read through the track's sample table, each frame wrapped into a single-image
+//! HEIF for libheif to decode. Otherwise the frames are the HEIC's top-level image items in
+//! file order, which is how bursts and item-based sequences are stored.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use libheif_rs::{HeifContext, ImageHandle, LibHeif};
+use toojpeg::MjpegAviWriter;
+use tracing::{debug, info_span};
+
+use crate::isobmff::Track;
+use crate::{encode_image_handle, is_heic_path, numbered_output_path, AlphaOptions, ConversionTiming, ConvertOptions, MetadataEdits};
+
+/// What `convert_heic_sequence` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceOutput {
+    /// Every `n`th frame, starting with the first, as `photo_1.jpg`, `photo_4.jpg`, … named
+    /// after the frame number. 1 exports all frames.
+    EveryNth(u32),
+    /// Only the key photo, the image viewers show for the sequence, to the path given
+    KeyPhoto,
+    /// All frames as one Motion JPEG AVI at `fps` frames per second, to the path given.
+    /// The frames have to be the same size, or be resized to one.
+    MotionJpeg {
+        /// Playback frame rate
+        fps: u32,
+    },
+}
+
+// Where the frames of a sequence come from
+enum Frames {
+    // Samples of a video track, read from the file as they are decoded
+    Track { file: File, track: Track },
+    // Top-level image items
+    Items(Vec<ImageHandle>),
+}
+
+impl Frames {
+    fn len(&self) -> usize {
+        match self {
+            Frames::Track { track, .. } => track.frame_count(),
+            Frames::Items(image_handles) => image_handles.len(),
+        }
+    }
+
+    // Coded size of a frame
+    fn size(&self, index: usize) -> (u32, u32) {
+        match self {
+            Frames::Track { track, .. } => (track.width, track.height),
+            Frames::Items(image_handles) => (image_handles[index].width(), image_handles[index].height()),
+        }
+    }
+
+    // Run `convert` on the image handle of a frame
+    fn with_frame<T>(
+        &mut self,
+        index: usize,
+        convert: impl FnOnce(&ImageHandle) -> Result<T, Box<dyn std::error::Error>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        match self {
+            Frames::Track { file, track } => {
+                let frame = track.frame_heif(file, index)?;
+                let context = HeifContext::read_from_bytes(&frame)?;
+                convert(&context.primary_image_handle()?)
+            }
+            Frames::Items(image_handles) => convert(&image_handles[index]),
+        }
+    }
+}
+
+/// Convert a HEIC image sequence or burst (`.heics` or `.heic`) to JPEG frames or a Motion
+/// JPEG AVI
+///
+/// Every frame is converted like `convert_heic_to_jpeg_with_options` converts a single image,
+/// with `options.images` ignored. Motion JPEG frames leave out gain maps, depth maps, EXIF,
+/// XMP and alpha sidecars, which players don't use. Returns the paths written.
+///
+/// Track frames predicted from other frames are decoded from the last sync frame on, which
+/// works for HEVC tracks without B-frames; other predicted frames fail to convert. The key
+/// photo of a sequence without a still image is its first frame.
+///
+/// # Arguments
+/// * `heic_path` - Path to input HEIC file
+/// * `output_path` - Path of the JPEG or AVI to write; numbered for `EveryNth`
+/// * `width` - Target frame width (0 to keep original)
+/// * `height` - Target frame height (0 to keep original)
+/// * `resize_filter` - Resize filter to use ("lanczos", "bilinear", "catmull_rom" or "mitchell")
+/// * `output` - What to write
+/// * `options` - Conversion options applied to each frame
+pub fn convert_heic_sequence(
+    heic_path: &str,
+    output_path: &str,
+    width: u32,
+    height: u32,
+    resize_filter: &str,
+    output: SequenceOutput,
+    options: &ConvertOptions,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let _span = info_span!("convert_heic_sequence", input = heic_path, ?output).entered();
+    if !is_heic_path(heic_path) {
+        return Err(anyhow!("Input is not a HEIC file: {}", heic_path).into());
+    }
+
+    let lib_heif = LibHeif::new();
+    let mut file = File::open(heic_path)?;
+    let track = Track::read(&mut file).map_err(|e| anyhow!("{}: {}", heic_path, e))?;
+    // Still images are items of the meta box, which a file holding only a track doesn't have
+    let context = HeifContext::read_from_file(heic_path);
+    let mut frames = match (track, &context) {
+        (Some(track), _) => {
+            debug!(frames = track.frame_count(), width = track.width, height = track.height, "reading sequence track");
+            Frames::Track { file, track }
+        }
+        (None, Ok(context)) => Frames::Items(context.top_level_image_handles()),
+        (None, Err(e)) => return Err(anyhow!("{}: {}", heic_path, e).into()),
+    };
+    let mut timing = ConversionTiming::default();
+
+    match output {
+        SequenceOutput::KeyPhoto => {
+            let mut convert = |image_handle: &ImageHandle| {
+                encode_image_handle(&lib_heif, image_handle, output_path, width, height, resize_filter, options, &mut timing)
+            };
+            let jpeg = match &context {
+                Ok(context) => convert(&context.primary_image_handle()?)?,
+                Err(_) => frames.with_frame(0, convert)?,
+            };
+            info_span!("write", bytes = jpeg.len()).in_scope(|| std::fs::write(output_path, jpeg))?;
+            Ok(vec![PathBuf::from(output_path)])
+        }
+        SequenceOutput::EveryNth(step) => {
+            if step == 0 {
+                return Err(anyhow!("Frame step must be at least 1").into());
+            }
+            let mut written = Vec::new();
+            for index in (0..frames.len()).step_by(step as usize) {
+                let path = numbered_output_path(output_path, index + 1);
+                let _span = info_span!("frame", number = index + 1, output = %path.display()).entered();
+                let jpeg = frames.with_frame(index, |image_handle| {
+                    encode_image_handle(
+                        &lib_heif,
+                        image_handle,
+                        &path.to_string_lossy(),
+                        width,
+                        height,
+                        resize_filter,
+                        options,
+                        &mut timing,
+                    )
+                })?;
+                info_span!("write", bytes = jpeg.len()).in_scope(|| std::fs::write(&path, jpeg))?;
+                written.push(path);
+            }
+            Ok(written)
+        }
+        SequenceOutput::MotionJpeg { fps } => {
+            let frame_options = ConvertOptions {
+                alpha: AlphaOptions { write_sidecar: false, ..options.alpha },
+                hdr_gain_map: false,
+                embed_depth: false,
+                preserve_metadata: false,
+                xmp_properties: Vec::new(),
+                metadata_edits: MetadataEdits::default(),
+                ..options.clone()
+            };
+            let frame_size = |index| if width == 0 || height == 0 { frames.size(index) } else { (width, height) };
+            if frames.len() == 0 {
+                return Err(anyhow!("HEIC has no frames").into());
+            }
+            let size = frame_size(0);
+            if (1..frames.len()).any(|index| frame_size(index) != size) {
+                return Err(anyhow!("Frames differ in size, give a target size for Motion JPEG").into());
+            }
+
+            // Frames are streamed into the file as they are encoded
+            let file = BufWriter::new(File::create(output_path)?);
+            let mut writer = MjpegAviWriter::new(file, size.0, size.1, fps).map_err(|e| anyhow!(e))?;
+            for index in 0..frames.len() {
+                let _span = info_span!("frame", number = index + 1).entered();
+                let jpeg = frames.with_frame(index, |image_handle| {
+                    encode_image_handle(
+                        &lib_heif,
+                        image_handle,
+                        output_path,
+                        width,
+                        height,
+                        resize_filter,
+                        &frame_options,
+                        &mut timing,
+                    )
+                })?;
+                writer.write_frame(&jpeg).map_err(|e| anyhow!(e))?;
+            }
+            writer.finish().map_err(|e| anyhow!(e))?;
+            debug!(frames = frames.len(), fps, "wrote Motion JPEG");
+            Ok(vec![PathBuf::from(output_path)])
+        }
+    }
+}
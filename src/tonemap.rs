@@ -0,0 +1,286 @@
+//! HDR to SDR tone mapping for PQ and HLG images.
+//!
+//! Samples are decoded to absolute luminance in nits, converted from BT.2020 to BT.709
+//! primaries, and their luminance is compressed to the target peak with the selected
+//! operator. RGB is scaled together so hues stay put, then encoded as sRGB.
+
+/// HDR transfer function of an image, from the `nclx` transfer characteristics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HdrTransfer {
+    /// SMPTE ST 2084 perceptual quantizer, absolute luminance up to 10000 nits (code 16)
+    Pq,
+    /// Hybrid log-gamma, relative scene light (code 18)
+    Hlg,
+}
+
+/// Curve that compresses HDR luminance into the SDR range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneMapOperator {
+    /// Extended Reinhard, `L (1 + L / Lw²) / (1 + L)`: simple, keeps shadows, flattens highlights
+    Reinhard,
+    /// John Hable's filmic curve from Uncharted 2, with a toe and a soft shoulder
+    Hable,
+    /// The ITU-R BT.2390 EETF: identity below a knee, a Hermite spline in PQ space above it
+    #[default]
+    Bt2390,
+}
+
+/// Tone mapping settings for PQ and HLG images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapOptions {
+    /// Curve used to compress highlights
+    pub operator: ToneMapOperator,
+    /// Luminance that becomes SDR white, 203 nits is the BT.2408 HDR reference white
+    pub target_peak_nits: f32,
+    /// Brightest luminance expected in the source: the mastering peak for PQ, the nominal
+    /// display peak for HLG
+    pub source_peak_nits: f32,
+}
+
+impl Default for ToneMapOptions {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapOperator::default(),
+            target_peak_nits: 203.0,
+            source_peak_nits: 1000.0,
+        }
+    }
+}
+
+// BT.2020 to BT.709 primaries, both with a D65 white point
+const BT2020_TO_BT709: [[f32; 3]; 3] = [
+    [1.6605, -0.5876, -0.0728],
+    [-0.1246, 1.1329, -0.0083],
+    [-0.0182, -0.1006, 1.1187],
+];
+
+/// Converts transfer-encoded HDR samples to sRGB-encoded SDR samples
+pub(crate) struct ToneMapper {
+    transfer: HdrTransfer,
+    bt2020: bool,
+    options: ToneMapOptions,
+    // BT.2390 works in PQ space relative to the source peak
+    source_pq: f32,
+    target_pq: f32,
+    hlg_gamma: f32,
+}
+
+impl ToneMapper {
+    pub(crate) fn new(transfer: HdrTransfer, bt2020: bool, options: &ToneMapOptions) -> Self {
+        let source_peak = options.source_peak_nits.max(1.0);
+        let target_peak = options.target_peak_nits.clamp(1.0, source_peak);
+        Self {
+            transfer,
+            bt2020,
+            options: ToneMapOptions { target_peak_nits: target_peak, source_peak_nits: source_peak, ..*options },
+            source_pq: pq_inverse_eotf(source_peak),
+            target_pq: pq_inverse_eotf(target_peak),
+            // BT.2100 system gamma for the nominal display peak
+            hlg_gamma: 1.2 + 0.42 * (source_peak / 1000.0).log10(),
+        }
+    }
+
+    /// Tone map interleaved RGB samples from 0.0 to 1.0 in place
+    pub(crate) fn apply(&self, samples: &mut [f32]) {
+        for pixel in samples.chunks_exact_mut(3) {
+            let mut rgb = self.to_nits([pixel[0], pixel[1], pixel[2]]);
+            if self.bt2020 {
+                rgb = BT2020_TO_BT709.map(|row| row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]);
+            }
+
+            let luminance = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+            let scale = if luminance > 0.0 {
+                self.map_luminance(luminance) / luminance / self.options.target_peak_nits
+            } else {
+                0.0
+            };
+            for (out, value) in pixel.iter_mut().zip(rgb) {
+                *out = srgb_oetf((value * scale).clamp(0.0, 1.0));
+            }
+        }
+    }
+
+    // Display light in nits for one pixel
+    fn to_nits(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match self.transfer {
+            HdrTransfer::Pq => rgb.map(pq_eotf),
+            HdrTransfer::Hlg => {
+                // Inverse OETF to scene light, then the OOTF for the nominal display
+                let scene = rgb.map(hlg_inverse_oetf);
+                let luminance = 0.2627 * scene[0] + 0.6780 * scene[1] + 0.0593 * scene[2];
+                let gain = self.options.source_peak_nits * luminance.max(0.0).powf(self.hlg_gamma - 1.0);
+                scene.map(|value| value * gain)
+            }
+        }
+    }
+
+    // Compress a luminance in nits to 0..=target peak
+    fn map_luminance(&self, nits: f32) -> f32 {
+        let target = self.options.target_peak_nits;
+        let white = self.options.source_peak_nits / target;
+        match self.options.operator {
+            ToneMapOperator::Reinhard => {
+                let x = nits / target;
+                (x * (1.0 + x / (white * white)) / (1.0 + x)).min(1.0) * target
+            }
+            ToneMapOperator::Hable => {
+                // The curve's usual exposure bias of 2
+                let x = (nits / target).min(white);
+                hable(2.0 * x) / hable(2.0 * white) * target
+            }
+            ToneMapOperator::Bt2390 => {
+                if self.target_pq >= self.source_pq {
+                    return nits.min(target);
+                }
+                let e1 = (pq_inverse_eotf(nits) / self.source_pq).min(1.0);
+                let max_luminance = self.target_pq / self.source_pq;
+                let knee = 1.5 * max_luminance - 0.5;
+                let e2 = if e1 < knee {
+                    e1
+                } else {
+                    let t = (e1 - knee) / (1.0 - knee);
+                    let (t2, t3) = (t * t, t * t * t);
+                    (2.0 * t3 - 3.0 * t2 + 1.0) * knee
+                        + (t3 - 2.0 * t2 + t) * (1.0 - knee)
+                        + (-2.0 * t3 + 3.0 * t2) * max_luminance
+                };
+                pq_eotf(e2 * self.source_pq).min(target)
+            }
+        }
+    }
+}
+
+// SMPTE ST 2084 constants
+const PQ_M1: f32 = 2610.0 / 16384.0;
+const PQ_M2: f32 = 2523.0 / 4096.0 * 128.0;
+const PQ_C1: f32 = 3424.0 / 4096.0;
+const PQ_C2: f32 = 2413.0 / 4096.0 * 32.0;
+const PQ_C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+// PQ signal (0..1) to nits
+fn pq_eotf(signal: f32) -> f32 {
+    let p = signal.clamp(0.0, 1.0).powf(1.0 / PQ_M2);
+    10000.0 * ((p - PQ_C1).max(0.0) / (PQ_C2 - PQ_C3 * p)).powf(1.0 / PQ_M1)
+}
+
+// Nits to PQ signal (0..1)
+fn pq_inverse_eotf(nits: f32) -> f32 {
+    let y = (nits / 10000.0).clamp(0.0, 1.0).powf(PQ_M1);
+    ((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y)).powf(PQ_M2)
+}
+
+// HLG signal (0..1) to relative scene light (0..1), BT.2100 table 5
+fn hlg_inverse_oetf(signal: f32) -> f32 {
+    const A: f32 = 0.17883277;
+    const B: f32 = 0.28466892;
+    const C: f32 = 0.5599107;
+    let signal = signal.clamp(0.0, 1.0);
+    if signal <= 0.5 {
+        signal * signal / 3.0
+    } else {
+        (((signal - C) / A).exp() + B) / 12.0
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+// Linear light (0..1) to the sRGB transfer curve
+fn srgb_oetf(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMapOperator; 3] =
+        [ToneMapOperator::Reinhard, ToneMapOperator::Hable, ToneMapOperator::Bt2390];
+
+    fn mapper(operator: ToneMapOperator, target_peak_nits: f32, source_peak_nits: f32) -> ToneMapper {
+        let options = ToneMapOptions { operator, target_peak_nits, source_peak_nits };
+        ToneMapper::new(HdrTransfer::Pq, false, &options)
+    }
+
+    #[test]
+    fn test_pq_round_trip() {
+        for nits in [0.0, 0.01, 1.0, 100.0, 203.0, 1000.0, 4000.0, 10000.0] {
+            let back = pq_eotf(pq_inverse_eotf(nits));
+            assert!((back - nits).abs() <= nits * 1e-3 + 1e-4, "{nits} nits came back as {back}");
+        }
+        assert_eq!(pq_inverse_eotf(10000.0), 1.0);
+        // Signals outside 0..1 are clamped rather than producing NaN
+        assert_eq!(pq_eotf(-0.5), 0.0);
+        assert_eq!(pq_eotf(1.5), pq_eotf(1.0));
+    }
+
+    #[test]
+    fn test_target_peak_is_clamped_to_source_peak() {
+        let brighter = mapper(ToneMapOperator::Bt2390, 2000.0, 1000.0);
+        assert_eq!(brighter.options.target_peak_nits, 1000.0);
+        // Nothing to compress, so BT.2390 passes luminance through up to the peak
+        assert_eq!(brighter.map_luminance(500.0), 500.0);
+        assert_eq!(brighter.map_luminance(1500.0), 1000.0);
+
+        let dark = mapper(ToneMapOperator::Reinhard, 0.0, 0.0);
+        assert_eq!(dark.options.target_peak_nits, 1.0);
+        assert_eq!(dark.options.source_peak_nits, 1.0);
+    }
+
+    #[test]
+    fn test_bt2390_is_identity_below_the_knee() {
+        let mapper = mapper(ToneMapOperator::Bt2390, 203.0, 1000.0);
+        let max_luminance = mapper.target_pq / mapper.source_pq;
+        let knee_nits = pq_eotf((1.5 * max_luminance - 0.5) * mapper.source_pq);
+        assert!(knee_nits > 0.0 && knee_nits < 203.0);
+
+        for nits in [0.5, 10.0, 50.0, knee_nits * 0.99] {
+            let mapped = mapper.map_luminance(nits);
+            assert!((mapped - nits).abs() <= nits * 1e-3, "{nits} nits mapped to {mapped}");
+        }
+        // Above the knee highlights are compressed, reaching the target at the source peak
+        assert!(mapper.map_luminance(knee_nits * 1.5) < knee_nits * 1.5);
+        assert!((mapper.map_luminance(1000.0) - 203.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_operators_are_monotonic_and_bounded() {
+        for operator in OPERATORS {
+            let mapper = mapper(operator, 203.0, 1000.0);
+            let mut previous = 0.0;
+            for step in 0..=2000 {
+                let nits = step as f32;
+                let mapped = mapper.map_luminance(nits);
+                assert!(mapped >= previous - 1e-3, "{operator:?} decreased at {nits} nits");
+                assert!(mapped <= 203.0, "{operator:?} exceeded the target at {nits} nits");
+                previous = mapped;
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_keeps_a_gray_ramp_ordered() {
+        for operator in OPERATORS {
+            let mapper = mapper(operator, 203.0, 1000.0);
+            let mut samples: Vec<f32> = (0..=100).flat_map(|step| [step as f32 / 100.0; 3]).collect();
+            mapper.apply(&mut samples);
+            for pair in samples.chunks_exact(3).collect::<Vec<_>>().windows(2) {
+                assert!(pair[1][0] >= pair[0][0], "{operator:?} output is not monotonic");
+                // Gray stays gray
+                assert!((pair[1][0] - pair[1][2]).abs() < 1e-5);
+            }
+            assert!(samples.iter().all(|&value| (0.0..=1.0).contains(&value)));
+        }
+    }
+}
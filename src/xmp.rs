@@ -0,0 +1,118 @@
+//! XMP metadata: reading it from HEICs and setting properties before it goes into the JPEG.
+//!
+//! HEICs store XMP (ratings, edit history, face regions) as a `mime` metadata item. Properties
+//! are edited on the serialized packet: an existing value is replaced where it is, a new one
+//! is added in an `rdf:Description` of its own, which XMP readers merge with the others.
+
+use libheif_rs::{HeifContext, ImageHandle};
+
+use crate::privacy::{scrub_xmp, ScrubReport};
+
+/// One simple XMP property to set on the output, such as `xmp:CreatorTool`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmpProperty {
+    /// Namespace URI, e.g. `http://ns.adobe.com/xap/1.0/`
+    pub namespace: String,
+    /// Prefix the namespace is bound to, e.g. `xmp`
+    pub prefix: String,
+    /// Property name without the prefix, e.g. `CreatorTool`
+    pub name: String,
+    /// Text value, escaped when written
+    pub value: String,
+}
+
+impl XmpProperty {
+    /// `xmp:CreatorTool`, the tool that produced the file
+    pub fn creator_tool(value: &str) -> Self {
+        Self {
+            namespace: "http://ns.adobe.com/xap/1.0/".to_string(),
+            prefix: "xmp".to_string(),
+            name: "CreatorTool".to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Read the XMP packet of a HEIC's primary image, `None` when it has none
+pub fn extract_xmp(heic_path: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let context = HeifContext::read_from_file(heic_path)?;
+    Ok(heif_xmp(&context.primary_image_handle()?))
+}
+
+/// XMP packet of an image, from its `application/rdf+xml` metadata item
+pub(crate) fn heif_xmp(image_handle: &ImageHandle) -> Option<String> {
+    let block = image_handle
+        .all_metadata()
+        .into_iter()
+        .find(|metadata| metadata.item_type.0 == *b"mime" && metadata.content_type == "application/rdf+xml")?;
+    let packet = String::from_utf8_lossy(&block.raw_data);
+    Some(packet.trim_end_matches('\0').to_string())
+}
+
+/// Set a property in an XMP packet, adding it when missing
+///
+/// The value replaces an existing attribute (`xmp:Rating="3"`) or simple element
+/// (`<xmp:Rating>3</xmp:Rating>`) of the same name. An empty `xmp` starts a new packet.
+pub fn set_xmp_property(xmp: &str, property: &XmpProperty) -> String {
+    let qualified = format!("{}:{}", property.prefix, property.name);
+    let value = escape(&property.value);
+
+    let attribute = format!("{}=\"", qualified);
+    let attribute_at = xmp
+        .match_indices(&attribute)
+        .find(|(at, _)| xmp[..*at].ends_with(|c: char| c.is_ascii_whitespace()))
+        .map(|(at, _)| at + attribute.len());
+    if let Some(start) = attribute_at {
+        if let Some(end) = xmp[start..].find('"').map(|end| start + end) {
+            return [&xmp[..start], &value, &xmp[end..]].concat();
+        }
+    }
+    let (open, close) = (format!("<{}>", qualified), format!("</{}>", qualified));
+    if let Some(start) = xmp.find(&open).map(|at| at + open.len()) {
+        if let Some(end) = xmp[start..].find(&close).map(|end| start + end) {
+            return [&xmp[..start], &value, &xmp[end..]].concat();
+        }
+    }
+
+    let description = format!(
+        r#"<rdf:Description rdf:about="" xmlns:{}="{}" {}="{}"/>"#,
+        property.prefix,
+        escape(&property.namespace),
+        qualified,
+        value
+    );
+    match xmp.rfind("</rdf:RDF>") {
+        Some(end) => [&xmp[..end], &description, &xmp[end..]].concat(),
+        None => format!(
+            concat!(
+                r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+                r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">{}</rdf:RDF>"#,
+                r#"</x:xmpmeta>"#,
+            ),
+            description
+        ),
+    }
+}
+
+/// XMP for the converted JPEG: the image's own with `ConvertOptions::preserve_metadata`,
+/// scrubbed into `report` when `scrub` is set, plus `ConvertOptions::xmp_properties`
+pub(crate) fn output_xmp(
+    image_handle: &ImageHandle,
+    preserve: bool,
+    properties: &[XmpProperty],
+    scrub: Option<&mut ScrubReport>,
+) -> Option<String> {
+    let mut xmp = if preserve { heif_xmp(image_handle) } else { None };
+    if let (Some(report), Some(packet)) = (scrub, xmp.as_deref()) {
+        xmp = Some(scrub_xmp(packet, report));
+    }
+    if properties.is_empty() {
+        return xmp;
+    }
+    Some(properties.iter().fold(xmp.unwrap_or_default(), |xmp, property| set_xmp_property(&xmp, property)))
+}
+
+// Escape text for an XML attribute or element
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}